@@ -1,8 +1,11 @@
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+
 mod utils;
 mod serialize;
 
 pub mod script;
 pub mod net;
+pub mod transaction;
 
 #[cfg(test)]
 mod test;
@@ -16,6 +19,9 @@ extern crate mio;
 extern crate bytes;
 extern crate rand;
 
+#[cfg(feature = "mmap")]
+extern crate memmap;
+
 use std::net::SocketAddr;
 
 use utils::Config;
@@ -24,6 +30,12 @@ pub fn main() {
     let config = Config::from_command_line().unwrap_or_else(
         |e| { println!("Error: {}", e); panic!() });
 
-    let addr: SocketAddr = format!("0.0.0.0:{}", config.port).parse().unwrap();
-    net::p2pclient::start(addr, config.connect_to, config.blocks_file);
+    let listen = if config.listen {
+        Some(SocketAddr::new(config.listen_address, config.port))
+    } else {
+        None
+    };
+
+    net::p2pclient::start(listen, config.connect_to, config.local_address(), config.data_dir,
+                          config.blocks_file, config.network, config.sync_window);
 }
@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+
+use super::messages::{BitcoinHash, BlockMessage, CFilterMessage, OutPoint, SerializeHash};
+use super::utxo::UtxoSet;
+use serialize::{Serialize, Deserialize, VarInt};
+use utils::CryptoUtils;
+
+// BIP158 basic filter: Golomb-Rice coded set of the scripts spent and
+// created by a block, light clients use it to find the blocks they need.
+pub const BASIC_FILTER_TYPE: u8 = 0x00;
+// Golomb-Rice parameter and inverse false positive rate
+const BASIC_FILTER_P: u8 = 19;
+const BASIC_FILTER_M: u64 = 784931;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockFilter {
+    // SipHash key, the first 16 bytes of the block hash
+    k0: u64,
+    k1: u64,
+    n: u64,
+    // Serialized filter: the number of elements followed by the coded set
+    data: Vec<u8>,
+}
+
+impl BlockFilter {
+    pub fn new(block_hash: &BitcoinHash, elements: &[Vec<u8>]) -> BlockFilter {
+        let (k0, k1) = siphash_key(block_hash);
+        let n = elements.len() as u64;
+
+        let mut values: Vec<u64> = elements.iter()
+            .map(|element| hash_to_range(siphash(k0, k1, element), n * BASIC_FILTER_M))
+            .collect();
+        values.sort();
+
+        let mut data = vec![];
+        VarInt::new(n).serialize(&mut data);
+
+        let mut writer = BitWriter::new(data);
+        let mut last = 0;
+        for value in values {
+            let delta = value - last;
+            writer.write_unary(delta >> BASIC_FILTER_P);
+            writer.write(delta, BASIC_FILTER_P);
+            last = value;
+        }
+
+        BlockFilter {
+            k0: k0,
+            k1: k1,
+            n: n,
+            data: writer.finish(),
+        }
+    }
+
+    // Filter of the scripts of the outputs created by `block` and of the ones
+    // it spends. `utxos` must be the set before connecting the block.
+    pub fn basic(block: &BlockMessage, utxos: &UtxoSet) -> Result<BlockFilter, String> {
+        let mut created = HashMap::new();
+        let mut elements = vec![];
+
+        for tx in &block.txns {
+            if !tx.is_coinbase() {
+                for input in &tx.tx_in {
+                    let output = &input.previous_output;
+                    let script = match utxos.get(output) {
+                        Some(entry) => &entry.script,
+                        None => match created.get(output) {
+                            Some(script) => *script,
+                            None => return Err(format!("Missing or spent output {:?}", output)),
+                        },
+                    };
+
+                    elements.push(script.clone());
+                }
+            }
+
+            let hash = tx.hash();
+            for (index, output) in tx.tx_out.iter().enumerate() {
+                created.insert(OutPoint::new(hash, index as u32), &output.pk_script);
+
+                // Data carrier outputs can't be spent, nobody looks for them
+                if output.pk_script.first().map_or(false, |op| *op != 0x6a) {
+                    elements.push(output.pk_script.clone());
+                }
+            }
+        }
+
+        elements.retain(|script| !script.is_empty());
+        elements.sort();
+        elements.dedup();
+
+        Ok(BlockFilter::new(&block.hash(), &elements))
+    }
+
+    pub fn from_bytes(block_hash: &BitcoinHash, data: Vec<u8>) -> Result<BlockFilter, String> {
+        let n = try!(VarInt::deserialize(&mut Cursor::new(&data[..]))).as_u64();
+        if n.checked_mul(BASIC_FILTER_M).is_none() {
+            return Err(format!("Too many elements in the filter: {}", n));
+        }
+
+        let (k0, k1) = siphash_key(block_hash);
+
+        Ok(BlockFilter {
+            k0: k0,
+            k1: k1,
+            n: n,
+            data: data,
+        })
+    }
+
+    pub fn from_message(message: CFilterMessage) -> Result<BlockFilter, String> {
+        if message.filter_type != BASIC_FILTER_TYPE {
+            return Err(format!("Unknown filter type {}", message.filter_type));
+        }
+
+        BlockFilter::from_bytes(&message.block_hash, message.filter)
+    }
+
+    pub fn data(&self) -> &[u8] { &self.data }
+
+    // Number of elements in the filter
+    pub fn len(&self) -> u64 { self.n }
+
+    // Header committing to this filter and, through `previous_header`, to
+    // the filters of every previous block
+    pub fn header(&self, previous_header: &BitcoinHash) -> BitcoinHash {
+        let mut data = CryptoUtils::sha256(&CryptoUtils::sha256(&self.data)).to_vec();
+        data.extend(previous_header.inner());
+        BitcoinHash::new(CryptoUtils::sha256(&CryptoUtils::sha256(&data)))
+    }
+
+    // False positives happen once every BASIC_FILTER_M elements
+    pub fn contains(&self, element: &[u8]) -> bool {
+        let target = hash_to_range(siphash(self.k0, self.k1, element),
+                                   self.n * BASIC_FILTER_M);
+
+        let mut reader = BitReader::new(&self.data[VarInt::new(self.n).encoded_len()..]);
+        let mut value = 0;
+        for _ in 0..self.n {
+            let quotient = match reader.read_unary() {
+                Some(quotient) => quotient,
+                None => return false,
+            };
+            let remainder = match reader.read(BASIC_FILTER_P) {
+                Some(remainder) => remainder,
+                None => return false,
+            };
+
+            value += quotient << BASIC_FILTER_P | remainder;
+            if value >= target {
+                return value == target;
+            }
+        }
+
+        false
+    }
+}
+
+fn siphash_key(block_hash: &BitcoinHash) -> (u64, u64) {
+    let read_u64 = |data: &[u8]| data.iter().rev().fold(0, |k, &byte| k << 8 | byte as u64);
+    (read_u64(&block_hash[..8]), read_u64(&block_hash[8..16]))
+}
+
+// Maps `hash` uniformly to [0, range)
+fn hash_to_range(hash: u64, range: u64) -> u64 {
+    ((hash as u128 * range as u128) >> 64) as u64
+}
+
+// SipHash-2-4, the hash function used by BIP158
+fn siphash(k0: u64, k1: u64, data: &[u8]) -> u64 {
+    let mut v = [k0 ^ 0x736f6d6570736575, k1 ^ 0x646f72616e646f6d,
+                 k0 ^ 0x6c7967656e657261, k1 ^ 0x7465646279746573];
+
+    let round = |v: &mut [u64; 4]| {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    };
+
+    let mut blocks = data.chunks(8);
+    for block in blocks.by_ref().take(data.len() / 8) {
+        let m = block.iter().rev().fold(0, |m, &byte| m << 8 | byte as u64);
+        v[3] ^= m;
+        round(&mut v);
+        round(&mut v);
+        v[0] ^= m;
+    }
+
+    // The last block holds the remaining bytes and the length
+    let tail = blocks.next().unwrap_or(&[]);
+    let m = tail.iter().rev().fold(0, |m, &byte| m << 8 | byte as u64) |
+            (data.len() as u64) << 56;
+    v[3] ^= m;
+    round(&mut v);
+    round(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        round(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+// Most significant bits first, like BIP158 expects
+struct BitWriter {
+    data: Vec<u8>,
+    // Bits used in the last byte, 0 when a new byte is needed
+    used: u8,
+}
+
+impl BitWriter {
+    fn new(data: Vec<u8>) -> BitWriter {
+        BitWriter {
+            data: data,
+            used: 0,
+        }
+    }
+
+    fn write_bit(&mut self, bit: bool) {
+        if self.used == 0 {
+            self.data.push(0);
+        }
+
+        if bit {
+            *self.data.last_mut().unwrap() |= 0x80 >> self.used;
+        }
+        self.used = (self.used + 1) % 8;
+    }
+
+    fn write(&mut self, value: u64, bits: u8) {
+        for i in (0..bits).rev() {
+            self.write_bit((value >> i) & 1 == 1);
+        }
+    }
+
+    // `value` ones followed by a zero
+    fn write_unary(&mut self, value: u64) {
+        for _ in 0..value {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+    }
+
+    fn finish(self) -> Vec<u8> { self.data }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader {
+            data: data,
+            position: 0,
+        }
+    }
+
+    fn read_bit(&mut self) -> Option<bool> {
+        let byte = match self.data.get(self.position / 8) {
+            Some(byte) => *byte,
+            None => return None,
+        };
+
+        let bit = byte & (0x80 >> (self.position % 8)) != 0;
+        self.position += 1;
+        Some(bit)
+    }
+
+    fn read(&mut self, bits: u8) -> Option<u64> {
+        let mut value = 0;
+        for _ in 0..bits {
+            match self.read_bit() {
+                Some(bit) => value = value << 1 | bit as u64,
+                None => return None,
+            }
+        }
+
+        Some(value)
+    }
+
+    fn read_unary(&mut self) -> Option<u64> {
+        let mut value = 0;
+        loop {
+            match self.read_bit() {
+                Some(true) => value += 1,
+                Some(false) => return Some(value),
+                None => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use net::messages::{TxIn, TxMessage, TxOut};
+    use net::store::BlockStore;
+    use transaction::Amount;
+
+    use rustc_serialize::hex::{FromHex, ToHex};
+
+    #[test]
+    fn test_siphash() {
+        // Vectors from the SipHash paper, key 00..0f
+        let (k0, k1) = (0x0706050403020100, 0x0f0e0d0c0b0a0908);
+        assert_eq!(siphash(k0, k1, &[]), 0x726fdb47dd0e0e31);
+
+        let data: Vec<u8> = (0..15).collect();
+        assert_eq!(siphash(k0, k1, &data), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn test_genesis_filter() {
+        // First BIP158 test vector, the testnet genesis block
+        let genesis = BlockStore::genesis_block(1296688602, 414098458);
+        let filter = BlockFilter::basic(&genesis, &UtxoSet::new()).unwrap();
+
+        assert_eq!(filter.data().to_hex(), "019dfca8");
+        assert_eq!(format!("{:?}", filter.header(&BitcoinHash::new([0; 32]))),
+                   "21584579B7EB08997773E5AEFF3A7F932700042D0ED2A6129012B7D7AE81B750");
+
+        assert!(filter.contains(&genesis.txns[0].tx_out[0].pk_script));
+        assert!(!filter.contains(&[0x51]));
+
+        let parsed = BlockFilter::from_message(
+            CFilterMessage::new(BASIC_FILTER_TYPE, genesis.hash(), vec![0x01, 0x9d, 0xfc, 0xa8]))
+            .unwrap();
+        assert_eq!(parsed, filter);
+        assert!(BlockFilter::from_message(
+            CFilterMessage::new(0x01, genesis.hash(), filter.data().to_vec())).is_err());
+    }
+
+    #[test]
+    fn test_basic_filter() {
+        let genesis = BlockStore::genesis_block(1296688602, 414098458);
+        let mut utxos = UtxoSet::new();
+        utxos.connect_block(&genesis, 0).unwrap();
+
+        let pay_to_hash = "76a914ffffffffffffffffffffffffffffffffffffffff88ac".from_hex().unwrap();
+        let coinbase_script = "51".from_hex().unwrap();
+        let null_data = "6a0401020304".from_hex().unwrap();
+
+        let input = |hash, index| TxIn::new(OutPoint::new(hash, index), vec![], 0xffffffff);
+        let output = |value, script: &Vec<u8>| TxOut::new(Amount::from_satoshis(value),
+                                                          script.clone());
+
+        let coinbase = TxMessage::new(1, vec![input(BitcoinHash::new([0; 32]), 0xffffffff)],
+                                      vec![output(50, &coinbase_script)], 0);
+        let spend = TxMessage::new(1, vec![input(genesis.txns[0].hash(), 0)],
+                                   vec![output(10, &pay_to_hash), output(0, &null_data)], 0);
+        // Spends an output created earlier in the same block
+        let chained = TxMessage::new(1, vec![input(spend.hash(), 0)],
+                                     vec![output(5, &pay_to_hash)], 0);
+
+        let mut block = genesis.clone();
+        block.metadata.prev_block = genesis.hash();
+        block.txns = vec![coinbase, spend, chained];
+
+        let filter = BlockFilter::basic(&block, &utxos).unwrap();
+        // The genesis output, the coinbase output and the P2PKH script once
+        assert_eq!(filter.len(), 3);
+        assert!(filter.contains(&genesis.txns[0].tx_out[0].pk_script));
+        assert!(filter.contains(&coinbase_script));
+        assert!(filter.contains(&pay_to_hash));
+        assert!(!filter.contains(&null_data));
+
+        // The filter only depends on its bytes and the block hash
+        let parsed = BlockFilter::from_bytes(&block.hash(), filter.data().to_vec()).unwrap();
+        assert!(parsed.contains(&pay_to_hash));
+
+        assert!(BlockFilter::basic(&block, &UtxoSet::new()).is_err());
+    }
+
+    #[test]
+    fn test_filter_overflow() {
+        let mut data = vec![];
+        VarInt::new(u64::max_value() / BASIC_FILTER_M + 1).serialize(&mut data);
+        assert!(BlockFilter::from_bytes(&BitcoinHash::new([0; 32]), data).is_err());
+    }
+}
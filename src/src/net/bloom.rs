@@ -0,0 +1,170 @@
+use super::messages::{FilterLoadMessage, TxMessage, SerializeHash};
+use script::BitcoinScript;
+use serialize::Serialize;
+
+// Limits from BIP37, bigger filters are rejected
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36000;
+pub const MAX_HASH_FUNCS: u32 = 50;
+// filteradd data can't be bigger than a script element
+pub const MAX_FILTERADD_SIZE: usize = 520;
+
+// BIP37 bloom filter set by a peer to only receive the transactions it
+// cares about
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    data: Vec<u8>,
+    n_hash_funcs: u32,
+    n_tweak: u32,
+}
+
+impl BloomFilter {
+    pub fn new(data: Vec<u8>, n_hash_funcs: u32, n_tweak: u32) -> Result<BloomFilter, String> {
+        if data.len() > MAX_BLOOM_FILTER_SIZE {
+            return Err(format!("Bloom filter too large, size={}", data.len()));
+        }
+
+        if n_hash_funcs > MAX_HASH_FUNCS {
+            return Err(format!("Too many bloom filter hash functions, n={}", n_hash_funcs));
+        }
+
+        Ok(BloomFilter {
+            data: data,
+            n_hash_funcs: n_hash_funcs,
+            n_tweak: n_tweak,
+        })
+    }
+
+    pub fn from_message(message: FilterLoadMessage) -> Result<BloomFilter, String> {
+        BloomFilter::new(message.filter, message.n_hash_funcs, message.n_tweak)
+    }
+
+    fn bit(&self, hash_num: u32, item: &[u8]) -> usize {
+        let seed = hash_num.wrapping_mul(0xfba4c795).wrapping_add(self.n_tweak);
+        murmur3(seed, item) as usize % (self.data.len() * 8)
+    }
+
+    pub fn insert(&mut self, item: &[u8]) {
+        if self.data.is_empty() {
+            return;
+        }
+
+        for i in 0..self.n_hash_funcs {
+            let bit = self.bit(i, item);
+            self.data[bit >> 3] |= 1 << (bit & 7);
+        }
+    }
+
+    pub fn contains(&self, item: &[u8]) -> bool {
+        if self.data.is_empty() {
+            return false;
+        }
+
+        (0..self.n_hash_funcs)
+            .map(|i| self.bit(i, item))
+            .all(|bit| self.data[bit >> 3] & (1 << (bit & 7)) != 0)
+    }
+
+    fn contains_push(&self, script: &[u8]) -> bool {
+        BitcoinScript::new(script.to_vec()).iter()
+            .filter_map(|(_, _, data)| data)
+            .any(|data| !data.is_empty() && self.contains(&data))
+    }
+
+    // A transaction matches if the filter contains its hash, any data pushed
+    // by its scripts or any of the outpoints it spends. The filter is never
+    // updated with the matched outputs.
+    pub fn matches_tx(&self, tx: &TxMessage) -> bool {
+        if self.contains(tx.hash().inner()) {
+            return true;
+        }
+
+        if tx.tx_out.iter().any(|output| self.contains_push(&output.pk_script)) {
+            return true;
+        }
+
+        tx.tx_in.iter().any(|input| {
+            let mut outpoint = vec![];
+            input.previous_output.serialize(&mut outpoint);
+            self.contains(&outpoint) || self.contains_push(&input.script)
+        })
+    }
+}
+
+// 32 bit MurmurHash3, the hash function used by BIP37
+fn murmur3(seed: u32, data: &[u8]) -> u32 {
+    const C1: u32 = 0xcc9e2d51;
+    const C2: u32 = 0x1b873593;
+
+    let mix = |k: u32| k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+
+    let mut h1 = seed;
+    let mut blocks = data.chunks(4);
+    for block in blocks.by_ref().take(data.len() / 4) {
+        let k1 = block[0] as u32 | (block[1] as u32) << 8 |
+                 (block[2] as u32) << 16 | (block[3] as u32) << 24;
+        h1 ^= mix(k1);
+        h1 = h1.rotate_left(13).wrapping_mul(5).wrapping_add(0xe6546b64);
+    }
+
+    if let Some(tail) = blocks.next() {
+        let k1 = tail.iter().rev().fold(0, |k, &byte| k << 8 | byte as u32);
+        h1 ^= mix(k1);
+    }
+
+    h1 ^= data.len() as u32;
+    h1 ^= h1 >> 16;
+    h1 = h1.wrapping_mul(0x85ebca6b);
+    h1 ^= h1 >> 13;
+    h1 = h1.wrapping_mul(0xc2b2ae35);
+    h1 ^ (h1 >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustc_serialize::hex::FromHex;
+
+    #[test]
+    fn test_murmur3() {
+        // Vectors from the official client
+        assert_eq!(murmur3(0x00000000, &[]), 0x00000000);
+        assert_eq!(murmur3(0xfba4c795, &[]), 0x6a396f08);
+        assert_eq!(murmur3(0xffffffff, &[]), 0x81f16f39);
+        assert_eq!(murmur3(0x00000000, &[0x00]), 0x514e28b7);
+        assert_eq!(murmur3(0xfba4c795, &[0x00]), 0xea3f0b17);
+        assert_eq!(murmur3(0x00000000, &[0xff]), 0xfd6cf10d);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11]), 0x16c6b7ab);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11, 0x22]), 0x8eb51c3d);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11, 0x22, 0x33]), 0xb4471bf8);
+        assert_eq!(murmur3(0x00000000, &[0x00, 0x11, 0x22, 0x33, 0x44]), 0xe2301fa8);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        // Same filter as the official client tests: 3 elements, 1% false
+        // positives, no tweak
+        let mut filter = BloomFilter::new(vec![0; 3], 5, 0).unwrap();
+
+        let first = "99108ad8ed9bb6274d3980bab5a85c048f0950c8".from_hex().unwrap();
+        filter.insert(&first);
+        assert!(filter.contains(&first));
+        assert!(!filter.contains(&"19108ad8ed9bb6274d3980bab5a85c048f0950c8".from_hex().unwrap()));
+
+        filter.insert(&"b5a2c786d9ef4658287ced5914b37a1b4aa32eee".from_hex().unwrap());
+        filter.insert(&"b9300670b4c5366e95b2699e8b18bc75e5f729c5".from_hex().unwrap());
+        assert_eq!(filter.data, vec![0x61, 0x4e, 0x9b]);
+    }
+
+    #[test]
+    fn test_filter_limits() {
+        assert!(BloomFilter::new(vec![0; MAX_BLOOM_FILTER_SIZE], MAX_HASH_FUNCS, 0).is_ok());
+        assert!(BloomFilter::new(vec![0; MAX_BLOOM_FILTER_SIZE + 1], 1, 0).is_err());
+        assert!(BloomFilter::new(vec![0; 1], MAX_HASH_FUNCS + 1, 0).is_err());
+
+        // An empty filter matches nothing
+        let mut filter = BloomFilter::new(vec![], 5, 0).unwrap();
+        filter.insert(&[0x01]);
+        assert!(!filter.contains(&[0x01]));
+    }
+}
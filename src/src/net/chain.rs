@@ -0,0 +1,232 @@
+use std::sync::{Arc, Mutex};
+
+use super::messages::{BlockMessage, BitcoinHash, NetworkType, SerializeHash};
+use super::store::BlockStore;
+use super::utxo::{SpentIndex, UtxoSet};
+use super::validation::{coinbase_height_prefix, validate_block};
+
+use script::{ScriptCache, ScriptFlags};
+
+// Number of parsed scripts kept between blocks
+const SCRIPT_CACHE_SIZE: usize = 10000;
+
+// First block enforcing each soft fork. Only BIP16, BIP34 and BIP66 are
+// listed: CLTV (BIP65), CSV (BIP68/112/113) and segwit (BIP141) need the
+// spending transaction or its witness, which scripts don't get. Blocks past
+// their activation are only held to the older rules.
+struct Deployments {
+    bip16: usize,
+    bip34: usize,
+    bip66: usize,
+}
+
+fn deployments(network_type: NetworkType) -> Deployments {
+    match network_type {
+        NetworkType::Main => Deployments {
+            bip16: 173805,
+            bip34: 227931,
+            bip66: 363725,
+        },
+        NetworkType::TestNet3 => Deployments {
+            // Block 514 is the only one breaking the P2SH rules
+            bip16: 515,
+            bip34: 21111,
+            bip66: 330776,
+        },
+        // We don't know the history of the other networks, nothing is enforced
+        NetworkType::TestNet | NetworkType::NameCoin | NetworkType::Unknown => Deployments {
+            bip16: usize::MAX,
+            bip34: usize::MAX,
+            bip66: usize::MAX,
+        },
+    }
+}
+
+// Script flags for the blocks at `height`
+pub fn script_flags(network_type: NetworkType, height: usize) -> ScriptFlags {
+    let deployments = deployments(network_type);
+
+    ScriptFlags {
+        p2sh: height >= deployments.bip16,
+        dersig: height >= deployments.bip66,
+        ..ScriptFlags::default()
+    }
+}
+
+// A chain of validated blocks and the outputs it leaves unspent. Blocks are
+// validated against the UTXO set before they are stored and must extend the
+// tip, forks are rejected. The p2p client doesn't use it yet, its State
+// stores every block it receives without validating it.
+pub struct ChainState {
+    store: BlockStore,
+    utxo: UtxoSet,
+    spent: SpentIndex,
+    network_type: NetworkType,
+    checksig: fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool,
+    cache: Arc<Mutex<ScriptCache>>,
+}
+
+impl ChainState {
+    // The UTXO set is not saved on disk, it's rebuilt from the blocks in
+    // `store`. They were validated when they were stored so only the
+    // outputs are replayed.
+    pub fn new(store: BlockStore, network_type: NetworkType,
+               checksig: fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool) -> Result<ChainState, String> {
+        let mut chain = ChainState {
+            store: store,
+            utxo: UtxoSet::new(),
+            spent: SpentIndex::new(),
+            network_type: network_type,
+            checksig: checksig,
+            cache: Arc::new(Mutex::new(ScriptCache::new(SCRIPT_CACHE_SIZE))),
+        };
+
+        // The outputs of the genesis block can't be spent
+        for height in 1..chain.store.height() + 1 {
+            let block = chain.store.get_hash_at_height(height).cloned()
+                .and_then(|hash| chain.store.get(&hash));
+            let block = match block {
+                Some(block) => block,
+                None => return Err(format!("Block at height {} is missing", height)),
+            };
+
+            try!(chain.utxo.connect_block(&block, height));
+            chain.spent.connect_block(&block, height);
+        }
+
+        Ok(chain)
+    }
+
+    pub fn store(&self) -> &BlockStore { &self.store }
+
+    pub fn utxo(&self) -> &UtxoSet { &self.utxo }
+
+    pub fn spent(&self) -> &SpentIndex { &self.spent }
+
+    pub fn tip(&self) -> (BitcoinHash, usize) { self.store.tip() }
+
+    // Validates `block` and makes it the new tip. If anything fails
+    // neither the store nor the UTXO set are modified.
+    pub fn connect_block(&mut self, block: BlockMessage) -> Result<(), String> {
+        let (tip, height) = self.store.tip();
+        let height = height + 1;
+
+        if block.metadata.prev_block != tip {
+            return Err(format!("Block doesn't extend the tip {:?}", tip));
+        }
+
+        let (data, hash) = block.serialize_hash();
+        if self.store.has(&hash) {
+            return Err(format!("Block {:?} is already stored", hash));
+        }
+
+        let flags = script_flags(self.network_type, height);
+        try!(validate_block(&block, &self.utxo, height, flags, self.checksig,
+                            Some(self.cache.clone())));
+
+        if height >= deployments(self.network_type).bip34 &&
+           !block.txns[0].tx_in[0].script.starts_with(&coinbase_height_prefix(height)) {
+            return Err(format!("The coinbase of {:?} doesn't start with the height", hash));
+        }
+
+        // Doesn't touch the set if it fails
+        try!(self.utxo.connect_block(&block, height));
+        self.spent.connect_block(&block, height);
+        self.store.insert(block, &hash, &data);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::messages::{BitcoinHash, NetworkType, OutPoint, SerializeHash};
+    use super::super::store::BlockStore;
+
+    use test::utils::{get_coinbase, get_file, get_next_block, get_tx, mock_checksig};
+
+    #[test]
+    fn test_connect_block() {
+        let store = BlockStore::new(get_file("chain_state"), NetworkType::TestNet3);
+        let mut chain = ChainState::new(store, NetworkType::TestNet3, mock_checksig).unwrap();
+        let (genesis, _) = chain.tip();
+
+        let first = get_next_block(genesis, vec![get_coinbase(1)]);
+        let first_hash = first.hash();
+        assert_eq!(chain.connect_block(first.clone()), Ok(()));
+        assert_eq!(chain.tip(), (first_hash, 1));
+
+        let second = get_next_block(first_hash, vec![get_coinbase(2)]);
+        let second_hash = second.hash();
+        assert_eq!(chain.connect_block(second.clone()), Ok(()));
+        assert_eq!(chain.tip(), (second_hash, 2));
+
+        assert_eq!(chain.utxo().len(), 2);
+        let output = chain.utxo().get(&OutPoint::new(first.txns[0].hash(), 0)).unwrap();
+        assert_eq!(output.height, 1);
+        assert!(output.coinbase);
+        assert!(chain.utxo().contains(&OutPoint::new(second.txns[0].hash(), 0)));
+
+        // Not on top of the tip
+        assert!(chain.connect_block(get_next_block(first_hash, vec![get_coinbase(3)])).is_err());
+
+        // Spends an output that doesn't exist, nothing changes
+        let mut spend = get_coinbase(3);
+        spend.tx_in[0].previous_output = OutPoint::new(BitcoinHash::new([1; 32]), 0);
+        let invalid = get_next_block(second_hash, vec![get_coinbase(3), spend]);
+        assert!(chain.connect_block(invalid.clone()).is_err());
+        assert!(!chain.store().has(&invalid.hash()));
+        assert_eq!(chain.tip(), (second_hash, 2));
+        assert_eq!(chain.utxo().len(), 2);
+        assert_eq!(chain.spent().len(), 0);
+    }
+
+    #[test]
+    fn test_reload() {
+        let mut store = BlockStore::new(get_file("chain_state_reload"), NetworkType::TestNet3);
+        let (genesis, _) = store.tip();
+
+        let first = get_next_block(genesis, vec![get_coinbase(1)]);
+        let (data, first_hash) = first.serialize_hash();
+        store.insert(first.clone(), &first_hash, &data);
+
+        let spend = get_tx(vec![OutPoint::new(first.txns[0].hash(), 0)], vec![1000]);
+        let second = get_next_block(first_hash, vec![get_coinbase(2), spend.clone()]);
+        let (data, second_hash) = second.serialize_hash();
+        store.insert(second, &second_hash, &data);
+
+        // Outputs of blocks stored before are known again
+        let chain = ChainState::new(store, NetworkType::TestNet3, mock_checksig).unwrap();
+        assert_eq!(chain.tip(), (second_hash, 2));
+        assert_eq!(chain.utxo().len(), 2);
+        assert!(!chain.utxo().contains(&OutPoint::new(first.txns[0].hash(), 0)));
+        assert!(chain.utxo().contains(&OutPoint::new(spend.hash(), 0)));
+        assert_eq!(chain.spent().len(), 1);
+    }
+
+    #[test]
+    fn test_script_flags() {
+        assert_eq!(script_flags(NetworkType::Main, 0), ScriptFlags::default());
+        assert!(!script_flags(NetworkType::Main, 173804).p2sh);
+        assert!(script_flags(NetworkType::Main, 173805).p2sh);
+        assert!(script_flags(NetworkType::Main, 173806).p2sh);
+        assert!(!script_flags(NetworkType::Main, 173805).dersig);
+
+        // Testnet activated everything earlier
+        assert!(!script_flags(NetworkType::TestNet3, 514).p2sh);
+        assert!(script_flags(NetworkType::TestNet3, 515).p2sh);
+        assert!(!script_flags(NetworkType::Main, 515).p2sh);
+
+        assert!(!script_flags(NetworkType::Main, 363724).dersig);
+        let bip66 = script_flags(NetworkType::Main, 363725);
+        assert!(bip66.p2sh && bip66.dersig);
+        assert!(!script_flags(NetworkType::TestNet3, 330775).dersig);
+        assert!(script_flags(NetworkType::TestNet3, 330776).dersig);
+
+        for &network_type in &[NetworkType::TestNet, NetworkType::NameCoin,
+                               NetworkType::Unknown] {
+            assert_eq!(script_flags(network_type, 1000000), ScriptFlags::default());
+        }
+    }
+}
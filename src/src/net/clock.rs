@@ -0,0 +1,53 @@
+use std::fmt;
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::Mutex;
+
+use time::SteadyTime;
+#[cfg(test)]
+use time::Duration;
+
+// Source of monotonic time for timeouts and ping measurements, injectable
+// so tests can move time forward without sleeping
+pub trait Clock: fmt::Debug + Send + Sync {
+    fn now(&self) -> SteadyTime;
+}
+
+pub type SharedClock = Arc<Clock>;
+
+#[derive(Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SteadyTime { SteadyTime::now() }
+}
+
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+// Clock that only moves when told to
+#[cfg(test)]
+#[derive(Debug)]
+pub struct MockClock {
+    now: Mutex<SteadyTime>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub fn new() -> Arc<MockClock> {
+        Arc::new(MockClock {
+            now: Mutex::new(SteadyTime::now()),
+        })
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now = *now + duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SteadyTime { *self.now.lock().unwrap() }
+}
@@ -0,0 +1,211 @@
+use std::collections::BTreeMap;
+
+use rustc_serialize::json::{Json, ToJson};
+
+use super::messages::{BlockMessage, BitcoinHash, NetworkType, OutPoint, SerializeHash};
+
+use script::{ScriptType, address, classify};
+use transaction::Amount;
+
+// Readable summary of a block, for debugging and RPC
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockDescription {
+    pub hash: BitcoinHash,
+    pub prev_block: BitcoinHash,
+    pub merkle_root: BitcoinHash,
+    pub timestamp: i64,
+    pub bits: u32,
+    pub difficulty: f64,
+    pub txns: Vec<TxDescription>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TxDescription {
+    pub txid: BitcoinHash,
+    pub inputs: Vec<InputDescription>,
+    pub outputs: Vec<OutputDescription>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputDescription {
+    // None for the coinbase input
+    pub previous_output: Option<OutPoint>,
+    pub sequence: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputDescription {
+    pub value: Amount,
+    pub script_type: ScriptType,
+    pub address: Option<String>,
+}
+
+// Difficulty relative to the minimum, computed like getdifficulty does
+pub fn difficulty(bits: u32) -> f64 {
+    let mut shift = (bits >> 24) & 0xff;
+    let mut difficulty = 0x0000ffff as f64 / (bits & 0x00ffffff) as f64;
+
+    while shift < 29 {
+        difficulty *= 256.0;
+        shift += 1;
+    }
+
+    while shift > 29 {
+        difficulty /= 256.0;
+        shift -= 1;
+    }
+
+    difficulty
+}
+
+impl BlockMessage {
+    // Addresses depend on the network, so it has to be given
+    pub fn describe(&self, network_type: NetworkType) -> BlockDescription {
+        let metadata = &self.metadata;
+
+        BlockDescription {
+            hash: self.hash(),
+            prev_block: metadata.prev_block,
+            merkle_root: metadata.merkle_root,
+            timestamp: metadata.timestamp.to_timespec().sec,
+            bits: metadata.bits,
+            difficulty: difficulty(metadata.bits),
+            txns: self.txns.iter().map(|tx| {
+                let coinbase = tx.is_coinbase();
+
+                TxDescription {
+                    txid: tx.hash(),
+                    inputs: tx.tx_in.iter().map(|input| InputDescription {
+                        previous_output: if coinbase {
+                            None
+                        } else {
+                            Some(input.previous_output.clone())
+                        },
+                        sequence: input.sequence,
+                    }).collect(),
+                    outputs: tx.tx_out.iter().map(|output| OutputDescription {
+                        value: output.value,
+                        script_type: classify(&output.pk_script),
+                        address: address(&output.pk_script, network_type),
+                    }).collect(),
+                }
+            }).collect(),
+        }
+    }
+}
+
+// Hashes are written like the official client does, big endian lowercase hex
+fn hash_to_json(hash: &BitcoinHash) -> Json {
+    Json::String(format!("{:?}", hash).to_lowercase())
+}
+
+// Names used by the official client
+fn script_type_name(script_type: ScriptType) -> &'static str {
+    match script_type {
+        ScriptType::PubKey => "pubkey",
+        ScriptType::PubKeyHash => "pubkeyhash",
+        ScriptType::ScriptHash => "scripthash",
+        ScriptType::MultiSig { .. } => "multisig",
+        ScriptType::NullData => "nulldata",
+        ScriptType::WitnessKeyHash => "witness_v0_keyhash",
+        ScriptType::WitnessScriptHash => "witness_v0_scripthash",
+        ScriptType::WitnessUnknown(_) => "witness_unknown",
+        ScriptType::NonStandard => "nonstandard",
+    }
+}
+
+impl ToJson for BlockDescription {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("hash".to_string(), hash_to_json(&self.hash));
+        object.insert("previousblockhash".to_string(), hash_to_json(&self.prev_block));
+        object.insert("merkleroot".to_string(), hash_to_json(&self.merkle_root));
+        object.insert("time".to_string(), self.timestamp.to_json());
+        object.insert("bits".to_string(), Json::String(format!("{:08x}", self.bits)));
+        object.insert("difficulty".to_string(), self.difficulty.to_json());
+        object.insert("tx".to_string(), self.txns.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for TxDescription {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("txid".to_string(), hash_to_json(&self.txid));
+        object.insert("vin".to_string(), self.inputs.to_json());
+        object.insert("vout".to_string(), self.outputs.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for InputDescription {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        match self.previous_output {
+            Some(ref output) => {
+                object.insert("txid".to_string(), hash_to_json(&output.hash));
+                object.insert("vout".to_string(), output.index.to_json());
+            },
+            None => {
+                object.insert("coinbase".to_string(), Json::Boolean(true));
+            },
+        }
+        object.insert("sequence".to_string(), self.sequence.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for OutputDescription {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("value".to_string(), self.value.to_btc().to_json());
+        object.insert("type".to_string(), script_type_name(self.script_type).to_json());
+        if let Some(ref address) = self.address {
+            object.insert("address".to_string(), address.to_json());
+        }
+        Json::Object(object)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::messages::NetworkType;
+    use super::super::store::BlockStore;
+
+    use rustc_serialize::json::ToJson;
+    use script::ScriptType;
+
+    #[test]
+    fn test_difficulty() {
+        assert_eq!(difficulty(0x1d00ffff), 1.0);
+        assert_eq!(difficulty(0x1c00ffff), 256.0);
+        assert_eq!(difficulty(0x1b0404cb), 16307.420938523983);
+    }
+
+    #[test]
+    fn test_describe_genesis() {
+        let genesis = BlockStore::genesis_block(1296688602, 414098458);
+        let description = genesis.describe(NetworkType::TestNet3);
+
+        assert_eq!(format!("{:?}", description.hash),
+                   "000000000933EA01AD0EE984209779BAAEC3CED90FA3F408719526F8D77F4943");
+        assert_eq!(description.timestamp, 1296688602);
+        assert_eq!(description.difficulty, 1.0);
+
+        assert_eq!(description.txns.len(), 1);
+        let coinbase = &description.txns[0];
+        assert_eq!(coinbase.txid, description.merkle_root);
+        assert_eq!(coinbase.inputs[0].previous_output, None);
+        assert_eq!(coinbase.outputs.len(), 1);
+        assert_eq!(coinbase.outputs[0].value.as_satoshis(), 5000000000);
+        // Paid to a pub key, there's no address
+        assert_eq!(coinbase.outputs[0].script_type, ScriptType::PubKey);
+        assert_eq!(coinbase.outputs[0].address, None);
+
+        let json = description.to_json();
+        assert_eq!(json["tx"][0]["vout"][0]["value"], 50.0.to_json());
+        assert_eq!(json["tx"][0]["vout"][0]["type"], "pubkey".to_json());
+        assert_eq!(json["bits"], "1d00ffff".to_json());
+    }
+}
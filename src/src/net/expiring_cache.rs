@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use time;
-use time::SteadyTime;
+
+use super::clock::{system_clock, SharedClock};
 
 use std::hash::Hash;
 use std::mem;
@@ -9,23 +10,25 @@ use std::mem;
 pub struct Timeout<T: Default + Copy> {
     value: T,
     timeout: time::SteadyTime,
+    clock: SharedClock,
 }
 
 impl<T: Default + Copy> Timeout<T> {
-    pub fn new()-> Timeout<T> {
+    pub fn with_clock(clock: SharedClock) -> Timeout<T> {
         Timeout {
             value: T::default(),
-            timeout: SteadyTime::now(),
+            timeout: clock.now(),
+            clock: clock,
         }
     }
 
     pub fn set(&mut self, value: T, timeout: time::Duration) {
         self.value = value;
-        self.timeout = SteadyTime::now() + timeout;
+        self.timeout = self.clock.now() + timeout;
     }
 
     pub fn get(&self) -> T {
-        if self.timeout < SteadyTime::now() {
+        if self.timeout < self.clock.now() {
             T::default()
         } else {
             self.value
@@ -38,20 +41,27 @@ pub struct ExpiringCache<V> {
     timeout: time::Duration,
     checking_interval: time::Duration,
     last_checked: time::SteadyTime,
+    clock: SharedClock,
 }
 
 impl<V: Eq + Hash + Clone> ExpiringCache<V> {
     pub fn new(timeout: time::Duration, checking_interval: time::Duration) -> ExpiringCache<V> {
+        ExpiringCache::with_clock(timeout, checking_interval, system_clock())
+    }
+
+    pub fn with_clock(timeout: time::Duration, checking_interval: time::Duration,
+                      clock: SharedClock) -> ExpiringCache<V> {
         ExpiringCache {
             store: HashMap::new(),
             timeout: timeout,
             checking_interval: checking_interval,
-            last_checked: SteadyTime::now(),
+            last_checked: clock.now(),
+            clock: clock,
         }
     }
 
     fn check_expiration(&mut self) {
-        let now = SteadyTime::now();
+        let now = self.clock.now();
 
         if self.last_checked + self.checking_interval > now {
             return;
@@ -65,7 +75,7 @@ impl<V: Eq + Hash + Clone> ExpiringCache<V> {
 
         mem::replace(&mut self.store, store);
 
-        self.last_checked = SteadyTime::now();
+        self.last_checked = now;
     }
 
     pub fn has(&mut self, key: &V) -> bool {
@@ -75,7 +85,8 @@ impl<V: Eq + Hash + Clone> ExpiringCache<V> {
 
     pub fn insert(&mut self, key: V) {
         self.check_expiration();
-        self.store.insert(key, SteadyTime::now() + self.timeout);
+        let expires = self.clock.now() + self.timeout;
+        self.store.insert(key, expires);
     }
 
     pub fn remove(&mut self, key: &V) {
@@ -85,3 +96,49 @@ impl<V: Eq + Hash + Clone> ExpiringCache<V> {
 
     pub fn len(&self) -> usize { self.store.len() }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::clock::MockClock;
+    use time::Duration;
+
+    #[test]
+    fn test_timeout() {
+        let clock = MockClock::new();
+        let mut timeout = Timeout::with_clock(clock.clone());
+        assert_eq!(timeout.get(), false);
+
+        timeout.set(true, Duration::seconds(15));
+        clock.advance(Duration::seconds(15));
+        assert_eq!(timeout.get(), true);
+
+        clock.advance(Duration::milliseconds(1));
+        assert_eq!(timeout.get(), false);
+    }
+
+    #[test]
+    fn test_expiring_cache() {
+        let clock = MockClock::new();
+        let mut cache = ExpiringCache::with_clock(
+            Duration::minutes(1), Duration::seconds(10), clock.clone());
+
+        cache.insert(1);
+        clock.advance(Duration::seconds(30));
+        cache.insert(2);
+        assert!(cache.has(&1));
+        assert!(cache.has(&2));
+
+        clock.advance(Duration::seconds(31));
+        assert!(!cache.has(&1));
+        assert!(cache.has(&2));
+        assert_eq!(cache.len(), 1);
+
+        clock.advance(Duration::seconds(5));
+        cache.insert(3);
+        clock.advance(Duration::seconds(30));
+        assert!(!cache.has(&2));
+        assert!(cache.has(&3));
+        assert_eq!(cache.len(), 1);
+    }
+}
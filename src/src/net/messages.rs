@@ -4,15 +4,18 @@ use super::IPAddress;
 use super::Services;
 
 use utils::CryptoUtils;
-use serialize::{Serialize, Serializer, Deserialize, Deserializer};
+use transaction::Amount;
+use serialize::{byte_order, Serialize, Serializer, Deserialize, Deserializer, Error, HashWriter,
+                VarInt, MAX_SIZE};
 
 use std::ops::Deref;
 
-use std::io::{Cursor, SeekFrom, Seek, Write};
-use std::net::Ipv6Addr;
+use std::io::{Cursor, Read, SeekFrom, Seek, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 use std::hash::{Hash, Hasher};
 
+use std::cmp;
 use std::fmt;
 use std::str;
 use std::usize;
@@ -31,6 +34,7 @@ pub enum NetworkType {
 #[derive(PartialEq, Copy, Clone, Debug)]
 pub enum Command {
     Addr,
+    AddrV2,
     GetAddr,
     Version,
     Verack,
@@ -46,6 +50,19 @@ pub enum Command {
     Headers,
     Block,
     FilterLoad,
+    FilterAdd,
+    FilterClear,
+    WtxidRelay,
+    // BIP157 compact block filters
+    GetCFilters,
+    CFilter,
+    GetCFHeaders,
+    CFHeaders,
+    GetCFCheckpt,
+    CFCheckpt,
+    // Retired commands that old peers may still send, dropped silently.
+    // The name is kept so that the message can be written back as it was.
+    Ignored([u8; 12]),
     Unknown,
 }
 
@@ -54,12 +71,7 @@ type BlockLocators = Vec<Bytes>;
 
 impl Serialize for Ipv6Addr {
     fn serialize(&self, serializer: &mut Serializer) {
-        for x in self.segments().iter() {
-            let bytes = serializer.to_bytes(*x as u64);
-            // Ip are encoded with big-endian integers
-            serializer.push(bytes[1]);
-            serializer.push(bytes[0]);
-        }
+        serializer.push_bytes(&byte_order::ipv6_to_be(self));
     }
 
     fn size() -> usize { 16 }
@@ -75,15 +87,11 @@ impl Serialize for Services {
 }
 
 impl Deserialize for IPAddress {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let services: Services = try!(Deserialize::deserialize(deserializer));
         let address: Ipv6Addr  = try!(Deserialize::deserialize(deserializer));
 
-        // The port is encoded in big endian
-        let mut data = [0; 2];
-        try!(deserializer.read_ex(&mut data));
-
-        let port = deserializer.to_u_slice(&[data[1], data[0]]) as u16;
+        let port = try!(byte_order::read_u16_be(deserializer));
 
         Ok(IPAddress::new(services, address, port))
     }
@@ -92,34 +100,24 @@ impl Deserialize for IPAddress {
 impl Serialize for IPAddress {
     fn serialize(&self, serializer: &mut Serializer) {
         self.services.serialize(serializer);
-        self. address.serialize(serializer);
-
-        // The port is encoded in big endian
-        let data = serializer.to_bytes(self.port as u64);
-        serializer.push(data[1]);
-        serializer.push(data[0]);
+        self.address.serialize(serializer);
+        byte_order::write_u16_be(serializer, self.port);
     }
 
     fn size() -> usize { Services::size() + Ipv6Addr::size() + u16::size() }
 }
 
 impl Deserialize for Ipv6Addr {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let mut data = [0; 16];
         try!(deserializer.read_ex(&mut data));
 
-        let mut s = [0u16; 8];
-        for i in 0..8 {
-            // IPs are big-endian so we need to swap the bytes
-            s[i] = deserializer.to_u_slice(&[data[2*i + 1], data[2*i]]) as u16;
-        }
-
-        Ok(Ipv6Addr::new(s[0], s[1], s[2], s[3], s[4], s[5], s[6], s[7]))
+        Ok(byte_order::ipv6_from_be(data))
     }
 }
 
 impl Deserialize for Services {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let data = try!(u64::deserialize(deserializer));
         Ok(Services::new(data == 1))
     }
@@ -159,7 +157,7 @@ impl Serialize for ShortFormatTm {
 }
 
 impl Deserialize for ShortFormatTm {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let sec = try!(deserializer.to_u_fixed(4));
         Ok(ShortFormatTm::new(time::at_utc(time::Timespec::new(sec as i64, 0))))
     }
@@ -198,9 +196,8 @@ impl Hash for BitcoinHash {
 
 impl fmt::Debug for BitcoinHash {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for i in 0..32 {
-            // Let's print the hash in the canonical form (i.e. big endian)
-            try!(write!(f, "{:02X}", self[31 - i]));
+        for byte in byte_order::reversed(&self.data).iter() {
+            try!(write!(f, "{:02X}", byte));
         }
 
         Ok(())
@@ -216,7 +213,7 @@ impl Serialize for BitcoinHash {
 }
 
 impl Deserialize for BitcoinHash {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         Ok(BitcoinHash {
             data: try!(Deserialize::deserialize(deserializer)),
         })
@@ -224,9 +221,8 @@ impl Deserialize for BitcoinHash {
 }
 
 impl Deserialize for Command {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let mut bytes = [0; 12];
-        try!(deserializer.read_ex(&mut bytes));
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let bytes: [u8; 12] = try!(Deserialize::deserialize(deserializer));
 
         match &bytes {
             b"version\0\0\0\0\0"      => Ok(Command::Version),
@@ -238,13 +234,27 @@ impl Deserialize for Command {
             b"getaddr\0\0\0\0\0"      => Ok(Command::GetAddr),
             b"notfound\0\0\0\0"       => Ok(Command::NotFound),
             b"addr\0\0\0\0\0\0\0\0"   => Ok(Command::Addr),
+            b"addrv2\0\0\0\0\0\0"     => Ok(Command::AddrV2),
             b"reject\0\0\0\0\0\0"     => Ok(Command::Reject),
             b"getblocks\0\0\0"        => Ok(Command::GetBlocks),
             b"getheaders\0\0"         => Ok(Command::GetHeaders),
             b"getdata\0\0\0\0\0"      => Ok(Command::GetData),
             b"headers\0\0\0\0\0"      => Ok(Command::Headers),
             b"block\0\0\0\0\0\0\0"    => Ok(Command::Block),
-            b"filterload\0\0"         => Ok(Command::Block),
+            b"filterload\0\0"         => Ok(Command::FilterLoad),
+            b"filteradd\0\0\0"        => Ok(Command::FilterAdd),
+            b"filterclear\0"          => Ok(Command::FilterClear),
+            b"wtxidrelay\0\0"         => Ok(Command::WtxidRelay),
+            b"getcfilters\0"          => Ok(Command::GetCFilters),
+            b"cfilter\0\0\0\0\0"      => Ok(Command::CFilter),
+            b"getcfheaders"           => Ok(Command::GetCFHeaders),
+            b"cfheaders\0\0\0"        => Ok(Command::CFHeaders),
+            b"getcfcheckpt"           => Ok(Command::GetCFCheckpt),
+            b"cfcheckpt\0\0\0"        => Ok(Command::CFCheckpt),
+            b"alert\0\0\0\0\0\0\0"    |
+            b"checkorder\0\0"         |
+            b"submitorder\0"          |
+            b"reply\0\0\0\0\0\0\0"    => Ok(Command::Ignored(bytes)),
             command                   => {
                 println!("Warning: unknown command `{:?}`", str::from_utf8(command));
                 Ok(Command::Unknown)
@@ -257,6 +267,7 @@ impl Serialize for Command {
     fn serialize(&self, serializer: &mut Serializer) {
         let bytes = match self {
             &Command::Addr        => b"addr\0\0\0\0\0\0\0\0",
+            &Command::AddrV2      => b"addrv2\0\0\0\0\0\0",
             &Command::GetAddr     => b"getaddr\0\0\0\0\0",
             &Command::Version     => b"version\0\0\0\0\0",
             &Command::Verack      => b"verack\0\0\0\0\0\0",
@@ -272,6 +283,16 @@ impl Serialize for Command {
             &Command::GetBlocks   => b"getblocks\0\0\0",
             &Command::Headers     => b"headers\0\0\0\0\0",
             &Command::FilterLoad  => b"filterload\0\0",
+            &Command::FilterAdd   => b"filteradd\0\0\0",
+            &Command::FilterClear => b"filterclear\0",
+            &Command::WtxidRelay  => b"wtxidrelay\0\0",
+            &Command::GetCFilters => b"getcfilters\0",
+            &Command::CFilter     => b"cfilter\0\0\0\0\0",
+            &Command::GetCFHeaders => b"getcfheaders",
+            &Command::CFHeaders   => b"cfheaders\0\0\0",
+            &Command::GetCFCheckpt => b"getcfcheckpt",
+            &Command::CFCheckpt   => b"cfcheckpt\0\0\0",
+            &Command::Ignored(ref bytes) => bytes,
             &Command::Unknown     => unimplemented!(),
         };
 
@@ -283,14 +304,14 @@ impl Serialize for Command {
 }
 
 impl Deserialize for NetworkType {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let data = try!(u32::deserialize(deserializer));
         match data {
             0xD9B4BEF9 => Ok(NetworkType::Main),
             0xDAB5BFFA => Ok(NetworkType::TestNet),
             0x0709110B => Ok(NetworkType::TestNet3),
             0xFEB4BEF9 => Ok(NetworkType::NameCoin),
-            _          => Err(format!("Unrecognized magic number {}", data)),
+            _          => Err(Error::InvalidValue(data as u64)),
         }
     }
 }
@@ -320,6 +341,8 @@ pub enum InventoryVectorType {
     MSG_TX,
     MSG_BLOCK,
     MSG_FILTERED_BLOCK,
+    // Transaction identified by its wtxid, see BIP339
+    MSG_WTX,
 }
 
 impl Serialize for InventoryVectorType {
@@ -329,6 +352,7 @@ impl Serialize for InventoryVectorType {
             &InventoryVectorType::MSG_TX             => 1,
             &InventoryVectorType::MSG_BLOCK          => 2,
             &InventoryVectorType::MSG_FILTERED_BLOCK => 3,
+            &InventoryVectorType::MSG_WTX            => 5,
         };
 
         index.serialize(serializer);
@@ -338,7 +362,7 @@ impl Serialize for InventoryVectorType {
 }
 
 impl Deserialize for InventoryVectorType {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let index = try!(u32::deserialize(deserializer));
 
         match index {
@@ -346,7 +370,8 @@ impl Deserialize for InventoryVectorType {
             1 => Ok(InventoryVectorType::MSG_TX),
             2 => Ok(InventoryVectorType::MSG_BLOCK),
             3 => Ok(InventoryVectorType::MSG_FILTERED_BLOCK),
-            vector_type => Err(format!("Unexpected inventory vector type = {}", vector_type)),
+            5 => Ok(InventoryVectorType::MSG_WTX),
+            vector_type => Err(Error::InvalidValue(vector_type as u64)),
         }
     }
 }
@@ -361,7 +386,9 @@ pub trait SerializeHash: Serialize {
     }
 
     fn hash(&self) -> BitcoinHash {
-        self.serialize_hash().1
+        let mut writer = HashWriter::new();
+        self.serialize(&mut writer);
+        BitcoinHash::new(writer.double_sha256())
     }
 }
 
@@ -388,10 +415,14 @@ macro_rules! message {
             fn size() -> usize {
                 $(<$ty as Serialize>::size() +)* 0
             }
+
+            fn serialized_size(&self) -> usize {
+                $(self.$element.serialized_size() +)* 0
+            }
         }
 
         impl Deserialize for $name {
-            fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+            fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
                 Ok($name {
                     $($element: try!(Deserialize::deserialize(deserializer))),*
                 })
@@ -407,6 +438,48 @@ message!(FilterLoadMessage;
     n_flags: u8
 );
 
+message!(FilterAddMessage;
+    data: Vec<u8>
+);
+
+// BIP157 messages, `filter_type` is 0 for the BIP158 basic filter
+message!(GetCFiltersMessage;
+    filter_type: u8,
+    start_height: u32,
+    stop_hash: BitcoinHash
+);
+
+message!(CFilterMessage;
+    filter_type: u8,
+    block_hash: BitcoinHash,
+    filter: Vec<u8>
+);
+
+message!(GetCFHeadersMessage;
+    filter_type: u8,
+    start_height: u32,
+    stop_hash: BitcoinHash
+);
+
+message!(CFHeadersMessage;
+    filter_type: u8,
+    stop_hash: BitcoinHash,
+    previous_filter_header: BitcoinHash,
+    filter_hashes: Vec<BitcoinHash>
+);
+
+message!(GetCFCheckptMessage;
+    filter_type: u8,
+    stop_hash: BitcoinHash
+);
+
+// Filter headers of every 1000th block up to `stop_hash`
+message!(CFCheckptMessage;
+    filter_type: u8,
+    stop_hash: BitcoinHash,
+    filter_headers: Vec<BitcoinHash>
+);
+
 message!(MessageHeader;
     network_type: NetworkType,
     command: Command,
@@ -434,6 +507,115 @@ message!(AddrMessage;
     addr_list: Vec<(ShortFormatTm, IPAddress)>
 );
 
+// Longest address we accept in an addrv2 message, see BIP155
+pub const MAX_ADDRV2_SIZE: u64 = 512;
+
+const ADDRV2_IPV4: u8 = 0x01;
+const ADDRV2_IPV6: u8 = 0x02;
+
+// Address tagged with the network it belongs to
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetworkAddress {
+    IPv4(Ipv4Addr),
+    IPv6(Ipv6Addr),
+    // Networks we don't understand (Tor, I2P, ...) are kept as they are
+    Unknown(u8, Vec<u8>),
+}
+
+impl Serialize for NetworkAddress {
+    fn serialize(&self, serializer: &mut Serializer) {
+        match self {
+            &NetworkAddress::IPv4(ref address) => {
+                serializer.push(ADDRV2_IPV4);
+                address.octets().to_vec().serialize(serializer);
+            },
+            &NetworkAddress::IPv6(ref address) => {
+                serializer.push(ADDRV2_IPV6);
+                VarInt::new(16).serialize(serializer);
+                address.serialize(serializer);
+            },
+            &NetworkAddress::Unknown(network, ref data) => {
+                serializer.push(network);
+                data.serialize(serializer);
+            },
+        }
+    }
+
+    fn size() -> usize { usize::MAX }
+}
+
+impl Deserialize for NetworkAddress {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let network = try!(u8::deserialize(deserializer));
+        let length = try!(VarInt::deserialize(deserializer)).as_u64();
+
+        if length > MAX_ADDRV2_SIZE {
+            return Err(Error::LengthTooLarge(length));
+        }
+
+        let mut data = vec![0; length as usize];
+        try!(deserializer.read_ex(&mut data));
+
+        match (network, data.len()) {
+            (ADDRV2_IPV4, 4) =>
+                Ok(NetworkAddress::IPv4(Ipv4Addr::new(data[0], data[1], data[2], data[3]))),
+            (ADDRV2_IPV6, 16) =>
+                Ok(NetworkAddress::IPv6(try!(Ipv6Addr::deserialize(&mut Cursor::new(&data[..]))))),
+            // A known network with the wrong address length
+            (ADDRV2_IPV4, _) | (ADDRV2_IPV6, _) => Err(Error::InvalidValue(length)),
+            _ => Ok(NetworkAddress::Unknown(network, data)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AddrV2Entry {
+    pub time: ShortFormatTm,
+    // Service bits are sent as a VarInt in addrv2
+    pub services: VarInt,
+    pub address: NetworkAddress,
+    pub port: u16,
+}
+
+impl AddrV2Entry {
+    pub fn new(time: ShortFormatTm, services: VarInt, address: NetworkAddress, port: u16)
+            -> AddrV2Entry {
+        AddrV2Entry {
+            time: time,
+            services: services,
+            address: address,
+            port: port,
+        }
+    }
+}
+
+impl Serialize for AddrV2Entry {
+    fn serialize(&self, serializer: &mut Serializer) {
+        self.time.serialize(serializer);
+        self.services.serialize(serializer);
+        self.address.serialize(serializer);
+        byte_order::write_u16_be(serializer, self.port);
+    }
+
+    fn size() -> usize { usize::MAX }
+}
+
+impl Deserialize for AddrV2Entry {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let time = try!(Deserialize::deserialize(deserializer));
+        let services = try!(Deserialize::deserialize(deserializer));
+        let address = try!(Deserialize::deserialize(deserializer));
+
+        let port = try!(byte_order::read_u16_be(deserializer));
+
+        Ok(AddrV2Entry::new(time, services, address, port))
+    }
+}
+
+message!(AddrV2Message;
+    addr_list: Vec<AddrV2Entry>
+);
+
 message!(RejectMessage;
     message: Command,
     ccode: u8,
@@ -464,6 +646,15 @@ message!(OutPoint;
     index: u32
 );
 
+impl Eq for OutPoint {}
+
+impl Hash for OutPoint {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Hash::hash(&self.hash, state);
+        Hash::hash(&self.index, state);
+    }
+}
+
 message!(TxIn;
     previous_output: OutPoint,
     script: Vec<u8>,
@@ -471,7 +662,7 @@ message!(TxIn;
 );
 
 message!(TxOut;
-    value: i64,
+    value: Amount,
     pk_script: Vec<u8>
 );
 
@@ -491,6 +682,9 @@ message!(BlockMetadata;
     nonce: u32
 );
 
+// Serialized size of BlockMetadata
+pub const BLOCK_HEADER_SIZE: usize = 80;
+
 #[derive(Debug, Clone, PartialEq)]
 pub struct BlockMessage {
     pub metadata: BlockMetadata,
@@ -500,6 +694,13 @@ pub struct BlockMessage {
 impl BlockMessage {
     pub fn prev_block(&self) -> &BitcoinHash { &self.metadata.prev_block }
     pub fn into_metadata(self) -> BlockMetadata { self.metadata }
+
+    // Hash of a serialized block, only the header is hashed so we don't
+    // need to serialize the block again. `data` must hold at least a header.
+    pub fn hash_from_bytes(data: &[u8]) -> BitcoinHash {
+        let header = &data[..BLOCK_HEADER_SIZE];
+        BitcoinHash::new(CryptoUtils::sha256(&CryptoUtils::sha256(header)))
+    }
 }
 
 impl SerializeHash for BlockMessage {
@@ -511,6 +712,11 @@ impl SerializeHash for BlockMessage {
 
         (buffer.into_inner(), hash)
     }
+
+    // The transactions are not part of the hash
+    fn hash(&self) -> BitcoinHash {
+        self.metadata.hash()
+    }
 }
 
 impl Serialize for BlockMessage {
@@ -523,7 +729,7 @@ impl Serialize for BlockMessage {
 }
 
 impl Deserialize for BlockMessage {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         Ok(BlockMessage {
             metadata: try!(Deserialize::deserialize(deserializer)),
             txns:     try!(Deserialize::deserialize(deserializer)),
@@ -531,26 +737,113 @@ impl Deserialize for BlockMessage {
     }
 }
 
-pub fn get_serialized_message(network_type: NetworkType,
-                              command: Command,
-                              message: Option<Box<Serialize>>) -> Vec<u8> {
-    let mut buffer = Cursor::new(vec![]);
-    message.map(|m| m.serialize(&mut buffer));
+impl MessageHeader {
+    // Header of a message carrying `payload`
+    pub fn for_payload(network_type: NetworkType, command: Command, payload: &[u8])
+        -> MessageHeader {
+        MessageHeader {
+            network_type: network_type,
+            command: command,
+            length: payload.len() as u32,
+            checksum: MessageHeader::checksum(payload),
+        }
+    }
+
+    // First 4 bytes of the double SHA256 of the payload
+    pub fn checksum(payload: &[u8]) -> [u8; 4] {
+        let hash = CryptoUtils::sha256(&CryptoUtils::sha256(payload));
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+
+    // Checks that `payload` is exactly the one this header describes
+    pub fn check_payload(&self, payload: &[u8]) -> Result<(), Error> {
+        if self.length as u64 > MAX_SIZE {
+            return Err(Error::LengthTooLarge(self.length as u64));
+        }
 
-    let checksum = CryptoUtils::sha256(&CryptoUtils::sha256(buffer.get_ref()));
+        if payload.len() < self.length as usize {
+            return Err(Error::UnexpectedEof);
+        }
 
-    let header = MessageHeader {
-        network_type: network_type,
-        command: command,
-        length: buffer.get_ref().len() as u32,
-        checksum: [checksum[0], checksum[1], checksum[2], checksum[3]],
-    };
+        if payload.len() > self.length as usize {
+            return Err(Error::TrailingData);
+        }
 
-    let mut header_buffer = Cursor::new(vec![]);
-    header.serialize(&mut header_buffer);
+        if MessageHeader::checksum(payload) != self.checksum {
+            return Err(Error::InvalidChecksum);
+        }
 
-    let mut result = header_buffer.into_inner();
-    result.extend(buffer.into_inner());
+        Ok(())
+    }
+}
+
+// A message framed like on the wire, the header followed by the payload.
+// Deserializing checks the checksum of the payload. Several commands share
+// a payload type, e.g. ping and pong, so the caller checks the command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkMessage<T> {
+    pub network_type: NetworkType,
+    pub command: Command,
+    pub payload: T,
+}
+
+impl<T> NetworkMessage<T> {
+    pub fn new(network_type: NetworkType, command: Command, payload: T) -> NetworkMessage<T> {
+        NetworkMessage {
+            network_type: network_type,
+            command: command,
+            payload: payload,
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for NetworkMessage<T> {
+    fn serialize(&self, serializer: &mut Serializer) {
+        let mut payload = vec![];
+        self.payload.serialize(&mut payload);
+
+        MessageHeader::for_payload(self.network_type, self.command, &payload)
+            .serialize(serializer);
+        serializer.push_bytes(&payload);
+    }
+
+    fn size() -> usize { MessageHeader::size() + T::size() }
+}
+
+impl<T: Deserialize> Deserialize for NetworkMessage<T> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let header = try!(MessageHeader::deserialize(deserializer));
+
+        // Never more than MAX_SIZE, check_payload rejects longer messages
+        let mut payload = vec![];
+        try!(Read::take(&mut *deserializer, cmp::min(header.length as u64, MAX_SIZE))
+             .read_to_end(&mut payload)
+             .map_err(|e| Error::Io(e.kind())));
+        try!(header.check_payload(&payload));
+
+        let mut cursor = Cursor::new(&payload[..]);
+        let message = try!(T::deserialize(&mut cursor));
+        if cursor.position() as usize != payload.len() {
+            return Err(Error::TrailingData);
+        }
+
+        Ok(NetworkMessage {
+            network_type: header.network_type,
+            command: header.command,
+            payload: message,
+        })
+    }
+}
+
+pub fn get_serialized_message(network_type: NetworkType,
+                              command: Command,
+                              message: Option<Box<Serialize>>) -> Vec<u8> {
+    let mut payload = vec![];
+    message.map(|m| m.serialize(&mut payload));
+
+    let mut result = vec![];
+    MessageHeader::for_payload(network_type, command, &payload).serialize(&mut result);
+    result.extend(payload);
 
     result
 }
@@ -561,7 +854,7 @@ mod tests {
     use std::io::Cursor;
     use super::*;
     use utils::Debug;
-    use serialize::{Deserialize, Serialize};
+    use serialize::{Deserialize, Deserializer, Serialize};
 
     #[test]
     fn test_real_tx_fd_length_script() {
@@ -588,4 +881,52 @@ mod tests {
 
         println!("{:?}", tx_obj);
     }
+
+    #[test]
+    fn test_command_bytes() {
+        let data = b"getheaders\0\0".to_vec();
+
+        let bytes: [u8; 12] = Deserialize::deserialize(&mut Cursor::new(&data[..])).unwrap();
+        assert_eq!(&bytes, b"getheaders\0\0");
+
+        let mut buffer = vec![];
+        bytes.serialize(&mut buffer);
+        assert_eq!(buffer, data);
+
+        let command = Command::deserialize(&mut Cursor::new(&data[..])).unwrap();
+        assert_eq!(command, Command::GetHeaders);
+
+        // Not enough data
+        let short: Result<[u8; 12], Error> = Deserialize::deserialize(&mut Cursor::new(&data[..11]));
+        assert_eq!(short, Err(Error::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_ignored_command() {
+        let bytes = b"checkorder\0\0";
+        let command = Command::deserialize(&mut Cursor::new(&bytes[..])).unwrap();
+        assert_eq!(command, Command::Ignored(*bytes));
+
+        let mut serialized = vec![];
+        command.serialize(&mut serialized);
+        assert_eq!(&serialized[..], &bytes[..]);
+    }
+
+    #[test]
+    fn test_remaining() {
+        let tx = "01000000010000000000000000000000000000000000000000000000000000000000000000ffffffff0704ffff001d0104ffffffff0100f2052a0100000043410496b538e853519c726a2c91e61ec11600ae1390813a627c66fb8be7947be63c52da7589379515d4e0a604f8141781e62294721166bf621e73a82cbf2342c858eeac00000000".from_hex().unwrap();
+
+        let mut cursor = Cursor::new(tx.clone());
+        assert_eq!(cursor.remaining(), Some(tx.len()));
+
+        TxMessage::deserialize(&mut cursor).unwrap();
+        assert_eq!(cursor.remaining(), Some(0));
+
+        let mut trailing = tx.clone();
+        trailing.push(0);
+
+        let mut cursor = Cursor::new(trailing);
+        TxMessage::deserialize(&mut cursor).unwrap();
+        assert_eq!(cursor.remaining(), Some(1));
+    }
 }
@@ -1,9 +1,18 @@
 mod rpcengine;
-mod store;
+mod clock;
 mod expiring_cache;
 
+pub mod blockfilter;
+pub mod bloom;
+pub mod chain;
+pub mod describe;
 pub mod messages;
 pub mod p2pclient;
+pub mod store;
+pub mod template;
+pub mod utxo;
+pub mod validation;
+pub mod versionbits;
 
 use std::net;
 
@@ -35,4 +44,14 @@ impl IPAddress {
             port: port,
         }
     }
+
+    // IPv4 addresses are sent as IPv4-mapped IPv6 addresses
+    pub fn from_socket_addr(services: Services, addr: net::SocketAddr) -> IPAddress {
+        let address = match addr.ip() {
+            net::IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            net::IpAddr::V6(ip) => ip,
+        };
+
+        IPAddress::new(services, address, addr.port())
+    }
 }
@@ -4,22 +4,25 @@ extern crate rand;
 use time;
 use time::Duration;
 
-use std::io::Cursor;
+use std::io::{self, Cursor};
 use std::fs::File;
+use std::path::{Path, PathBuf};
 use std::net::ToSocketAddrs;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Mutex, MutexGuard, Arc};
 use std::thread;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::cmp;
 
 use mio::Sender;
 use mio::tcp;
 
-use utils::Debug;
-use serialize::{Serialize, Deserialize};
+use serialize::{Serialize, Deserialize, Deserializer, VarInt};
 
 use super::IPAddress;
 use super::Services;
+use super::bloom::{BloomFilter, MAX_BLOOM_FILTER_SIZE, MAX_FILTERADD_SIZE};
+use super::clock::{system_clock, SharedClock};
 use super::expiring_cache::ExpiringCache;
 use super::expiring_cache::Timeout;
 use super::messages::*;
@@ -28,6 +31,8 @@ use super::rpcengine::RPCEngine;
 use super::rpcengine;
 use super::store::BlockStore;
 
+use transaction::mempool::{Mempool, DEFAULT_MAX_MEMPOOL_SIZE};
+
 struct BitcoinClient {
     version: i32,
     services: Services,
@@ -35,13 +40,22 @@ struct BitcoinClient {
     state: Arc<Mutex<State>>,
     channel: mio::Sender<Message>,
     network_type: NetworkType,
+    // The address we tell peers to reach us at
+    local_address: SocketAddr,
 }
 
 struct State {
     peers: HashMap<mio::Token, Peer>,
-    tx_store: HashMap<BitcoinHash, TxMessage>,
+    tx_store: Mempool,
+    // Blocks are stored without being validated, see chain::ChainState
     block_store: BlockStore,
     pending_inv: ExpiringCache<BitcoinHash>,
+    // Transactions we asked some peer for, so we don't ask again when
+    // other peers announce them. Blocks use pending_inv.
+    requested: ExpiringCache<BitcoinHash>,
+    // We stop asking a peer for blocks once we are this close to the
+    // height it announced in its version message
+    sync_window: usize,
 }
 
 #[derive(PartialEq, Copy, Clone, Debug)]
@@ -50,25 +64,70 @@ enum ConnectionType {
     Outbound,
 }
 
+// Outbound peers go through every state, inbound peers are only added once
+// their version arrives so they start at VersionReceived
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum HandshakeState {
+    Connected,
+    VersionSent,
+    VersionReceived,
+    Ready,
+}
+
 #[derive(Debug)]
 struct Peer {
     ping_time: time::Tm,
+    ping_sent: time::SteadyTime,
     ping: i64,
     ping_data: u64,
     version: Option<VersionMessage>,
-    verak_received: bool,
+    // Height of the best chain the peer is known to have. It starts at the
+    // height of its version and grows with the blocks it announces.
+    best_height: usize,
+    handshake: HandshakeState,
     connection_type: ConnectionType,
     waiting_for_blocks: Timeout<bool>,
+    // Blocks we asked for with getdata but haven't received yet
+    blocks_in_flight: HashSet<BitcoinHash>,
+    // Blocks announced by this peer waiting for a free in-flight slot
+    blocks_queued: VecDeque<BitcoinHash>,
+    // The relay flag of the version message, peers that don't want
+    // transactions get none until they load a filter (BIP37)
+    relay: bool,
+    // Set with filterload, only matching transactions are announced
+    filter: Option<BloomFilter>,
+    clock: SharedClock,
 }
 
 impl State {
     pub fn new(network_type: NetworkType, blocks_file: File) -> State {
         State {
             peers: HashMap::new(),
-            tx_store: HashMap::new(),
+            tx_store: Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE),
             block_store: BlockStore::new(blocks_file, network_type),
             pending_inv: ExpiringCache::new(Duration::minutes(2), Duration::seconds(10)),
+            requested: ExpiringCache::new(Duration::minutes(1), Duration::seconds(10)),
+            sync_window: DEFAULT_SYNC_WINDOW,
+        }
+    }
+
+    pub fn set_sync_window(&mut self, sync_window: usize) {
+        self.sync_window = sync_window;
+    }
+
+    // Uses the block file of `network_type` inside `data_dir`
+    pub fn open(network_type: NetworkType, data_dir: &Path) -> Result<State, String> {
+        let path = BlockStore::path(data_dir, network_type);
+
+        // Older versions kept the blocks of every network in ./block.dat
+        let legacy_path = Path::new("block.dat");
+        if legacy_path.exists() && !path.exists() {
+            println!("Warning: {:?} is no longer used, move it to {:?} to keep its blocks",
+                     legacy_path, path);
         }
+
+        let blocks_file = try!(BlockStore::open_file(&path));
+        Ok(State::new(network_type, blocks_file))
     }
 
     pub fn is_pending_inv(&mut self, hash: &BitcoinHash) -> bool{
@@ -86,15 +145,82 @@ impl State {
 
     pub fn pending_inv_len(&self) -> usize { self.pending_inv.len() }
 
+    // Queues new blocks announced by `token` and returns the transactions
+    // we should ask it for. Anything we have or already asked for is skipped.
+    pub fn process_inv(&mut self, token: mio::Token, inventory: Vec<InventoryVector>)
+        -> Vec<InventoryVector> {
+        let mut new_data = vec![];
+        let mut new_blocks = 0;
+
+        for inventory in inventory {
+            match inventory.type_ {
+                InventoryVectorType::MSG_TX => {
+                    if !self.has_tx(&inventory.hash) &&
+                       !self.requested.has(&inventory.hash) {
+                        self.requested.insert(inventory.hash);
+                        new_data.push(inventory);
+                    }
+                },
+                InventoryVectorType::MSG_BLOCK => {
+                    if !self.has_block(&inventory.hash) {
+                        new_blocks += 1;
+
+                        if !self.is_pending_inv(&inventory.hash) {
+                            // Blocks are requested as slots free up, see request_blocks
+                            self.get_peer(&token).map(|p| p.queue_block(inventory.hash));
+                            self.add_inv(inventory.hash);
+                        }
+                    }
+                },
+                type_ => println!("Unhandled inv {:?}", type_),
+            }
+        }
+
+        // The peer has at least that many blocks after our tip
+        if new_blocks > 0 {
+            let height = self.height() + new_blocks;
+            self.get_peer(&token).map(|p| p.has_height(height));
+        }
+
+        new_data
+    }
+
     pub fn height(&self) -> usize { self.block_store.height() }
 
+    // Whether to send another getblocks to `token`. Only one is outstanding
+    // per peer and none once we are within the sync window of its height.
+    pub fn should_get_blocks(&self, token: &mio::Token) -> bool {
+        if self.pending_inv_len() > MAX_BLOCKS_PENDING {
+            return false;
+        }
+
+        match self.peers.get(token) {
+            Some(peer) => !peer.is_waiting_for_blocks() &&
+                          peer.is_ahead_of(self.height() + self.sync_window),
+            None => false,
+        }
+    }
+
     pub fn block_locators(&self) -> Vec<BitcoinHash> {
         self.block_store.block_locators()
     }
 
+    pub fn get_headers(&self, locators: &[BitcoinHash], hash_stop: &BitcoinHash)
+        -> Vec<BlockMetadata> {
+        self.block_store.get_headers(locators, hash_stop, MAX_HEADERS)
+    }
+
+    // Blocks to announce in response to getblocks
+    pub fn get_block_hashes(&self, locators: &[BitcoinHash], hash_stop: &BitcoinHash)
+        -> Vec<BitcoinHash> {
+        self.block_store.get_block_hashes(locators, hash_stop, MAX_BLOCKS_INV)
+    }
+
     pub fn add_peer(&mut self, token: mio::Token, version: Option<VersionMessage>) -> ConnectionType {
         if let Some(peer) = self.peers.get_mut(&token) {
-            peer.version = version;
+            if let Some(version) = version {
+                peer.received_version(version);
+            }
             return ConnectionType::Outbound;
         }
 
@@ -114,61 +240,91 @@ impl State {
 
     pub fn get_peers(&self) -> &HashMap<mio::Token, Peer> { &self.peers }
 
+    // The inv each peer, other than `from`, should get for `tx`
+    pub fn tx_announcements(&self, from: mio::Token, tx: &TxMessage)
+        -> Vec<(mio::Token, InventoryVector)> {
+        let inventory = InventoryVector::new(InventoryVectorType::MSG_TX, tx.hash());
+
+        self.peers.iter()
+            .filter(|&(token, peer)| *token != from && peer.is_ready())
+            .filter(|&(_, peer)| peer.relays_tx(tx))
+            .map(|(token, _)| (*token, inventory.clone()))
+            .collect()
+    }
+
+    // Unknown peers haven't even started the handshake
+    pub fn is_ready(&self, token: &mio::Token) -> bool {
+        self.peers.get(token).map_or(false, |peer| peer.is_ready())
+    }
+
     pub fn get_peer(&mut self, token: &mio::Token) -> Option<&mut Peer> {
         self.peers.get_mut(token)
     }
 
     pub fn has_tx(&self, hash: &BitcoinHash) -> bool {
-        self.tx_store.get(hash).is_some()
+        self.tx_store.contains(hash)
     }
 
-    pub fn add_tx(&mut self, tx: TxMessage) {
-        self.tx_store.insert(tx.hash(), tx);
+    pub fn get_tx(&self, hash: &BitcoinHash) -> Option<TxMessage> {
+        self.tx_store.get(hash).cloned()
     }
 
-    pub fn get_hash_at_height(&self, height: usize) -> Option<&BitcoinHash> {
-        self.block_store.get_hash_at_height(height)
+    pub fn add_tx(&mut self, tx: TxMessage) -> Result<(), String> {
+        self.requested.remove(&tx.hash());
+
+        // We don't keep a UTXO set so the fee is only known when all the
+        // inputs spend transactions that are in the mempool.
+        let fee = tx.fee(&|output| self.tx_store.output_value(output))
+            .map(|fee| fee.as_satoshis())
+            .unwrap_or(0);
+
+        try!(self.tx_store.insert(tx, fee));
+        Ok(())
     }
 
     pub fn get_block(&mut self, hash: &BitcoinHash) -> Option<BlockMessage> {
         self.block_store.get(hash)
     }
 
-    pub fn block_height(&self, hash: &BitcoinHash) -> Option<usize> {
-        self.block_store.get_height(hash)
-    }
-
     pub fn has_block(&self, hash: &BitcoinHash) -> bool {
         self.block_store.has(hash)
     }
 
-    pub fn add_block(&mut self, block: BlockMessage, hash: &BitcoinHash, data: &[u8]) {
-        self.block_store.insert(block, hash, data);
+    pub fn add_block(&mut self, block: BlockMessage, hash: &BitcoinHash, data: &[u8]) -> bool {
+        self.block_store.insert(block, hash, data)
     }
 }
 
 impl Peer {
     pub fn new_inbound(version: VersionMessage) -> Peer {
-        Peer {
-            ping_time: time::now(),
-            ping: -1,
-            ping_data: 0,
-            version: Some(version),
-            verak_received: false,
-            connection_type: ConnectionType::Inbound,
-            waiting_for_blocks: Timeout::new(),
-        }
+        Peer::with_clock(Some(version), ConnectionType::Inbound, system_clock())
     }
 
     pub fn new_outbound() -> Peer {
+        Peer::with_clock(None, ConnectionType::Outbound, system_clock())
+    }
+
+    fn with_clock(version: Option<VersionMessage>, connection_type: ConnectionType,
+                  clock: SharedClock) -> Peer {
         Peer {
             ping_time: time::now(),
+            ping_sent: clock.now(),
             ping: -1,
             ping_data: 0,
-            version: None,
-            verak_received: false,
-            connection_type: ConnectionType::Outbound,
-            waiting_for_blocks: Timeout::new(),
+            relay: version.as_ref().map_or(true, |version| version.relay),
+            best_height: version.as_ref().map_or(0, start_height),
+            handshake: if version.is_some() {
+                HandshakeState::VersionReceived
+            } else {
+                HandshakeState::Connected
+            },
+            version: version,
+            connection_type: connection_type,
+            waiting_for_blocks: Timeout::with_clock(clock.clone()),
+            blocks_in_flight: HashSet::new(),
+            blocks_queued: VecDeque::new(),
+            filter: None,
+            clock: clock,
         }
     }
 
@@ -184,32 +340,233 @@ impl Peer {
         self.waiting_for_blocks.get()
     }
 
+    // Peers we haven't got a version from yet might have anything
+    pub fn is_ahead_of(&self, height: usize) -> bool {
+        match self.version {
+            Some(_) => self.best_height > height,
+            None => true,
+        }
+    }
+
+    pub fn has_height(&mut self, height: usize) {
+        self.best_height = cmp::max(self.best_height, height);
+    }
+
+    pub fn queue_block(&mut self, hash: BitcoinHash) {
+        self.blocks_queued.push_back(hash);
+    }
+
+    // Returns the blocks we should ask for, never exceeding MAX_BLOCKS_IN_FLIGHT
+    pub fn next_blocks_to_request(&mut self) -> Vec<BitcoinHash> {
+        let mut blocks = vec![];
+
+        while self.blocks_in_flight.len() < MAX_BLOCKS_IN_FLIGHT {
+            match self.blocks_queued.pop_front() {
+                Some(hash) => {
+                    if self.blocks_in_flight.insert(hash) {
+                        blocks.push(hash);
+                    }
+                },
+                None => break,
+            }
+        }
+
+        blocks
+    }
+
+    pub fn received_block(&mut self, hash: &BitcoinHash) {
+        self.blocks_in_flight.remove(hash);
+    }
+
     pub fn ping_time(&self) -> time::Tm { self.ping_time }
 
-    pub fn received_verack(&mut self) {
-        self.verak_received = true;
+    pub fn sent_version(&mut self) {
+        if self.handshake == HandshakeState::Connected {
+            self.handshake = HandshakeState::VersionSent;
+        }
+    }
+
+    // Whether the peer already sent its version
+    pub fn has_version(&self) -> bool {
+        self.handshake == HandshakeState::VersionReceived || self.is_ready()
+    }
+
+    pub fn received_version(&mut self, version: VersionMessage) {
+        if !self.has_version() {
+            self.relay = version.relay;
+            self.best_height = cmp::max(self.best_height, start_height(&version));
+            self.version = Some(version);
+            self.handshake = HandshakeState::VersionReceived;
+        }
+    }
+
+    // Returns false if the verack is out of order
+    pub fn received_verack(&mut self) -> bool {
+        if self.handshake != HandshakeState::VersionReceived {
+            return false;
+        }
+
+        self.handshake = HandshakeState::Ready;
+        true
+    }
+
+    pub fn is_ready(&self) -> bool { self.handshake == HandshakeState::Ready }
+
+    pub fn load_filter(&mut self, filter: BloomFilter) {
+        self.filter = Some(filter);
+        self.relay = true;
+    }
+
+    pub fn add_to_filter(&mut self, data: &[u8]) -> Result<(), String> {
+        if data.len() > MAX_FILTERADD_SIZE {
+            return Err(format!("filteradd data too large, size={}", data.len()));
+        }
+
+        match self.filter {
+            Some(ref mut filter) => Ok(filter.insert(data)),
+            None => Err("filteradd received without a filter".to_string()),
+        }
+    }
+
+    pub fn clear_filter(&mut self) {
+        self.filter = None;
+    }
+
+    // Without a filter the peer gets every transaction, unless it asked
+    // for none in its version
+    pub fn relays_tx(&self, tx: &TxMessage) -> bool {
+        self.relay && self.filter.as_ref().map_or(true, |filter| filter.matches_tx(tx))
     }
 
     pub fn sent_ping(&mut self, ping_data: u64) {
         self.ping_time = time::now();
+        self.ping_sent = self.clock.now();
         self.ping_data = ping_data;
     }
 
     pub fn got_pong(&mut self, pong_data: u64) {
         if self.ping_data == pong_data {
-            self.ping = (time::now() - self.ping_time).num_milliseconds();
+            self.ping = (self.clock.now() - self.ping_sent).num_milliseconds();
         } else {
             println!("Invalid ping!");
         }
     }
 }
 
+// Addresses we can try to connect to, entries that can't be resolved or
+// that nobody can listen on are skipped.
+fn addr_sockets(addr_list: &[(ShortFormatTm, IPAddress)]) -> Vec<SocketAddr> {
+    let mut sockets = vec![];
+
+    for &(_, ref addr) in addr_list {
+        if addr.address.is_unspecified() || addr.port == 0 {
+            println!("Skipping invalid address {:?}", addr);
+            continue;
+        }
+
+        match (addr.address, addr.port).to_socket_addrs() {
+            Ok(resolved) => sockets.extend(resolved),
+            Err(err) => println!("Could not resolve {:?}: {:?}", addr, err),
+        }
+    }
+
+    sockets
+}
+
+const MAIN_DNS_SEEDS: &'static [&'static str] = &[
+    "seed.bitcoin.sipa.be",
+    "dnsseed.bluematt.me",
+    "dnsseed.bitcoin.dashjr.org",
+    "seed.bitcoinstats.com",
+    "seed.bitcoin.jonasschnelli.ch",
+    "seed.btc.petertodd.net",
+    "seed.bitcoin.sprovoost.nl",
+    "dnsseed.emzy.de",
+];
+
+const TESTNET_DNS_SEEDS: &'static [&'static str] = &[
+    "testnet-seed.bitcoin.jonasschnelli.ch",
+    "seed.tbtc.petertodd.net",
+    "seed.testnet.bitcoin.sprovoost.nl",
+    "testnet-seed.bluematt.me",
+];
+
+// Hostnames resolving to nodes of `network_type` and the port they listen on
+fn dns_seeds(network_type: NetworkType) -> (&'static [&'static str], u16) {
+    match network_type {
+        NetworkType::Main => (MAIN_DNS_SEEDS, 8333),
+        NetworkType::TestNet3 => (TESTNET_DNS_SEEDS, 18333),
+        _ => (&[], 0),
+    }
+}
+
+fn resolve_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    (host, port).to_socket_addrs().map(|addresses| addresses.collect())
+}
+
+// Finds peers through the DNS seeds of `network_type` and calls `connect`
+// once for every address found, up to MAX_OUTBOUND_PEERS of them. Seeds
+// that fail to resolve are skipped.
+fn bootstrap_from_seeds<F>(network_type: NetworkType,
+                           resolve: fn(&str, u16) -> io::Result<Vec<SocketAddr>>,
+                           mut connect: F) where F: FnMut(SocketAddr) {
+    let (seeds, port) = dns_seeds(network_type);
+    let mut found = HashSet::new();
+
+    for seed in seeds {
+        if found.len() >= MAX_OUTBOUND_PEERS {
+            break;
+        }
+
+        let addresses = match resolve(seed, port) {
+            Ok(addresses) => addresses,
+            Err(err) => {
+                println!("Could not resolve seed {}: {:?}", seed, err);
+                continue;
+            },
+        };
+
+        for address in addresses {
+            if found.len() >= MAX_OUTBOUND_PEERS {
+                break;
+            }
+
+            // Seeds often return the same nodes
+            if found.insert(address) {
+                connect(address);
+            }
+        }
+    }
+
+    if found.is_empty() {
+        println!("No peers found for {:?}", network_type);
+    }
+}
+
 const VERSION: i32 = 70001;
+// Maximum number of headers sent in a single headers message
+const MAX_HEADERS: usize = 2000;
+// Maximum number of blocks announced in response to getblocks
+const MAX_BLOCKS_INV: usize = 500;
+// Maximum number of entries in inv, getdata and notfound messages
+const MAX_INV_SIZE: usize = 50000;
+// Maximum number of addresses in an addr message
+const MAX_ADDR_SIZE: usize = 1000;
+// Maximum number of blocks requested from a single peer at any time
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+// No more getblocks are sent while this many announced blocks are pending
+const MAX_BLOCKS_PENDING: usize = 100;
+// Blocks from the announced tip of a peer at which we stop syncing from it
+pub const DEFAULT_SYNC_WINDOW: usize = 0;
+// Peers that don't send anything for this long are disconnected
+const INACTIVITY_TIMEOUT_MINUTES: i64 = 20;
+// Number of peers we connect to when bootstrapping from the DNS seeds
+const MAX_OUTBOUND_PEERS: usize = 8;
 type StateMutex<'a> = MutexGuard<'a, State>;
 
 impl BitcoinClient {
     fn new(state: Arc<Mutex<State>>, channel: Sender<Message>,
-           network_type: NetworkType) -> BitcoinClient {
+           network_type: NetworkType, local_address: SocketAddr) -> BitcoinClient {
         let client = BitcoinClient {
             version: VERSION,
             services: Services::new(true),
@@ -217,6 +574,7 @@ impl BitcoinClient {
             state: state,
             channel: channel,
             network_type: network_type,
+            local_address: local_address,
         };
 
         client
@@ -241,11 +599,7 @@ impl BitcoinClient {
     }
 
     fn get_blocks(&self, state: &mut StateMutex, token: mio::Token) {
-        if state.pending_inv_len() > 100 {
-            return;
-        }
-
-        if state.get_peer(&token).unwrap().is_waiting_for_blocks() {
+        if !state.should_get_blocks(&token) {
             return;
         }
 
@@ -262,7 +616,10 @@ impl BitcoinClient {
 
     fn handle_verack(&self, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
-        state.get_peer(&token).unwrap().received_verack();
+        if !state.get_peer(&token).map_or(false, |p| p.received_verack()) {
+            println!("Ignoring verack from {:?} before its version", token);
+            return;
+        }
 
         self.send_message(Command::GetAddr, token, None);
 
@@ -282,11 +639,7 @@ impl BitcoinClient {
             services: self.services,
             timestamp: time::now(),
             addr_recv: recipient_ip,
-            addr_from: IPAddress::new(
-                self.services,
-                // TODO: use upnp
-                "0:0:0:0:0:ffff:c0a8:3865".parse().unwrap(),
-                18334),
+            addr_from: IPAddress::from_socket_addr(self.services, self.local_address),
             // TODO: figure it out this
             nonce: rand::random::<u64>(),
             user_agent: self.user_agent.clone(),
@@ -297,6 +650,10 @@ impl BitcoinClient {
 
     fn handle_version(&self, message: VersionMessage, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
+        if state.get_peer(&token).map_or(false, |p| p.has_version()) {
+            println!("Ignoring duplicate version from {:?}", token);
+            return;
+        }
 
         let version = self.generate_version_message(message.addr_recv, state.height() as i32);
         let connection_type = state.add_peer(token, Some(message));
@@ -308,11 +665,29 @@ impl BitcoinClient {
         self.send_message(Command::Verack, token, None);
     }
 
+    // Asks the event loop to connect, a failure only loses this address
+    fn connect_to(&self, socket: SocketAddr) {
+        if let Err(err) = self.channel.send(Message::Connect(socket)) {
+            println!("Could not connect to {:?}: {:?}", socket, err);
+        }
+    }
+
     fn handle_addr(&self, message: AddrMessage, _: mio::Token) {
-        for (_,addr) in message.addr_list {
-            for socket in (addr.address, addr.port).to_socket_addrs().unwrap() {
-                self.channel.send(Message::Connect(socket)).unwrap();
-            }
+        for socket in addr_sockets(&message.addr_list) {
+            self.connect_to(socket);
+        }
+    }
+
+    fn handle_addrv2(&self, message: AddrV2Message, _: mio::Token) {
+        for entry in message.addr_list {
+            let socket = match entry.address {
+                NetworkAddress::IPv4(address) => SocketAddr::new(IpAddr::V4(address), entry.port),
+                NetworkAddress::IPv6(address) => SocketAddr::new(IpAddr::V6(address), entry.port),
+                // We can't connect to other networks
+                NetworkAddress::Unknown(..) => continue,
+            };
+
+            self.connect_to(socket);
         }
     }
 
@@ -338,66 +713,96 @@ impl BitcoinClient {
     }
 
     fn handle_block(&self, message: BlockMessage, token: mio::Token, data: &Cursor<&[u8]>) {
-        let hash = message.hash();
+        // We need to skip the message header
+        let data = &data.get_ref()[24..];
+        let hash = BlockMessage::hash_from_bytes(data);
+
         let mut state = self.state.lock().unwrap();
         state.received_data(&hash);
-        // We need to skip the header
-        state.add_block(message, &hash, &data.get_ref()[24..]);
+        state.get_peer(&token).map(|p| p.received_block(&hash));
+        state.add_block(message, &hash, data);
 
+        self.request_blocks(&mut state, token);
         self.get_blocks(&mut state, token);
     }
 
-    fn handle_getblocks(&self, message: GetHeadersMessage, token: mio::Token) {
-        for hash in message.block_locators.iter() {
-            if self.lock_state().block_height(hash).is_some() {
-                self.send_inv(hash, message.hash_stop, token);
-                break;
-            }
+    fn request_blocks(&self, state: &mut StateMutex, token: mio::Token) {
+        let blocks = match state.get_peer(&token) {
+            Some(peer) => peer.next_blocks_to_request(),
+            None => return,
+        };
+
+        if blocks.len() == 0 {
+            return;
         }
-    }
 
-    fn send_inv(&self, hash_start: &BitcoinHash, hash_stop: BitcoinHash, token: mio::Token) {
-        let state = self.state.lock().unwrap();
+        let inv = blocks.into_iter()
+            .map(|hash| InventoryVector::new(InventoryVectorType::MSG_BLOCK, hash))
+            .collect();
 
-        let height = state.block_height(hash_start)
-                .expect(&format!("Could not find height for {:?}", hash_start));
+        self.send_message(Command::GetData, token, Some(Box::new(InvMessage::new(inv))));
+    }
 
-        println!("send_inv token={:?} height={:?}", token, height);
+    fn handle_getblocks(&self, message: GetHeadersMessage, token: mio::Token) {
+        let inv: Vec<_> = self.lock_state()
+            .get_block_hashes(&message.block_locators, &message.hash_stop).into_iter()
+            .map(|hash| InventoryVector::new(InventoryVectorType::MSG_BLOCK, hash))
+            .collect();
 
-        let mut inv = vec![];
+        if inv.len() > 0 {
+            self.send_message(Command::Inv, token, Some(Box::new(InvMessage::new(inv))));
+        }
+    }
 
-        for h in height..height+500 {
-            match state.get_hash_at_height(h) {
-                Some(hash) => {
-                    inv.push(InventoryVector::new(InventoryVectorType::MSG_BLOCK,
-                                       *hash));
-                    if hash_stop == *hash {
-                        break;
-                    }
-                }
-                None => break,
+    fn handle_filterload(&self, message: FilterLoadMessage, token: mio::Token) {
+        let filter = match BloomFilter::from_message(message) {
+            Ok(filter) => filter,
+            Err(err) => {
+                println!("Invalid filterload: {}", err);
+                return;
             }
-        }
+        };
 
-        if inv.len() > 0 {
-            self.send_message(Command::Inv, token, Some(Box::new(InvMessage::new(inv))));
+        self.lock_state().get_peer(&token).map(|p| p.load_filter(filter));
+    }
+
+    fn handle_filteradd(&self, message: FilterAddMessage, token: mio::Token) {
+        let mut state = self.lock_state();
+        if let Some(Err(err)) = state.get_peer(&token).map(|p| p.add_to_filter(&message.data)) {
+            println!("Invalid filteradd: {}", err);
         }
     }
 
-    fn handle_filterload(&self, message: FilterLoadMessage, _: mio::Token) {
-        // TODO: actually do something
-        println!("Filterload {:?}", message);
+    fn handle_filterclear(&self, token: mio::Token) {
+        self.lock_state().get_peer(&token).map(|p| p.clear_filter());
     }
 
-    fn handle_getheaders(&self, _: GetHeadersMessage, token: mio::Token) {
-        // TODO: actually do something
-        let response = HeadersMessage::new(vec![]);
+    fn handle_getheaders(&self, message: GetHeadersMessage, token: mio::Token) {
+        let headers = self.lock_state()
+            .get_headers(&message.block_locators, &message.hash_stop);
+
+        // We only send headers so the transaction count is always 0
+        let response = HeadersMessage::new(headers.into_iter().map(|h| (h, 0)).collect());
         self.send_message(Command::Headers, token, Some(Box::new(response)));
     }
 
     fn handle_tx(&self, message: TxMessage, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
-        state.add_tx(message);
+        let hash = message.hash();
+        let is_new = !state.has_tx(&hash);
+
+        let announcements = state.tx_announcements(token, &message);
+
+        if let Err(err) = state.add_tx(message) {
+            println!("Rejected tx {:?}: {}", hash, err);
+        }
+
+        if is_new && state.has_tx(&hash) {
+            for (peer_token, inventory) in announcements {
+                self.send_message(Command::Inv, peer_token,
+                                  Some(Box::new(InvMessage::new(vec![inventory]))));
+            }
+        }
 
         self.get_blocks(&mut state, token);
     }
@@ -407,7 +812,11 @@ impl BitcoinClient {
 
         for inventory in message.inventory {
             match inventory.type_ {
-                InventoryVectorType::MSG_TX => unimplemented!(),
+                InventoryVectorType::MSG_TX => {
+                    if let Some(tx) = state.get_tx(&inventory.hash) {
+                        self.send_message(Command::Tx, token, Some(Box::new(tx)));
+                    }
+                },
                 InventoryVectorType::MSG_BLOCK => {
                     if let Some(block) = state.get_block(&inventory.hash) {
                         self.send_message(Command::Block, token, Some(Box::new(block)));
@@ -426,32 +835,13 @@ impl BitcoinClient {
     fn handle_inv(&self, message: InvMessage, token: mio::Token) {
         let mut state = self.state.lock().unwrap();
 
-        let mut new_data = vec![];
-
-        for inventory in message.inventory {
-            match inventory.type_ {
-                InventoryVectorType::MSG_TX => {
-                    if !state.has_tx(&inventory.hash) {
-                        new_data.push(InventoryVector::new(
-                                InventoryVectorType::MSG_TX,
-                                inventory.hash));
-                    }
-                },
-                InventoryVectorType::MSG_BLOCK => {
-                    if !state.has_block(&inventory.hash) &&
-                       !state.is_pending_inv(&inventory.hash) {
-                        new_data.push(InventoryVector::new(
-                                InventoryVectorType::MSG_BLOCK,
-                                inventory.hash));
-                        state.add_inv(inventory.hash);
-                    }
-                },
-                type_ => println!("Unhandled inv {:?}", type_),
-            }
+        let new_data = state.process_inv(token, message.inventory);
+        if new_data.len() > 0 {
+            self.send_message(Command::GetData, token,
+                              Some(Box::new(InvMessage::new(new_data))));
         }
 
-        self.send_message(Command::GetData, token,
-                          Some(Box::new(InvMessage::new(new_data))));
+        self.request_blocks(&mut state, token);
 
         state.get_peer(&token).unwrap().got_inv();
     }
@@ -479,6 +869,17 @@ impl BitcoinClient {
             return Err(format!("Received packet for wrong version: {:?}", header.network_type));
         }
 
+        if let Some((min, max)) = payload_size_limits(header.command) {
+            let length = header.length as usize;
+            if length < min || length > max {
+                return Err(format!("Invalid {:?} payload size {}", header.command, length));
+            }
+        }
+
+        if !is_handshake_command(header.command) && !self.lock_state().is_ready(&token) {
+            return Err(format!("Received {:?} before the handshake completed", header.command));
+        }
+
         match header.command {
             Command::Tx => {
                 let message = try!(TxMessage::deserialize(message_bytes));
@@ -512,14 +913,19 @@ impl BitcoinClient {
             Command::Verack => {
                 self.handle_verack(token);
             },
+            // Our version is below 70016 so we don't relay by wtxid (BIP339)
+            Command::WtxidRelay => {
+                println!("Ignoring wtxidrelay from {:?}", token);
+            },
             Command::GetAddr => {
                 self.handle_getaddr(token);
             },
             Command::Block => {
                 let message = try!(BlockMessage::deserialize(message_bytes));
-                if message_bytes.get_ref().len() as u64 != message_bytes.position() {
-                    Debug::print_bytes(message_bytes.get_ref());
-                    panic!();
+                match message_bytes.remaining() {
+                    Some(0) => {},
+                    Some(n) => return Err(format!("{} trailing bytes after block", n)),
+                    None => unreachable!(),
                 }
                 self.handle_block(message, token, message_bytes);
             },
@@ -535,6 +941,13 @@ impl BitcoinClient {
                 let message = try!(FilterLoadMessage::deserialize(message_bytes));
                 self.handle_filterload(message, token);
             },
+            Command::FilterAdd => {
+                let message = try!(FilterAddMessage::deserialize(message_bytes));
+                self.handle_filteradd(message, token);
+            },
+            Command::FilterClear => {
+                self.handle_filterclear(token);
+            },
             Command::Headers => {
                 let message = try!(HeadersMessage::deserialize(message_bytes));
                 self.handle_headers(message, token);
@@ -543,10 +956,20 @@ impl BitcoinClient {
                 let message = try!(AddrMessage::deserialize(message_bytes));
                 self.handle_addr(message, token);
             },
+            Command::AddrV2 => {
+                let message = try!(AddrV2Message::deserialize(message_bytes));
+                self.handle_addrv2(message, token);
+            },
             Command::Reject => {
                 let message = try!(RejectMessage::deserialize(message_bytes));
                 self.handle_reject(message, token);
             },
+            // We don't keep a filter index nor advertise one
+            Command::GetCFilters | Command::GetCFHeaders | Command::GetCFCheckpt |
+            Command::CFilter | Command::CFHeaders | Command::CFCheckpt => {
+                println!("Ignoring {:?} from {:?}", header.command, token);
+            },
+            Command::Ignored(_) => {},
             Command::Unknown => {
                 return Err(format!("Unknown message. {:?}", message_bytes));
             },
@@ -560,6 +983,11 @@ impl rpcengine::MessageHandler for BitcoinClient {
     fn handle(&self, token: mio::Token, message: Vec<u8>) {
         let mut cursor = Cursor::new(&message[..]);
         let handled = MessageHeader::deserialize(&mut cursor)
+            .and_then(|m| {
+                try!(m.check_payload(&message[cursor.position() as usize..]));
+                Ok(m)
+            })
+            .map_err(String::from)
             .and_then(|m| self.handle_command(m, token, &mut cursor));
 
         if let Err(x) = handled {
@@ -571,41 +999,649 @@ impl rpcengine::MessageHandler for BitcoinClient {
         let mut state = self.state.lock().unwrap();
 
         state.add_peer(token, None);
+        state.get_peer(&token).map(|p| p.sent_version());
 
-        let ip = match addr {
-            SocketAddr::V4(ipv4) => ipv4.ip().to_ipv6_mapped(),
-            SocketAddr::V6(ipv6) => *ipv6.ip(),
-        };
-
-        let ip_address = IPAddress::new(Services::new(true), ip, addr.port());
+        let ip_address = IPAddress::from_socket_addr(Services::new(true), addr);
         let version = self.generate_version_message(ip_address, state.height() as i32);
 
         self.send_message(Command::Version, token, Some(Box::new(version)));
     }
 }
 
-pub fn start(address: SocketAddr, connect_to: Option<SocketAddr>, blocks_file: File) {
-    let server = tcp::TcpListener::bind(&address).unwrap();
+// Smallest and largest payload of `command`, checked before deserializing
+// it. Other commands are only limited by the size of a message.
+fn payload_size_limits(command: Command) -> Option<(usize, usize)> {
+    // Lists start with their length
+    let list = |max: usize, item_size: usize| {
+        (1, VarInt::new(max as u64).encoded_len() + max * item_size)
+    };
+
+    match command {
+        Command::Ping | Command::Pong => Some((8, 8)),
+        Command::Verack | Command::GetAddr | Command::WtxidRelay | Command::FilterClear =>
+            Some((0, 0)),
+        // Inventory vectors are a type and a hash
+        Command::Inv | Command::GetData | Command::NotFound => Some(list(MAX_INV_SIZE, 36)),
+        // Headers are followed by an empty transaction count
+        Command::Headers => Some(list(MAX_HEADERS, 81)),
+        Command::Addr => Some(list(MAX_ADDR_SIZE, 30)),
+        Command::FilterAdd => Some(list(MAX_FILTERADD_SIZE, 1)),
+        // The filter is followed by n_hash_funcs, n_tweak and n_flags
+        Command::FilterLoad => {
+            let (_, max) = list(MAX_BLOOM_FILTER_SIZE, 1);
+            Some((1 + 9, max + 9))
+        },
+        _ => None,
+    }
+}
+
+// Heights below 0 are treated as an empty chain
+fn start_height(version: &VersionMessage) -> usize {
+    cmp::max(version.start_height, 0) as usize
+}
+
+// The only messages we process before verack
+fn is_handshake_command(command: Command) -> bool {
+    match command {
+        Command::Version | Command::Verack | Command::WtxidRelay | Command::Reject |
+        Command::Ignored(_) | Command::Unknown => true,
+        _ => false,
+    }
+}
+
+// Without a `listen` address the node only makes outbound connections.
+// Blocks are saved in `data_dir` unless a `blocks_file` is given. Peers are
+// told to reach us at `local_address`. Syncing from a peer stops
+// `sync_window` blocks before the best height it is known to have.
+pub fn start(listen: Option<SocketAddr>, connect_to: Option<SocketAddr>, local_address: SocketAddr,
+             data_dir: PathBuf, blocks_file: Option<File>, network_type: NetworkType,
+             sync_window: usize) {
+    let server = listen.map(|address| tcp::TcpListener::bind(&address).unwrap());
     let mut event_loop = mio::EventLoop::new().unwrap();
-    event_loop.register(&server, rpcengine::SERVER, mio::EventSet::readable(),
-                        mio::PollOpt::edge()).unwrap();
 
-    let state = Arc::new(Mutex::new(State::new(NetworkType::TestNet3, blocks_file)));
+    let mut state = match blocks_file {
+        Some(file) => State::new(network_type, file),
+        None => match State::open(network_type, &data_dir) {
+            Ok(state) => state,
+            Err(e) => {
+                println!("Error: {}", e);
+                return;
+            },
+        },
+    };
+    state.set_sync_window(sync_window);
+    let state = Arc::new(Mutex::new(state));
 
     let client = Arc::new(
-            BitcoinClient::new(state.clone(), event_loop.channel(), NetworkType::TestNet3));
+            BitcoinClient::new(state.clone(), event_loop.channel(), network_type,
+                               local_address));
 
     let handler: Arc<rpcengine::MessageHandler> = client.clone();
 
-    println!("running bitcoin server; port={}", address.port());
+    match listen {
+        Some(address) => println!("running bitcoin server; port={}", address.port()),
+        None => println!("running bitcoin client; not accepting inbound connections"),
+    }
+
     let child = thread::spawn(move || {
-        let mut engine = RPCEngine::new(server, handler);
+        let mut engine = RPCEngine::new(server, handler,
+                                        Duration::minutes(INACTIVITY_TIMEOUT_MINUTES));
+        engine.register(&mut event_loop).unwrap();
+        RPCEngine::schedule_idle_check(&mut event_loop);
         event_loop.run(&mut engine).unwrap();
     });
 
-    if let Some(address) = connect_to {
-        client.connect(address);
+    match connect_to {
+        Some(address) => client.connect(address),
+        None => bootstrap_from_seeds(network_type, resolve_host,
+                                     |address| client.connect_to(address)),
     }
 
     let _ = child.join();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{addr_sockets, bootstrap_from_seeds, BitcoinClient, ConnectionType, HandshakeState,
+                Peer, State, MAX_BLOCKS_IN_FLIGHT, MAX_OUTBOUND_PEERS};
+    use super::super::{IPAddress, Services};
+    use super::super::rpcengine::{Message, RPCEngine};
+    use super::super::store::BlockStore;
+    use super::super::bloom::{BloomFilter, MAX_FILTERADD_SIZE};
+    use super::super::clock::MockClock;
+    use super::super::messages::{BitcoinHash, Command, GetHeadersMessage, InventoryVector,
+                                 InventoryVectorType, MessageHeader, NetworkType, SerializeHash,
+                                 ShortFormatTm, TxMessage, TxOut, VersionMessage};
+    use serialize::{Deserialize, Serialize};
+    use test::utils::{get_file, get_next_block};
+    use transaction::Amount;
+
+    use mio;
+    use rand;
+    use time;
+    use time::Duration;
+
+    use std::env;
+    use std::fs;
+    use std::io::{self, Cursor};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+
+    fn get_hash(i: usize) -> BitcoinHash {
+        let mut hash = [0u8; 32];
+        hash[0] = i as u8;
+        hash[1] = (i >> 8) as u8;
+        BitcoinHash::new(hash)
+    }
+
+    #[test]
+    fn test_ping() {
+        let clock = MockClock::new();
+        let mut peer = Peer::with_clock(None, ConnectionType::Outbound, clock.clone());
+        assert_eq!(peer.ping, -1);
+
+        peer.sent_ping(42);
+        clock.advance(Duration::milliseconds(250));
+
+        // A pong with the wrong nonce doesn't count
+        peer.got_pong(43);
+        assert_eq!(peer.ping, -1);
+
+        peer.got_pong(42);
+        assert_eq!(peer.ping, 250);
+    }
+
+    #[test]
+    fn test_getblocks_timeout() {
+        let clock = MockClock::new();
+        let mut peer = Peer::with_clock(None, ConnectionType::Outbound, clock.clone());
+        assert!(!peer.is_waiting_for_blocks());
+
+        peer.sent_getblocks();
+        clock.advance(Duration::seconds(10));
+        assert!(peer.is_waiting_for_blocks());
+
+        clock.advance(Duration::seconds(6));
+        assert!(!peer.is_waiting_for_blocks());
+    }
+
+    #[test]
+    fn test_blocks_in_flight() {
+        let mut peer = Peer::new_outbound();
+        for i in 0..500 {
+            peer.queue_block(get_hash(i));
+        }
+
+        let requested = peer.next_blocks_to_request();
+        assert_eq!(requested.len(), MAX_BLOCKS_IN_FLIGHT);
+        assert_eq!(requested[0], get_hash(0));
+        assert_eq!(peer.blocks_in_flight.len(), MAX_BLOCKS_IN_FLIGHT);
+
+        // Nothing else until some blocks arrive
+        assert_eq!(peer.next_blocks_to_request().len(), 0);
+
+        peer.received_block(&get_hash(0));
+        peer.received_block(&get_hash(1));
+
+        let requested = peer.next_blocks_to_request();
+        assert_eq!(requested, vec![get_hash(MAX_BLOCKS_IN_FLIGHT),
+                                   get_hash(MAX_BLOCKS_IN_FLIGHT + 1)]);
+        assert_eq!(peer.blocks_in_flight.len(), MAX_BLOCKS_IN_FLIGHT);
+    }
+
+    #[test]
+    fn test_data_dir() {
+        let root = env::temp_dir().join(format!("bitcoin-rust-data-{}", rand::random::<u64>()));
+        let first = root.join("first");
+        let second = root.join("second");
+
+        for &(network_type, ref data_dir) in &[(NetworkType::TestNet3, &first),
+                                               (NetworkType::TestNet3, &second),
+                                               (NetworkType::Main, &first)] {
+            let state = State::open(network_type, data_dir).unwrap();
+            assert_eq!(state.height(), 0);
+        }
+
+        let first_path = BlockStore::path(&first, NetworkType::TestNet3);
+        let second_path = BlockStore::path(&second, NetworkType::TestNet3);
+        let main_path = BlockStore::path(&first, NetworkType::Main);
+        assert!(first_path != second_path && first_path != main_path);
+
+        // Every store only has its own genesis block
+        let genesis_size = fs::metadata(&first_path).unwrap().len();
+        assert!(genesis_size > 0);
+        assert_eq!(fs::metadata(&second_path).unwrap().len(), genesis_size);
+        assert_eq!(fs::metadata(&main_path).unwrap().len(), genesis_size);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn get_version() -> VersionMessage {
+        let services = Services::new(true);
+        let address = IPAddress::new(services, "2001:db8::1".parse().unwrap(), 18333);
+
+        VersionMessage {
+            version: 70016,
+            services: services,
+            timestamp: time::now(),
+            addr_recv: address,
+            addr_from: address,
+            nonce: 0,
+            user_agent: "/test/".to_string(),
+            start_height: 0,
+            relay: true,
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let mut peer = Peer::new_outbound();
+        assert_eq!(peer.handshake, HandshakeState::Connected);
+
+        // verack before version
+        assert!(!peer.received_verack());
+        assert_eq!(peer.handshake, HandshakeState::Connected);
+
+        peer.sent_version();
+        assert_eq!(peer.handshake, HandshakeState::VersionSent);
+        assert!(!peer.has_version());
+
+        peer.received_version(get_version());
+        assert_eq!(peer.handshake, HandshakeState::VersionReceived);
+        assert!(!peer.is_ready());
+
+        assert!(peer.received_verack());
+        assert!(peer.is_ready());
+
+        // Duplicates don't change anything
+        let mut version = get_version();
+        version.start_height = 100;
+        peer.received_version(version);
+        assert_eq!(peer.version.as_ref().unwrap().start_height, 0);
+        assert!(!peer.received_verack());
+        assert!(peer.is_ready());
+
+        let peer = Peer::new_inbound(get_version());
+        assert_eq!(peer.handshake, HandshakeState::VersionReceived);
+    }
+
+    #[test]
+    fn test_messages_before_handshake() {
+        let event_loop: mio::EventLoop<RPCEngine> = mio::EventLoop::new().unwrap();
+        let state = State::new(NetworkType::TestNet3, get_file("before_handshake"));
+        let client = BitcoinClient::new(Arc::new(Mutex::new(state)), event_loop.channel(),
+                                        NetworkType::TestNet3, "0.0.0.0:18333".parse().unwrap());
+        let token = mio::Token(1);
+
+        let genesis = client.lock_state().block_locators()[0];
+        let mut payload = vec![];
+        GetHeadersMessage::new(70001, vec![genesis], BitcoinHash::new([0; 32]))
+            .serialize(&mut payload);
+
+        let getblocks = || {
+            let mut header = get_header(b"getblocks\0\0\0");
+            header.length = payload.len() as u32;
+            client.handle_command(header, token, &mut Cursor::new(&payload[..]))
+        };
+        let error = Err(format!("Received GetBlocks before the handshake completed"));
+
+        assert_eq!(getblocks(), error);
+
+        client.lock_state().add_peer(token, Some(get_version()));
+        assert_eq!(getblocks(), error);
+
+        // Handshake messages are still processed
+        let wtxidrelay = get_header(b"wtxidrelay\0\0");
+        assert_eq!(client.handle_command(wtxidrelay, token, &mut Cursor::new(&[][..])), Ok(()));
+
+        assert!(client.lock_state().get_peer(&token).unwrap().received_verack());
+        let filterclear = get_header(b"filterclear\0");
+        assert_eq!(client.handle_command(filterclear, token, &mut Cursor::new(&[][..])), Ok(()));
+
+        // The same getblocks is fine now
+        assert!(client.lock_state().is_ready(&token));
+        assert_eq!(getblocks(), Ok(()));
+    }
+
+    #[test]
+    fn test_block_trailing_bytes() {
+        let event_loop: mio::EventLoop<RPCEngine> = mio::EventLoop::new().unwrap();
+        let state = State::new(NetworkType::TestNet3, get_file("block_trailing_bytes"));
+        let client = BitcoinClient::new(Arc::new(Mutex::new(state)), event_loop.channel(),
+                                        NetworkType::TestNet3, "0.0.0.0:18333".parse().unwrap());
+        let token = mio::Token(1);
+
+        client.lock_state().add_peer(token, Some(get_version()));
+        assert!(client.lock_state().get_peer(&token).unwrap().received_verack());
+
+        let genesis = client.lock_state().block_locators()[0];
+        let mut payload = vec![];
+        client.lock_state().get_block(&genesis).unwrap().serialize(&mut payload);
+        payload.push(0);
+
+        let mut header = get_header(b"block\0\0\0\0\0\0\0");
+        header.length = payload.len() as u32;
+        assert_eq!(client.handle_command(header, token, &mut Cursor::new(&payload[..])),
+                   Err(format!("1 trailing bytes after block")));
+    }
+
+    #[test]
+    fn test_payload_size_limits() {
+        let event_loop: mio::EventLoop<RPCEngine> = mio::EventLoop::new().unwrap();
+        let state = State::new(NetworkType::TestNet3, get_file("payload_size_limits"));
+        let client = BitcoinClient::new(Arc::new(Mutex::new(state)), event_loop.channel(),
+                                        NetworkType::TestNet3, "0.0.0.0:18333".parse().unwrap());
+        let token = mio::Token(1);
+        let handle = |header: MessageHeader| {
+            client.handle_command(header, token, &mut Cursor::new(&[][..]))
+        };
+
+        let mut ping = get_header(b"ping\0\0\0\0\0\0\0\0");
+        ping.length = 7;
+        assert_eq!(handle(ping.clone()), Err(format!("Invalid Ping payload size 7")));
+        ping.length = 9;
+        assert_eq!(handle(ping.clone()), Err(format!("Invalid Ping payload size 9")));
+
+        // The right size gets as far as the handshake check
+        ping.length = 8;
+        assert_eq!(handle(ping), Err(format!("Received Ping before the handshake completed")));
+
+        let mut verack = get_header(b"verack\0\0\0\0\0\0");
+        verack.length = 1;
+        assert!(handle(verack).is_err());
+
+        // 50000 entries fit, one more doesn't
+        let mut inv = get_header(b"inv\0\0\0\0\0\0\0\0\0");
+        inv.length = 3 + 50000 * 36;
+        assert_eq!(handle(inv.clone()),
+                   Err(format!("Received Inv before the handshake completed")));
+        inv.length = 3 + 50001 * 36;
+        assert_eq!(handle(inv), Err(format!("Invalid Inv payload size 1800039")));
+
+        let mut getdata = get_header(b"getdata\0\0\0\0\0");
+        getdata.length = 0;
+        assert!(handle(getdata).is_err());
+    }
+
+    fn get_header(command: &[u8; 12]) -> MessageHeader {
+        let mut data = vec![0x0b, 0x11, 0x09, 0x07];
+        data.extend(command.iter());
+        data.extend(&[0; 8]);
+        MessageHeader::deserialize(&mut Cursor::new(&data[..])).unwrap()
+    }
+
+    #[test]
+    fn test_version_addr_from() {
+        let event_loop: mio::EventLoop<RPCEngine> = mio::EventLoop::new().unwrap();
+        let state = State::new(NetworkType::TestNet3, get_file("version_addr_from"));
+        let client = BitcoinClient::new(Arc::new(Mutex::new(state)), event_loop.channel(),
+                                        NetworkType::TestNet3, "203.0.113.5:18444".parse().unwrap());
+
+        let services = Services::new(true);
+        let peer = IPAddress::new(services, "2001:db8::1".parse().unwrap(), 18333);
+        let version = client.generate_version_message(peer, 0);
+
+        assert_eq!(version.addr_recv, peer);
+        assert_eq!(version.addr_from,
+                   IPAddress::new(services, "::ffff:203.0.113.5".parse().unwrap(), 18444));
+    }
+
+    #[test]
+    fn test_ignored_commands() {
+        let event_loop: mio::EventLoop<RPCEngine> = mio::EventLoop::new().unwrap();
+        let state = State::new(NetworkType::TestNet3, get_file("ignored_commands"));
+        let client = BitcoinClient::new(Arc::new(Mutex::new(state)), event_loop.channel(),
+                                        NetworkType::TestNet3, "0.0.0.0:18333".parse().unwrap());
+
+        let header = get_header(b"alert\0\0\0\0\0\0\0");
+        assert_eq!(header.command, Command::Ignored(*b"alert\0\0\0\0\0\0\0"));
+        assert_eq!(client.handle_command(header, mio::Token(1), &mut Cursor::new(&[][..])),
+                   Ok(()));
+
+        let header = get_header(b"nonsense\0\0\0\0");
+        assert_eq!(header.command, Command::Unknown);
+        assert!(client.handle_command(header, mio::Token(1), &mut Cursor::new(&[][..])).is_err());
+    }
+
+    #[test]
+    fn test_addr_sockets() {
+        let now = ShortFormatTm::new(time::now());
+        let services = Services::new(true);
+        let first = Ipv4Addr::new(10, 0, 0, 1).to_ipv6_mapped();
+        let second = "2001:db8::1".parse().unwrap();
+
+        let addr_list = vec![
+            (now, IPAddress::new(services, first, 18333)),
+            (now, IPAddress::new(services, Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 0), 18333)),
+            (now, IPAddress::new(services, first, 0)),
+            (now, IPAddress::new(services, second, 8333)),
+        ];
+
+        let sockets = addr_sockets(&addr_list);
+        assert_eq!(sockets.len(), 2);
+        assert_eq!(sockets[0].port(), 18333);
+        assert_eq!(sockets[1], SocketAddr::new(IpAddr::V6(second), 8333));
+    }
+
+    fn resolve_fixed(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+        let ipv4 = |last| SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), port);
+
+        match host {
+            "seed.bitcoin.sipa.be" => Ok(vec![ipv4(1), ipv4(2)]),
+            // Already returned by the first seed
+            "dnsseed.bluematt.me" => Ok(vec![ipv4(2)]),
+            "seed.tbtc.petertodd.net" => Ok(vec!["[2001:db8::1]:18333".parse().unwrap()]),
+            "seed.testnet.bitcoin.sprovoost.nl" => Ok((1..20).map(ipv4).collect()),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "no such host")),
+        }
+    }
+
+    // Keeps the addresses of the Connect messages sent to the event loop
+    struct ConnectRecorder {
+        connected: Vec<SocketAddr>,
+    }
+
+    impl mio::Handler for ConnectRecorder {
+        type Timeout = ();
+        type Message = Message;
+
+        fn notify(&mut self, _: &mut mio::EventLoop<ConnectRecorder>, message: Message) {
+            if let Message::Connect(address) = message {
+                self.connected.push(address);
+            }
+        }
+    }
+
+    #[test]
+    fn test_bootstrap_from_seeds() {
+        let seeds = |network_type| {
+            let mut event_loop = mio::EventLoop::new().unwrap();
+            let state = State::new(NetworkType::TestNet3, get_file("bootstrap_from_seeds"));
+            let client = BitcoinClient::new(Arc::new(Mutex::new(state)), event_loop.channel(),
+                                            NetworkType::TestNet3,
+                                            "0.0.0.0:18333".parse().unwrap());
+
+            bootstrap_from_seeds(network_type, resolve_fixed,
+                                 |address| client.connect_to(address));
+
+            let mut recorder = ConnectRecorder { connected: vec![] };
+            event_loop.run_once(&mut recorder, Some(0)).unwrap();
+            recorder.connected
+        };
+
+        assert_eq!(seeds(NetworkType::Main), vec!["10.0.0.1:8333".parse().unwrap(),
+                                                  "10.0.0.2:8333".parse().unwrap()]);
+
+        // Only the first peers are used when the seeds return many of them
+        let testnet = seeds(NetworkType::TestNet3);
+        assert_eq!(testnet.len(), MAX_OUTBOUND_PEERS);
+        assert_eq!(testnet[0], "[2001:db8::1]:18333".parse().unwrap());
+        assert_eq!(testnet[1..], (1..8).map(|last| {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)), 18333)
+        }).collect::<Vec<_>>()[..]);
+
+        assert!(seeds(NetworkType::NameCoin).is_empty());
+    }
+
+    #[test]
+    fn test_filter() {
+        let key_hash = [0x42; 20];
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend(&key_hash);
+        script.extend(&[0x88, 0xac]);
+
+        let output = TxOut::new(Amount::from_satoshis(1000), script);
+        let tx = TxMessage::new(1, vec![], vec![output], 0);
+        let other_tx = TxMessage::new(2, vec![], vec![], 0);
+
+        let mut peer = Peer::new_outbound();
+        assert!(peer.add_to_filter(&key_hash).is_err());
+        assert!(peer.relays_tx(&tx));
+
+        peer.load_filter(BloomFilter::new(vec![0; 16], 5, 0).unwrap());
+        assert!(!peer.relays_tx(&tx));
+
+        peer.add_to_filter(&key_hash).unwrap();
+        assert!(peer.relays_tx(&tx));
+        assert!(!peer.relays_tx(&other_tx));
+        assert!(peer.add_to_filter(&[0; MAX_FILTERADD_SIZE + 1]).is_err());
+
+        // filterclear goes back to relaying everything
+        peer.clear_filter();
+        assert!(peer.relays_tx(&other_tx));
+        assert!(peer.add_to_filter(&key_hash).is_err());
+    }
+
+    #[test]
+    fn test_no_relay() {
+        let tx = TxMessage::new(1, vec![], vec![], 0);
+        let mut state = State::new(NetworkType::TestNet3, get_file("no_relay"));
+
+        let sender = mio::Token(1);
+        let relaying = mio::Token(2);
+        let not_relaying = mio::Token(3);
+
+        let mut version = get_version();
+        for &token in &[sender, relaying, not_relaying] {
+            version.relay = token != not_relaying;
+            state.add_peer(token, Some(version.clone()));
+            assert!(state.get_peer(&token).unwrap().received_verack());
+        }
+
+        let inventory = InventoryVector::new(InventoryVectorType::MSG_TX, tx.hash());
+        assert_eq!(state.tx_announcements(sender, &tx), vec![(relaying, inventory.clone())]);
+
+        // Loading a filter turns relaying back on
+        let mut filter = BloomFilter::new(vec![0; 16], 5, 0).unwrap();
+        filter.insert(tx.hash().inner());
+        state.get_peer(&not_relaying).unwrap().load_filter(filter);
+
+        let mut announcements = state.tx_announcements(sender, &tx);
+        announcements.sort_by_key(|&(token, _)| token.0);
+        assert_eq!(announcements, vec![(relaying, inventory.clone()), (not_relaying, inventory)]);
+    }
+
+    #[test]
+    fn test_duplicate_inv() {
+        let mut state = State::new(NetworkType::TestNet3, get_file("duplicate_inv"));
+        let first = mio::Token(1);
+        let second = mio::Token(2);
+        state.add_peer(first, None);
+        state.add_peer(second, None);
+
+        let inv = vec![InventoryVector::new(InventoryVectorType::MSG_BLOCK, get_hash(1)),
+                       InventoryVector::new(InventoryVectorType::MSG_TX, get_hash(2))];
+
+        let tx_data = state.process_inv(first, inv.clone());
+        assert_eq!(tx_data, vec![inv[1].clone()]);
+        assert!(state.process_inv(second, inv.clone()).is_empty());
+
+        // Only the first peer gets a getdata for the block
+        let blocks = state.get_peer(&first).unwrap().next_blocks_to_request();
+        assert_eq!(blocks, vec![get_hash(1)]);
+        assert!(state.get_peer(&second).unwrap().next_blocks_to_request().is_empty());
+    }
+
+    fn add_next_block(state: &mut State) {
+        let tip = *state.block_store.get_hash_at_height(state.height()).unwrap();
+        let block = get_next_block(tip, vec![]);
+
+        let (data, hash) = block.serialize_hash();
+        assert!(state.add_block(block, &hash, &data));
+    }
+
+    #[test]
+    fn test_sync_window() {
+        let event_loop: mio::EventLoop<RPCEngine> = mio::EventLoop::new().unwrap();
+        let state = State::new(NetworkType::TestNet3, get_file("sync_window"));
+        let client = BitcoinClient::new(Arc::new(Mutex::new(state)), event_loop.channel(),
+                                        NetworkType::TestNet3, "0.0.0.0:18333".parse().unwrap());
+
+        let address = IPAddress::new(Services::new(true), "2001:db8::1".parse().unwrap(), 18333);
+        let token = mio::Token(1);
+
+        let mut state = client.lock_state();
+        state.add_peer(token, Some(client.generate_version_message(address, 2)));
+        assert!(state.should_get_blocks(&token));
+
+        // Only one getblocks at a time
+        state.get_peer(&token).unwrap().sent_getblocks();
+        assert!(!state.should_get_blocks(&token));
+        state.get_peer(&token).unwrap().got_inv();
+
+        add_next_block(&mut state);
+        assert_eq!(state.height(), 1);
+        assert!(state.should_get_blocks(&token));
+
+        // We reached the height the peer announced
+        add_next_block(&mut state);
+        assert_eq!(state.height(), 2);
+        assert!(!state.should_get_blocks(&token));
+
+        // Stopping early with a wider window
+        let mut state = State::new(NetworkType::TestNet3, get_file("sync_window_wide"));
+        state.set_sync_window(1);
+        state.add_peer(token, Some(client.generate_version_message(address, 2)));
+        assert!(state.should_get_blocks(&token));
+        add_next_block(&mut state);
+        assert!(!state.should_get_blocks(&token));
+
+        // Peers that didn't send a version yet
+        let other = mio::Token(2);
+        state.add_peer(other, None);
+        assert!(state.should_get_blocks(&other));
+    }
+
+    #[test]
+    fn test_sync_window_peer_grows() {
+        let event_loop: mio::EventLoop<RPCEngine> = mio::EventLoop::new().unwrap();
+        let state = State::new(NetworkType::TestNet3, get_file("sync_window_grows"));
+        let client = BitcoinClient::new(Arc::new(Mutex::new(state)), event_loop.channel(),
+                                        NetworkType::TestNet3, "0.0.0.0:18333".parse().unwrap());
+
+        let address = IPAddress::new(Services::new(true), "2001:db8::1".parse().unwrap(), 18333);
+        let token = mio::Token(1);
+
+        let mut state = client.lock_state();
+        state.add_peer(token, Some(client.generate_version_message(address, 1)));
+        add_next_block(&mut state);
+        assert!(!state.should_get_blocks(&token));
+
+        // Blocks we already have don't move the peer ahead
+        let tip = *state.block_store.get_hash_at_height(1).unwrap();
+        let inventory = vec![InventoryVector::new(InventoryVectorType::MSG_BLOCK, tip)];
+        state.process_inv(token, inventory);
+        assert!(!state.should_get_blocks(&token));
+
+        // The peer found two blocks after the handshake
+        let inventory = vec![InventoryVector::new(InventoryVectorType::MSG_BLOCK, get_hash(1)),
+                             InventoryVector::new(InventoryVectorType::MSG_BLOCK, get_hash(2))];
+        state.process_inv(token, inventory);
+        assert!(state.should_get_blocks(&token));
+
+        add_next_block(&mut state);
+        assert!(state.should_get_blocks(&token));
+        add_next_block(&mut state);
+        assert!(!state.should_get_blocks(&token));
+    }
+}
@@ -7,7 +7,7 @@ use mio::tcp::*;
 use mio::util::Slab;
 use bytes::Buf;
 use std::mem;
-use std::io::Cursor;
+use std::io::{self, Cursor};
 use std::sync::{Arc, Mutex};
 
 use std::net::SocketAddr;
@@ -22,19 +22,31 @@ use std::thread;
 
 use std::cmp;
 
+use time::{Duration, SteadyTime};
+
 pub const SERVER: mio::Token = mio::Token(0);
 
+// How often we look for connections that have been idle for too long
+const IDLE_CHECK_INTERVAL_MS: u64 = 30 * 1000;
+
+// Largest payload we accept, same as the maximum block relay size.
+// Peers declaring anything bigger are disconnected before we buffer it.
+const MAX_MESSAGE_SIZE: usize = 32 * 1024 * 1024;
+
 pub trait MessageHandler: Sync + Send {
     fn handle(&self, token: mio::Token, message: Vec<u8>);
     fn new_connection(&self, token: mio::Token, addr: SocketAddr);
 }
 
 pub struct RPCEngine {
-    server: TcpListener,
+    // None when running in connect-only mode
+    server: Option<TcpListener>,
     connections: Slab<Connection>,
     handler: Arc<MessageHandler>,
     jobs: Arc<Mutex<VecDeque<(mio::Token, Vec<u8>)>>>,
     threads_counter: Arc<Mutex<usize>>,
+    // Connections that don't send anything for this long are closed
+    inactivity_timeout: Duration,
 }
 
 impl RPCEngine {
@@ -58,7 +70,8 @@ impl RPCEngine {
         });
     }
 
-    pub fn new(server: TcpListener, handler: Arc<MessageHandler>) -> RPCEngine {
+    pub fn new(server: Option<TcpListener>, handler: Arc<MessageHandler>,
+               inactivity_timeout: Duration) -> RPCEngine {
         // Token 0 is reserver for the server
         let slab = Slab::new_starting_at(mio::Token(1), 1024);
         let engine = RPCEngine {
@@ -67,11 +80,38 @@ impl RPCEngine {
             handler: handler,
             jobs: Arc::new(Mutex::new(VecDeque::new())),
             threads_counter: Arc::new(Mutex::new(0)),
+            inactivity_timeout: inactivity_timeout,
         };
 
         engine
     }
 
+    // Starts accepting inbound connections, if we have a listener
+    pub fn register(&self, event_loop: &mut mio::EventLoop<RPCEngine>) -> io::Result<()> {
+        match self.server {
+            Some(ref server) => event_loop.register(server, SERVER, mio::EventSet::readable(),
+                                                    mio::PollOpt::edge()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn schedule_idle_check(event_loop: &mut mio::EventLoop<RPCEngine>) {
+        event_loop.timeout_ms((), IDLE_CHECK_INTERVAL_MS).unwrap();
+    }
+
+    fn close_idle_connections(&mut self, event_loop: &mut mio::EventLoop<RPCEngine>) {
+        let idle = select_idle(
+            self.connections.iter().map(|c| (c.token, &c.state)),
+            SteadyTime::now(), self.inactivity_timeout);
+
+        for token in idle {
+            if let Some(connection) = self.connections.remove(token) {
+                println!("Closing idle connection {:?}", token);
+                let _ = event_loop.deregister(&connection.socket);
+            }
+        }
+    }
+
     fn add_new_peer(&mut self, event_loop: &mut mio::EventLoop<RPCEngine>,
                     socket: TcpStream) -> mio::Token {
         // TODO: handle errors
@@ -89,7 +129,12 @@ impl RPCEngine {
     }
 
     fn handle_new_connection(&mut self, event_loop: &mut mio::EventLoop<RPCEngine>) {
-        match self.server.accept() {
+        let accepted = match self.server {
+            Some(ref server) => server.accept(),
+            None => return,
+        };
+
+        match accepted {
             Ok(Some((socket, _))) => {
                 self.add_new_peer(event_loop, socket);
             }
@@ -168,6 +213,20 @@ impl mio::Handler for RPCEngine {
             Message::SendMessage(token, data) => self.send_message(event_loop, token, data),
         }
     }
+
+    fn timeout(&mut self, event_loop: &mut mio::EventLoop<RPCEngine>, _: ()) {
+        self.close_idle_connections(event_loop);
+        RPCEngine::schedule_idle_check(event_loop);
+    }
+}
+
+// Returns the connections that haven't received anything for longer than `timeout`
+fn select_idle<'a, I>(connections: I, now: SteadyTime, timeout: Duration) -> Vec<mio::Token>
+where I: Iterator<Item=(mio::Token, &'a State)> {
+    connections
+        .filter(|&(_, state)| state.is_idle(now, timeout))
+        .map(|(token, _)| token)
+        .collect()
 }
 
 #[derive(Debug)]
@@ -217,6 +276,8 @@ impl Connection {
                 vec![]
             }
             Ok(Some(_)) => {
+                self.state.touch();
+
                 let mut done = false;
                 let mut result = vec![];
                 while !done {
@@ -303,6 +364,7 @@ struct State {
     writing_buf: Cursor<Vec<u8>>,
     writing_queue: VecDeque<Vec<u8>>,
     connection_state: ConnectionState,
+    last_activity: SteadyTime,
 }
 
 impl State {
@@ -312,11 +374,18 @@ impl State {
             writing_buf: Cursor::new(vec![]),
             writing_queue: VecDeque::new(),
             connection_state: ConnectionState::Active,
+            last_activity: SteadyTime::now(),
         }
     }
 
     pub fn close(&mut self) { self.connection_state = ConnectionState::Closed }
 
+    pub fn touch(&mut self) { self.last_activity = SteadyTime::now() }
+
+    pub fn is_idle(&self, now: SteadyTime, timeout: Duration) -> bool {
+        now - self.last_activity > timeout
+    }
+
     pub fn connection_state(&self) -> &ConnectionState { &self.connection_state }
 
     pub fn has_more_messages(&self) -> bool {
@@ -351,6 +420,12 @@ impl State {
         }
 
         if let Some(message_len) = self.get_message_length() {
+            if message_len > MAX_MESSAGE_SIZE {
+                self.reading_buf.clear();
+                self.close();
+                return Err(format!("Message is too big, length={}", message_len));
+            }
+
             // The input doesn't have the full message, let's wait
             if self.reading_buf.len() < 24 + message_len {
                 return Ok(vec![]);
@@ -386,3 +461,102 @@ impl State {
         &mut self.writing_buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectionState, Message, MessageHandler, RPCEngine, State, select_idle,
+                MAX_MESSAGE_SIZE};
+    use super::super::messages::{Command, MessageHeader, NetworkType};
+
+    use mio;
+    use mio::Handler;
+    use serialize::Serialize;
+    use time::{Duration, SteadyTime};
+
+    use std::net::{self, SocketAddr};
+    use std::sync::{Arc, Mutex};
+
+    struct ConnectionRecorder {
+        connections: Mutex<Vec<SocketAddr>>,
+    }
+
+    impl MessageHandler for ConnectionRecorder {
+        fn handle(&self, _: mio::Token, _: Vec<u8>) {}
+
+        fn new_connection(&self, _: mio::Token, addr: SocketAddr) {
+            self.connections.lock().unwrap().push(addr);
+        }
+    }
+
+    fn get_header(length: u32) -> Vec<u8> {
+        let header = MessageHeader {
+            network_type: NetworkType::TestNet3,
+            command: Command::Block,
+            length: length,
+            checksum: [0; 4],
+        };
+
+        let mut buffer = vec![];
+        header.serialize(&mut buffer);
+        buffer
+    }
+
+    #[test]
+    fn test_connect_only() {
+        let recorder = Arc::new(ConnectionRecorder { connections: Mutex::new(vec![]) });
+        let mut event_loop = mio::EventLoop::new().unwrap();
+        let mut engine = RPCEngine::new(None, recorder.clone(), Duration::minutes(20));
+
+        // Nothing to register without a listener
+        engine.register(&mut event_loop).unwrap();
+        assert!(engine.server.is_none());
+
+        // Outbound connections work as usual
+        let peer = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = peer.local_addr().unwrap();
+        engine.notify(&mut event_loop, Message::Connect(addr));
+
+        assert_eq!(*recorder.connections.lock().unwrap(), vec![addr]);
+        assert_eq!(engine.connections.count(), 1);
+    }
+
+    #[test]
+    fn test_select_idle() {
+        let now = SteadyTime::now();
+        let timeout = Duration::minutes(20);
+
+        let active = State::new();
+        let mut idle = State::new();
+        idle.last_activity = now - Duration::minutes(21);
+        let mut almost_idle = State::new();
+        almost_idle.last_activity = now - Duration::minutes(19);
+
+        let connections = vec![(mio::Token(1), &active), (mio::Token(2), &idle),
+                               (mio::Token(3), &almost_idle)];
+
+        assert_eq!(select_idle(connections.into_iter(), now, timeout), vec![mio::Token(2)]);
+
+        // Receiving data makes the connection active again
+        idle.touch();
+        assert!(!idle.is_idle(SteadyTime::now(), timeout));
+    }
+
+    #[test]
+    fn test_max_message_size() {
+        let mut state = State::new();
+        state.mut_read_buf().extend(get_header(MAX_MESSAGE_SIZE as u32));
+        // Not enough data yet, we keep waiting
+        assert_eq!(state.try_get_rpc(), Ok(vec![]));
+        assert_eq!(state.connection_state(), &ConnectionState::Active);
+
+        let mut state = State::new();
+        state.mut_read_buf().extend(get_header(MAX_MESSAGE_SIZE as u32 + 1));
+        assert!(state.try_get_rpc().is_err());
+        assert_eq!(state.connection_state(), &ConnectionState::Closed);
+        assert!(state.reading_buf.is_empty());
+
+        let mut state = State::new();
+        state.mut_read_buf().extend(get_header(u32::max_value()));
+        assert!(state.try_get_rpc().is_err());
+    }
+}
@@ -1,23 +1,59 @@
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
 
-use serialize::{Serialize, Deserialize};
+use serialize::{Serialize, Deserialize, Deserializer};
+use transaction::Amount;
 use super::messages::{BlockMetadata, NetworkType, BlockMessage, BitcoinHash,
                       TxIn, TxOut, OutPoint, TxMessage, ShortFormatTm,
                       SerializeHash};
 
 use std::io::{Seek, SeekFrom};
 
+#[cfg(feature = "mmap")]
+use memmap::{Mmap, Protection};
+#[cfg(feature = "mmap")]
+use serialize::Error;
+
 use time;
 
-pub struct BlockBlobStore {
+// Where blocks are saved. The node uses a File, tests can keep everything
+// in memory with a Cursor.
+pub trait BlockBackend: Deserializer + Write + Seek {
+    // Makes sure everything written so far is saved
+    fn sync(&mut self);
+    fn truncate(&mut self, length: u64);
+}
+
+impl BlockBackend for File {
+    fn sync(&mut self) {
+        self.sync_all().unwrap();
+    }
+
+    fn truncate(&mut self, length: u64) {
+        self.set_len(length).unwrap();
+    }
+}
+
+impl BlockBackend for Cursor<Vec<u8>> {
+    fn sync(&mut self) {}
+
+    fn truncate(&mut self, length: u64) {
+        self.get_mut().truncate(length as usize);
+    }
+}
+
+pub struct BlockBlobStore<B: BlockBackend = File> {
     store: HashMap<BitcoinHash, (BlockMetadata, usize)>,
-    disk_store: File,
+    disk_store: B,
     last_index: usize,
+    // Map of the store, only redone when blocks were written past its end
+    #[cfg(feature = "mmap")]
+    map: Option<Mmap>,
 }
 
-impl BlockBlobStore {
+impl<B: BlockBackend> BlockBlobStore<B> {
     pub fn has(&self, hash: &BitcoinHash) -> bool {
         self.store.get(hash).is_some()
     }
@@ -30,22 +66,28 @@ impl BlockBlobStore {
         self.store.get(hash).map(|data| data.1)
             .map(|pos| {
                 self.disk_store.seek(SeekFrom::Start(pos as u64)).unwrap();
+                Self::read_block(&mut self.disk_store)
+            })
+    }
 
-                let length: u64        = Deserialize::deserialize(&mut self.disk_store).unwrap();
-                let hash: BitcoinHash  = Deserialize::deserialize(&mut self.disk_store).unwrap();
-                let block: BlockMessage= Deserialize::deserialize(&mut self.disk_store).unwrap();
+    fn read_block<R: Deserializer>(reader: &mut R) -> BlockMessage {
+        let length: u64        = Deserialize::deserialize(reader).unwrap();
+        let hash: BitcoinHash  = Deserialize::deserialize(reader).unwrap();
+        let block: BlockMessage= Deserialize::deserialize(reader).unwrap();
 
-                let (serialized, real_hash) = block.serialize_hash();
+        let (serialized, real_hash) = block.serialize_hash();
 
-                assert_eq!(serialized.len() as u64, length);
-                assert_eq!(hash, real_hash);
+        assert_eq!(serialized.len() as u64, length);
+        assert_eq!(hash, real_hash);
 
-                block
-            })
+        block
     }
 
     pub fn insert(&mut self, block: BlockMessage, hash: &BitcoinHash, data: &[u8]) {
         if self.store.get(hash).is_none() {
+            // get_block may have left us in the middle of the store
+            self.disk_store.seek(SeekFrom::End(0)).unwrap();
+
             // Let's save the length and hash to double check data on disk
             (data.len() as u64).serialize(&mut self.disk_store);
             self.disk_store.write_all(hash.inner()).unwrap();
@@ -53,12 +95,13 @@ impl BlockBlobStore {
 
             self.store.insert(hash.clone(), (block.into_metadata(), self.last_index));
 
-            self.disk_store.sync_all().unwrap();
-            self.last_index += 0;
+            self.disk_store.sync();
+            // length + hash + data
+            self.last_index += 8 + 32 + data.len();
         }
     }
 
-    fn get_next_object(file: &mut File) ->
+    fn get_next_object(file: &mut B) ->
         Result<(u64, BitcoinHash, BlockMetadata), String> {
         let pos = file.seek(SeekFrom::Current(0)).unwrap();
         let length: u64 = try!(Deserialize::deserialize(file));
@@ -70,14 +113,14 @@ impl BlockBlobStore {
             Err(_) => {
                 // Let's truncate the file, the client probably crashed mid-writing
                 println!("Truncating to {}", pos);
-                file.set_len(pos).unwrap();
+                file.truncate(pos);
             }
         }
 
         Ok((length, hash, data))
     }
 
-    pub fn new(disk_store_: File) -> BlockBlobStore {
+    pub fn new(disk_store_: B) -> BlockBlobStore<B> {
         let mut disk_store = disk_store_;
 
         let mut store = HashMap::new();
@@ -99,18 +142,90 @@ impl BlockBlobStore {
             store: store,
             disk_store: disk_store,
             last_index: last_index as usize,
+            #[cfg(feature = "mmap")]
+            map: None,
         }
     }
 }
 
-pub struct BlockStore {
-    store: BlockBlobStore,
+// A block in the memory map of the store, nothing is copied or
+// deserialized until it's asked for
+#[cfg(feature = "mmap")]
+pub struct MappedBlock<'a> {
+    data: &'a [u8],
+}
+
+#[cfg(feature = "mmap")]
+impl<'a> MappedBlock<'a> {
+    // The serialized block
+    pub fn raw(&self) -> &'a [u8] { self.data }
+
+    pub fn header(&self) -> Result<BlockMetadata, Error> {
+        Deserialize::deserialize(&mut Cursor::new(self.data))
+    }
+
+    pub fn block(&self) -> Result<BlockMessage, Error> {
+        Deserialize::deserialize(&mut Cursor::new(self.data))
+    }
+}
+
+#[cfg(feature = "mmap")]
+impl BlockBlobStore<File> {
+    // Finds the block in a memory map of the store instead of reading it
+    // through the file. Returns None if the block is unknown or can't be
+    // mapped.
+    pub fn get_block_mmap(&mut self, hash: &BitcoinHash) -> Option<MappedBlock> {
+        let pos = match self.store.get(hash) {
+            Some(data) => data.1,
+            None => return None,
+        };
+
+        // The store grows as we receive blocks
+        let mapped = self.map.as_ref().map_or(0, |map| map.len());
+        if mapped < self.last_index {
+            self.map = Mmap::open(&self.disk_store, Protection::Read).ok();
+        }
+
+        let data = match self.map {
+            Some(ref map) => unsafe { map.as_slice() },
+            None => return None,
+        };
+
+        // length + hash + data, like insert writes it
+        if data.len() < pos + 8 + 32 {
+            return None;
+        }
+
+        let length = {
+            let mut cursor = Cursor::new(&data[pos..pos + 8]);
+            let length: Result<u64, Error> = Deserialize::deserialize(&mut cursor);
+            match length {
+                Ok(length) => length as usize,
+                Err(_) => return None,
+            }
+        };
+
+        let start = pos + 8 + 32;
+        if &data[pos + 8..start] != &hash.inner()[..] || data.len() - start < length {
+            return None;
+        }
+
+        Some(MappedBlock { data: &data[start..start + length] })
+    }
+}
+
+// Called with the hash and height of the new best block
+pub type TipObserver = Box<FnMut(&BitcoinHash, usize) + Send>;
+
+pub struct BlockStore<B: BlockBackend = File> {
+    store: BlockBlobStore<B>,
     height_store_rev: HashMap<BitcoinHash, usize>,
     height_store: Vec<BitcoinHash>,
     highest_block: BitcoinHash,
+    tip_observers: Vec<TipObserver>,
 }
 
-impl BlockStore {
+impl<B: BlockBackend> BlockStore<B> {
     pub fn has(&self, hash: &BitcoinHash) -> bool { self.store.has(hash) }
 
     pub fn get(&mut self, hash: &BitcoinHash) -> Option<BlockMessage> {
@@ -121,18 +236,46 @@ impl BlockStore {
         self.height_store.get(height)
     }
 
+    pub fn get_metadata(&self, hash: &BitcoinHash) -> Option<&BlockMetadata> {
+        self.store.get(hash)
+    }
+
+    // Hash and height of the best block
+    pub fn tip(&self) -> (BitcoinHash, usize) {
+        (self.highest_block, self.height())
+    }
+
     pub fn get_height(&self, hash: &BitcoinHash) -> Option<usize> {
         self.height_store_rev.get(hash).cloned()
     }
 
     pub fn height(&self) -> usize { self.height_store_rev[&self.highest_block] }
 
-    pub fn insert(&mut self, block: BlockMessage, hash: &BitcoinHash, data: &[u8]) {
+    pub fn add_tip_observer(&mut self, observer: TipObserver) {
+        self.tip_observers.push(observer);
+    }
+
+    // Returns false if we already knew about this block
+    pub fn insert(&mut self, block: BlockMessage, hash: &BitcoinHash, data: &[u8]) -> bool {
+        if self.store.has(hash) {
+            return false;
+        }
+
         self.store.insert(block, hash, data);
 
+        let previous_tip = self.highest_block;
         self.highest_block =
             Self::insert_chain(hash, &self.store, &mut self.height_store_rev,
                                &mut self.height_store, self.highest_block);
+
+        if self.highest_block != previous_tip {
+            let (tip, height) = (self.highest_block, self.height());
+            for observer in self.tip_observers.iter_mut() {
+                observer(&tip, height);
+            }
+        }
+
+        true
     }
 
     fn reload_chain(&mut self) {
@@ -143,21 +286,64 @@ impl BlockStore {
         }
     }
 
+    // Height of the first locator that is part of our best chain. If we don't
+    // know any of the locators we start right after genesis.
+    fn find_fork(&self, locators: &[BitcoinHash]) -> usize {
+        locators.iter()
+            .filter_map(|hash| self.get_height(hash)
+                                   .and_then(|h| if self.height_store[h] == *hash { Some(h) }
+                                                 else { None }))
+            .next()
+            .unwrap_or(0)
+    }
+
+    // Hashes of the best chain blocks following the first known locator, up
+    // to the tip, hash_stop or max blocks, whichever comes first.
+    pub fn get_block_hashes(&self, locators: &[BitcoinHash], hash_stop: &BitcoinHash,
+                            max: usize) -> Vec<BitcoinHash> {
+        let start = self.find_fork(locators);
+
+        let mut hashes = vec![];
+        for height in (start + 1)..(self.height() + 1) {
+            let hash = self.height_store[height];
+            hashes.push(hash);
+
+            if hashes.len() >= max || hash == *hash_stop {
+                break;
+            }
+        }
+
+        hashes
+    }
+
+    // Same as get_block_hashes, but returns the headers
+    pub fn get_headers(&self, locators: &[BitcoinHash], hash_stop: &BitcoinHash,
+                       max: usize) -> Vec<BlockMetadata> {
+        self.get_block_hashes(locators, hash_stop, max).iter()
+            .map(|hash| self.store.get(hash).unwrap().clone())
+            .collect()
+    }
+
+    // The last 10 blocks one by one, then doubling the step back each time
+    // and always ending with the genesis block
     pub fn block_locators(&self) -> Vec<BitcoinHash> {
-        let height = self.height();
-        let mut index = 0;
+        let mut height = self.height();
+        let mut step = 1;
         let mut locator = vec![];
 
-        while height > index {
-            locator.push(self.height_store[height - index]);
-            if index < 10 {
-                index += 1;
-            } else {
-                index *= 2;
+        loop {
+            locator.push(self.height_store[height]);
+            if height == 0 {
+                break;
             }
+
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+
+            height = height.saturating_sub(step);
         }
 
-        locator.push(self.height_store[0]);
         locator
     }
 
@@ -167,7 +353,7 @@ impl BlockStore {
     // store) so this function cannot be non-static until rust supports partial borrows. Maybe
     // double check if there are other possibilities.
     fn insert_chain(hash: &BitcoinHash,
-                    store: &BlockBlobStore,
+                    store: &BlockBlobStore<B>,
                     height_store_rev: &mut HashMap<BitcoinHash, usize>,
                     height_store: &mut Vec<BitcoinHash>,
                     highest_block: BitcoinHash) -> BitcoinHash {
@@ -223,7 +409,7 @@ impl BlockStore {
         }
     }
 
-    pub fn new(disk_store: File, network_type: NetworkType) -> BlockStore {
+    pub fn new(disk_store: B, network_type: NetworkType) -> BlockStore<B> {
         let genesis_hash = BitcoinHash::new(match network_type {
             NetworkType::TestNet3 =>
                 [0x43, 0x49, 0x7F, 0xD7, 0xF8, 0x26, 0x95, 0x71,
@@ -241,9 +427,9 @@ impl BlockStore {
         });
 
         let genesis_block = match network_type {
-            NetworkType::TestNet3 => Self::genesis_block_testnet3(),
+            NetworkType::TestNet3 => BlockStore::genesis_block(1296688602, 414098458),
             NetworkType::TestNet =>  unimplemented!(),
-            NetworkType::Main     => unimplemented!(),
+            NetworkType::Main     => BlockStore::genesis_block(1231006505, 2083236893),
             NetworkType::NameCoin => unimplemented!(),
             NetworkType::Unknown =>  unreachable!(),
         };
@@ -253,6 +439,7 @@ impl BlockStore {
             height_store_rev: HashMap::new(),
             height_store: vec![genesis_hash],
             highest_block: genesis_hash,
+            tip_observers: vec![],
         };
 
         let (serialized, confirm_hash) = genesis_block.serialize_hash();
@@ -266,7 +453,42 @@ impl BlockStore {
         store
     }
 
-    fn genesis_block_testnet3() -> BlockMessage {
+}
+
+impl BlockStore {
+    // Each network keeps its blocks in its own directory under `data_dir`
+    pub fn path(data_dir: &Path, network_type: NetworkType) -> PathBuf {
+        let network = match network_type {
+            NetworkType::Main => "main",
+            NetworkType::TestNet => "testnet",
+            NetworkType::TestNet3 => "testnet3",
+            NetworkType::NameCoin => "namecoin",
+            NetworkType::Unknown => unreachable!(),
+        };
+
+        data_dir.join(network).join("block.dat")
+    }
+
+    // Opens the block file at `path` for appending, creating it and its
+    // directory if needed
+    pub fn open_file(path: &Path) -> Result<File, String> {
+        if let Some(dir) = path.parent() {
+            try!(fs::create_dir_all(dir)
+                 .map_err(|e| format!("Can't create {:?}: {:?}", dir, e)));
+        }
+
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| format!("Can't open {:?}: {:?}", path, e))
+    }
+
+    // Main and testnet3 share the same genesis transaction, only
+    // the timestamp and nonce differ.
+    pub fn genesis_block(timestamp: i64, nonce: u32) -> BlockMessage {
         let metadata = BlockMetadata {
             version: 1,
             prev_block: BitcoinHash::new([0; 32]),
@@ -276,45 +498,45 @@ impl BlockStore {
                 0x7F, 0xC8, 0x1B, 0xC3, 0x88, 0x8A, 0x51, 0x32,
                 0x3A, 0x9F, 0xB8, 0xAA, 0x4B, 0x1E, 0x5E, 0x4A]),
             timestamp:
-                ShortFormatTm::new(time::at_utc(time::Timespec::new(1296688602, 0))),
+                ShortFormatTm::new(time::at_utc(time::Timespec::new(timestamp, 0))),
             bits: 486604799,
-            nonce: 414098458,
+            nonce: nonce,
         };
 
 
         let out_point = OutPoint {
             hash: BitcoinHash::new([0; 32]),
-            index: 0,
+            index: 0xffffffff,
         };
 
         let tx_in = TxIn {
             previous_output: out_point,
             script: vec![
-                0x73, 0x6B, 0x6E, 0x61, 0x62, 0x20, 0x72, 0x6F,
-                0x66, 0x20, 0x74, 0x75, 0x6F, 0x6C, 0x69, 0x61,
-                0x62, 0x20, 0x64, 0x6E, 0x6F, 0x63, 0x65, 0x73,
-                0x20, 0x66, 0x6F, 0x20, 0x6B, 0x6E, 0x69, 0x72,
-                0x62, 0x20, 0x6E, 0x6F, 0x20, 0x72, 0x6F, 0x6C,
-                0x6C, 0x65, 0x63, 0x6E, 0x61, 0x68, 0x43, 0x20,
-                0x39, 0x30, 0x30, 0x32, 0x2F, 0x6E, 0x61, 0x4A,
-                0x2F, 0x33, 0x30, 0x20, 0x73, 0x65, 0x6D, 0x69,
-                0x54, 0x20, 0x65, 0x68, 0x54, 0x45, 0x04, 0x01,
-                0x1D, 0x00, 0xFF, 0xFF, 0x04],
+                0x04, 0xFF, 0xFF, 0x00, 0x1D, 0x01, 0x04, 0x45,
+                0x54, 0x68, 0x65, 0x20, 0x54, 0x69, 0x6D, 0x65,
+                0x73, 0x20, 0x30, 0x33, 0x2F, 0x4A, 0x61, 0x6E,
+                0x2F, 0x32, 0x30, 0x30, 0x39, 0x20, 0x43, 0x68,
+                0x61, 0x6E, 0x63, 0x65, 0x6C, 0x6C, 0x6F, 0x72,
+                0x20, 0x6F, 0x6E, 0x20, 0x62, 0x72, 0x69, 0x6E,
+                0x6B, 0x20, 0x6F, 0x66, 0x20, 0x73, 0x65, 0x63,
+                0x6F, 0x6E, 0x64, 0x20, 0x62, 0x61, 0x69, 0x6C,
+                0x6F, 0x75, 0x74, 0x20, 0x66, 0x6F, 0x72, 0x20,
+                0x62, 0x61, 0x6E, 0x6B, 0x73],
             sequence: 4294967295,
         };
 
         let tx_out = TxOut {
-            value: 5000000000,
+            value: Amount::from_satoshis(5000000000),
             pk_script: vec![
-                0x5F, 0x1D, 0xF1, 0x6B, 0x2B, 0x70, 0x4C, 0x8A,
-                0x57, 0x8D, 0x0B, 0xBA, 0xF7, 0x4D, 0x38, 0x5C,
-                0xDE, 0x12, 0xC1, 0x1E, 0xE5, 0x04, 0x55, 0xF3,
-                0xC4, 0x38, 0xEF, 0x4C, 0x3F, 0xBC, 0xF6, 0x49,
-                0xB6, 0xDE, 0x61, 0x1F, 0xEA, 0xE0, 0x62, 0x79,
-                0xA6, 0x09, 0x39, 0xE0, 0x28, 0xA8, 0xD6, 0x5C,
-                0x10, 0xB7, 0x30, 0x71, 0xA6, 0xF1, 0x67, 0x19,
-                0x27, 0x48, 0x55, 0xFE, 0xB0, 0xFD, 0x8A, 0x67,
-                0x04],
+                0x41, 0x04, 0x67, 0x8A, 0xFD, 0xB0, 0xFE, 0x55,
+                0x48, 0x27, 0x19, 0x67, 0xF1, 0xA6, 0x71, 0x30,
+                0xB7, 0x10, 0x5C, 0xD6, 0xA8, 0x28, 0xE0, 0x39,
+                0x09, 0xA6, 0x79, 0x62, 0xE0, 0xEA, 0x1F, 0x61,
+                0xDE, 0xB6, 0x49, 0xF6, 0xBC, 0x3F, 0x4C, 0xEF,
+                0x38, 0xC4, 0xF3, 0x55, 0x04, 0xE5, 0x1E, 0xC1,
+                0x12, 0xDE, 0x5C, 0x38, 0x4D, 0xF7, 0xBA, 0x0B,
+                0x8D, 0x57, 0x8A, 0x4C, 0x70, 0x2B, 0x6B, 0xF1,
+                0x1D, 0x5F, 0xAC],
         };
 
         let tx = TxMessage {
@@ -330,3 +552,225 @@ impl BlockStore {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::messages::{NetworkType, BitcoinHash, SerializeHash};
+
+    use std::io::Cursor;
+    use std::sync::{Arc, Mutex};
+
+    use test::utils::get_next_block;
+    #[cfg(feature = "mmap")]
+    use test::utils::get_file;
+
+    fn in_memory() -> Cursor<Vec<u8>> {
+        Cursor::new(vec![])
+    }
+
+    // Builds a chain of `length` blocks on top of the testnet3 genesis block
+    fn get_chain<B: BlockBackend>(store: &mut BlockStore<B>, length: u32) -> Vec<BitcoinHash> {
+        let mut hashes = vec![*store.get_hash_at_height(0).unwrap()];
+
+        for _ in 0..length {
+            let block = get_next_block(*hashes.last().unwrap(), vec![]);
+            let (data, hash) = block.serialize_hash();
+            assert!(store.insert(block, &hash, &data));
+            hashes.push(hash);
+        }
+
+        hashes
+    }
+
+    #[test]
+    fn test_store_path() {
+        let data_dir = Path::new("data");
+        assert_eq!(BlockStore::path(data_dir, NetworkType::Main),
+                   Path::new("data/main/block.dat"));
+        assert_eq!(BlockStore::path(data_dir, NetworkType::TestNet3),
+                   Path::new("data/testnet3/block.dat"));
+    }
+
+    #[test]
+    fn test_get_block() {
+        let mut store = BlockStore::new(in_memory(), NetworkType::TestNet3);
+        let hashes = get_chain(&mut store, 3);
+
+        for (i, hash) in hashes.iter().enumerate().skip(1) {
+            let block = store.get(hash).unwrap();
+            assert_eq!(block.hash(), *hash);
+            assert_eq!(block.metadata.prev_block, hashes[i - 1]);
+        }
+
+        assert_eq!(store.get(&hashes[0]).unwrap().txns.len(), 1);
+    }
+
+    #[test]
+    fn test_insert_after_get() {
+        let mut store = BlockStore::new(in_memory(), NetworkType::TestNet3);
+        let hashes = get_chain(&mut store, 2);
+
+        // Reading a block moves the position away from the end of the store
+        assert_eq!(store.get(&hashes[1]).unwrap().hash(), hashes[1]);
+
+        let block = get_next_block(hashes[2], vec![]);
+        let (data, hash) = block.serialize_hash();
+        assert!(store.insert(block, &hash, &data));
+
+        assert_eq!(store.get(&hashes[1]).unwrap().hash(), hashes[1]);
+        assert_eq!(store.get(&hashes[2]).unwrap().hash(), hashes[2]);
+        assert_eq!(store.get(&hash).unwrap().hash(), hash);
+    }
+
+    #[test]
+    fn test_reload_chain() {
+        let mut store = BlockStore::new(in_memory(), NetworkType::TestNet3);
+        let hashes = get_chain(&mut store, 3);
+
+        // Reading the same data again gives back the same chain
+        let mut data = store.store.disk_store.clone();
+        data.set_position(0);
+
+        let mut reloaded = BlockStore::new(data, NetworkType::TestNet3);
+        assert_eq!(reloaded.tip(), (hashes[3], 3));
+        assert_eq!(reloaded.get(&hashes[2]).unwrap().hash(), hashes[2]);
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn test_get_block_mmap() {
+        let mut store = BlockStore::new(get_file("get_block_mmap"), NetworkType::TestNet3);
+        let hashes = get_chain(&mut store, 3);
+
+        for hash in hashes.iter() {
+            let expected = store.get(hash).unwrap();
+            let mapped = store.store.get_block_mmap(hash).unwrap();
+            assert_eq!(mapped.header(), Ok(expected.metadata.clone()));
+            assert_eq!(mapped.block(), Ok(expected));
+        }
+
+        // Blocks stored after the store was mapped are found too
+        let next = get_next_block(*hashes.last().unwrap(), vec![]);
+        let (data, next_hash) = next.serialize_hash();
+        assert!(store.insert(next.clone(), &next_hash, &data));
+        assert_eq!(store.store.get_block_mmap(&next_hash).unwrap().block(), Ok(next));
+
+        assert!(store.store.get_block_mmap(&BitcoinHash::new([0; 32])).is_none());
+    }
+
+    #[test]
+    fn test_genesis_main() {
+        // BlockStore::new checks the genesis hash
+        let store = BlockStore::new(in_memory(), NetworkType::Main);
+        assert_eq!(store.height(), 0);
+    }
+
+    #[test]
+    fn test_insert_duplicate() {
+        let mut store = BlockStore::new(in_memory(), NetworkType::TestNet3);
+        let hashes = get_chain(&mut store, 2);
+
+        let block = get_next_block(hashes[1], vec![]);
+        let (data, hash) = block.serialize_hash();
+        assert_eq!(hash, hashes[2]);
+
+        assert!(!store.insert(block, &hash, &data));
+        assert_eq!(store.height(), 2);
+        assert_eq!(store.get_hash_at_height(2), Some(&hashes[2]));
+    }
+
+    #[test]
+    fn test_tip_observer() {
+        let mut store = BlockStore::new(in_memory(), NetworkType::TestNet3);
+
+        let tips = Arc::new(Mutex::new(vec![]));
+        let observer_tips = tips.clone();
+        store.add_tip_observer(Box::new(move |hash: &BitcoinHash, height| {
+            observer_tips.lock().unwrap().push((*hash, height));
+        }));
+
+        let hashes = get_chain(&mut store, 2);
+        assert_eq!(*tips.lock().unwrap(), vec![(hashes[1], 1), (hashes[2], 2)]);
+
+        // A fork that is not longer than the best chain doesn't move the tip
+        let mut fork = get_next_block(hashes[1], vec![]);
+        fork.metadata.nonce = 100;
+        let (data, hash) = fork.serialize_hash();
+        assert!(store.insert(fork, &hash, &data));
+        assert_eq!(tips.lock().unwrap().len(), 2);
+
+        // Neither does a block we can't link
+        let orphan = get_next_block(BitcoinHash::new([0xff; 32]), vec![]);
+        let (data, hash) = orphan.serialize_hash();
+        assert!(store.insert(orphan, &hash, &data));
+        assert_eq!(tips.lock().unwrap().len(), 2);
+
+        let block = get_next_block(hashes[2], vec![]);
+        let (data, hash) = block.serialize_hash();
+        assert!(store.insert(block, &hash, &data));
+        assert_eq!(tips.lock().unwrap().last(), Some(&(hash, 3)));
+    }
+
+    #[test]
+    fn test_get_headers() {
+        let mut store = BlockStore::new(in_memory(), NetworkType::TestNet3);
+        let hashes = get_chain(&mut store, 10);
+        let no_stop = BitcoinHash::new([0; 32]);
+
+        assert_eq!(store.height(), 10);
+
+        // The first known locator wins, unknown ones are skipped
+        let locators = vec![BitcoinHash::new([0xff; 32]), hashes[4], hashes[2]];
+        let headers = store.get_headers(&locators, &no_stop, 2000);
+        assert_eq!(headers.len(), 6);
+        for (i, header) in headers.iter().enumerate() {
+            assert_eq!(header.prev_block, hashes[4 + i]);
+            assert_eq!(header.hash(), hashes[5 + i]);
+        }
+
+        // hash_stop is included in the response
+        let headers = store.get_headers(&locators, &hashes[7], 2000);
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers.last().unwrap().hash(), hashes[7]);
+
+        // max number of headers
+        let headers = store.get_headers(&locators, &no_stop, 2);
+        assert_eq!(headers.len(), 2);
+
+        // No known locator, we start from genesis
+        let headers = store.get_headers(&[], &no_stop, 2000);
+        assert_eq!(headers.len(), 10);
+        assert_eq!(headers[0].prev_block, hashes[0]);
+
+        // Our tip, nothing to send
+        assert_eq!(store.get_headers(&[hashes[10]], &no_stop, 2000).len(), 0);
+    }
+
+    #[test]
+    fn test_get_block_hashes() {
+        let mut store = BlockStore::new(in_memory(), NetworkType::TestNet3);
+        let hashes = get_chain(&mut store, 10);
+        let no_stop = BitcoinHash::new([0; 32]);
+
+        // From genesis all the way to the tip, the locator itself is not included
+        assert_eq!(store.get_block_hashes(&[hashes[0]], &no_stop, 500), &hashes[1..]);
+        assert_eq!(store.get_block_hashes(&[hashes[1]], &no_stop, 500), &hashes[2..]);
+        assert_eq!(store.get_block_hashes(&[], &no_stop, 500), &hashes[1..]);
+
+        assert_eq!(store.get_block_hashes(&[hashes[1]], &hashes[3], 500), &hashes[2..4]);
+        assert_eq!(store.get_block_hashes(&[hashes[1]], &no_stop, 3), &hashes[2..5]);
+        assert!(store.get_block_hashes(&[hashes[10]], &no_stop, 500).is_empty());
+    }
+
+    #[test]
+    fn test_block_locators() {
+        let mut store = BlockStore::new(in_memory(), NetworkType::TestNet3);
+        assert_eq!(store.block_locators(), vec![*store.get_hash_at_height(0).unwrap()]);
+
+        let hashes = get_chain(&mut store, 100);
+        let heights = [100, 99, 98, 97, 96, 95, 94, 93, 92, 91, 89, 85, 77, 61, 29, 0];
+        let expected: Vec<_> = heights.iter().map(|&height| hashes[height]).collect();
+        assert_eq!(store.block_locators(), expected);
+    }
+}
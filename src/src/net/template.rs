@@ -0,0 +1,198 @@
+use std::collections::HashSet;
+
+use super::messages::{BlockMessage, BlockMetadata, BitcoinHash, OutPoint, ShortFormatTm,
+                      TxIn, TxMessage, TxOut, SerializeHash};
+use super::store::BlockStore;
+use super::validation::{MAX_BLOCK_SIGOPS, MAX_BLOCK_WEIGHT, WITNESS_SCALE_FACTOR,
+                        block_subsidy, coinbase_height_prefix, count_tx_sigops,
+                        merkle_root};
+
+use script::count_sigops;
+use transaction::{Amount, SEQUENCE_FINAL};
+use transaction::mempool::Mempool;
+
+use time;
+
+const TEMPLATE_VERSION: i32 = 4;
+
+// Weight kept free for the header and the coinbase, same default as Core
+const RESERVED_WEIGHT: usize = 4000;
+
+// Assembles a candidate block on top of the best block we know of,
+// similar to getblocktemplate. The nonce is left to the miner.
+pub struct BlockTemplate {
+    payout_script: Vec<u8>,
+    max_weight: usize,
+    timestamp: time::Tm,
+}
+
+impl BlockTemplate {
+    pub fn new(payout_script: Vec<u8>) -> BlockTemplate {
+        BlockTemplate {
+            payout_script: payout_script,
+            max_weight: MAX_BLOCK_WEIGHT,
+            timestamp: time::now_utc(),
+        }
+    }
+
+    pub fn max_weight(mut self, max_weight: usize) -> BlockTemplate {
+        self.max_weight = max_weight;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: time::Tm) -> BlockTemplate {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn build(&self, store: &BlockStore, mempool: &Mempool) -> BlockMessage {
+        let (tip, height) = store.tip();
+
+        // We don't retarget, the new block uses the same target as the tip
+        let bits = store.get_metadata(&tip).expect("The tip is always stored").bits;
+
+        let (mut txns, fees) = self.select_transactions(mempool);
+        txns.insert(0, coinbase(height + 1, fees, &self.payout_script));
+
+        let hashes: Vec<BitcoinHash> = txns.iter().map(|tx| tx.hash()).collect();
+
+        BlockMessage {
+            metadata: BlockMetadata {
+                version: TEMPLATE_VERSION,
+                prev_block: tip,
+                merkle_root: merkle_root(&hashes),
+                timestamp: ShortFormatTm::new(self.timestamp),
+                bits: bits,
+                nonce: 0,
+            },
+            txns: txns,
+        }
+    }
+
+    // Picks transactions by fee rate until the block is full. Transactions
+    // spending mempool outputs wait until their parents are in the block.
+    // Returns the selected transactions and their total fee.
+    fn select_transactions(&self, mempool: &Mempool) -> (Vec<TxMessage>, u64) {
+        let mut selected = vec![];
+        let mut included = HashSet::new();
+        let mut fees = 0;
+        let mut weight = RESERVED_WEIGHT;
+        let mut sigops = count_sigops(&self.payout_script, false);
+
+        let mut pending = mempool.by_fee_rate();
+        loop {
+            let selected_before = selected.len();
+            let mut waiting = vec![];
+
+            for (tx, fee) in pending {
+                let has_pending_parent = tx.tx_in.iter().any(|input| {
+                    let parent = &input.previous_output.hash;
+                    mempool.contains(parent) && !included.contains(parent)
+                });
+
+                if has_pending_parent {
+                    waiting.push((tx, fee));
+                    continue;
+                }
+
+                let tx_weight = tx.vsize() * WITNESS_SCALE_FACTOR;
+                let tx_sigops = count_tx_sigops(tx);
+                if weight + tx_weight > self.max_weight || sigops + tx_sigops > MAX_BLOCK_SIGOPS {
+                    continue;
+                }
+
+                weight += tx_weight;
+                sigops += tx_sigops;
+                fees += fee;
+                included.insert(tx.hash());
+                selected.push(tx.clone());
+            }
+
+            // Stop when the remaining transactions depend on something we left out
+            if waiting.is_empty() || selected.len() == selected_before {
+                break;
+            }
+
+            pending = waiting;
+        }
+
+        (selected, fees)
+    }
+}
+
+fn coinbase(height: usize, fees: u64, payout_script: &[u8]) -> TxMessage {
+    let mut script = coinbase_height_prefix(height);
+
+    // Extra nonce, this also keeps the script at least 2 bytes long
+    script.push(0x00);
+
+    TxMessage {
+        version: 1,
+        tx_in: vec![TxIn::new(OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+                              script, SEQUENCE_FINAL)],
+        tx_out: vec![TxOut::new(Amount::from_satoshis(block_subsidy(height) + fees),
+                                payout_script.to_vec())],
+        lock_time: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::messages::{BitcoinHash, NetworkType, OutPoint, SerializeHash};
+    use super::super::store::BlockStore;
+    use super::super::validation::{WITNESS_SCALE_FACTOR, merkle_root};
+
+    use transaction::COIN;
+    use transaction::mempool::{Mempool, DEFAULT_MAX_MEMPOOL_SIZE};
+    use test::utils::{get_file, get_outpoint, get_tx};
+
+    #[test]
+    fn test_block_template() {
+        let store = BlockStore::new(get_file("template"), NetworkType::TestNet3);
+        let (tip, _) = store.tip();
+
+        let cheap = get_tx(vec![get_outpoint(1)], vec![1000]);
+        let expensive = get_tx(vec![get_outpoint(2)], vec![1000]);
+        let parent = get_tx(vec![get_outpoint(3)], vec![1000]);
+        // Pays more than its parent but has to come after it
+        let child = get_tx(vec![OutPoint::new(parent.hash(), 0)], vec![900]);
+
+        let mut mempool = Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE);
+        mempool.insert(cheap.clone(), 100);
+        mempool.insert(expensive.clone(), 5000);
+        mempool.insert(parent.clone(), 1000);
+        mempool.insert(child.clone(), 2000);
+
+        let payout = vec![0x51];
+        let block = BlockTemplate::new(payout.clone()).build(&store, &mempool);
+
+        assert_eq!(block.metadata.prev_block, tip);
+        assert_eq!(&block.txns[1..], &[expensive.clone(), parent.clone(), cheap.clone(),
+                                       child.clone()]);
+
+        let coinbase = &block.txns[0];
+        assert!(coinbase.is_coinbase());
+        assert_eq!(coinbase.tx_in[0].script, vec![0x51, 0x00]);
+        assert_eq!(coinbase.tx_out[0].pk_script, payout);
+        assert_eq!(coinbase.tx_out[0].value.as_satoshis(), 50 * COIN + 8100);
+
+        let hashes: Vec<BitcoinHash> = block.txns.iter().map(|tx| tx.hash()).collect();
+        assert_eq!(block.metadata.merkle_root, merkle_root(&hashes));
+
+        // Only room for one transaction, the one with the highest fee rate
+        let max_weight = RESERVED_WEIGHT + cheap.vsize() * WITNESS_SCALE_FACTOR;
+        let block = BlockTemplate::new(payout).max_weight(max_weight).build(&store, &mempool);
+        assert_eq!(&block.txns[1..], &[expensive]);
+        assert_eq!(block.txns[0].tx_out[0].value.as_satoshis(), 50 * COIN + 5000);
+    }
+
+    #[test]
+    fn test_coinbase_height() {
+        assert_eq!(coinbase(0, 0, &[]).tx_in[0].script, vec![0x00, 0x00]);
+        assert_eq!(coinbase(16, 0, &[]).tx_in[0].script, vec![0x60, 0x00]);
+        assert_eq!(coinbase(17, 0, &[]).tx_in[0].script, vec![0x01, 0x11, 0x00]);
+        assert_eq!(coinbase(500000, 0, &[]).tx_in[0].script,
+                   vec![0x03, 0x20, 0xa1, 0x07, 0x00]);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::{HashMap, HashSet};
+
+use super::messages::{BitcoinHash, BlockMessage, OutPoint, TxMessage, SerializeHash};
+use transaction::Amount;
+
+// Coinbase outputs can only be spent after this many blocks
+pub const COINBASE_MATURITY: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UtxoEntry {
+    pub value: Amount,
+    pub script: Vec<u8>,
+    // Height of the block that created this output
+    pub height: usize,
+    pub coinbase: bool,
+}
+
+impl UtxoEntry {
+    // Whether this output can be spent in a block at `height`
+    pub fn is_mature(&self, height: usize) -> bool {
+        !self.coinbase || height >= self.height + COINBASE_MATURITY
+    }
+}
+
+pub struct UtxoSet {
+    outputs: HashMap<OutPoint, UtxoEntry>,
+}
+
+impl UtxoSet {
+    pub fn new() -> UtxoSet {
+        UtxoSet {
+            outputs: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, output: &OutPoint) -> Option<&UtxoEntry> {
+        self.outputs.get(output)
+    }
+
+    pub fn contains(&self, output: &OutPoint) -> bool {
+        self.outputs.contains_key(output)
+    }
+
+    pub fn len(&self) -> usize { self.outputs.len() }
+
+    // Checks that `tx`, included in a block at `height`, doesn't spend
+    // coinbase outputs that are not mature yet.
+    pub fn check_maturity(&self, tx: &TxMessage, height: usize) -> Result<(), String> {
+        for input in &tx.tx_in {
+            let output = &input.previous_output;
+            let entry = match self.get(output) {
+                Some(entry) => entry,
+                None => return Err(format!("Missing or spent output {:?}", output)),
+            };
+
+            if !entry.is_mature(height) {
+                return Err(format!("Spending immature coinbase {:?} at height {}, created at {}",
+                                   output, height, entry.height));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Removes the outputs spent by `block` and adds the ones it creates.
+    // Fails without touching the set if an input spends an output that
+    // doesn't exist or that is already spent.
+    pub fn connect_block(&mut self, block: &BlockMessage, height: usize) -> Result<(), String> {
+        let mut created = HashSet::new();
+        let mut spent = HashSet::new();
+
+        for tx in &block.txns {
+            let hash = tx.hash();
+
+            if !tx.is_coinbase() {
+                for input in &tx.tx_in {
+                    let output = &input.previous_output;
+                    if !self.contains(output) && !created.contains(output) {
+                        return Err(format!("Missing or spent output {:?}", output));
+                    }
+
+                    if !spent.insert(output.clone()) {
+                        return Err(format!("Output {:?} is spent twice", output));
+                    }
+                }
+            }
+
+            for index in 0..tx.tx_out.len() {
+                created.insert(OutPoint::new(hash, index as u32));
+            }
+        }
+
+        for tx in &block.txns {
+            self.apply_tx(tx, height);
+        }
+
+        Ok(())
+    }
+
+    fn apply_tx(&mut self, tx: &TxMessage, height: usize) {
+        let coinbase = tx.is_coinbase();
+
+        if !coinbase {
+            for input in &tx.tx_in {
+                self.outputs.remove(&input.previous_output);
+            }
+        }
+
+        let hash = tx.hash();
+        for (index, output) in tx.tx_out.iter().enumerate() {
+            self.outputs.insert(OutPoint::new(hash, index as u32), UtxoEntry {
+                value: output.value,
+                script: output.pk_script.clone(),
+                height: height,
+                coinbase: coinbase,
+            });
+        }
+    }
+}
+
+// The input that spent an output
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spender {
+    pub txid: BitcoinHash,
+    pub input: u32,
+    // Height of the block with the spending transaction
+    pub height: usize,
+}
+
+// Unlike the UTXO set, which forgets outputs once they are spent, this
+// remembers who spent every output of the connected blocks.
+pub struct SpentIndex {
+    spenders: HashMap<OutPoint, Spender>,
+}
+
+impl SpentIndex {
+    pub fn new() -> SpentIndex {
+        SpentIndex {
+            spenders: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, output: &OutPoint) -> Option<&Spender> {
+        self.spenders.get(output)
+    }
+
+    pub fn is_spent(&self, output: &OutPoint) -> bool {
+        self.spenders.contains_key(output)
+    }
+
+    pub fn len(&self) -> usize { self.spenders.len() }
+
+    // `block` must have been validated already, e.g. by UtxoSet::connect_block
+    pub fn connect_block(&mut self, block: &BlockMessage, height: usize) {
+        for tx in block.txns.iter().filter(|tx| !tx.is_coinbase()) {
+            let txid = tx.hash();
+            for (index, input) in tx.tx_in.iter().enumerate() {
+                self.spenders.insert(input.previous_output.clone(), Spender {
+                    txid: txid,
+                    input: index as u32,
+                    height: height,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::messages::{BitcoinHash, OutPoint, SerializeHash};
+    use test::utils::{get_block, get_coinbase, get_tx};
+    use transaction::Amount;
+
+    #[test]
+    fn test_connect_block() {
+        let mut utxo = UtxoSet::new();
+
+        let coinbase = get_coinbase(1);
+        let coinbase_output = OutPoint::new(coinbase.hash(), 0);
+        utxo.connect_block(&get_block(vec![coinbase]), 1).unwrap();
+
+        assert_eq!(utxo.len(), 1);
+        let entry = utxo.get(&coinbase_output).unwrap().clone();
+        assert_eq!(entry.value, Amount::from_satoshis(5000000000));
+        assert_eq!(entry.height, 1);
+        assert!(entry.coinbase);
+
+        // Spends the coinbase, and one of the new outputs in the same block
+        let spend = get_tx(vec![coinbase_output.clone()], vec![3000000000, 2000000000]);
+        let spend_hash = spend.hash();
+        let chained = get_tx(vec![OutPoint::new(spend_hash, 1)], vec![1000000000]);
+        let chained_hash = chained.hash();
+
+        utxo.connect_block(&get_block(vec![get_coinbase(2), spend, chained]), 2).unwrap();
+
+        assert!(!utxo.contains(&coinbase_output));
+        assert!(!utxo.contains(&OutPoint::new(spend_hash, 1)));
+
+        let entry = utxo.get(&OutPoint::new(spend_hash, 0)).unwrap();
+        assert_eq!(entry.value, Amount::from_satoshis(3000000000));
+        assert_eq!(entry.height, 2);
+        assert!(!entry.coinbase);
+        assert!(utxo.contains(&OutPoint::new(chained_hash, 0)));
+
+        // Both coinbase outputs, spend:0 and chained:0
+        assert_eq!(utxo.len(), 3);
+    }
+
+    #[test]
+    fn test_spent_index() {
+        let mut spent = SpentIndex::new();
+
+        let coinbase = get_coinbase(1);
+        let coinbase_output = OutPoint::new(coinbase.hash(), 0);
+        spent.connect_block(&get_block(vec![coinbase]), 1);
+        // Coinbase inputs don't spend anything
+        assert_eq!(spent.len(), 0);
+
+        let other_output = OutPoint::new(BitcoinHash::new([1; 32]), 3);
+        let spend = get_tx(vec![other_output.clone(), coinbase_output.clone()], vec![1000]);
+        let spend_hash = spend.hash();
+        spent.connect_block(&get_block(vec![get_coinbase(2), spend]), 2);
+
+        assert_eq!(spent.len(), 2);
+        assert_eq!(spent.get(&coinbase_output),
+                   Some(&Spender { txid: spend_hash, input: 1, height: 2 }));
+        assert_eq!(spent.get(&other_output).map(|s| s.input), Some(0));
+        assert!(!spent.is_spent(&OutPoint::new(spend_hash, 0)));
+    }
+
+    #[test]
+    fn test_check_maturity() {
+        let mut utxo = UtxoSet::new();
+
+        let coinbase = get_coinbase(1);
+        let coinbase_output = OutPoint::new(coinbase.hash(), 0);
+        utxo.connect_block(&get_block(vec![coinbase]), 10).unwrap();
+
+        let spend = get_tx(vec![coinbase_output], vec![1000]);
+        assert!(utxo.check_maturity(&spend, 10 + 50).is_err());
+        assert!(utxo.check_maturity(&spend, 10 + 99).is_err());
+        assert_eq!(utxo.check_maturity(&spend, 10 + 100), Ok(()));
+
+        // Regular outputs can be spent right away
+        utxo.connect_block(&get_block(vec![get_coinbase(2), spend.clone()]), 110).unwrap();
+        let spend_regular = get_tx(vec![OutPoint::new(spend.hash(), 0)], vec![1]);
+        assert_eq!(utxo.check_maturity(&spend_regular, 110), Ok(()));
+
+        let missing = get_tx(vec![OutPoint::new(BitcoinHash::new([1; 32]), 0)], vec![1]);
+        assert!(utxo.check_maturity(&missing, 1000).is_err());
+    }
+
+    #[test]
+    fn test_connect_block_missing_output() {
+        let mut utxo = UtxoSet::new();
+
+        let coinbase = get_coinbase(1);
+        let coinbase_output = OutPoint::new(coinbase.hash(), 0);
+        utxo.connect_block(&get_block(vec![coinbase]), 1).unwrap();
+
+        let missing = get_tx(vec![OutPoint::new(BitcoinHash::new([1; 32]), 0)], vec![1]);
+        assert!(utxo.connect_block(&get_block(vec![get_coinbase(2), missing]), 2).is_err());
+
+        let first = get_tx(vec![coinbase_output.clone()], vec![1]);
+        let second = get_tx(vec![coinbase_output.clone()], vec![2]);
+        assert!(utxo.connect_block(&get_block(vec![get_coinbase(2), first, second]), 2).is_err());
+
+        // Nothing changed
+        assert_eq!(utxo.len(), 1);
+        assert!(utxo.contains(&coinbase_output));
+    }
+}
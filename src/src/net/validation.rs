@@ -0,0 +1,515 @@
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+use super::messages::{BlockMessage, BitcoinHash, OutPoint, TxMessage, SerializeHash};
+use super::utxo::{UtxoEntry, UtxoSet};
+
+use script::{Parser, ScriptCache, ScriptFlags, ScriptType, count_sigops};
+use serialize::{ScriptNum, Serialize};
+use transaction::{Amount, COIN};
+use utils::CryptoUtils;
+
+pub fn merkle_root(hashes: &[BitcoinHash]) -> BitcoinHash {
+    if hashes.is_empty() {
+        return BitcoinHash::new([0; 32]);
+    }
+
+    let mut level: Vec<BitcoinHash> = hashes.to_vec();
+    while level.len() > 1 {
+        // Odd levels pair the last hash with itself
+        if level.len() % 2 == 1 {
+            let last = *level.last().unwrap();
+            level.push(last);
+        }
+
+        level = level.chunks(2).map(|pair| {
+            let mut data = vec![];
+            data.extend(pair[0].iter().cloned());
+            data.extend(pair[1].iter().cloned());
+
+            BitcoinHash::new(CryptoUtils::sha256(&CryptoUtils::sha256(&data)))
+        }).collect();
+    }
+
+    level[0]
+}
+
+// BIP141 witness merkle root, the coinbase wtxid is replaced by zeros
+pub fn witness_merkle_root(wtxids: &[BitcoinHash]) -> BitcoinHash {
+    let mut hashes = wtxids.to_vec();
+    if let Some(coinbase) = hashes.first_mut() {
+        *coinbase = BitcoinHash::new([0; 32]);
+    }
+
+    merkle_root(&hashes)
+}
+
+// Runs the scripts of input `index` of `tx`, which spends `spent`.
+// The cache, if any, lets us skip scripts that can never succeed.
+pub fn verify_input(tx: &TxMessage, index: usize, spent: &UtxoEntry, flags: ScriptFlags,
+                    checksig: &Fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool,
+                    cache: Option<&Mutex<ScriptCache>>) -> Result<(), String> {
+    if let Some(cache) = cache {
+        let parsed = cache.lock().unwrap().get(&spent.script);
+        if parsed.malformed || parsed.script_type == ScriptType::NullData {
+            return Err(format!("Input {} of {:?} spends an unspendable output",
+                               index, tx.hash()));
+        }
+    }
+
+    let script_sig = tx.tx_in[index].script.clone();
+
+    match Parser::execute_with_flags(script_sig, spent.script.clone(), checksig, flags) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(format!("Script verification failed for input {} of {:?}",
+                                 index, tx.hash())),
+        Err(e) => Err(e),
+    }
+}
+
+// Consensus limit on the signature operations in a block
+pub const MAX_BLOCK_SIGOPS: usize = 20000;
+
+const HALVING_INTERVAL: usize = 210000;
+
+// New coins created by the coinbase at `height`, in satoshis
+pub fn block_subsidy(height: usize) -> u64 {
+    let halvings = height / HALVING_INTERVAL;
+    // Shifting by 64 or more bits would overflow
+    if halvings >= 64 {
+        return 0;
+    }
+
+    (50 * COIN) >> halvings
+}
+
+// BIP34: the coinbase script must start with this push of the block height
+pub fn coinbase_height_prefix(height: usize) -> Vec<u8> {
+    match height {
+        0 => vec![0x00],
+        // OP_1 to OP_16
+        1...16 => vec![0x50 + height as u8],
+        // The length prefix of a ScriptNum is also the push opcode
+        _ => {
+            let mut script = vec![];
+            ScriptNum::new(height as i32).unwrap().serialize(&mut script);
+            script
+        },
+    }
+}
+
+// Consensus limit on the weight of a block, see BIP141
+pub const MAX_BLOCK_WEIGHT: usize = 4000000;
+
+// Without witness data every byte weighs this much
+pub const WITNESS_SCALE_FACTOR: usize = 4;
+
+// Legacy count: every script of the transaction, CHECKMULTISIG always counts as 20
+pub fn count_tx_sigops(tx: &TxMessage) -> usize {
+    let inputs: usize = tx.tx_in.iter().map(|i| count_sigops(&i.script, false)).sum();
+    let outputs: usize = tx.tx_out.iter().map(|o| count_sigops(&o.pk_script, false)).sum();
+
+    inputs + outputs
+}
+
+pub fn count_block_sigops(block: &BlockMessage) -> usize {
+    block.txns.iter().map(count_tx_sigops).sum()
+}
+
+// Number of threads used to verify the scripts of a block
+const SCRIPT_THREADS: usize = 8;
+
+// Input `input` of the transaction at `tx` in the block, spending `spent`
+struct ScriptJob {
+    tx: usize,
+    input: usize,
+    spent: UtxoEntry,
+}
+
+// Runs the jobs on SCRIPT_THREADS threads, if more than one fails the
+// error of the first one in block order is returned. The threads borrow the
+// block instead of taking a copy of it.
+fn verify_scripts(txns: &[TxMessage], jobs: &[ScriptJob], flags: ScriptFlags,
+                  checksig: fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool,
+                  cache: Option<&Mutex<ScriptCache>>) -> Result<(), String> {
+    let next = AtomicUsize::new(0);
+    let failure: Mutex<Option<(usize, String)>> = Mutex::new(None);
+
+    let panicked = thread::scope(|scope| {
+        let workers: Vec<_> = (0..cmp::min(SCRIPT_THREADS, jobs.len())).map(|_| {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::SeqCst);
+                    if index >= jobs.len() {
+                        break;
+                    }
+
+                    let job = &jobs[index];
+                    let result = verify_input(&txns[job.tx], job.input, &job.spent, flags,
+                                              &checksig, cache);
+                    if let Err(e) = result {
+                        let mut failure = failure.lock().unwrap();
+                        if failure.as_ref().map_or(true, |&(first, _)| index < first) {
+                            *failure = Some((index, e));
+                        }
+
+                        // No need to check the rest of the block
+                        next.store(jobs.len(), Ordering::SeqCst);
+                    }
+                }
+            })
+        }).collect();
+
+        // The interpreter still panics on some malformed scripts
+        let mut panicked = false;
+        for worker in workers {
+            panicked |= worker.join().is_err();
+        }
+        panicked
+    });
+
+    if let Some((_, e)) = failure.lock().unwrap().take() {
+        return Err(e);
+    }
+
+    if panicked {
+        return Err(format!("Script verification crashed"));
+    }
+
+    Ok(())
+}
+
+// Checks that `block` can be connected at `height` on top of `utxo`.
+pub fn validate_block(block: &BlockMessage, utxo: &UtxoSet, height: usize, flags: ScriptFlags,
+                      checksig: fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool,
+                      cache: Option<Arc<Mutex<ScriptCache>>>) -> Result<(), String> {
+    match block.txns.first() {
+        Some(tx) if tx.is_coinbase() => {},
+        _ => return Err(format!("The first transaction is not a coinbase")),
+    }
+
+    let hashes: Vec<BitcoinHash> = block.txns.iter().map(|tx| tx.hash()).collect();
+    for (tx, hash) in block.txns.iter().zip(hashes.iter()) {
+        if let Err(e) = tx.is_valid_structure() {
+            return Err(format!("Invalid transaction {:?}: {}", hash, e));
+        }
+    }
+    if merkle_root(&hashes) != block.metadata.merkle_root {
+        return Err(format!("Merkle root mismatch"));
+    }
+
+    let sigops = count_block_sigops(block);
+    if sigops > MAX_BLOCK_SIGOPS {
+        return Err(format!("Too many signature operations: {}", sigops));
+    }
+
+    // Outputs created and spent by the transactions we checked so far
+    let mut created: HashMap<OutPoint, UtxoEntry> = HashMap::new();
+    let mut spent: HashSet<OutPoint> = HashSet::new();
+
+    // Scripts don't depend on each other, they are verified in parallel at the end
+    let mut jobs = vec![];
+    let mut fees = 0;
+
+    for (tx_index, (tx, hash)) in block.txns.iter().zip(hashes.iter()).enumerate().skip(1) {
+        if tx.is_coinbase() {
+            return Err(format!("Extra coinbase {:?}", hash));
+        }
+
+        for (index, input) in tx.tx_in.iter().enumerate() {
+            let output = &input.previous_output;
+
+            let entry = match created.get(output).or(utxo.get(output)) {
+                Some(entry) => entry,
+                None => return Err(format!("Missing output {:?}", output)),
+            };
+
+            if !spent.insert(output.clone()) {
+                return Err(format!("Output {:?} is spent twice", output));
+            }
+
+            if !entry.is_mature(height) {
+                return Err(format!("Spending immature coinbase {:?}", output));
+            }
+
+            jobs.push(ScriptJob {
+                tx: tx_index,
+                input: index,
+                spent: entry.clone(),
+            });
+        }
+
+        let fee = tx.fee(&|output| created.get(output).or(utxo.get(output)).map(|e| e.value));
+        match fee {
+            Some(fee) => fees += fee.as_satoshis(),
+            None => return Err(format!("Outputs of {:?} are worth more than its inputs", hash)),
+        }
+
+        for (index, output) in tx.tx_out.iter().enumerate() {
+            created.insert(OutPoint::new(*hash, index as u32), UtxoEntry {
+                value: output.value,
+                script: output.pk_script.clone(),
+                height: height,
+                coinbase: false,
+            });
+        }
+    }
+
+    let coinbase_value = block.txns[0].tx_out.iter()
+        .fold(Some(Amount::from_satoshis(0)), |sum, output| {
+            sum.and_then(|sum| sum.checked_add(output.value))
+        });
+    let coinbase_value = match coinbase_value {
+        Some(value) => value.as_satoshis(),
+        None => return Err(format!("The coinbase outputs are out of range")),
+    };
+
+    if coinbase_value > block_subsidy(height) + fees {
+        return Err(format!("The coinbase pays too much: {}", coinbase_value));
+    }
+
+    verify_scripts(&block.txns, &jobs, flags, checksig, cache.as_ref().map(|c| &**c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::messages::{BlockMessage, BitcoinHash, OutPoint, TxMessage, TxOut,
+                                 SerializeHash};
+    use super::super::store::BlockStore;
+    use super::super::utxo::UtxoSet;
+
+    use script::{ScriptCache, ScriptFlags, check_witness_commitment, witness_commitment};
+    use test::utils::{get_block, get_coinbase, get_tx, mock_checksig};
+    use transaction::Amount;
+
+    use std::sync::{Arc, Mutex};
+
+    fn validate(block: &BlockMessage, utxo: &UtxoSet, height: usize) -> Result<(), String> {
+        validate_block(block, utxo, height, ScriptFlags::default(), mock_checksig, None)
+    }
+
+    #[test]
+    fn test_merkle_root() {
+        let hashes: Vec<BitcoinHash> = (0..3).map(|i| BitcoinHash::new([i; 32])).collect();
+
+        assert_eq!(merkle_root(&hashes[..1]), hashes[0]);
+        // The last hash is paired with itself
+        assert_eq!(merkle_root(&hashes),
+                   merkle_root(&[merkle_root(&hashes[..2]), merkle_root(&[hashes[2], hashes[2]])]));
+        assert!(merkle_root(&hashes[..2]) != merkle_root(&[hashes[1], hashes[0]]));
+    }
+
+    #[test]
+    fn test_witness_merkle_root() {
+        let wtxids: Vec<BitcoinHash> = (1..4).map(|i| BitcoinHash::new([i; 32])).collect();
+        let zero = BitcoinHash::new([0; 32]);
+
+        assert_eq!(witness_merkle_root(&wtxids),
+                   merkle_root(&[zero, wtxids[1], wtxids[2]]));
+        // Only the coinbase is special
+        assert_eq!(witness_merkle_root(&wtxids[..1]), zero);
+        assert!(witness_merkle_root(&wtxids) != witness_merkle_root(&wtxids[..2]));
+
+        // The commitment ends up in the coinbase outputs
+        let reserved = [0; 32];
+        let commitment = witness_commitment(&witness_merkle_root(&wtxids), &reserved);
+        let mut script = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        script.extend(commitment.iter().cloned());
+
+        let outputs = vec![TxOut::new(Amount::default(), script)];
+        assert!(check_witness_commitment(&outputs, &witness_merkle_root(&wtxids), &reserved));
+        assert!(!check_witness_commitment(&outputs, &merkle_root(&wtxids), &reserved));
+    }
+
+    #[test]
+    fn test_validate_genesis() {
+        let genesis = BlockStore::genesis_block(1296688602, 414098458);
+        assert_eq!(validate(&genesis, &UtxoSet::new(), 0), Ok(()));
+
+        let mut bad_merkle = genesis.clone();
+        bad_merkle.metadata.merkle_root = BitcoinHash::new([0; 32]);
+        assert!(validate(&bad_merkle, &UtxoSet::new(), 0).is_err());
+
+        let mut no_coinbase = genesis.clone();
+        no_coinbase.txns.clear();
+        assert!(validate(&no_coinbase, &UtxoSet::new(), 0).is_err());
+    }
+
+    #[test]
+    fn test_validate_block() {
+        let mut utxo = UtxoSet::new();
+        let coinbase = get_coinbase(1);
+        let coinbase_output = OutPoint::new(coinbase.hash(), 0);
+
+        let first = get_block(vec![coinbase]);
+        assert_eq!(validate(&first, &utxo, 1), Ok(()));
+        utxo.connect_block(&first, 1).unwrap();
+
+        let spend = get_tx(vec![coinbase_output.clone()], vec![4000000000]);
+        let chained = get_tx(vec![OutPoint::new(spend.hash(), 0)], vec![3000000000]);
+        let block = get_block(vec![get_coinbase(101), spend.clone(), chained]);
+        assert_eq!(validate(&block, &utxo, 101), Ok(()));
+
+        // Coinbase not mature yet
+        assert!(validate(&block, &utxo, 100).is_err());
+
+        let double_spend = get_tx(vec![coinbase_output.clone()], vec![1000]);
+        let block = get_block(vec![get_coinbase(101), spend.clone(), double_spend]);
+        assert!(validate(&block, &utxo, 101).is_err());
+
+        // Spending the same output twice in a single transaction
+        let duplicate = get_tx(vec![coinbase_output.clone(), coinbase_output.clone()],
+                               vec![1000]);
+        let block = get_block(vec![get_coinbase(101), duplicate.clone()]);
+        assert_eq!(validate(&block, &utxo, 101),
+                   Err(format!("Invalid transaction {:?}: Transaction spends an output twice",
+                               duplicate.hash())));
+
+        let too_expensive = get_tx(vec![coinbase_output.clone()], vec![5000000001]);
+        let block = get_block(vec![get_coinbase(101), too_expensive]);
+        assert!(validate(&block, &utxo, 101).is_err());
+
+        let mut bad_script = spend.clone();
+        bad_script.tx_in[0].script = vec![0x52];
+        let block = get_block(vec![get_coinbase(101), bad_script]);
+        assert!(validate(&block, &utxo, 101).is_err());
+
+        let missing = get_tx(vec![OutPoint::new(BitcoinHash::new([1; 32]), 0)], vec![1]);
+        let block = get_block(vec![get_coinbase(101), missing]);
+        assert!(validate(&block, &utxo, 101).is_err());
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0), 5000000000);
+        assert_eq!(block_subsidy(209999), 5000000000);
+        assert_eq!(block_subsidy(210000), 2500000000);
+        assert_eq!(block_subsidy(420000), 1250000000);
+        // The subsidy is already zero after 33 halvings
+        assert_eq!(block_subsidy(33 * 210000), 0);
+        assert_eq!(block_subsidy(64 * 210000), 0);
+        assert_eq!(block_subsidy(1000 * 210000), 0);
+    }
+
+    #[test]
+    fn test_coinbase_height_prefix() {
+        assert_eq!(coinbase_height_prefix(0), vec![0x00]);
+        assert_eq!(coinbase_height_prefix(16), vec![0x60]);
+        assert_eq!(coinbase_height_prefix(17), vec![0x01, 0x11]);
+        // The sign bit needs an extra byte
+        assert_eq!(coinbase_height_prefix(128), vec![0x02, 0x80, 0x00]);
+        // First BIP34 block on main
+        assert_eq!(coinbase_height_prefix(227931), vec![0x03, 0x5b, 0x7a, 0x03]);
+    }
+
+    #[test]
+    fn test_coinbase_value() {
+        let mut utxo = UtxoSet::new();
+        let coinbase = get_coinbase(1);
+        let coinbase_output = OutPoint::new(coinbase.hash(), 0);
+        utxo.connect_block(&get_block(vec![coinbase]), 1).unwrap();
+
+        // Pays a fee of 1 BTC
+        let spend = get_tx(vec![coinbase_output], vec![4000000000]);
+
+        let mut coinbase = get_coinbase(101);
+        coinbase.tx_out[0].value = Amount::from_satoshis(6000000000);
+        let block = get_block(vec![coinbase.clone(), spend.clone()]);
+        assert_eq!(validate(&block, &utxo, 101), Ok(()));
+
+        coinbase.tx_out[0].value = Amount::from_satoshis(6000000001);
+        let block = get_block(vec![coinbase, spend.clone()]);
+        assert!(validate(&block, &utxo, 101).is_err());
+
+        // After the first halving the same block pays too much
+        let block = get_block(vec![get_coinbase(101), spend]);
+        assert_eq!(validate(&block, &utxo, 101), Ok(()));
+        assert!(validate(&block, &utxo, 210000).is_err());
+    }
+
+    #[test]
+    fn test_validate_many_inputs() {
+        let mut utxo = UtxoSet::new();
+        let mut coinbase = get_coinbase(1);
+        coinbase.tx_out = get_tx(vec![], vec![1000; 100]).tx_out;
+        let coinbase_hash = coinbase.hash();
+        utxo.connect_block(&get_block(vec![coinbase]), 1).unwrap();
+
+        // 10 transactions spending 10 outputs each, and one spending all of them
+        let mut txns = vec![get_coinbase(101)];
+        for i in 0..10 {
+            let inputs = (0..10).map(|j| OutPoint::new(coinbase_hash, i * 10 + j)).collect();
+            txns.push(get_tx(inputs, vec![9000]));
+        }
+        let inputs = txns[1..].iter().map(|tx| OutPoint::new(tx.hash(), 0)).collect();
+        txns.push(get_tx(inputs, vec![90000]));
+
+        assert_eq!(validate(&get_block(txns.clone()), &utxo, 101), Ok(()));
+
+        // A single bad input makes the whole block invalid
+        for &(tx, input) in &[(1, 0), (5, 7), (10, 9), (11, 3)] {
+            let mut bad = txns.clone();
+            bad[tx].tx_in[input].script = vec![0x52];
+            if tx < 11 {
+                // Keeps the last transaction pointing to the modified one
+                bad[11].tx_in[tx - 1].previous_output = OutPoint::new(bad[tx].hash(), 0);
+            }
+
+            let error = validate(&get_block(bad), &utxo, 101).unwrap_err();
+            assert!(error.starts_with("Script verification failed"));
+        }
+    }
+
+    #[test]
+    fn test_validate_with_cache() {
+        let mut utxo = UtxoSet::new();
+        let mut coinbase = get_coinbase(1);
+        coinbase.tx_out = get_tx(vec![], vec![1000; 10]).tx_out;
+        // OP_RETURN, nothing can spend this
+        coinbase.tx_out[9].pk_script = vec![0x6a];
+        let coinbase_hash = coinbase.hash();
+        utxo.connect_block(&get_block(vec![coinbase]), 1).unwrap();
+
+        let cache = Arc::new(Mutex::new(ScriptCache::new(100)));
+        let validate_cached = |txns: Vec<TxMessage>| {
+            validate_block(&get_block(txns), &utxo, 101, ScriptFlags::default(), mock_checksig,
+                           Some(cache.clone()))
+        };
+
+        // Every output has the same script
+        let inputs = (0..9).map(|i| OutPoint::new(coinbase_hash, i)).collect();
+        assert_eq!(validate_cached(vec![get_coinbase(101), get_tx(inputs, vec![9000])]), Ok(()));
+        assert_eq!(cache.lock().unwrap().len(), 1);
+        assert_eq!(cache.lock().unwrap().hits(), 8);
+
+        let spend_return = get_tx(vec![OutPoint::new(coinbase_hash, 9)], vec![1000]);
+        assert!(validate_cached(vec![get_coinbase(101), spend_return]).is_err());
+    }
+
+    #[test]
+    fn test_block_sigops() {
+        let genesis = BlockStore::genesis_block(1296688602, 414098458);
+        // The genesis output is a pay to pub key
+        assert_eq!(count_block_sigops(&genesis), 1);
+
+        // 1000 bare CHECKMULTISIG outputs are exactly at the limit
+        let mut coinbase = get_coinbase(1);
+        coinbase.tx_out = get_tx(vec![], vec![1; 1000]).tx_out;
+        for output in coinbase.tx_out.iter_mut() {
+            output.pk_script = vec![0xae];
+        }
+
+        let block = get_block(vec![coinbase.clone()]);
+        assert_eq!(count_block_sigops(&block), MAX_BLOCK_SIGOPS);
+        assert_eq!(validate(&block, &UtxoSet::new(), 1), Ok(()));
+
+        coinbase.tx_in[0].script.push(0xac);
+        let block = get_block(vec![coinbase]);
+        assert_eq!(count_block_sigops(&block), MAX_BLOCK_SIGOPS + 1);
+        assert!(validate(&block, &UtxoSet::new(), 1).is_err());
+    }
+}
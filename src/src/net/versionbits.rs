@@ -0,0 +1,204 @@
+// BIP9 version bits: soft forks are deployed by having miners signal
+// support with a bit of the block version.
+
+// Versions signaling with BIP9 start with 001 in the top bits
+pub const VERSIONBITS_TOP_MASK: u32 = 0xE0000000;
+pub const VERSIONBITS_TOP_BITS: u32 = 0x20000000;
+// Bits available for deployments
+pub const VERSIONBITS_NUM_BITS: u8 = 29;
+
+// Blocks per retarget window and how many of them have to signal
+pub const MAIN_PERIOD: usize = 2016;
+pub const MAIN_THRESHOLD: usize = 1916;
+pub const TESTNET_THRESHOLD: usize = 1512;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ThresholdState {
+    Defined,
+    Started,
+    LockedIn,
+    Active,
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Deployment {
+    pub bit: u8,
+    // Median time past at which signaling starts and gives up
+    pub start_time: i64,
+    pub timeout: i64,
+}
+
+pub fn uses_version_bits(version: i32) -> bool {
+    version as u32 & VERSIONBITS_TOP_MASK == VERSIONBITS_TOP_BITS
+}
+
+pub fn signals(version: i32, bit: u8) -> bool {
+    bit < VERSIONBITS_NUM_BITS && uses_version_bits(version) && (version as u32 >> bit) & 1 == 1
+}
+
+// All the deployment bits set in `version`
+pub fn signaled_bits(version: i32) -> Vec<u8> {
+    (0..VERSIONBITS_NUM_BITS).filter(|&bit| signals(version, bit)).collect()
+}
+
+// Follows the state of a deployment block by block. The state only changes
+// at the end of each period of `period` blocks.
+#[derive(Debug, Clone)]
+pub struct VersionBitsTally {
+    deployment: Deployment,
+    period: usize,
+    threshold: usize,
+    state: ThresholdState,
+    // Blocks seen in the current period and how many of them signaled
+    blocks: usize,
+    count: usize,
+}
+
+impl VersionBitsTally {
+    pub fn new(deployment: Deployment, period: usize, threshold: usize) -> VersionBitsTally {
+        VersionBitsTally {
+            deployment: deployment,
+            period: period,
+            threshold: threshold,
+            state: ThresholdState::Defined,
+            blocks: 0,
+            count: 0,
+        }
+    }
+
+    // State of the blocks following the ones added so far
+    pub fn state(&self) -> ThresholdState { self.state }
+
+    // Signaling blocks in the current period
+    pub fn count(&self) -> usize { self.count }
+
+    // Adds the next block, `median_time_past` is the median time of the
+    // 11 blocks ending with this one
+    pub fn add_block(&mut self, version: i32, median_time_past: i64) {
+        if self.state == ThresholdState::Started && signals(version, self.deployment.bit) {
+            self.count += 1;
+        }
+
+        self.blocks += 1;
+        if self.blocks < self.period {
+            return;
+        }
+
+        self.state = self.next_state(median_time_past);
+        self.blocks = 0;
+        self.count = 0;
+    }
+
+    fn next_state(&self, median_time_past: i64) -> ThresholdState {
+        match self.state {
+            ThresholdState::Defined if median_time_past >= self.deployment.timeout =>
+                ThresholdState::Failed,
+            ThresholdState::Defined if median_time_past >= self.deployment.start_time =>
+                ThresholdState::Started,
+            ThresholdState::Started if median_time_past >= self.deployment.timeout =>
+                ThresholdState::Failed,
+            ThresholdState::Started if self.count >= self.threshold =>
+                ThresholdState::LockedIn,
+            ThresholdState::LockedIn => ThresholdState::Active,
+            state => state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNALING: i32 = 0x20000002;
+    const NOT_SIGNALING: i32 = 0x20000000;
+
+    fn get_deployment() -> Deployment {
+        Deployment {
+            bit: 1,
+            start_time: 1000,
+            timeout: 5000,
+        }
+    }
+
+    fn add_period(tally: &mut VersionBitsTally, signaling: usize, median_time_past: i64) {
+        for i in 0..MAIN_PERIOD {
+            let version = if i < signaling { SIGNALING } else { NOT_SIGNALING };
+            tally.add_block(version, median_time_past);
+        }
+    }
+
+    #[test]
+    fn test_signals() {
+        assert!(uses_version_bits(0x20000000));
+        assert!(uses_version_bits(0x3fffffff));
+        assert!(!uses_version_bits(4));
+        assert!(!uses_version_bits(0x60000002));
+
+        assert!(signals(SIGNALING, 1));
+        assert!(!signals(SIGNALING, 0));
+        assert!(!signals(NOT_SIGNALING, 1));
+        // Old style versions don't signal anything
+        assert!(!signals(0x00000002, 1));
+
+        assert_eq!(signaled_bits(0x20000013), vec![0, 1, 4]);
+        assert_eq!(signaled_bits(0x30000000), vec![28]);
+        assert!(signaled_bits(4).is_empty());
+    }
+
+    #[test]
+    fn test_locked_in() {
+        let mut tally = VersionBitsTally::new(get_deployment(), MAIN_PERIOD, MAIN_THRESHOLD);
+        assert_eq!(tally.state(), ThresholdState::Defined);
+
+        // Signaling before the start time doesn't count
+        add_period(&mut tally, MAIN_PERIOD, 500);
+        assert_eq!(tally.state(), ThresholdState::Defined);
+
+        add_period(&mut tally, MAIN_PERIOD, 1000);
+        assert_eq!(tally.state(), ThresholdState::Started);
+
+        // One block short of the threshold
+        add_period(&mut tally, MAIN_THRESHOLD - 1, 2000);
+        assert_eq!(tally.state(), ThresholdState::Started);
+
+        for _ in 0..MAIN_THRESHOLD - 1 {
+            tally.add_block(SIGNALING, 3000);
+        }
+        for _ in 0..MAIN_PERIOD - MAIN_THRESHOLD {
+            tally.add_block(NOT_SIGNALING, 3000);
+        }
+        assert_eq!(tally.count(), MAIN_THRESHOLD - 1);
+        assert_eq!(tally.state(), ThresholdState::Started);
+
+        // The last block of the period reaches the threshold
+        tally.add_block(SIGNALING, 3000);
+        assert_eq!(tally.state(), ThresholdState::LockedIn);
+
+        // Locked in deployments activate after another period, past the
+        // timeout or not
+        add_period(&mut tally, 0, 6000);
+        assert_eq!(tally.state(), ThresholdState::Active);
+        add_period(&mut tally, 0, 7000);
+        assert_eq!(tally.state(), ThresholdState::Active);
+    }
+
+    #[test]
+    fn test_failed() {
+        let mut tally = VersionBitsTally::new(get_deployment(), MAIN_PERIOD, MAIN_THRESHOLD);
+        add_period(&mut tally, 0, 1000);
+        assert_eq!(tally.state(), ThresholdState::Started);
+
+        // Enough signaling but the deployment already timed out
+        add_period(&mut tally, MAIN_PERIOD, 5000);
+        assert_eq!(tally.state(), ThresholdState::Failed);
+
+        add_period(&mut tally, MAIN_PERIOD, 6000);
+        assert_eq!(tally.state(), ThresholdState::Failed);
+
+        // Timing out before it even started
+        let mut tally = VersionBitsTally::new(get_deployment(), MAIN_PERIOD, TESTNET_THRESHOLD);
+        add_period(&mut tally, 0, 5000);
+        assert_eq!(tally.state(), ThresholdState::Failed);
+    }
+}
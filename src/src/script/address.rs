@@ -0,0 +1,168 @@
+use net::messages::NetworkType;
+use utils::CryptoUtils;
+
+use super::{ScriptType, classify};
+
+const BASE58_ALPHABET: &'static [u8] =
+    b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+const BECH32_CHARSET: &'static [u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+
+// Address paying to `script`, None if the script has no address form
+pub fn address(script: &[u8], network_type: NetworkType) -> Option<String> {
+    // Version bytes of pay to pub key hash and script hash, bech32 prefix
+    let (pubkey_hash, script_hash, hrp) = match network_type {
+        NetworkType::Main => (0x00, 0x05, "bc"),
+        NetworkType::TestNet | NetworkType::TestNet3 => (0x6f, 0xc4, "tb"),
+        NetworkType::NameCoin | NetworkType::Unknown => return None,
+    };
+
+    match classify(script) {
+        ScriptType::PubKeyHash => Some(base58check(pubkey_hash, &script[3..23])),
+        ScriptType::ScriptHash => Some(base58check(script_hash, &script[2..22])),
+        ScriptType::WitnessKeyHash | ScriptType::WitnessScriptHash =>
+            Some(segwit_address(hrp, 0, &script[2..])),
+        _ => None,
+    }
+}
+
+// Base58 of version + payload + the first 4 bytes of their double sha256
+pub fn base58check(version: u8, payload: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(payload.iter().cloned());
+
+    let checksum = CryptoUtils::sha256(&CryptoUtils::sha256(&data));
+    data.extend(checksum[..4].iter().cloned());
+
+    base58(&data)
+}
+
+fn base58(data: &[u8]) -> String {
+    // Base 58 digits, least significant first
+    let mut digits: Vec<u8> = vec![];
+    for byte in data {
+        let mut carry = *byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    // Each leading zero byte is written as a '1'
+    let zeros = data.iter().take_while(|byte| **byte == 0).count();
+
+    let mut result: String = (0..zeros).map(|_| '1').collect();
+    result.extend(digits.iter().rev().map(|digit| BASE58_ALPHABET[*digit as usize] as char));
+    result
+}
+
+// BIP173 address of a witness program
+pub fn segwit_address(hrp: &str, version: u8, program: &[u8]) -> String {
+    let mut data = vec![version];
+    data.extend(to_base32(program));
+
+    bech32(hrp, &data)
+}
+
+// Regroups 8 bit bytes in 5 bit values, the last one is padded with zeros
+fn to_base32(data: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    let mut accumulator: u32 = 0;
+    let mut bits = 0;
+
+    for byte in data {
+        accumulator = ((accumulator << 8) | *byte as u32) & 0xffff;
+        bits += 8;
+
+        while bits >= 5 {
+            bits -= 5;
+            result.push(((accumulator >> bits) & 0x1f) as u8);
+        }
+    }
+
+    if bits > 0 {
+        result.push(((accumulator << (5 - bits)) & 0x1f) as u8);
+    }
+
+    result
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    let generator = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+    let mut checksum: u32 = 1;
+    for value in values {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ffffff) << 5) ^ *value as u32;
+
+        for (i, g) in generator.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= *g;
+            }
+        }
+    }
+
+    checksum
+}
+
+fn bech32(hrp: &str, data: &[u8]) -> String {
+    let mut values: Vec<u8> = hrp.bytes().map(|b| b >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|b| b & 0x1f));
+    values.extend(data.iter().cloned());
+    values.extend(&[0; 6]);
+
+    let checksum = bech32_polymod(&values) ^ 1;
+
+    let mut result = format!("{}1", hrp);
+    result.extend(data.iter().map(|d| BECH32_CHARSET[*d as usize] as char));
+    result.extend((0..6).map(|i| BECH32_CHARSET[((checksum >> (5 * (5 - i))) & 0x1f) as usize] as char));
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::messages::NetworkType;
+    use rustc_serialize::hex::FromHex;
+
+    fn address_hex(script: &str, network_type: NetworkType) -> Option<String> {
+        address(&script.from_hex().unwrap(), network_type)
+    }
+
+    #[test]
+    fn test_address() {
+        // The address of the genesis block coinbase key
+        let p2pkh = "76a91462e907b15cbf27d5425399ebf6f0fb50ebb88f1888ac";
+        assert_eq!(address_hex(p2pkh, NetworkType::Main),
+                   Some("1A1zP1eP5QGefi2DMPTfTL5SLmv7DivfNa".to_string()));
+        assert_eq!(address_hex(p2pkh, NetworkType::TestNet3),
+                   Some("mpXwg4jMtRhuSpVq4xS3HFHmCmWp9NyGKt".to_string()));
+
+        assert_eq!(address_hex("a914748284390f9e263a4b766a75d0633c50426eb87587",
+                               NetworkType::Main),
+                   Some("3CK4fEwbMP7heJarmU4eqA3sMbVJyEnU3V".to_string()));
+
+        // BIP173 test vectors
+        let p2wpkh = "0014751e76e8199196d454941c45d1b3a323f1433bd6";
+        assert_eq!(address_hex(p2wpkh, NetworkType::Main),
+                   Some("bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4".to_string()));
+        assert_eq!(address_hex(p2wpkh, NetworkType::TestNet3),
+                   Some("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".to_string()));
+
+        assert_eq!(address_hex("6a0401020304", NetworkType::Main), None);
+        assert_eq!(address_hex(p2pkh, NetworkType::NameCoin), None);
+    }
+
+    #[test]
+    fn test_base58_leading_zeros() {
+        assert_eq!(base58(&[0, 0, 1]), "112");
+        assert_eq!(base58(&[]), "");
+    }
+}
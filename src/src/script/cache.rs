@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::{BitcoinScript, ScriptType, classify};
+
+// What we learn about a script without executing it
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedScript {
+    pub script_type: ScriptType,
+    // (index, length) of the data pushed by each push operation
+    pub pushes: Vec<(usize, usize)>,
+    // A push runs past the end of the script or an opcode is unknown,
+    // executing the script will always fail.
+    pub malformed: bool,
+}
+
+impl ParsedScript {
+    pub fn parse(script: &[u8]) -> ParsedScript {
+        let bitcoin_script = BitcoinScript::new(script.to_vec());
+        let mut iter = bitcoin_script.iter();
+
+        let pushes = iter.by_ref()
+            .filter_map(|(index, _, data)| data.map(|d| (index, d.len())))
+            .collect();
+
+        ParsedScript {
+            script_type: classify(script),
+            pushes: pushes,
+            malformed: iter.error(),
+        }
+    }
+}
+
+// Least recently used cache of parsed scripts, keyed by the script bytes
+pub struct ScriptCache {
+    entries: HashMap<Vec<u8>, (ParsedScript, u64)>,
+    // Last use of each script, the first entry is the least recently used
+    usage: BTreeMap<u64, Vec<u8>>,
+    capacity: usize,
+    tick: u64,
+    hits: u64,
+}
+
+impl ScriptCache {
+    pub fn new(capacity: usize) -> ScriptCache {
+        assert!(capacity > 0);
+
+        ScriptCache {
+            entries: HashMap::new(),
+            usage: BTreeMap::new(),
+            capacity: capacity,
+            tick: 0,
+            hits: 0,
+        }
+    }
+
+    pub fn get(&mut self, script: &[u8]) -> ParsedScript {
+        self.tick += 1;
+        let tick = self.tick;
+
+        if let Some(entry) = self.entries.get_mut(script) {
+            self.hits += 1;
+            self.usage.remove(&entry.1);
+            self.usage.insert(tick, script.to_vec());
+            entry.1 = tick;
+
+            return entry.0.clone();
+        }
+
+        if self.entries.len() >= self.capacity {
+            let oldest = self.usage.keys().next().cloned().unwrap();
+            let script = self.usage.remove(&oldest).unwrap();
+            self.entries.remove(&script);
+        }
+
+        let parsed = ParsedScript::parse(script);
+        self.entries.insert(script.to_vec(), (parsed.clone(), tick));
+        self.usage.insert(tick, script.to_vec());
+
+        parsed
+    }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    // Number of lookups that didn't need to parse the script
+    pub fn hits(&self) -> u64 { self.hits }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::ScriptType;
+
+    fn p2pkh(hash: u8) -> Vec<u8> {
+        let mut script = vec![0x76, 0xa9, 0x14];
+        script.extend(vec![hash; 20]);
+        script.extend(vec![0x88, 0xac]);
+        script
+    }
+
+    #[test]
+    fn test_script_cache() {
+        let mut cache = ScriptCache::new(2);
+
+        let parsed = cache.get(&p2pkh(1));
+        assert_eq!(parsed.script_type, ScriptType::PubKeyHash);
+        assert_eq!(parsed.pushes, vec![(2, 20)]);
+        assert!(!parsed.malformed);
+        assert_eq!(cache.hits(), 0);
+
+        assert_eq!(cache.get(&p2pkh(1)), parsed);
+        assert_eq!(cache.hits(), 1);
+
+        // p2pkh(1) was used last, p2pkh(2) is evicted
+        cache.get(&p2pkh(2));
+        cache.get(&p2pkh(1));
+        cache.get(&p2pkh(3));
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.hits(), 2);
+
+        cache.get(&p2pkh(1));
+        assert_eq!(cache.hits(), 3);
+        cache.get(&p2pkh(2));
+        assert_eq!(cache.hits(), 3);
+    }
+
+    #[test]
+    fn test_parse_malformed() {
+        let parsed = ParsedScript::parse(&[0x51, 0x4c, 0x05, 0x01]);
+        assert!(parsed.malformed);
+        assert_eq!(parsed.script_type, ScriptType::NonStandard);
+    }
+}
@@ -0,0 +1,324 @@
+#[cfg(test)]
+use crypto::hmac::Hmac;
+#[cfg(test)]
+use crypto::mac::Mac;
+#[cfg(test)]
+use crypto::sha2::Sha256;
+
+use super::secp256k1::{lift_x, mul_mod, pow_mod, sub_mod, fadd, fmul, Point, U256,
+                       G_X, G_Y, N, N_INV_EXP, P, ZERO};
+#[cfg(test)]
+use super::secp256k1::add_mod;
+
+// ECDSA over secp256k1, the signatures checked by OP_CHECKSIG.
+//
+// Signing is not constant time and can leak the key through timing, so
+// ecdsa_sign and public_key are only built for the tests.
+
+// Half the group order, higher S values are made low like BIP62 asks
+#[cfg(test)]
+const HALF_N: U256 = U256([0xdfe92f46681b20a0, 0x5d576e7357a4501d,
+                           0xffffffffffffffff, 0x7fffffffffffffff]);
+
+fn reduce(value: U256) -> U256 {
+    if value >= N { value.overflowing_sub(&N).0 } else { value }
+}
+
+fn generator() -> Point {
+    Point::from_affine(G_X, G_Y)
+}
+
+// The secret must be in [1, N)
+#[cfg(test)]
+fn parse_secret(secret: &[u8; 32]) -> Option<U256> {
+    let d = U256::from_bytes(secret);
+    if d == ZERO || d >= N { None } else { Some(d) }
+}
+
+// Compressed public key of `secret`
+#[cfg(test)]
+pub(crate) fn public_key(secret: &[u8; 32]) -> Option<[u8; 33]> {
+    let d = match parse_secret(secret) {
+        Some(d) => d,
+        None => return None,
+    };
+
+    // d < N so the result can't be the point at infinity
+    let (x, y) = generator().mul(&d).to_affine().unwrap();
+
+    let mut key = [0u8; 33];
+    key[0] = if y.is_odd() { 0x03 } else { 0x02 };
+    key[1..].copy_from_slice(&x.to_bytes());
+    Some(key)
+}
+
+fn parse_public_key(key: &[u8]) -> Option<Point> {
+    match (key.len(), key.first()) {
+        (33, Some(&prefix)) if prefix == 0x02 || prefix == 0x03 => {
+            // lift_x gives the even y
+            lift_x(&U256::from_bytes(&key[1..])).map(|point| {
+                if prefix == 0x03 {
+                    Point { y: sub_mod(&ZERO, &point.y, &P), ..point }
+                } else {
+                    point
+                }
+            })
+        },
+        (65, Some(&0x04)) => {
+            let x = U256::from_bytes(&key[1..33]);
+            let y = U256::from_bytes(&key[33..]);
+            if x >= P || y >= P {
+                return None;
+            }
+
+            // y^2 = x^3 + 7
+            let curve = fadd(&fmul(&fmul(&x, &x), &x), &U256([7, 0, 0, 0]));
+            if fmul(&y, &y) != curve {
+                return None;
+            }
+
+            Some(Point::from_affine(x, y))
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+fn hmac(key: &[u8], data: &[&[u8]]) -> [u8; 32] {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    for part in data {
+        hmac.input(part);
+    }
+
+    let mut result = [0u8; 32];
+    result.copy_from_slice(hmac.result().code());
+    result
+}
+
+// Deterministic nonce from RFC6979 with HMAC-SHA256
+#[cfg(test)]
+fn nonce(secret: &[u8; 32], msg: &[u8; 32]) -> U256 {
+    let h1 = reduce(U256::from_bytes(msg)).to_bytes();
+    let mut v = [0x01u8; 32];
+    let mut k = [0x00u8; 32];
+
+    k = hmac(&k, &[&v, &[0x00], secret, &h1]);
+    v = hmac(&k, &[&v]);
+    k = hmac(&k, &[&v, &[0x01], secret, &h1]);
+    v = hmac(&k, &[&v]);
+
+    loop {
+        v = hmac(&k, &[&v]);
+
+        let candidate = U256::from_bytes(&v);
+        if candidate != ZERO && candidate < N {
+            return candidate;
+        }
+
+        k = hmac(&k, &[&v, &[0x00]]);
+        v = hmac(&k, &[&v]);
+    }
+}
+
+// DER encoding of a positive integer, see is_valid_signature_encoding
+#[cfg(test)]
+fn der_integer(value: &U256) -> Vec<u8> {
+    let bytes = value.to_bytes();
+    let start = bytes.iter().position(|byte| *byte != 0).unwrap_or(31);
+
+    let mut result = vec![0x02];
+    if bytes[start] & 0x80 != 0 {
+        result.push((32 - start + 1) as u8);
+        result.push(0x00);
+    } else {
+        result.push((32 - start) as u8);
+    }
+    result.extend(&bytes[start..]);
+    result
+}
+
+// Signs the hash `msg` with a low S, the result is DER encoded without the
+// hash type. Returns None if `secret` is not a valid key.
+#[cfg(test)]
+pub(crate) fn ecdsa_sign(msg: &[u8; 32], secret: &[u8; 32]) -> Option<Vec<u8>> {
+    let d = match parse_secret(secret) {
+        Some(d) => d,
+        None => return None,
+    };
+
+    let e = reduce(U256::from_bytes(msg));
+    let k = nonce(secret, msg);
+
+    // k < N so R can't be the point at infinity
+    let (x, _) = generator().mul(&k).to_affine().unwrap();
+    let r = reduce(x);
+
+    // s = (e + r * d) / k
+    let k_inv = pow_mod(&k, &N_INV_EXP, &N);
+    let mut s = mul_mod(&k_inv, &add_mod(&e, &mul_mod(&r, &d, &N), &N), &N);
+    if s > HALF_N {
+        s = sub_mod(&ZERO, &s, &N);
+    }
+
+    // Both only happen with negligible probability
+    if r == ZERO || s == ZERO {
+        return None;
+    }
+
+    let r = der_integer(&r);
+    let s = der_integer(&s);
+
+    let mut signature = vec![0x30, (r.len() + s.len()) as u8];
+    signature.extend(r);
+    signature.extend(s);
+    Some(signature)
+}
+
+// Reads 0x02 <length> <value>, returns the value and the rest of `data`
+fn parse_der_integer(data: &[u8]) -> Option<(U256, &[u8])> {
+    if data.len() < 2 || data[0] != 0x02 || data.len() < 2 + data[1] as usize {
+        return None;
+    }
+
+    let (value, rest) = data[2..].split_at(data[1] as usize);
+    let start = value.iter().position(|byte| *byte != 0).unwrap_or(value.len());
+    if value.len() - start > 32 {
+        return None;
+    }
+
+    let mut bytes = [0u8; 32];
+    bytes[32 - (value.len() - start)..].copy_from_slice(&value[start..]);
+    Some((U256::from_bytes(&bytes), rest))
+}
+
+// Checks a DER encoded signature, without hash type, of the hash `msg`.
+// High S values are accepted, that's a policy rule.
+pub fn ecdsa_verify(msg: &[u8; 32], public_key: &[u8], signature: &[u8]) -> bool {
+    let q = match parse_public_key(public_key) {
+        Some(q) => q,
+        None => return false,
+    };
+
+    if signature.len() < 2 || signature[0] != 0x30 || signature[1] as usize != signature.len() - 2 {
+        return false;
+    }
+
+    let (r, rest) = match parse_der_integer(&signature[2..]) {
+        Some(result) => result,
+        None => return false,
+    };
+    let (s, rest) = match parse_der_integer(rest) {
+        Some(result) => result,
+        None => return false,
+    };
+
+    if !rest.is_empty() || r == ZERO || r >= N || s == ZERO || s >= N {
+        return false;
+    }
+
+    let e = reduce(U256::from_bytes(msg));
+    let w = pow_mod(&s, &N_INV_EXP, &N);
+    let point = generator().mul(&mul_mod(&e, &w, &N)).add(&q.mul(&mul_mod(&r, &w, &N)));
+
+    match point.to_affine() {
+        Some((x, _)) => reduce(x) == r,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustc_serialize::hex::{FromHex, ToHex};
+    use utils::CryptoUtils;
+
+    fn get_secret(hex: &str) -> [u8; 32] {
+        let mut secret = [0u8; 32];
+        secret.copy_from_slice(&hex.from_hex().unwrap());
+        secret
+    }
+
+    fn get_der(r: &str, s: &str) -> Vec<u8> {
+        let r = der_integer(&U256::from_bytes(&r.from_hex().unwrap()));
+        let s = der_integer(&U256::from_bytes(&s.from_hex().unwrap()));
+        let mut signature = vec![0x30, (r.len() + s.len()) as u8];
+        signature.extend(r);
+        signature.extend(s);
+        signature
+    }
+
+    #[test]
+    fn test_public_key() {
+        let one = get_secret("0000000000000000000000000000000000000000000000000000000000000001");
+        assert_eq!(public_key(&one).unwrap().to_hex(),
+                   "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+
+        // N - 1 is -1, the negated generator
+        let minus_one =
+            get_secret("fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364140");
+        assert_eq!(public_key(&minus_one).unwrap().to_hex(),
+                   "0379be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798");
+
+        assert_eq!(public_key(&[0; 32]), None);
+        assert_eq!(public_key(&N.to_bytes()), None);
+    }
+
+    #[test]
+    fn test_rfc6979_vectors() {
+        let msg = CryptoUtils::sha256(b"Satoshi Nakamoto");
+
+        let one = get_secret("0000000000000000000000000000000000000000000000000000000000000001");
+        assert_eq!(nonce(&one, &msg).to_bytes().to_hex(),
+                   "8f8a276c19f4149656b280621e358cce24f5f52542772691ee69063b74f15d15");
+        assert_eq!(ecdsa_sign(&msg, &one).unwrap(),
+                   get_der("934b1ea10a4b3c1757e2b0c017d0b6143ce3c9a7e6a4a49860d7a6ab210ee3d8",
+                           "2442ce9d2b916064108014783e923ec36b49743e2ffa1c4496f01a512aafd9e5"));
+
+        let minus_one =
+            get_secret("fffffffffffffffffffffffffffffffebaaedce6af48a03bbfd25e8cd0364140");
+        assert_eq!(ecdsa_sign(&msg, &minus_one).unwrap(),
+                   get_der("fd567d121db66e382991534ada77a6bd3106f0a1098c231e47993447cd6af2d0",
+                           "6b39cd0eb1bc8603e159ef5c20a5c8ad685a45b06ce9bebed3f153d10d93bed5"));
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let secret = get_secret("c28a9f80738f770d527803a566cf6fc3edf6cea586c4fc4a5223a5ad797e1ac3");
+        let key = public_key(&secret).unwrap();
+        let msg = CryptoUtils::sha256(b"bitcoin-rust");
+
+        let signature = ecdsa_sign(&msg, &secret).unwrap();
+        let mut with_hash_type = signature.clone();
+        with_hash_type.push(0x01);
+        assert!(::script::is_valid_signature_encoding(&with_hash_type));
+        assert!(::script::is_low_s(&with_hash_type));
+
+        assert!(ecdsa_verify(&msg, &key, &signature));
+
+        // The same key uncompressed
+        let point = parse_public_key(&key).unwrap();
+        let mut uncompressed = vec![0x04];
+        uncompressed.extend(point.x.to_bytes().iter());
+        uncompressed.extend(point.y.to_bytes().iter());
+        assert!(ecdsa_verify(&msg, &uncompressed, &signature));
+
+        let other_msg = CryptoUtils::sha256(b"bitcoin-rust!");
+        assert!(!ecdsa_verify(&other_msg, &key, &signature));
+
+        let other_key = public_key(&get_secret(
+            "0000000000000000000000000000000000000000000000000000000000000001")).unwrap();
+        assert!(!ecdsa_verify(&msg, &other_key, &signature));
+
+        let mut tampered = signature.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 0x01;
+        assert!(!ecdsa_verify(&msg, &key, &tampered));
+
+        // Trailing data and garbage keys
+        let mut trailing = signature.clone();
+        trailing.push(0x00);
+        assert!(!ecdsa_verify(&msg, &key, &trailing));
+        assert!(!ecdsa_verify(&msg, &key[..32], &signature));
+    }
+}
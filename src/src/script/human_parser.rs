@@ -21,6 +21,12 @@ impl Parser {
             return Self::parse_string_literal(&result);
         }
 
+        let bracket = Regex::new(r"^<(?P<n>[+-]?[0-9]+)>$").unwrap();
+        if bracket.is_match(token) {
+            let result = bracket.replace_all(token, "$n");
+            return Self::get_op_codes(&result);
+        }
+
         let number = Regex::new(r"^[+-]?[0-9]+$").unwrap();
         if number.is_match(token) {
             return Self::parse_number(token);
@@ -31,6 +37,15 @@ impl Parser {
 
     fn parse_number(token: &str) -> Result<Vec<u8>, String> {
         let result = token.parse::<i64>().unwrap();
+
+        // Small integers have dedicated opcodes, this matches Core's encoding
+        match result {
+            -1 => return Ok(vec![OpCode::_1Negate.to_byte()]),
+            0 => return Ok(vec![OpCode::_0.to_byte()]),
+            1 ... 16 => return Ok(vec![OpCode::_1.to_byte() + (result - 1) as u8]),
+            _ => {},
+        }
+
         let mut result_array = IntUtils::to_vec_u8(result);
         let len = result_array.len();
         result_array.insert(0, len as u8);
@@ -59,7 +74,10 @@ impl Parser {
     }
 
     fn get_op_codes(token: &str) -> Result<Vec<u8>, String> {
-        match OpCode::from_str(&token.to_string()) {
+        // Bitcoin Core and most block explorers print opcodes as `OP_DUP`
+        let name = if token.starts_with("OP_") { &token[3..] } else { token };
+
+        match OpCode::from_str(name) {
             Some(x) => Ok(vec![x.to_byte()]),
             None => Self::parse_non_op_code(token),
         }
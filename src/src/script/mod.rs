@@ -1,21 +1,62 @@
 mod op_codes;
 mod human_parser;
+mod schnorr;
+mod secp256k1;
+mod stack;
+mod cache;
+mod ecdsa;
+mod address;
+mod signature;
 
 use self::op_codes::OpCode;
 
-pub struct Context {
+use net::messages::{BitcoinHash, TxOut};
+use utils::CryptoUtils;
+
+pub use self::schnorr::schnorr_checksig;
+pub use self::ecdsa::ecdsa_verify;
+#[cfg(test)]
+pub(crate) use self::ecdsa::{ecdsa_sign, public_key};
+pub use self::stack::{StackItem, ScriptError, ScriptErrorKind};
+pub use self::cache::{ParsedScript, ScriptCache};
+pub use self::address::{address, base58check, segwit_address};
+pub use self::signature::{is_valid_signature_encoding, is_low_s, is_minimal_push};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct ScriptFlags {
+    // SCRIPT_VERIFY_DISCOURAGE_UPGRADABLE_NOPS: NOP1, NOP3-NOP10 invalidate the script
+    pub discourage_upgradable_nops: bool,
+    // SCRIPT_VERIFY_STRICTENC: CHECKSIG and CHECKMULTISIG fail on badly encoded pub keys
+    pub strictenc: bool,
+    // Re-enables some of the disabled arithmetic opcodes (2MUL, 2DIV, LSHIFT, RSHIFT)
+    pub allow_arithmetic_ext: bool,
+    // BIP342: enables CHECKSIGADD
+    pub tapscript: bool,
+    // BIP16: pay to script hash outputs also run the redeem script
+    pub p2sh: bool,
+    // BIP66: CHECKSIG and CHECKMULTISIG fail on signatures that aren't strict DER
+    pub dersig: bool,
+}
+
+#[derive(Clone)]
+pub struct Context<'a> {
     script: BitcoinScript,
-    stack: Vec<Vec<u8>>,
+    stack: Vec<StackItem>,
     valid: bool,
-    altstack: Vec<Vec<u8>>,
+    altstack: Vec<StackItem>,
+    // Where the script code starts, right after the last executed
+    // OP_CODESEPARATOR
     codeseparator: usize,
-    // fn(codeseparator: usize, pub_key_str: Vec<u8>, sig_str: Vec<u8) -> bool
-    checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool,
+    // fn(script_code: &[u8], pub_key_str: Vec<u8>, sig_str: Vec<u8) -> bool
+    checksig: &'a Fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool,
     // Whether or not the last OP_IF, OP_ELSE or OP_NOTIF has been executed
     conditional_executed: Vec<bool>,
+    flags: ScriptFlags,
+    // Why the script became invalid, if we know
+    error: Option<ScriptError>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct BitcoinScript {
     script: Vec<u8>,
     pointer: usize,
@@ -71,6 +112,15 @@ impl BitcoinScript {
 
     pub fn eof(&self) -> bool { self.eof }
 
+    // Iterates over the opcodes without executing them
+    pub fn iter(&self) -> ScriptIter {
+        ScriptIter {
+            script: &self.script,
+            pointer: 0,
+            error: false,
+        }
+    }
+
     pub fn current(&mut self) -> Option<OpCode> {
         self.set_eof();
 
@@ -82,9 +132,64 @@ impl BitcoinScript {
     }
 }
 
-impl Context {
-    pub fn new(script: Vec<u8>, stack: Vec<Vec<u8>>,
-               checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool) -> Context {
+// Yields (index, opcode, pushed data), iteration stops at the first unknown
+// opcode or truncated push, see ScriptIter::error.
+pub struct ScriptIter<'a> {
+    script: &'a [u8],
+    pointer: usize,
+    error: bool,
+}
+
+impl<'a> ScriptIter<'a> {
+    pub fn error(&self) -> bool { self.error }
+}
+
+impl<'a> Iterator for ScriptIter<'a> {
+    type Item = (usize, OpCode, Option<Vec<u8>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error || self.pointer >= self.script.len() {
+            return None;
+        }
+
+        let index = self.pointer;
+        let op_code = match OpCode::from_byte(self.script[index]) {
+            Some(op) => op,
+            None => {
+                self.error = true;
+                return None;
+            }
+        };
+
+        let length = match op_codes::read_push_length(self.script, index) {
+            Ok(length) => length,
+            Err(_) => {
+                self.error = true;
+                return None;
+            }
+        };
+
+        let start = index + 1 + op_codes::push_length_size(self.script[index]);
+        if start + length > self.script.len() {
+            self.error = true;
+            return None;
+        }
+
+        self.pointer = start + length;
+
+        let data = if self.script[index] <= 0x4e {
+            Some(self.script[start..start + length].to_vec())
+        } else {
+            None
+        };
+
+        Some((index, op_code, data))
+    }
+}
+
+impl<'a> Context<'a> {
+    pub fn new(script: Vec<u8>, stack: Vec<StackItem>,
+               checksig: &'a Fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool) -> Context<'a> {
         Context {
             script: BitcoinScript::new(script),
             stack: stack,
@@ -93,12 +198,44 @@ impl Context {
             codeseparator: 0,
             checksig: checksig,
             conditional_executed: vec![],
+            flags: ScriptFlags::default(),
+            error: None,
         }
     }
 
     pub fn valid(&self) -> bool {
         self.valid && self.script.valid()
     }
+
+    // Unlike valid() this stays false once the script ran to completion
+    pub fn failed(&self) -> bool {
+        !self.valid
+    }
+
+    pub fn error(&self) -> Option<&ScriptError> {
+        self.error.as_ref()
+    }
+
+    pub fn stack(&self) -> &[StackItem] {
+        &self.stack
+    }
+
+    pub fn altstack(&self) -> &[StackItem] {
+        &self.altstack
+    }
+
+    // Copy of the current state, useful to explore branches without
+    // affecting the execution.
+    pub fn snapshot(&self) -> Context<'a> {
+        self.clone()
+    }
+
+    // The part of the script covered by legacy signatures: everything after
+    // the last executed OP_CODESEPARATOR, without pushes of `sigs`.
+    pub fn script_code(&self, sigs: &[&[u8]]) -> Vec<u8> {
+        sigs.iter().fold(self.script.script[self.codeseparator..].to_vec(),
+                         |script, sig| find_and_delete(&script, &push_data(sig)))
+    }
 }
 
 pub struct Parser;
@@ -108,34 +245,84 @@ impl Parser {
         human_parser::Parser::parse(script)
     }
 
-    fn no_checksig_allowed(_: usize, _: &Vec<u8>, _: &Vec<u8>) -> bool { false }
+    fn no_checksig_allowed(_: &[u8], _: &Vec<u8>, _: &Vec<u8>) -> bool { false }
 
     pub fn execute(sig_script: Vec<u8>, script_pub_key: Vec<u8>,
-                   checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool)
+                   checksig: &Fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool)
+    -> Result<bool, String> {
+        Self::execute_with_flags(sig_script, script_pub_key, checksig, ScriptFlags::default())
+    }
+
+    pub fn execute_with_flags(sig_script: Vec<u8>, script_pub_key: Vec<u8>,
+                              checksig: &Fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool,
+                              flags: ScriptFlags)
     -> Result<bool, String> {
+        let is_p2sh = flags.p2sh && classify(&script_pub_key) == ScriptType::ScriptHash;
+        // BIP16 sigScripts can only push data
+        if is_p2sh && !is_push_only(&sig_script) {
+            return Ok(false);
+        }
+
         // OP_CHECKSIG is not allowed when executing sigScript
         // TODO: ideally we should just invalidate the context
         let sig_script_context = try!(Self::execute_base(vec![],
                                                         sig_script,
-                                                        Parser::no_checksig_allowed));
+                                                        &Parser::no_checksig_allowed,
+                                                        flags));
 
         if !sig_script_context.valid {
             return Ok(false);
         }
 
+        // The redeem script runs on the stack left by sigScript
+        let mut redeem_stack = if is_p2sh { sig_script_context.stack.clone() } else { vec![] };
+
         let script_pub_key_context = try!(Self::execute_base(sig_script_context.stack,
-                                                            script_pub_key, checksig));
+                                                            script_pub_key, checksig, flags));
+
+        if !script_pub_key_context.valid ||
+           !op_codes::is_true(&script_pub_key_context.stack.last()) {
+            return Ok(false);
+        }
 
-        Ok(script_pub_key_context.valid &&
-           op_codes::is_true(&script_pub_key_context.stack.last()))
+        if !is_p2sh {
+            return Ok(true);
+        }
+
+        // Can't be empty, the hash of the redeem script matched
+        let redeem_script = redeem_stack.pop().unwrap();
+        let redeem_context = try!(Self::execute_base(redeem_stack, redeem_script.to_vec(),
+                                                     checksig, flags));
+
+        Ok(redeem_context.valid && op_codes::is_true(&redeem_context.stack.last()))
     }
 
-    fn execute_base(input_stack: Vec<Vec<u8>>,
-                    script: Vec<u8>,
-                    checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool)
-    -> Result<Context, String> {
-        let mut context = Context::new(script.clone(), input_stack, checksig);
+    // Runs `script` starting from the given stacks and returns the final
+    // context, valid or not, so that the resulting state can be inspected.
+    pub fn execute_with_state<'a>(stack: Vec<Vec<u8>>, altstack: Vec<Vec<u8>>, script: Vec<u8>,
+                                  checksig: &'a Fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool,
+                                  flags: ScriptFlags)
+    -> Result<Context<'a>, String> {
+        let mut context = Context::new(script, stack.into_iter().map(StackItem::from).collect(),
+                                       checksig);
+        context.altstack = altstack.into_iter().map(StackItem::from).collect();
+        context.flags = flags;
+
+        Self::run(context)
+    }
+
+    fn execute_base<'a>(input_stack: Vec<StackItem>,
+                        script: Vec<u8>,
+                        checksig: &'a Fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool,
+                        flags: ScriptFlags)
+    -> Result<Context<'a>, String> {
+        let mut context = Context::new(script, input_stack, checksig);
+        context.flags = flags;
+
+        Self::run(context)
+    }
 
+    fn run(mut context: Context) -> Result<Context, String> {
         if context.script.script.len() == 0 {
             return Ok(context);
         }
@@ -149,32 +336,297 @@ impl Parser {
                 }
             };
 
+            let pc = context.script.index();
+            if context.stack.len() < op_code.min_stack() {
+                context.valid = false;
+                context.error = Some(ScriptError::new(ScriptErrorKind::StackUnderflow)
+                                     .at(op_code, pc));
+                return Ok(context);
+            }
+
             context = op_code.execute(context);
 
+            // Errors raised by the opcode don't know where they happened
+            if let Some(error) = context.error {
+                if error.position.is_none() {
+                    context.error = Some(error.at(op_code, pc));
+                }
+            }
+
             if !op_code.is_advancing() {
                 context.script.next();
             }
         }
 
+        // Every IF needs a matching ENDIF
+        if !context.conditional_executed.is_empty() {
+            context.valid = false;
+        }
+
         Ok(context)
     }
 }
 
+// Returns true if the script only contains push operations, OP_0 to OP_16
+// included. Truncated pushes make the script not push only.
+pub fn is_push_only(script: &[u8]) -> bool {
+    let script = BitcoinScript::new(script.to_vec());
+    let mut iter = script.iter();
+
+    let push_only = iter.by_ref()
+        .all(|(_, op_code, _)| op_code.to_byte() <= OpCode::_16.to_byte());
+
+    push_only && !iter.error()
+}
+
+// Script pushing `data` using the smallest PUSHDATA able to hold it, this is
+// how signatures are matched by find_and_delete.
+pub fn push_data(data: &[u8]) -> Vec<u8> {
+    let mut script = if data.len() < 0x4c {
+        vec![data.len() as u8]
+    } else if data.len() <= 0xff {
+        vec![0x4c, data.len() as u8]
+    } else if data.len() <= 0xffff {
+        vec![0x4d, data.len() as u8, (data.len() >> 8) as u8]
+    } else {
+        let length = data.len() as u32;
+        vec![0x4e, length as u8, (length >> 8) as u8, (length >> 16) as u8, (length >> 24) as u8]
+    };
+
+    script.extend(data);
+    script
+}
+
+// DUP HASH160 <pubkey hash> EQUALVERIFY CHECKSIG
+pub fn build_p2pkh_script_pubkey(pubkey_hash: &[u8; 20]) -> Vec<u8> {
+    let mut script = vec![OpCode::Dup.to_byte(), OpCode::Hash160.to_byte()];
+    script.extend(push_data(pubkey_hash));
+    script.push(OpCode::EqualVerify.to_byte());
+    script.push(OpCode::CheckSig.to_byte());
+    script
+}
+
+// <signature> <pubkey>, `signature` includes the hash type byte
+pub fn build_p2pkh_script_sig(signature: &[u8], pubkey: &[u8]) -> Vec<u8> {
+    let mut script = push_data(signature);
+    script.extend(push_data(pubkey));
+    script
+}
+
+// Removes every operation of `script` that is exactly `pattern`, like
+// FindAndDelete in the official client. Opcodes we don't know are a single
+// byte like any other, bytes after a truncated push are kept untouched.
+pub fn find_and_delete(script: &[u8], pattern: &[u8]) -> Vec<u8> {
+    if pattern.is_empty() {
+        return script.to_vec();
+    }
+
+    let mut result = vec![];
+    let mut index = 0;
+    while index < script.len() {
+        let end = match op_codes::read_push_length(script, index) {
+            Ok(length) => index + 1 + op_codes::push_length_size(script[index]) + length,
+            Err(_) => script.len() + 1,
+        };
+
+        if end > script.len() {
+            result.extend(&script[index..]);
+            break;
+        }
+
+        if &script[index..end] != pattern {
+            result.extend(&script[index..end]);
+        }
+
+        index = end;
+    }
+
+    result
+}
+
+// Worst case used for CHECKMULTISIG when we don't know the number of keys
+pub const MAX_PUBKEYS_PER_MULTISIG: usize = 20;
+
+// Signature operations in `script`. In accurate mode CHECKMULTISIG counts
+// as the number of keys pushed right before it, if that's OP_1 to OP_16.
+pub fn count_sigops(script: &[u8], accurate: bool) -> usize {
+    let script = BitcoinScript::new(script.to_vec());
+    let mut count = 0;
+    let mut last_op = None;
+
+    for (_, op_code, _) in script.iter() {
+        match op_code {
+            OpCode::CheckSig | OpCode::CheckSigVerify => count += 1,
+            OpCode::CheckMultiSig | OpCode::CheckMultiSigVerify => {
+                count += match last_op.and_then(|op: OpCode| small_int(op.to_byte())) {
+                    Some(keys) if accurate && keys > 0 => keys,
+                    _ => MAX_PUBKEYS_PER_MULTISIG,
+                };
+            },
+            _ => {},
+        }
+
+        last_op = Some(op_code);
+    }
+
+    count
+}
+
+// Standard output script templates, see Solver in the official client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptType {
+    PubKey,
+    PubKeyHash,
+    ScriptHash,
+    MultiSig { required: usize, keys: usize },
+    // OP_RETURN followed by pushes, unspendable
+    NullData,
+    WitnessKeyHash,
+    WitnessScriptHash,
+    // Witness programs of versions we don't know about, the value is the version
+    WitnessUnknown(u8),
+    NonStandard,
+}
+
+// Value of OP_0 and OP_1 to OP_16
+fn small_int(op: u8) -> Option<usize> {
+    match op {
+        0x00 => Some(0),
+        0x51 ... 0x60 => Some((op - 0x50) as usize),
+        _ => None,
+    }
+}
+
+fn is_pubkey_push(data: &Option<Vec<u8>>) -> bool {
+    match data {
+        &Some(ref key) => key.len() == 33 || key.len() == 65,
+        &None => false,
+    }
+}
+
+pub fn classify(script: &[u8]) -> ScriptType {
+    let len = script.len();
+
+    if len == 23 && script[0] == 0xa9 && script[1] == 0x14 && script[22] == 0x87 {
+        return ScriptType::ScriptHash;
+    }
+
+    // Witness program: a version opcode and a single 2 to 40 bytes push
+    if len >= 4 && len <= 42 && script[1] as usize == len - 2 {
+        match (small_int(script[0]), len - 2) {
+            (Some(0), 20) => return ScriptType::WitnessKeyHash,
+            (Some(0), 32) => return ScriptType::WitnessScriptHash,
+            (Some(0), _) => {},
+            (Some(version), _) => return ScriptType::WitnessUnknown(version as u8),
+            (None, _) => {},
+        }
+    }
+
+    if len > 0 && script[0] == 0x6a && is_push_only(&script[1..]) {
+        return ScriptType::NullData;
+    }
+
+    if (len == 35 && script[0] == 0x21 || len == 67 && script[0] == 0x41) &&
+        script[len - 1] == 0xac {
+        return ScriptType::PubKey;
+    }
+
+    if len == 25 && script[0..3] == [0x76, 0xa9, 0x14] && script[23..25] == [0x88, 0xac] {
+        return ScriptType::PubKeyHash;
+    }
+
+    // OP_m <pub keys> OP_n CHECKMULTISIG
+    let ops: Vec<(usize, OpCode, Option<Vec<u8>>)> = BitcoinScript::new(script.to_vec())
+        .iter().collect();
+    if ops.len() >= 4 && ops.last().map(|op| op.0) == Some(len - 1) &&
+        script[len - 1] == 0xae {
+        let required = small_int(script[ops[0].0]);
+        let keys = small_int(script[ops[ops.len() - 2].0]);
+        let pub_keys = &ops[1..ops.len() - 2];
+
+        if let (Some(required), Some(keys)) = (required, keys) {
+            if required >= 1 && required <= keys && keys == pub_keys.len() &&
+                pub_keys.iter().all(|op| is_pubkey_push(&op.2)) {
+                return ScriptType::MultiSig { required: required, keys: keys };
+            }
+        }
+    }
+
+    ScriptType::NonStandard
+}
+
+// The hashes or pub keys a standard script pays to: the hash of P2PKH,
+// P2SH, P2WPKH and P2WSH or the keys of P2PK and bare multisig, in script
+// order. Wallets can match these against what they own.
+pub fn extract_destinations(script: &[u8]) -> Vec<Vec<u8>> {
+    match classify(script) {
+        ScriptType::PubKey => vec![script[1..script.len() - 1].to_vec()],
+        ScriptType::PubKeyHash => vec![script[3..23].to_vec()],
+        ScriptType::ScriptHash => vec![script[2..22].to_vec()],
+        ScriptType::WitnessKeyHash | ScriptType::WitnessScriptHash => vec![script[2..].to_vec()],
+        ScriptType::MultiSig { .. } => BitcoinScript::new(script.to_vec()).iter()
+            .filter_map(|(_, _, data)| data)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// BIP141 witness commitment: OP_RETURN, a 36 byte push, this header and
+// the 32 byte commitment.
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// Value committed to in the coinbase, see parse_witness_commitment
+pub fn witness_commitment(witness_root: &BitcoinHash, reserved_value: &[u8; 32]) -> [u8; 32] {
+    let mut data = vec![];
+    data.extend(witness_root.iter().cloned());
+    data.extend(reserved_value.iter().cloned());
+
+    CryptoUtils::sha256(&CryptoUtils::sha256(&data))
+}
+
+// Returns the witness commitment of a coinbase, if there is more than one
+// the last one wins.
+pub fn parse_witness_commitment(outputs: &[TxOut]) -> Option<[u8; 32]> {
+    outputs.iter().rev()
+        .map(|output| &output.pk_script)
+        .find(|script| script.len() >= 38 && script[0..6] == WITNESS_COMMITMENT_HEADER)
+        .map(|script| {
+            let mut commitment = [0; 32];
+            commitment.copy_from_slice(&script[6..38]);
+            commitment
+        })
+}
+
+// Checks the coinbase commitment against the witness merkle root of the block
+// and the witness reserved value found in the coinbase input.
+pub fn check_witness_commitment(outputs: &[TxOut], witness_root: &BitcoinHash,
+                                reserved_value: &[u8; 32]) -> bool {
+    parse_witness_commitment(outputs) == Some(witness_commitment(witness_root, reserved_value))
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use net::messages::{BitcoinHash, OutPoint, SerializeHash, TxIn, TxMessage, TxOut};
+    use test::utils::mock_checksig;
+    use transaction::Amount;
+    use utils::CryptoUtils;
+
+    use std::panic;
 
     mod official_test;
 
-    fn mock_checksig(_: usize, _: &Vec<u8>, _: &Vec<u8>) -> bool { true }
+    fn equal_checksig(_: &[u8], x: &Vec<u8>, y: &Vec<u8>) -> bool { x.eq(y) }
 
-    fn equal_checksig(_: usize, x: &Vec<u8>, y: &Vec<u8>) -> bool { x.eq(y) }
+    // The tests below push the expected script code in place of the key
+    fn script_code_checksig(script_code: &[u8], pub_key: &Vec<u8>, _: &Vec<u8>) -> bool {
+        script_code == &pub_key[..]
+    }
 
     fn test_base(script_sig: &str,
                  script_pub_key: &str,
                  expected: bool,
-                 checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool) -> Result<bool, String> {
+                 checksig: fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool) -> Result<bool, String> {
         print!("\n\n sig=`{}` pub_key=`{}` [expected={}]\n",
                script_sig, script_pub_key, expected);
 
@@ -184,14 +636,14 @@ mod tests {
         print!("\n\n sig=`{:?}` pub_key=`{:?}` [expected={}]\n",
                raw_script_sig, raw_script_pub_key, expected);
 
-        let result = Parser::execute(raw_script_sig, raw_script_pub_key, checksig).unwrap();
+        let result = Parser::execute(raw_script_sig, raw_script_pub_key, &checksig).unwrap();
         Ok(result == expected)
     }
 
     fn test_with_checksig(script_sig: &str,
                           script_pub_key: &str,
                           expected: bool,
-                          checksig: fn(usize, &Vec<u8>, &Vec<u8>) -> bool) {
+                          checksig: fn(&[u8], &Vec<u8>, &Vec<u8>) -> bool) {
         assert!(test_base(script_sig, script_pub_key, expected, checksig).unwrap());
     }
 
@@ -203,14 +655,482 @@ mod tests {
         test_with_checksig("", script, expected, mock_checksig);
     }
 
+    // Runs a vector like script_tests.cpp does: scriptPubKey is the output of
+    // a crediting transaction and scriptSig spends it, so that signatures can
+    // be checked against the real sighash.
+    fn execute_official(script_sig: Vec<u8>, script_pub_key: Vec<u8>, flags: ScriptFlags)
+                        -> Result<bool, String> {
+        let credit = TxMessage {
+            version: 1,
+            tx_in: vec![TxIn {
+                previous_output: OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff),
+                script: vec![0x00, 0x00],
+                sequence: 0xffffffff,
+            }],
+            tx_out: vec![TxOut::new(Amount::default(), script_pub_key.clone())],
+            lock_time: 0,
+        };
+        let spend = TxMessage {
+            version: 1,
+            tx_in: vec![TxIn {
+                previous_output: OutPoint::new(credit.hash(), 0),
+                script: script_sig.clone(),
+                sequence: 0xffffffff,
+            }],
+            tx_out: vec![TxOut::new(Amount::default(), vec![])],
+            lock_time: 0,
+        };
+
+        let checksig = |script_code: &[u8], pub_key: &Vec<u8>, sig: &Vec<u8>| {
+            spend.check_signature(0, script_code, pub_key, sig)
+        };
+        Parser::execute_with_flags(script_sig, script_pub_key, &checksig, flags)
+    }
+
+    fn parse_official(sig: &str, pub_key: &str, flags: &str)
+                      -> Result<(Vec<u8>, Vec<u8>, ScriptFlags), String> {
+        Ok((try!(Parser::preprocess_human_readable(sig)),
+            try!(Parser::preprocess_human_readable(pub_key)),
+            try!(official_test::Tester::parse_flags(flags))))
+    }
+
     #[test]
     fn test_official_client_compat() {
-        let result = official_test::Tester::test(|sig, pub_key, _| {
-            let result = test_base(sig, pub_key, true, mock_checksig);
-            result.is_ok() && result.unwrap()
+        let result = official_test::Tester::test(|sig, pub_key, flags| {
+            let (raw_sig, raw_pub_key, flags) = match parse_official(sig, pub_key, flags) {
+                Ok(parsed) => parsed,
+                Err(_) => return false,
+            };
+            execute_official(raw_sig, raw_pub_key, flags) == Ok(true)
         });
 
-        assert_eq!(result, 576);
+        assert_eq!(result, 138);
+    }
+
+    #[test]
+    fn test_official_client_invalid() {
+        let (rejected, total) = official_test::Tester::test_invalid(|sig, pub_key, flags| {
+            let (raw_sig, raw_pub_key, flags) = match parse_official(sig, pub_key, flags) {
+                Ok(parsed) => parsed,
+                Err(_) => return false,
+            };
+
+            // A panic in the interpreter is a bug, not a rejection
+            let result = panic::catch_unwind(|| execute_official(raw_sig, raw_pub_key, flags));
+
+            match result {
+                Ok(Ok(valid)) => !valid,
+                Ok(Err(_)) => true,
+                Err(_) => false,
+            }
+        });
+
+        assert_eq!(total, 44);
+        assert_eq!(rejected, total);
+    }
+
+    #[test]
+    fn test_context_snapshot() {
+        let mut context = Context::new(vec![0x51, 0x52], vec![StackItem::new(vec![0x01])],
+                                       &mock_checksig);
+        context.altstack.push(StackItem::new(vec![0x02]));
+
+        let snapshot = context.snapshot();
+        assert_eq!(snapshot, context);
+
+        context.stack.push(StackItem::new(vec![0x03]));
+        context.altstack.pop();
+        context.script.next();
+
+        assert_eq!(snapshot.stack, vec![vec![0x01]]);
+        assert_eq!(snapshot.altstack, vec![vec![0x02]]);
+        assert_eq!(snapshot.script.index(), 0);
+    }
+
+    #[test]
+    fn test_script_iter() {
+        let raw_script = Parser::preprocess_human_readable("1 0x02 0x0100 EQUAL").unwrap();
+        let script = BitcoinScript::new(raw_script);
+
+        let mut iter = script.iter();
+        assert_eq!(iter.next(), Some((0, OpCode::_1, None)));
+        assert_eq!(iter.next(), Some((1, OpCode::Push2Bytes, Some(vec![0x01, 0x00]))));
+        assert_eq!(iter.next(), Some((4, OpCode::Equal, None)));
+        assert_eq!(iter.next(), None);
+        assert!(!iter.error());
+
+        let script = BitcoinScript::new(vec![0x00, 0x4d, 0x02, 0x00, 0xaa, 0xbb, 0x4c]);
+        let ops: Vec<_> = script.iter().collect();
+        assert_eq!(ops, vec![(0, OpCode::_0, Some(vec![])),
+                             (1, OpCode::PushData2, Some(vec![0xaa, 0xbb]))]);
+
+        // Truncated PUSHDATA1 at the end
+        let mut iter = script.iter();
+        assert_eq!(iter.by_ref().count(), 2);
+        assert!(iter.error());
+    }
+
+    #[test]
+    fn test_nested_conditionals() {
+        test_parse_execute("1 IF 1 IF 2 ELSE 3 ENDIF ELSE 4 ENDIF 2 EQUAL", true);
+        test_parse_execute("1 IF 0 IF 2 ELSE 3 ENDIF ELSE 4 ENDIF 3 EQUAL", true);
+        test_parse_execute("0 IF 1 IF 2 ELSE 3 ENDIF ELSE 4 ENDIF 4 EQUAL", true);
+        test_parse_execute("0 IF 0 IF 2 ELSE 3 ENDIF ELSE 0 NOTIF 4 ELSE 5 ENDIF ENDIF 4 EQUAL", true);
+
+        // Each ELSE flips the branch, as in the official client
+        test_parse_execute("1 IF 0 IF 1 ELSE 2 ELSE 3 ENDIF ENDIF 2 EQUAL", true);
+        test_parse_execute("0 IF 1 ELSE 2 ELSE 3 ELSE 4 ENDIF 4 EQUAL", true);
+        test_parse_execute("1 IF 1 ELSE 2 ELSE 3 ENDIF DEPTH 2 EQUAL", true);
+
+        // ELSE and ENDIF without IF
+        test_parse_execute("1 ELSE 1 ENDIF", false);
+        test_parse_execute("1 ENDIF", false);
+        test_parse_execute("1 IF 1 ENDIF ENDIF", false);
+    }
+
+    #[test]
+    fn test_unbalanced_conditionals() {
+        test_parse_execute("1 IF 1", false);
+        test_parse_execute("0 IF 1", false);
+        test_parse_execute("1 IF 1 ELSE 0", false);
+        test_parse_execute("1 IF 1 IF 1 ENDIF", false);
+
+        // The signature script must be balanced on its own
+        test_execute("1 IF", "1 ENDIF", false);
+    }
+
+    #[test]
+    fn test_skip_pushdata_in_branch() {
+        // The pushed data contains an ENDIF that must not be executed
+        test_parse_execute("0 IF 0x4d 0x0300 0x006800 ENDIF DEPTH 0 EQUAL", true);
+        test_parse_execute("0 IF 0x4c 0x02 0x6768 ELSE 1 ENDIF", true);
+        test_parse_execute("1 IF 1 ELSE 0x4e 0x01000000 0x68 ENDIF", true);
+    }
+
+    fn get_commitment_output(commitment: &[u8]) -> TxOut {
+        let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+        script.extend(commitment.iter().cloned());
+        TxOut::new(Amount::default(), script)
+    }
+
+    #[test]
+    fn test_witness_commitment() {
+        let payout = TxOut::new(Amount::from_satoshis(1250000000),
+                                Parser::preprocess_human_readable("DUP HASH160").unwrap());
+        assert_eq!(parse_witness_commitment(&[payout.clone()]), None);
+
+        let root = BitcoinHash::new([0x11; 32]);
+        let reserved = [0; 32];
+
+        let mut data = vec![0x11; 32];
+        data.extend(reserved.iter().cloned());
+        let commitment = CryptoUtils::sha256(&CryptoUtils::sha256(&data));
+
+        let outputs = vec![payout.clone(), get_commitment_output(&commitment)];
+        assert_eq!(parse_witness_commitment(&outputs), Some(commitment));
+        assert!(check_witness_commitment(&outputs, &root, &reserved));
+        assert!(!check_witness_commitment(&outputs, &BitcoinHash::new([0x12; 32]), &reserved));
+        assert!(!check_witness_commitment(&outputs, &root, &[1; 32]));
+
+        // The last commitment wins, extra data after it is allowed
+        let mut last = get_commitment_output(&[0x22; 32]);
+        last.pk_script.push(0x01);
+        let outputs = vec![get_commitment_output(&commitment), payout.clone(), last];
+        assert_eq!(parse_witness_commitment(&outputs), Some([0x22; 32]));
+
+        // Too short to hold a commitment
+        assert_eq!(parse_witness_commitment(&[get_commitment_output(&[0x22; 31])]), None);
+    }
+
+    #[test]
+    fn test_is_push_only() {
+        let push_only = Parser::preprocess_human_readable(
+            "0 1 16 -1 'abc' 0x4c 0x02 0x0102 0x4d 0x0100 0x01").unwrap();
+        assert!(is_push_only(&push_only));
+        assert!(is_push_only(&[]));
+
+        let dup = Parser::preprocess_human_readable("'abc' DUP").unwrap();
+        assert!(!is_push_only(&dup));
+
+        // Truncated pushes
+        assert!(!is_push_only(&[0x02, 0x01]));
+        assert!(!is_push_only(&[0x4d, 0x01]));
+    }
+
+    fn classify_str(script: &str) -> ScriptType {
+        classify(&Parser::preprocess_human_readable(script).unwrap())
+    }
+
+    #[test]
+    fn test_classify() {
+        let key = format!("0x21 0x02{}", "11".repeat(32));
+        let hash = format!("0x14 0x{}", "22".repeat(20));
+        let hash32 = format!("0x20 0x{}", "33".repeat(32));
+
+        assert_eq!(classify_str(&format!("{} CHECKSIG", key)), ScriptType::PubKey);
+        assert_eq!(classify_str(&format!("DUP HASH160 {} EQUALVERIFY CHECKSIG", hash)),
+                   ScriptType::PubKeyHash);
+        assert_eq!(classify_str(&format!("HASH160 {} EQUAL", hash)), ScriptType::ScriptHash);
+        assert_eq!(classify_str(&format!("0 {}", hash)), ScriptType::WitnessKeyHash);
+        assert_eq!(classify_str(&format!("0 {}", hash32)), ScriptType::WitnessScriptHash);
+        assert_eq!(classify_str(&format!("1 {}", hash32)), ScriptType::WitnessUnknown(1));
+        assert_eq!(classify_str("RETURN 0x04 0xaabbccdd"), ScriptType::NullData);
+        assert_eq!(classify_str("RETURN"), ScriptType::NullData);
+        assert_eq!(classify_str(&format!("2 {} {} {} 3 CHECKMULTISIG", key, key, key)),
+                   ScriptType::MultiSig { required: 2, keys: 3 });
+
+        assert_eq!(classify_str(&format!("3 {} {} 2 CHECKMULTISIG", key, key)),
+                   ScriptType::NonStandard);
+        assert_eq!(classify_str(&format!("DUP HASH160 {} EQUAL CHECKSIG", hash)),
+                   ScriptType::NonStandard);
+        assert_eq!(classify_str("RETURN DUP"), ScriptType::NonStandard);
+        assert_eq!(classify(&[]), ScriptType::NonStandard);
+    }
+
+    fn destinations_str(script: &str) -> Vec<Vec<u8>> {
+        extract_destinations(&Parser::preprocess_human_readable(script).unwrap())
+    }
+
+    #[test]
+    fn test_extract_destinations() {
+        let mut key = vec![0x02];
+        key.extend(vec![0x11; 32]);
+        let mut uncompressed_key = vec![0x04];
+        uncompressed_key.extend(vec![0x44; 64]);
+        let push_key = format!("0x21 0x02{}", "11".repeat(32));
+        let push_uncompressed = format!("0x41 0x04{}", "44".repeat(64));
+        let hash = format!("0x14 0x{}", "22".repeat(20));
+        let hash32 = format!("0x20 0x{}", "33".repeat(32));
+
+        assert_eq!(destinations_str(&format!("{} CHECKSIG", push_key)), vec![key.clone()]);
+        assert_eq!(destinations_str(&format!("{} CHECKSIG", push_uncompressed)),
+                   vec![uncompressed_key.clone()]);
+        assert_eq!(destinations_str(&format!("DUP HASH160 {} EQUALVERIFY CHECKSIG", hash)),
+                   vec![vec![0x22; 20]]);
+        assert_eq!(destinations_str(&format!("HASH160 {} EQUAL", hash)), vec![vec![0x22; 20]]);
+        assert_eq!(destinations_str(&format!("0 {}", hash)), vec![vec![0x22; 20]]);
+        assert_eq!(destinations_str(&format!("0 {}", hash32)), vec![vec![0x33; 32]]);
+        assert_eq!(destinations_str(&format!("1 {} {} 2 CHECKMULTISIG",
+                                             push_key, push_uncompressed)),
+                   vec![key, uncompressed_key]);
+
+        // Nothing to pay to
+        assert!(destinations_str("RETURN 0x04 0xaabbccdd").is_empty());
+        assert!(destinations_str(&format!("1 {}", hash32)).is_empty());
+        assert!(destinations_str("2 3 ADD 5 EQUAL").is_empty());
+    }
+
+    fn count_sigops_str(script: &str, accurate: bool) -> usize {
+        count_sigops(&Parser::preprocess_human_readable(script).unwrap(), accurate)
+    }
+
+    #[test]
+    fn test_count_sigops() {
+        let key = format!("0x21 0x02{}", "11".repeat(32));
+
+        assert_eq!(count_sigops_str(&format!("{} CHECKSIG", key), false), 1);
+        assert_eq!(count_sigops_str("CHECKSIGVERIFY CHECKSIG", true), 2);
+        assert_eq!(count_sigops_str("CHECKMULTISIG", false), 20);
+        assert_eq!(count_sigops_str("CHECKMULTISIG", true), 20);
+
+        let multisig = format!("2 {} {} {} 3 CHECKMULTISIG", key, key, key);
+        assert_eq!(count_sigops_str(&multisig, true), 3);
+        assert_eq!(count_sigops_str(&multisig, false), 20);
+        assert_eq!(count_sigops_str("0 CHECKMULTISIGVERIFY", true), 20);
+
+        // Pushed data doesn't count, even if it looks like CHECKSIG
+        assert_eq!(count_sigops_str("0x01 0xac", false), 0);
+    }
+
+    #[test]
+    fn test_p2sh() {
+        // The redeem script is 2 3 ADD 5 EQUAL
+        let redeem_script = vec![0x52, 0x53, 0x93, 0x55, 0x87];
+        let hash = CryptoUtils::ripemd160(&CryptoUtils::sha256(&redeem_script));
+
+        let mut script_pub_key = vec![0xa9, 0x14];
+        script_pub_key.extend(hash.iter().cloned());
+        script_pub_key.push(0x87);
+
+        let mut sig_script = vec![redeem_script.len() as u8];
+        sig_script.extend(redeem_script.iter().cloned());
+
+        let p2sh = ScriptFlags { p2sh: true, ..ScriptFlags::default() };
+        let execute = |sig_script: &Vec<u8>, flags| Parser::execute_with_flags(
+            sig_script.clone(), script_pub_key.clone(), &mock_checksig, flags);
+
+        assert_eq!(execute(&sig_script, p2sh), Ok(true));
+
+        // 2 3 ADD 6 EQUAL hashes to something else
+        let mut wrong = sig_script.clone();
+        wrong[4] = 0x56;
+        assert_eq!(execute(&wrong, p2sh), Ok(false));
+
+        // The hash matches but the redeem script fails, this is only
+        // caught with the P2SH flag.
+        let failing = vec![0x00];
+        let hash = CryptoUtils::ripemd160(&CryptoUtils::sha256(&failing));
+        let mut script_pub_key = vec![0xa9, 0x14];
+        script_pub_key.extend(hash.iter().cloned());
+        script_pub_key.push(0x87);
+
+        let execute = |sig_script: Vec<u8>, flags| Parser::execute_with_flags(
+            sig_script, script_pub_key.clone(), &mock_checksig, flags);
+        assert_eq!(execute(vec![0x01, 0x00], ScriptFlags::default()), Ok(true));
+        assert_eq!(execute(vec![0x01, 0x00], p2sh), Ok(false));
+
+        // sigScript must be push only
+        assert_eq!(execute(vec![0x51, 0x75, 0x01, 0x00], ScriptFlags::default()), Ok(true));
+        assert_eq!(execute(vec![0x51, 0x75, 0x01, 0x00], p2sh), Ok(false));
+    }
+
+    #[test]
+    fn test_parse_op_prefix() {
+        assert_eq!(Parser::preprocess_human_readable("OP_DUP OP_HASH160"),
+                   Parser::preprocess_human_readable("DUP HASH160"));
+        assert_eq!(Parser::preprocess_human_readable("OP_1 OP_16 OP_CHECKSIG"),
+                   Ok(vec![0x51, 0x60, 0xac]));
+
+        assert_eq!(Parser::preprocess_human_readable("<5> <17> <-1>"),
+                   Parser::preprocess_human_readable("5 17 -1"));
+        assert!(Parser::preprocess_human_readable("<abc>").is_err());
+    }
+
+    #[test]
+    fn test_parse_small_numbers() {
+        assert_eq!(Parser::preprocess_human_readable("1"), Ok(vec![0x51]));
+        assert_eq!(Parser::preprocess_human_readable("+1"), Ok(vec![0x51]));
+        assert_eq!(Parser::preprocess_human_readable("016"), Ok(vec![0x60]));
+        assert_eq!(Parser::preprocess_human_readable("-1"), Ok(vec![0x4f]));
+        assert_eq!(Parser::preprocess_human_readable("-0"), Ok(vec![0x00]));
+        assert_eq!(Parser::preprocess_human_readable("17"), Ok(vec![0x01, 0x11]));
+        assert_eq!(Parser::preprocess_human_readable("-2"), Ok(vec![0x01, 0x82]));
+    }
+
+    fn test_arithmetic_ext(script: &str, expected: bool) {
+        let raw_script = Parser::preprocess_human_readable(script).unwrap();
+        let flags = ScriptFlags { allow_arithmetic_ext: true, ..ScriptFlags::default() };
+
+        let result = Parser::execute_with_flags(vec![], raw_script, &mock_checksig, flags);
+        assert_eq!(result, Ok(expected));
+    }
+
+    #[test]
+    fn test_op_2mul_2div() {
+        test_arithmetic_ext("3 2MUL 6 EQUAL", true);
+        test_arithmetic_ext("-3 2MUL -6 EQUAL", true);
+        test_arithmetic_ext("7 2DIV 3 EQUAL", true);
+        test_arithmetic_ext("-7 2DIV -3 EQUAL", true);
+
+        // Disabled without the flag
+        test_parse_execute("3 2MUL 6 EQUAL", false);
+        test_parse_execute("7 2DIV 3 EQUAL", false);
+    }
+
+    #[test]
+    fn test_codeseparator() {
+        let checksig = script_code_checksig;
+        // Without a CODESEPARATOR the whole script is signed
+        test_with_checksig("'sig' 0x01 0xac", "1 DROP CHECKSIG", false, checksig);
+        test_with_checksig("'sig' 0x03 0x5175ac", "1 DROP CHECKSIG", true, checksig);
+        test_with_checksig("'sig' 0x01 0xac", "1 DROP CODESEPARATOR CHECKSIG", true, checksig);
+        // Only the last executed CODESEPARATOR counts
+        test_with_checksig("'sig' 0x01 0xac", "CODESEPARATOR 1 DROP CODESEPARATOR CHECKSIG",
+                           true, checksig);
+        test_with_checksig("'sig' 0x01 0xac", "1 DROP 0 IF CODESEPARATOR ENDIF CHECKSIG",
+                           false, checksig);
+        // The signature itself is not part of the script code
+        test_with_checksig("'sig' 0x02 0x75ac", "CODESEPARATOR 'sig' DROP CHECKSIG", true,
+                           checksig);
+        test_with_checksig("'sig' 0x02 0x75ac", "CODESEPARATOR 'gis' DROP CHECKSIG", false,
+                           checksig);
+    }
+
+    fn execute_error(script: Vec<u8>) -> Option<String> {
+        let context = Parser::execute_base(vec![], script, &mock_checksig,
+                                           ScriptFlags::default()).unwrap();
+        context.error().map(|error| error.to_string())
+    }
+
+    #[test]
+    fn test_script_error_position() {
+        // 1 2 3 ADD ADD ADD
+        assert_eq!(execute_error(vec![0x51, 0x52, 0x53, 0x93, 0x93, 0x93]),
+                   Some("StackUnderflow at op ADD (pc=5)".to_string()));
+
+        // Errors found by the opcode itself: a 5 byte number and 1 PICK
+        assert_eq!(execute_error(vec![0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0x8b]),
+                   Some("NumberOverflow at op 1ADD (pc=6)".to_string()));
+        assert_eq!(execute_error(vec![0x51, 0x79]),
+                   Some("StackUnderflow at op PICK (pc=1)".to_string()));
+        assert_eq!(execute_error(vec![0x6c]),
+                   Some("AltStackUnderflow at op FROMALTSTACK (pc=0)".to_string()));
+        // 0 1 16 CHECKMULTISIG
+        assert_eq!(execute_error(vec![0x00, 0x51, 0x60, 0xae]),
+                   Some("StackUnderflow at op CHECKMULTISIG (pc=3)".to_string()));
+
+        assert_eq!(execute_error(vec![0x51, 0x51, 0x93]), None);
+    }
+
+    #[test]
+    fn test_execute_with_state() {
+        // 3 TOALTSTACK FROMALTSTACK TOALTSTACK ADD
+        let context = Parser::execute_with_state(vec![vec![0x01], vec![0x02]], vec![vec![0x05]],
+                                                 vec![0x53, 0x6b, 0x6c, 0x6b, 0x93],
+                                                 &mock_checksig, ScriptFlags::default()).unwrap();
+        assert!(!context.failed());
+        assert_eq!(context.stack().to_vec(), vec![StackItem::from_number(3)]);
+        assert_eq!(context.altstack().to_vec(), vec![StackItem::new(vec![0x05]),
+                                                    StackItem::from_number(3)]);
+
+        // Failing scripts still return their state
+        let context = Parser::execute_with_state(vec![], vec![vec![0x05]], vec![0x6c, 0x6c],
+                                                 &mock_checksig, ScriptFlags::default()).unwrap();
+        assert!(context.failed());
+        assert_eq!(context.stack().to_vec(), vec![StackItem::new(vec![0x05])]);
+        assert!(context.altstack().is_empty());
+        assert_eq!(context.error().unwrap().kind, ScriptErrorKind::AltStackUnderflow);
+    }
+
+    #[test]
+    fn test_push_data() {
+        assert_eq!(push_data(&[]), vec![0x00]);
+        assert_eq!(push_data(&[0xab]), vec![0x01, 0xab]);
+        assert_eq!(push_data(&[0; 0x4c])[..2], [0x4c, 0x4c]);
+        assert_eq!(push_data(&[0; 0x100])[..3], [0x4d, 0x00, 0x01]);
+        assert_eq!(push_data(&[0; 0x10000])[..5], [0x4e, 0x00, 0x00, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn test_build_p2pkh() {
+        let script_pubkey = build_p2pkh_script_pubkey(&[0x22; 20]);
+        assert_eq!(classify(&script_pubkey), ScriptType::PubKeyHash);
+        assert_eq!(extract_destinations(&script_pubkey), vec![vec![0x22; 20]]);
+        assert_eq!(script_pubkey, Parser::preprocess_human_readable(
+            &format!("DUP HASH160 0x14 0x{} EQUALVERIFY CHECKSIG", "22".repeat(20))).unwrap());
+
+        let mut key = vec![0x02];
+        key.extend(vec![0x11; 32]);
+        let signature = vec![0x30; 72];
+        let script_sig = build_p2pkh_script_sig(&signature, &key);
+        assert!(is_push_only(&script_sig));
+
+        let script = BitcoinScript::new(script_sig.clone());
+        let pushes: Vec<_> = script.iter().map(|(_, _, data)| data.unwrap()).collect();
+        assert_eq!(pushes, vec![signature, key]);
+    }
+
+    #[test]
+    fn test_find_and_delete() {
+        assert_eq!(find_and_delete(&[0x01, 0xab, 0x51, 0x01, 0xab], &[0x01, 0xab]), vec![0x51]);
+        // Only whole operations are matched
+        assert_eq!(find_and_delete(&[0x02, 0x01, 0xab], &[0x01, 0xab]), vec![0x02, 0x01, 0xab]);
+        // A truncated push at the end is kept
+        assert_eq!(find_and_delete(&[0x01, 0xab, 0x4c], &[0x01, 0xab]), vec![0x4c]);
+        assert_eq!(find_and_delete(&[0xab, 0x02, 0xab], &[0xab]), vec![0x02, 0xab]);
+        // OP_VERIF isn't in our table, the walk goes on after it
+        assert_eq!(find_and_delete(&[0xab, 0x65, 0xab, 0x51], &[0xab]), vec![0x65, 0x51]);
+        assert_eq!(find_and_delete(&[0x51, 0x52], &[]), vec![0x51, 0x52]);
     }
 
     #[test]
@@ -1,16 +1,14 @@
 use super::Context;
+use super::stack::{StackItem, ScriptError, ScriptErrorKind};
+use super::signature::is_valid_signature_encoding;
 
-use utils::IntUtils;
 use utils::CryptoUtils;
 
-use serialize::Deserialize;
-use super::BitcoinScript;
+use super::{BitcoinScript, MAX_PUBKEYS_PER_MULTISIG};
 
 use std::fmt;
 use std::cmp;
 
-use std::io::Cursor;
-
 fn op_dup(context: Context) -> Context {
     pick(context, 0)
 }
@@ -18,7 +16,7 @@ fn op_dup(context: Context) -> Context {
 fn op_ifdup(context: Context) -> Context {
     assert!(context.stack.len() > 0);
 
-    if is_true(&context.stack.last()) {
+    if context.stack.last().unwrap().as_bool() {
         return op_dup(context);
     }
 
@@ -26,7 +24,7 @@ fn op_ifdup(context: Context) -> Context {
 }
 
 fn stack_op<F>(context: Context, op: F) -> Context
-where F: Fn(&mut Vec<Vec<u8>>) {
+where F: Fn(&mut Vec<StackItem>) {
     let mut new_context = context;
     op(&mut new_context.stack);
 
@@ -37,7 +35,7 @@ fn op_depth(context: Context) -> Context {
     assert!(context.stack.len() <= 0x7f);
 
     stack_op(context, |st| {
-        let size = IntUtils::to_vec_u8(st.len() as i64);
+        let size = StackItem::from_number(st.len() as i64);
         st.push(size);
     })
 }
@@ -76,7 +74,9 @@ fn op_toaltstack(context: Context) -> Context {
 }
 
 fn op_fromaltstack(context: Context) -> Context {
-    assert!(context.altstack.len() > 0);
+    if context.altstack.is_empty() {
+        return op_fail(context, ScriptErrorKind::AltStackUnderflow);
+    }
 
     let mut new_context = context;
     let el = new_context.altstack.pop().unwrap();
@@ -93,8 +93,14 @@ fn op_pick(context: Context) -> Context {
     assert!(context.stack.len() > 0);
 
     let mut new_context = context;
-    let el = new_context.stack.pop().unwrap();
-    let size = IntUtils::to_i32(&el);
+    let size = match new_context.stack.pop().unwrap().as_number() {
+        Ok(size) => size,
+        Err(error) => return op_fail(new_context, error.kind),
+    };
+
+    if size < 0 || size as usize >= new_context.stack.len() {
+        return op_fail(new_context, ScriptErrorKind::StackUnderflow);
+    }
 
     pick(new_context, size as usize)
 }
@@ -113,9 +119,14 @@ fn op_roll(context: Context) -> Context {
     assert!(context.stack.len() > 0);
 
     let mut new_context = context;
-    let size = IntUtils::to_i32(&new_context.stack.pop().unwrap());
-    assert!(size <= 0xff);
-    assert!(size >= 0x00);
+    let size = match new_context.stack.pop().unwrap().as_number() {
+        Ok(size) => size,
+        Err(error) => return op_fail(new_context, error.kind),
+    };
+
+    if size < 0 || size > 0xff || size as usize >= new_context.stack.len() {
+        return op_fail(new_context, ScriptErrorKind::StackUnderflow);
+    }
 
     roll(new_context, size as u8)
 }
@@ -151,20 +162,58 @@ fn op_2swap(context: Context) -> Context {
     roll(roll(context, 3), 3)
 }
 
+// Pops `count` numbers, the result is in stack order: the top of the stack
+// comes last. Marks the context invalid if one of them is too big.
+fn pop_numbers(context: &mut Context, count: usize) -> Option<Vec<i64>> {
+    assert!(context.stack.len() >= count);
+
+    let start = context.stack.len() - count;
+    let numbers: Result<Vec<i64>, _> = context.stack.drain(start..)
+        .map(|item| item.as_number())
+        .collect();
+
+    if let Err(error) = numbers {
+        context.valid = false;
+        context.error = Some(error);
+    }
+
+    numbers.ok()
+}
+
+fn numeric_op<F>(context: Context, count: usize, op: F) -> Context
+where F: Fn(&[i64]) -> i64 {
+    let mut new_context = context;
+
+    if let Some(inputs) = pop_numbers(&mut new_context, count) {
+        new_context.stack.push(StackItem::from_number(op(&inputs)));
+    }
+
+    new_context
+}
+
 fn unary_op<F>(context: Context, op: F) -> Context
-where F: Fn(i32) -> i64 {
-    assert!(context.stack.len() > 0);
+where F: Fn(i64) -> i64 {
+    numeric_op(context, 1, |inputs| op(inputs[0]))
+}
 
-    stack_op(context, |st| {
-        let input = IntUtils::to_i32(&st.pop().unwrap());
-        st.push(IntUtils::to_vec_u8(op(input) as i64));
-    })
+fn op_1add(context: Context)   -> Context { unary_op(context, |a| a + 1) }
+fn op_1sub(context: Context)   -> Context { unary_op(context, |a| a - 1) }
+fn op_negate(context: Context) -> Context { unary_op(context, |a| a * -1) }
+fn op_abs(context: Context)    -> Context { unary_op(context, |a| a.abs()) }
+
+fn arithmetic_ext_op<F>(context: Context, op: F) -> Context
+where F: Fn(i64) -> i64 {
+    if !context.flags.allow_arithmetic_ext {
+        return op_mark_invalid(context);
+    }
+
+    unary_op(context, op)
 }
 
-fn op_1add(context: Context)   -> Context { unary_op(context, |a| a as i64 + 1 ) }
-fn op_1sub(context: Context)   -> Context { unary_op(context, |a| a as i64 - 1) }
-fn op_negate(context: Context) -> Context { unary_op(context, |a| a as i64 * -1) }
-fn op_abs(context: Context)    -> Context { unary_op(context, |a| a.abs() as i64) }
+fn op_2mul(context: Context)   -> Context { arithmetic_ext_op(context, |a| a * 2) }
+// Integer division already rounds towards zero
+fn op_2div(context: Context)   -> Context { arithmetic_ext_op(context, |a| a / 2) }
+
 fn op_not(context: Context) -> Context {
     unary_op(context, |a| if a == 0 { 1 } else { 0 })
 }
@@ -174,23 +223,61 @@ fn op_0notequal(context: Context) -> Context {
 }
 
 fn binary_op<F>(context: Context, op: F) -> Context
-where F: Fn(i32, i32) -> i64 {
-    assert!(context.stack.len() >= 2);
-
-    stack_op(context, |st| {
-        let input1 = IntUtils::to_i32(&st.pop().unwrap());
-        let input2 = IntUtils::to_i32(&st.pop().unwrap());
-        st.push(IntUtils::to_vec_u8(op(input2, input1)));
-    })
+where F: Fn(i64, i64) -> i64 {
+    numeric_op(context, 2, |inputs| op(inputs[0], inputs[1]))
 }
 
 fn bool_binary_op<F>(context: Context, op: F) -> Context
-where F: Fn(i32, i32) -> bool {
+where F: Fn(i64, i64) -> bool {
     binary_op(context, |a, b| if op(a, b) { 1 } else { 0 })
 }
 
-fn op_add(context: Context) -> Context { binary_op(context, |a, b| a as i64 + b as i64) }
-fn op_sub(context: Context) -> Context { binary_op(context, |a, b| a as i64 - b as i64) }
+fn op_add(context: Context) -> Context { binary_op(context, |a, b| a + b) }
+fn op_sub(context: Context) -> Context { binary_op(context, |a, b| a - b) }
+
+// Shifts operate on the magnitude, the sign is preserved. Like the original
+// bignum implementation the result is not truncated to 4 bytes.
+fn shift_op<F>(context: Context, op: F) -> Context
+where F: Fn(i64, u32) -> Option<i64> {
+    if !context.flags.allow_arithmetic_ext {
+        return op_mark_invalid(context);
+    }
+
+    let mut new_context = context;
+    let (value, bits) = match pop_numbers(&mut new_context, 2) {
+        Some(inputs) => (inputs[0], inputs[1]),
+        None => return new_context,
+    };
+
+    if bits < 0 {
+        return op_mark_invalid(new_context);
+    }
+
+    match op(value.abs(), bits as u32) {
+        Some(result) => {
+            new_context.stack.push(StackItem::from_number(value.signum() * result));
+            new_context
+        },
+        // The result doesn't fit in our integers
+        None => op_mark_invalid(new_context),
+    }
+}
+
+fn op_lshift(context: Context) -> Context {
+    shift_op(context, |a, bits| {
+        if a == 0 {
+            Some(0)
+        } else if bits < 63 && a <= i64::max_value() >> bits {
+            Some(a << bits)
+        } else {
+            None
+        }
+    })
+}
+
+fn op_rshift(context: Context) -> Context {
+    shift_op(context, |a, bits| Some(if bits < 63 { a >> bits } else { 0 }))
+}
 
 fn op_booland(context: Context) -> Context {
     bool_binary_op(context, |a, b| a != 0 && b != 0)
@@ -229,30 +316,25 @@ fn op_greaterthanorequal(context: Context) -> Context {
 }
 
 fn op_min(context: Context) -> Context {
-    binary_op(context, |a, b| cmp::min(a,b) as i64)
+    binary_op(context, |a, b| cmp::min(a, b))
 }
 
 fn op_max(context: Context) -> Context {
-    binary_op(context, |a, b| cmp::max(a,b) as i64)
+    binary_op(context, |a, b| cmp::max(a, b))
 }
 
 fn ternary_op<F>(context: Context, op: F) -> Context
-where F: Fn(i32, i32, i32) -> i32 {
-    assert!(context.stack.len() >= 3);
-
-    stack_op(context, |st| {
-        let input1 = IntUtils::to_i32(&st.pop().unwrap());
-        let input2 = IntUtils::to_i32(&st.pop().unwrap());
-        let input3 = IntUtils::to_i32(&st.pop().unwrap());
-        st.push(IntUtils::to_vec_u8(op(input3, input2, input1) as i64));
-    })
+where F: Fn(i64, i64, i64) -> i64 {
+    numeric_op(context, 3, |inputs| op(inputs[0], inputs[1], inputs[2]))
 }
 
 fn bool_ternary_op<F>(context: Context, op: F) -> Context
-where F: Fn(i32, i32, i32) -> bool {
+where F: Fn(i64, i64, i64) -> bool {
     ternary_op(context, |a, b, c| if op(a, b, c) { 1 } else { 0 })
 }
 
+// Stack: <x> <min> <max>, true if x is in the half-open interval [min, max).
+// Like the official client a reversed range is empty, so the result is false.
 fn op_within(context: Context) -> Context {
     bool_ternary_op(context, |x, min, max| x >= min && x < max)
 }
@@ -260,58 +342,73 @@ fn op_within(context: Context) -> Context {
 fn op_sha256(context: Context) -> Context {
     stack_op(context, |st| {
         let last = st.pop().unwrap();
-        st.push(CryptoUtils::sha256(&last).to_vec());
+        st.push(StackItem::new(CryptoUtils::sha256(&last).to_vec()));
     })
 }
 
 fn op_sha1(context: Context) -> Context {
     stack_op(context, |st| {
         let last = st.pop().unwrap();
-        st.push(CryptoUtils::sha1(&last).to_vec());
+        st.push(StackItem::new(CryptoUtils::sha1(&last).to_vec()));
     })
 }
 
 fn op_hash256(context: Context) -> Context {
     stack_op(context, |st| {
         let last = st.pop().unwrap();
-        st.push(CryptoUtils::sha256(&CryptoUtils::sha256(&last)).to_vec());
+        st.push(StackItem::new(CryptoUtils::sha256(&CryptoUtils::sha256(&last)).to_vec()));
     })
 }
 
 fn op_ripemd160(context: Context) -> Context {
     stack_op(context, |st| {
         let last = st.pop().unwrap();
-        st.push(CryptoUtils::ripemd160(&last).to_vec());
+        st.push(StackItem::new(CryptoUtils::ripemd160(&last).to_vec()));
     })
 }
 
 fn op_codeseparator(context: Context) -> Context {
     let mut new_context = context;
 
-    new_context.codeseparator = new_context.script.index();
+    new_context.codeseparator = new_context.script.index() + 1;
 
     new_context
 }
 
-fn get_boolean(data: bool) -> Vec<u8> {
-    if data {
-        vec![0x01]
-    } else {
-        vec![]
+// Only compressed (0x02/0x03) and uncompressed (0x04) keys are accepted
+fn is_valid_pubkey(key: &[u8]) -> bool {
+    match key.len() {
+        33 => key[0] == 0x02 || key[0] == 0x03,
+        65 => key[0] == 0x04,
+        _ => false,
     }
 }
 
 fn op_checksig(context: Context) -> Context {
     assert!(context.stack.len() >= 2);
 
-    let codeseparator = context.codeseparator;
     let checksig = context.checksig;
     let mut new_context = context;
 
     let pub_key_str = new_context.stack.pop().unwrap();
     let sig_str = new_context.stack.pop().unwrap();
 
-    let result = get_boolean(checksig(codeseparator, &pub_key_str, &sig_str));
+    if new_context.flags.strictenc && !is_valid_pubkey(&pub_key_str) {
+        return op_mark_invalid(new_context);
+    }
+
+    // An empty signature is always false, there's nothing to verify
+    if sig_str.is_empty() {
+        new_context.stack.push(StackItem::from_bool(false));
+        return new_context;
+    }
+
+    if new_context.flags.dersig && !is_valid_signature_encoding(&sig_str) {
+        return op_mark_invalid(new_context);
+    }
+
+    let script_code = new_context.script_code(&[&sig_str]);
+    let result = StackItem::from_bool(checksig(&script_code, &pub_key_str, &sig_str));
 
     new_context.stack.push(result);
 
@@ -322,17 +419,53 @@ fn op_checksigverify(context: Context) -> Context {
     op_verify(op_checksig(context))
 }
 
+// Stack: <sig> <n> <pub_key>, pushes n + 1 if the signature is valid, n otherwise
+fn op_checksigadd(context: Context) -> Context {
+    if !context.flags.tapscript {
+        return op_mark_invalid(context);
+    }
+
+    assert!(context.stack.len() >= 3);
+
+    let checksig = context.checksig;
+    let mut new_context = context;
+
+    let pub_key_str = new_context.stack.pop().unwrap();
+    let n = match new_context.stack.pop().unwrap().as_number() {
+        Ok(n) => n,
+        Err(_) => return op_mark_invalid(new_context),
+    };
+    let sig_str = new_context.stack.pop().unwrap();
+
+    // Tapscript signatures don't commit to the script code
+    let script_code = new_context.script_code(&[]);
+    let result = if checksig(&script_code, &pub_key_str, &sig_str) { n + 1 } else { n };
+    new_context.stack.push(StackItem::from_number(result));
+
+    new_context
+}
+
 fn op_checkmultisig(context: Context) -> Context {
-    assert!(context.stack.len() > 1);
+    if context.stack.len() < 2 {
+        return op_fail(context, ScriptErrorKind::StackUnderflow);
+    }
 
-    let codeseparator = context.codeseparator;
     let checksig = context.checksig;
+    let strictenc = context.flags.strictenc;
+    let dersig = context.flags.dersig;
     let mut new_context = context;
 
-    let pub_keys_number = IntUtils::to_i32(&new_context.stack.pop().unwrap());
-    assert!(pub_keys_number >= 0);
-    assert!(pub_keys_number <= 20);
-    assert!(new_context.stack.len() > pub_keys_number as usize);
+    let pub_keys_number = match new_context.stack.pop().unwrap().as_number() {
+        Ok(n) => n,
+        Err(error) => return op_fail(new_context, error.kind),
+    };
+    if pub_keys_number < 0 || pub_keys_number > MAX_PUBKEYS_PER_MULTISIG as i64 {
+        return op_fail(new_context, ScriptErrorKind::PubKeyCount);
+    }
+
+    if new_context.stack.len() <= pub_keys_number as usize {
+        return op_fail(new_context, ScriptErrorKind::StackUnderflow);
+    }
 
     let mut pub_keys = vec![];
     for _ in 0..pub_keys_number {
@@ -340,10 +473,17 @@ fn op_checkmultisig(context: Context) -> Context {
     }
     pub_keys.reverse();
 
-    let sig_strs_number = IntUtils::to_i32(&new_context.stack.pop().unwrap());
-    assert!(sig_strs_number >= 0);
-    assert!(sig_strs_number <= pub_keys_number);
-    assert!(new_context.stack.len() > sig_strs_number as usize);
+    let sig_strs_number = match new_context.stack.pop().unwrap().as_number() {
+        Ok(n) => n,
+        Err(error) => return op_fail(new_context, error.kind),
+    };
+    if sig_strs_number < 0 || sig_strs_number > pub_keys_number {
+        return op_fail(new_context, ScriptErrorKind::SigCount);
+    }
+
+    if new_context.stack.len() <= sig_strs_number as usize {
+        return op_fail(new_context, ScriptErrorKind::StackUnderflow);
+    }
 
     let mut sig_strs = vec![];
     for _ in 0..sig_strs_number {
@@ -351,12 +491,26 @@ fn op_checkmultisig(context: Context) -> Context {
     }
     sig_strs.reverse();
 
+    // Every signature is removed from the script code before checking any
+    let script_code = {
+        let sigs: Vec<&[u8]> = sig_strs.iter().map(|sig| &sig[..]).collect();
+        new_context.script_code(&sigs)
+    };
+
     let mut verified = 0;
     while pub_keys.len() > 0 && sig_strs.len() > 0 {
         let sig_str = sig_strs.pop().unwrap();
+        if dersig && !sig_str.is_empty() && !is_valid_signature_encoding(&sig_str) {
+            return op_mark_invalid(new_context);
+        }
+
         while pub_keys.len() > 0 {
             let pub_key = pub_keys.pop().unwrap();
-            if checksig(codeseparator, &pub_key, &sig_str) {
+            if strictenc && !is_valid_pubkey(&pub_key) {
+                return op_mark_invalid(new_context);
+            }
+
+            if checksig(&script_code, &pub_key, &sig_str) {
                 verified += 1;
                 break;
             }
@@ -368,7 +522,7 @@ fn op_checkmultisig(context: Context) -> Context {
     // pops an extra element from the stack that we have
     // to emulate here.
     new_context.stack.pop();
-    new_context.stack.push(get_boolean(result));
+    new_context.stack.push(StackItem::from_bool(result));
 
     new_context
 }
@@ -380,8 +534,7 @@ fn op_checkmultisigverify(context: Context) -> Context {
 fn op_hash160(context: Context) -> Context {
     stack_op(context, |st| {
         let last = st.pop().unwrap();
-        st.push(CryptoUtils::ripemd160(&CryptoUtils::sha256(&last))
-                .to_vec());
+        st.push(StackItem::new(CryptoUtils::ripemd160(&CryptoUtils::sha256(&last)).to_vec()));
     })
 }
 
@@ -396,81 +549,83 @@ fn op_equal(context: Context) -> Context {
         let x = st.pop().unwrap();
         let y = st.pop().unwrap();
 
-        st.push(get_boolean(x.eq(&y)));
+        st.push(StackItem::from_bool(x == y));
     })
 }
 
 fn op_false(context: Context) -> Context {
-    stack_op(context, |st| st.push(vec![]))
+    stack_op(context, |st| st.push(StackItem::new(vec![])))
 }
 
-fn op_pushdata(context: Context) -> Context {
-    let mut new_context = context;
-    let byte = new_context.script.current().unwrap().to_byte();
+// Number of bytes used to encode the length of a push
+pub fn push_length_size(op: u8) -> usize {
+    match op {
+        0x4c => 1,
+        0x4d => 2,
+        0x4e => 4,
+        _ => 0,
+    }
+}
 
-    new_context.script.next();
-    let data = new_context.script.read(byte as usize);
-    new_context.stack.push(data);
+// Returns the number of bytes pushed by the opcode at index i, 0 for
+// opcodes that don't push data.
+pub fn read_push_length(script: &[u8], i: usize) -> Result<usize, ()> {
+    let op = script[i];
+    let size = push_length_size(op);
 
-    new_context
-}
+    if size == 0 {
+        return Ok(if op <= 0x4b { op as usize } else { 0 });
+    }
 
-fn op_pushdata1(context: Context) -> Context {
-    op_pushdata_base::<u8>(context, 1)
-}
+    if i + size >= script.len() {
+        return Err(());
+    }
 
-fn op_pushdata2(context: Context) -> Context {
-    op_pushdata_base::<u16>(context, 2)
-}
+    // Lengths are little endian
+    let mut length = 0;
+    for j in (1..size + 1).rev() {
+        length = (length << 8) | script[i + j] as usize;
+    }
 
-fn op_pushdata4(context: Context) -> Context {
-    op_pushdata_base::<u32>(context, 4)
+    Ok(length)
 }
 
-// TODO: see if there's a better way to do this
-trait ToUsize {
-    fn to_usize(self) -> usize;
+fn op_pushdata(context: Context) -> Context {
+    op_pushdata_base(context, 0)
 }
 
-impl ToUsize for u8 {
-    fn to_usize(self) -> usize { self as usize }
+fn op_pushdata1(context: Context) -> Context {
+    op_pushdata_base(context, 1)
 }
 
-impl ToUsize for u16 {
-    fn to_usize(self) -> usize { self as usize }
+fn op_pushdata2(context: Context) -> Context {
+    op_pushdata_base(context, 2)
 }
 
-impl ToUsize for u32 {
-    fn to_usize(self) -> usize { self as usize }
+fn op_pushdata4(context: Context) -> Context {
+    op_pushdata_base(context, 4)
 }
 
-fn op_pushdata_base<T: Deserialize + ToUsize>(context: Context, size: usize) -> Context {
+fn op_pushdata_base(context: Context, size: usize) -> Context {
     let mut new_context = context;
 
-    new_context.script.next();
-
-    let data = new_context.script.read(size);
-
-    if data.len() < size {
-        // not enough data
-        new_context.valid = false;
-        return new_context;
-    }
-
-    let bytes = T::deserialize(&mut Cursor::new(data));
+    match read_push_length(&new_context.script.script, new_context.script.pointer) {
+        Ok(length) => {
+            // Skip the length bytes
+            for _ in 0..size {
+                new_context.script.next();
+            }
 
-    match bytes {
-        Ok(b) => {
             let mut data = vec![];
-            let b_usize = b.to_usize();
-            if b_usize > 0 {
+            if length > 0 {
                 new_context.script.next();
-                data = new_context.script.read(b_usize);
+                data = new_context.script.read(length);
             }
 
-            new_context.stack.push(data);
+            new_context.stack.push(StackItem::new(data));
         }
         Err(_) => {
+            // not enough data
             new_context.valid = false;
         }
     }
@@ -479,7 +634,7 @@ fn op_pushdata_base<T: Deserialize + ToUsize>(context: Context, size: usize) ->
 }
 
 fn push_to_stack(context: Context, data: u8) -> Context {
-    stack_op(context, |st| st.push(vec![data]))
+    stack_op(context, |st| st.push(StackItem::new(vec![data])))
 }
 
 fn op_1negate(context: Context) -> Context {
@@ -506,13 +661,23 @@ fn op_16(context: Context) -> Context { push_to_stack(context, 0x10) }
 
 fn op_nop(context: Context) -> Context { context }
 
+// NOPs reserved for future soft-forks, these are valid unless the
+// caller asked us to discourage them.
+fn op_upgradable_nop(context: Context) -> Context {
+    if context.flags.discourage_upgradable_nops {
+        op_mark_invalid(context)
+    } else {
+        context
+    }
+}
+
 fn op_if(context: Context) -> Context {
     let mut new_context = context;
     let last = new_context.stack.pop().unwrap();
 
     new_context.script.next();
 
-    if is_true(&Some(&last)) {
+    if last.as_bool() {
         new_context.conditional_executed.push(true);
         new_context
     } else {
@@ -544,6 +709,11 @@ fn goto_next_branch(context: Context) -> Context {
                         break;
                     }
                 }
+                // Disabled opcodes fail even when they are not executed
+                op if op.category() == OpCategory::Disabled => {
+                    new_context.valid = false;
+                    return new_context;
+                },
                 _ => {}
             },
             None => {
@@ -558,39 +728,18 @@ fn goto_next_branch(context: Context) -> Context {
     new_context
 }
 
-// TODO: this should use more the existing logic that implements pushdata
+// Returns the index of the first op code that doesn't push data
 fn get_next_op(script: &BitcoinScript) -> usize {
     let mut i = script.pointer;
     let sc = &script.script;
 
     while i < sc.len() {
         match sc[i] {
-            0x01 ... 0x4b => {
-                i += sc[i] as usize;
-            },
-            0x4c => {
-                if sc.len() <= i + 1 {
-                    return sc.len();
-                }
-                i += sc[i + 1] as usize;
-            },
-            0x4d => {
-                if sc.len() <= i + 2 {
-                    return sc.len();
+            0x01 ... 0x4e => {
+                match read_push_length(sc, i) {
+                    Ok(length) => i += push_length_size(sc[i]) + length,
+                    Err(_) => return sc.len(),
                 }
-                let bytes_array = &sc[i+1..i+3];
-                let bytes = u16::deserialize(&mut Cursor::new(bytes_array));
-                // TODO: handle errors
-                i += bytes.unwrap() as usize;
-            },
-            0x4e => {
-                if sc.len() <= i + 4 {
-                    return sc.len();
-                }
-                let bytes_array = &sc[i+1..i+5];
-                let bytes = u32::deserialize(&mut Cursor::new(bytes_array));
-                // TODO: handle errors
-                i += bytes.unwrap() as usize;
             },
             _ => {
                 return i;
@@ -603,9 +752,16 @@ fn get_next_op(script: &BitcoinScript) -> usize {
     sc.len()
 }
 
+// Like the official client, every ELSE flips the branch so
+// `IF .. ELSE .. ELSE .. ENDIF` is valid.
 fn op_else(context: Context) -> Context {
     let mut new_context = context;
-    let conditional_executed = new_context.conditional_executed.pop().unwrap();
+    let conditional_executed = match new_context.conditional_executed.pop() {
+        Some(x) => x,
+        // ELSE without IF
+        None => return op_mark_invalid(new_context),
+    };
+
     new_context.script.next();
     new_context.conditional_executed.push(!conditional_executed);
 
@@ -622,7 +778,7 @@ fn op_notif(context: Context) -> Context {
 
     new_context.script.next();
 
-    if !is_true(&Some(&last)) {
+    if !last.as_bool() {
         new_context.conditional_executed.push(true);
         new_context
     } else {
@@ -634,28 +790,17 @@ fn op_notif(context: Context) -> Context {
 fn op_endif(context: Context) -> Context {
     let mut new_context = context;
 
-    new_context.conditional_executed.pop();
-
-    new_context
-}
-
-fn to_bool(element: &Vec<u8>) -> bool {
-    if element.len() == 0 {
-        return false;
+    if new_context.conditional_executed.pop().is_none() {
+        // ENDIF without IF
+        return op_mark_invalid(new_context);
     }
 
-    for i in 0..element.len()-1 {
-        if *element.get(i).unwrap() != 0x00 {
-            return true;
-        }
-    }
-
-    return *element.last().unwrap() != 0x80;
+    new_context
 }
 
-pub fn is_true(element: &Option<&Vec<u8>>) -> bool {
+pub fn is_true(element: &Option<&StackItem>) -> bool {
     match element {
-        &Some(x) => to_bool(x),
+        &Some(x) => x.as_bool(),
         &None => false,
     }
 }
@@ -677,24 +822,31 @@ fn op_mark_invalid(context: Context) -> Context {
     return new_context;
 }
 
+// Like op_mark_invalid, also recording why
+fn op_fail(context: Context, kind: ScriptErrorKind) -> Context {
+    let mut new_context = op_mark_invalid(context);
+    new_context.error = Some(ScriptError::new(kind));
+    new_context
+}
+
 fn op_size(context: Context) -> Context {
     assert!(context.stack.len() > 0);
 
     stack_op(context, |st| {
-        let size = IntUtils::to_vec_u8(st.last().unwrap().len() as i64);
+        let size = StackItem::from_number(st.last().unwrap().len() as i64);
         st.push(size);
     })
 }
 
-impl fmt::Debug for Context {
+impl<'a> fmt::Debug for Context<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "Context(data={:?}, stack={:?}, valid={:?}, branch_executed={:?})",
                self.script, self.stack, self.valid, self.conditional_executed)
     }
 }
 
-impl cmp::PartialEq for Context {
-    fn eq(&self, other: &Context) -> bool {
+impl<'a> cmp::PartialEq for Context<'a> {
+    fn eq(&self, other: &Context<'a>) -> bool {
         self.script == other.script && self.stack == other.stack &&
             self.valid == other.valid
     }
@@ -722,7 +874,7 @@ macro_rules! op_codes {
                 }
             }
 
-            pub fn execute(&self, context: Context) -> Context {
+            pub fn execute<'a>(&self, context: Context<'a>) -> Context<'a> {
                 match self {
                     $(&OpCode::$element => $func(context)),*
                 }
@@ -741,6 +893,12 @@ macro_rules! op_codes {
                     _ => None,
                 }
             }
+
+            pub fn name(&self) -> &'static str {
+                match self {
+                    $(&OpCode::$element => $tostring),*
+                }
+            }
         }
     }
 }
@@ -872,23 +1030,34 @@ op_codes!(
     Rot:                 ("ROT",                0x7b, op_rot),
     Swap:                ("SWAP",               0x7c, op_swap),
     Tuck:                ("TUCK",               0x7d, op_tuck),
-    // opcodes 0x7e - 0x81 (disabled opcodes)
+    Cat:                 ("CAT",                0x7e, op_mark_invalid),
+    Substr:              ("SUBSTR",             0x7f, op_mark_invalid),
+    Left:                ("LEFT",               0x80, op_mark_invalid),
+    Right:               ("RIGHT",              0x81, op_mark_invalid),
     Size:                ("SIZE",               0x82, op_size),
-    // opcodes 0x83 - 0x86 (disabled opcodes)
+    Invert:              ("INVERT",             0x83, op_mark_invalid),
+    And:                 ("AND",                0x84, op_mark_invalid),
+    Or:                  ("OR",                 0x85, op_mark_invalid),
+    Xor:                 ("XOR",                0x86, op_mark_invalid),
     Equal:               ("EQUAL",              0x87, op_equal),
     EqualVerify:         ("EQUALVERIFY",        0x88, op_equalverify),
     Reserved1:           ("RESERVED1",          0x89, op_mark_invalid),
     Reserved2:           ("RESERVED2",          0x8a, op_mark_invalid),
     _1Add:               ("1ADD",               0x8b, op_1add),
     _1Sub:               ("1SUB",               0x8c, op_1sub),
-    // opcodes 0x8d - 0x8e (disabled opcodes)
+    _2Mul:               ("2MUL",               0x8d, op_2mul),
+    _2Div:               ("2DIV",               0x8e, op_2div),
     Negate:              ("NEGATE",             0x8f, op_negate),
     Abs:                 ("ABS",                0x90, op_abs),
     Not:                 ("NOT",                0x91, op_not),
     _0NotEqual:          ("0NOTEQUAL",          0x92, op_0notequal),
     Add:                 ("ADD",                0x93, op_add),
     Sub:                 ("SUB",                0x94, op_sub),
-    // opcodes 0x95 - 0x99 (disabled opcodes)
+    Mul:                 ("MUL",                0x95, op_mark_invalid),
+    Div:                 ("DIV",                0x96, op_mark_invalid),
+    Mod:                 ("MOD",                0x97, op_mark_invalid),
+    LShift:              ("LSHIFT",             0x98, op_lshift),
+    RShift:              ("RSHIFT",             0x99, op_rshift),
     BoolAnd:             ("BOOLAND",            0x9a, op_booland),
     BoolOr:              ("BOOLOR",             0x9b, op_boolor),
     NumEqual:            ("NUMEQUAL",           0x9c, op_numequal),
@@ -911,18 +1080,18 @@ op_codes!(
     CheckSigVerify:      ("CHECKSIGVERIFY",     0xad, op_checksigverify),
     CheckMultiSig:       ("CHECKMULTISIG",      0xae, op_checkmultisig),
     CheckMultiSigVerify: ("CHECKMULTISIGVERIFY",0xaf, op_checkmultisigverify),
-    Nop1:                ("NOP1",               0xb0, op_nop),
+    Nop1:                ("NOP1",               0xb0, op_upgradable_nop),
     // TODO: CheckLockTimeVerify
     CheckLockTimeVerify: ("CHECKLOCKTIMEVERIFY",0xb1, op_nop),
-    Nop3:                ("NOP3",               0xb2, op_nop),
-    Nop4:                ("NOP4",               0xb3, op_nop),
-    Nop5:                ("NOP5",               0xb4, op_nop),
-    Nop6:                ("NOP6",               0xb5, op_nop),
-    Nop7:                ("NOP7",               0xb6, op_nop),
-    Nop8:                ("NOP8",               0xb7, op_nop),
-    Nop9:                ("NOP9",               0xb8, op_nop),
-    Nop10:               ("NOP10",              0xb9, op_nop),
-    Invalid11:           ("INVALID11",          0xba, op_mark_invalid),
+    Nop3:                ("NOP3",               0xb2, op_upgradable_nop),
+    Nop4:                ("NOP4",               0xb3, op_upgradable_nop),
+    Nop5:                ("NOP5",               0xb4, op_upgradable_nop),
+    Nop6:                ("NOP6",               0xb5, op_upgradable_nop),
+    Nop7:                ("NOP7",               0xb6, op_upgradable_nop),
+    Nop8:                ("NOP8",               0xb7, op_upgradable_nop),
+    Nop9:                ("NOP9",               0xb8, op_upgradable_nop),
+    Nop10:               ("NOP10",              0xb9, op_upgradable_nop),
+    CheckSigAdd:         ("CHECKSIGADD",        0xba, op_checksigadd),
     Invalid12:           ("INVALID12",          0xbb, op_mark_invalid),
     Invalid13:           ("INVALID13",          0xbc, op_mark_invalid),
     Invalid14:           ("INVALID14",          0xbd, op_mark_invalid),
@@ -992,22 +1161,146 @@ op_codes!(
     Invalid78:           ("INVALID78",          0xfd, op_mark_invalid),
     Invalid79:           ("INVALID79",          0xfe, op_mark_invalid),
     Invalid80:           ("INVALID80",          0xff, op_mark_invalid);
+
     // Advancing op codes
     If, NotIf, Else
 );
 
+// What an opcode does, for tools showing scripts to users
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OpCategory {
+    // Pushes data or a constant number
+    Push,
+    // IF, ELSE, VERIFY, RETURN...
+    Conditional,
+    Stack,
+    // EQUAL and EQUALVERIFY, the other bitwise opcodes are disabled
+    Bitwise,
+    // 2MUL, 2DIV, LSHIFT and RSHIFT are only enabled by allow_arithmetic_ext
+    Arithmetic,
+    Crypto,
+    Locktime,
+    // Removed from the language, scripts containing them always fail
+    Disabled,
+    // Fail when executed
+    Reserved,
+    // Not assigned to anything, fail when executed
+    Invalid,
+    // OP_NOP, does nothing
+    NoOp,
+    // NOP1 and NOP3 to NOP10, kept for soft forks
+    UpgradableNoOp,
+}
+
+impl OpCode {
+    pub fn category(&self) -> OpCategory {
+        match self.to_byte() {
+            0x00 ... 0x4f | 0x51 ... 0x60 => OpCategory::Push,
+            0x50 | 0x62 | 0x89 | 0x8a => OpCategory::Reserved,
+            0x61 => OpCategory::NoOp,
+            0x63 ... 0x6a => OpCategory::Conditional,
+            0x6b ... 0x7d | 0x82 => OpCategory::Stack,
+            0x7e ... 0x81 | 0x83 ... 0x86 | 0x95 ... 0x97 => OpCategory::Disabled,
+            0x87 | 0x88 => OpCategory::Bitwise,
+            0x8b ... 0xa5 => OpCategory::Arithmetic,
+            0xa6 ... 0xaf | 0xba => OpCategory::Crypto,
+            0xb1 => OpCategory::Locktime,
+            0xb0 ... 0xb9 => OpCategory::UpgradableNoOp,
+            _ => OpCategory::Invalid,
+        }
+    }
+
+    // Stack elements needed to run the opcode, the interpreter checks this
+    // before executing it. PICK, ROLL and CHECKMULTISIG check the rest of
+    // their arguments themselves, DROP on an empty stack is a no-op.
+    pub fn min_stack(&self) -> usize {
+        match *self {
+            OpCode::If | OpCode::NotIf | OpCode::Verify | OpCode::ToAltStack |
+            OpCode::IfDup | OpCode::Dup | OpCode::Nip | OpCode::Pick | OpCode::Roll |
+            OpCode::Size | OpCode::_1Add | OpCode::_1Sub | OpCode::_2Mul | OpCode::_2Div |
+            OpCode::Negate | OpCode::Abs | OpCode::Not | OpCode::_0NotEqual |
+            OpCode::Ripemd160 | OpCode::Sha1 | OpCode::Sha256 | OpCode::Hash160 |
+            OpCode::Hash256 => 1,
+
+            OpCode::_2Dup | OpCode::Over | OpCode::Swap |
+            OpCode::Tuck | OpCode::Equal | OpCode::EqualVerify | OpCode::Add | OpCode::Sub |
+            OpCode::LShift | OpCode::RShift | OpCode::BoolAnd | OpCode::BoolOr |
+            OpCode::NumEqual | OpCode::NumEqualVerify | OpCode::NumNotEqual |
+            OpCode::LessThan | OpCode::GreaterThan | OpCode::LessThanOrEqual |
+            OpCode::GreaterThanOrEqual | OpCode::Min | OpCode::Max | OpCode::CheckSig |
+            OpCode::CheckSigVerify | OpCode::CheckMultiSig |
+            OpCode::CheckMultiSigVerify => 2,
+
+            OpCode::_3Dup | OpCode::Rot | OpCode::Within | OpCode::CheckSigAdd => 3,
+            OpCode::_2Over | OpCode::_2Swap => 4,
+            OpCode::_2Rot => 6,
+            _ => 0,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use super::super::*;
 
+    use test::utils::mock_checksig;
+
     use rustc_serialize::base64::FromBase64;
     const ZERO : u8 = 0x80;
 
-    fn mock_checksig(_: usize, _: &Vec<u8>, _: &Vec<u8>) -> bool { true }
+    fn get_stack(stack: Vec<Vec<u8>>) -> Vec<StackItem> {
+        stack.into_iter().map(StackItem::from).collect()
+    }
+
+    fn get_context(stack: Vec<Vec<u8>>) -> Context<'static> {
+        Context::new(vec![], get_stack(stack), &mock_checksig)
+    }
+
+    #[test]
+    fn test_context_debug() {
+        let context = get_context(vec![vec![], vec![0x01], vec![0xff, 0x80],
+                                       vec![0xde, 0xad, 0xbe, 0xef, 0x01]]);
+        let formatted = format!("{:?}", context);
+
+        assert!(formatted.contains(
+            "stack=[<empty> (0), 0x01 (1), 0xff80 (-255), 0xdeadbeef01]"), "{}", formatted);
+    }
+
+    #[test]
+    fn test_category() {
+        assert_eq!(OpCode::Add.category(), OpCategory::Arithmetic);
+        assert_eq!(OpCode::Cat.category(), OpCategory::Disabled);
+        assert_eq!(OpCode::Nop.category(), OpCategory::NoOp);
+
+        assert_eq!(OpCode::_0.category(), OpCategory::Push);
+        assert_eq!(OpCode::_16.category(), OpCategory::Push);
+        assert_eq!(OpCode::Reserved.category(), OpCategory::Reserved);
+        assert_eq!(OpCode::Nop1.category(), OpCategory::UpgradableNoOp);
+        assert_eq!(OpCode::CheckLockTimeVerify.category(), OpCategory::Locktime);
+        assert_eq!(OpCode::Mod.category(), OpCategory::Disabled);
+        assert_eq!(OpCode::CheckSigAdd.category(), OpCategory::Crypto);
+        assert_eq!(OpCode::Invalid80.category(), OpCategory::Invalid);
+    }
 
-    fn get_context(stack: Vec<Vec<u8>>) -> Context {
-        Context::new(vec![], stack, mock_checksig)
+    #[test]
+    fn test_disabled_opcodes() {
+        for op in &["CAT", "SUBSTR", "LEFT", "RIGHT", "INVERT", "AND", "OR", "XOR", "MUL",
+                    "DIV", "MOD"] {
+            let op_code = OpCode::from_str(op).unwrap();
+            assert_eq!(op_code.category(), OpCategory::Disabled);
+
+            let script = vec![0x51, 0x51, op_code.to_byte()];
+            assert_eq!(Parser::execute(vec![], script, &mock_checksig), Ok(false));
+
+            // Even in a branch that is not executed
+            let script = vec![0x00, 0x63, op_code.to_byte(), 0x68, 0x51];
+            assert_eq!(Parser::execute(vec![], script, &mock_checksig), Ok(false));
+        }
+
+        // Reserved opcodes only fail when executed
+        let script = vec![0x00, 0x63, OpCode::Reserved.to_byte(), 0x68, 0x51];
+        assert_eq!(Parser::execute(vec![], script, &mock_checksig), Ok(true));
     }
 
     #[test]
@@ -1118,11 +1411,185 @@ mod tests {
         test_nop(OpCode::Nop);
     }
 
+    #[test]
+    fn test_op_upgradable_nop() {
+        test_nop(OpCode::Nop4);
+
+        let mut context = get_context(vec![vec![0x02], vec![0x03]]);
+        context.flags.discourage_upgradable_nops = true;
+        let output = OpCode::Nop4.execute(context);
+        assert!(!output.valid);
+
+        // Plain NOP is always valid
+        let mut context = get_context(vec![vec![0x02], vec![0x03]]);
+        context.flags.discourage_upgradable_nops = true;
+        let output = OpCode::Nop.execute(context);
+        assert!(output.valid);
+    }
+
+    fn get_pubkey(prefix: u8, length: usize) -> Vec<u8> {
+        let mut key = vec![prefix];
+        key.extend(vec![0x01; length - 1]);
+        key
+    }
+
+    fn test_strictenc_checksig(pub_key: Vec<u8>) -> Context<'static> {
+        let mut context = get_context(vec![vec![0x30], pub_key]);
+        context.flags.strictenc = true;
+        OpCode::CheckSig.execute(context)
+    }
+
+    #[test]
+    fn test_op_checksig_strictenc() {
+        let output = test_strictenc_checksig(get_pubkey(0x02, 33));
+        assert!(output.valid);
+        assert_eq!(output.stack, vec![vec![0x01]]);
+
+        let output = test_strictenc_checksig(get_pubkey(0x04, 65));
+        assert!(output.valid);
+        assert_eq!(output.stack, vec![vec![0x01]]);
+
+        let output = test_strictenc_checksig(get_pubkey(0x04, 64));
+        assert!(!output.valid);
+
+        // Without the flag any key is passed on to checksig
+        let output = OpCode::CheckSig.execute(
+            get_context(vec![vec![0x30], get_pubkey(0x04, 64)]));
+        assert!(output.valid);
+    }
+
+    fn unreachable_checksig(_: &[u8], _: &Vec<u8>, _: &Vec<u8>) -> bool {
+        panic!("checksig should not be called")
+    }
+
+    #[test]
+    fn test_op_checksig_empty_signature() {
+        let context = Context::new(vec![], get_stack(vec![vec![], get_pubkey(0x02, 33)]),
+                                   &unreachable_checksig);
+        let output = OpCode::CheckSig.execute(context);
+        assert!(output.valid);
+        assert_eq!(output.stack, vec![vec![]]);
+
+        // Not an encoding error under STRICTENC either
+        let mut context = Context::new(vec![], get_stack(vec![vec![], get_pubkey(0x02, 33)]),
+                                       &unreachable_checksig);
+        context.flags.strictenc = true;
+        let output = OpCode::CheckSig.execute(context);
+        assert!(output.valid);
+        assert_eq!(output.stack, vec![vec![]]);
+
+        // CHECKSIGVERIFY fails
+        let context = Context::new(vec![], get_stack(vec![vec![], get_pubkey(0x02, 33)]),
+                                   &unreachable_checksig);
+        assert!(!OpCode::CheckSigVerify.execute(context).valid);
+    }
+
+    #[test]
+    fn test_op_checksig_dersig() {
+        // R = 1, S = 1 and SIGHASH_ALL
+        let der = vec![0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01, 0x01];
+
+        let mut context = get_context(vec![der.clone(), get_pubkey(0x02, 33)]);
+        context.flags.dersig = true;
+        let output = OpCode::CheckSig.execute(context);
+        assert!(output.valid);
+        assert_eq!(output.stack, vec![vec![0x01]]);
+
+        let mut context = get_context(vec![vec![0x30], get_pubkey(0x02, 33)]);
+        context.flags.dersig = true;
+        assert!(!OpCode::CheckSig.execute(context).valid);
+
+        // 0 <sig> 1 <key> 1
+        let mut context = get_context(vec![vec![], vec![0x30], vec![0x01],
+                                           get_pubkey(0x02, 33), vec![0x01]]);
+        context.flags.dersig = true;
+        assert!(!OpCode::CheckMultiSig.execute(context).valid);
+
+        let mut context = get_context(vec![vec![], der, vec![0x01],
+                                           get_pubkey(0x02, 33), vec![0x01]]);
+        context.flags.dersig = true;
+        let output = OpCode::CheckMultiSig.execute(context);
+        assert!(output.valid);
+        assert_eq!(output.stack, vec![vec![0x01]]);
+    }
+
+    #[test]
+    fn test_op_checkmultisig_strictenc() {
+        let mut context = get_context(vec![vec![], vec![0x30], vec![0x01],
+                                           get_pubkey(0x03, 33), get_pubkey(0x05, 64),
+                                           vec![0x02]]);
+        context.flags.strictenc = true;
+        let output = OpCode::CheckMultiSig.execute(context);
+        assert!(!output.valid);
+    }
+
+    #[test]
+    fn test_op_checkmultisig_limits() {
+        // 0 <21 keys> 21
+        let mut stack = vec![vec![], vec![]];
+        stack.extend(vec![get_pubkey(0x02, 33); 21]);
+        stack.push(vec![21]);
+        let output = OpCode::CheckMultiSig.execute(get_context(stack));
+        assert!(!output.valid);
+        assert_eq!(output.error().unwrap().kind, ScriptErrorKind::PubKeyCount);
+
+        // 0 <sig> <sig> 2 <key> 1
+        let output = OpCode::CheckMultiSig.execute(get_context(vec![
+            vec![], vec![0x30], vec![0x30], vec![0x02], get_pubkey(0x02, 33), vec![0x01]]));
+        assert!(!output.valid);
+        assert_eq!(output.error().unwrap().kind, ScriptErrorKind::SigCount);
+
+        let output = OpCode::CheckMultiSig.execute(get_context(vec![vec![0x01]]));
+        assert!(!output.valid);
+        assert_eq!(output.error().unwrap().kind, ScriptErrorKind::StackUnderflow);
+
+        // Counts that aren't numbers: 0 0 <5 byte number>, 0 <5 byte number> 0
+        let too_big = vec![0x01, 0x00, 0x00, 0x00, 0x00];
+        let output = OpCode::CheckMultiSig.execute(get_context(vec![
+            vec![], vec![], too_big.clone()]));
+        assert!(!output.valid);
+        assert_eq!(output.error().unwrap().kind, ScriptErrorKind::NumberOverflow);
+
+        let output = OpCode::CheckMultiSig.execute(get_context(vec![
+            vec![], too_big, vec![]]));
+        assert!(!output.valid);
+        assert_eq!(output.error().unwrap().kind, ScriptErrorKind::NumberOverflow);
+
+        // 20 keys are fine
+        let mut stack = vec![vec![], vec![]];
+        stack.extend(vec![get_pubkey(0x02, 33); 20]);
+        stack.push(vec![20]);
+        let output = OpCode::CheckMultiSig.execute(get_context(stack));
+        assert!(output.valid);
+        assert_eq!(output.stack, get_stack(vec![vec![0x01]]));
+    }
+
+    #[test]
+    fn test_op_checkmultisig_underflow() {
+        // 20 keys declared but only 2 on the stack
+        let output = OpCode::CheckMultiSig.execute(get_context(vec![
+            get_pubkey(0x02, 33), get_pubkey(0x03, 33), vec![20]]));
+        assert!(!output.valid);
+        assert_eq!(output.error().unwrap().kind, ScriptErrorKind::StackUnderflow);
+
+        // No room left for the signature count
+        let output = OpCode::CheckMultiSig.execute(get_context(vec![
+            get_pubkey(0x02, 33), vec![0x01]]));
+        assert!(!output.valid);
+        assert_eq!(output.error().unwrap().kind, ScriptErrorKind::StackUnderflow);
+
+        // <sig> 1 <key> 1 without the extra element popped at the end
+        let output = OpCode::CheckMultiSig.execute(get_context(vec![
+            vec![0x30], vec![0x01], get_pubkey(0x02, 33), vec![0x01]]));
+        assert!(!output.valid);
+        assert_eq!(output.error().unwrap().kind, ScriptErrorKind::StackUnderflow);
+    }
+
     #[test]
     fn test_op_pushdata4() {
         let script = vec![0x4e, 0x02, 0x00, 0x00, 0x00, 0x03, 0x04];
-        let context = Context::new(script.clone(), vec![], mock_checksig);
-        let mut expected = Context::new(script, vec![vec![0x03, 0x04]], mock_checksig);
+        let context = Context::new(script.clone(), vec![], &mock_checksig);
+        let mut expected = Context::new(script, get_stack(vec![vec![0x03, 0x04]]), &mock_checksig);
         advance(&mut expected, 6);
 
         let output = OpCode::PushData4.execute(context);
@@ -1132,8 +1599,8 @@ mod tests {
     #[test]
     fn test_op_pushdata2() {
         let script = vec![0x4d, 0x02, 0x00, 0x03, 0x04];
-        let context = Context::new(script.clone(), vec![], mock_checksig);
-        let mut expected = Context::new(script, vec![vec![0x03, 0x04]], mock_checksig);
+        let context = Context::new(script.clone(), vec![], &mock_checksig);
+        let mut expected = Context::new(script, get_stack(vec![vec![0x03, 0x04]]), &mock_checksig);
         advance(&mut expected, 4);
 
         let output = OpCode::PushData2.execute(context);
@@ -1149,8 +1616,8 @@ mod tests {
     #[test]
     fn test_op_pushdata1() {
         let script = vec![0x4c, 0x02, 0x03, 0x04];
-        let context = Context::new(script.clone(), vec![], mock_checksig);
-        let mut expected = Context::new(script, vec![vec![0x03, 0x04]], mock_checksig);
+        let context = Context::new(script.clone(), vec![], &mock_checksig);
+        let mut expected = Context::new(script, get_stack(vec![vec![0x03, 0x04]]), &mock_checksig);
         advance(&mut expected, 3);
 
         let output = OpCode::PushData1.execute(context);
@@ -1159,8 +1626,8 @@ mod tests {
 
     #[test]
     fn test_op_pushdata_generic() {
-        let context = Context::new(vec![0x01, 0x03], vec![], mock_checksig);
-        let mut expected = Context::new(vec![0x01, 0x03], vec![vec![0x03]], mock_checksig);
+        let context = Context::new(vec![0x01, 0x03], vec![], &mock_checksig);
+        let mut expected = Context::new(vec![0x01, 0x03], get_stack(vec![vec![0x03]]), &mock_checksig);
         advance(&mut expected, 1);
 
         let output = OpCode::Push1Byte.execute(context);
@@ -1349,7 +1816,7 @@ mod tests {
     #[test]
     fn test_op_fromaltstack() {
         let mut context = get_context(vec![]);
-        context.altstack = vec![vec![0x01]];
+        context.altstack = get_stack(vec![vec![0x01]]);
 
         assert_eq!(get_context(vec![vec![0x01]]), OpCode::FromAltStack.execute(context));
     }
@@ -1357,7 +1824,7 @@ mod tests {
     #[test]
     fn test_op_toaltstack() {
         let mut expected = get_context(vec![]);
-        expected.altstack = vec![vec![0x01]];
+        expected.altstack = get_stack(vec![vec![0x01]]);
 
         let context = get_context(vec![vec![0x01]]);
 
@@ -1379,15 +1846,122 @@ mod tests {
     #[test]
     fn test_op_codeseparator() {
         let script = vec![0x00, 0x01, 0x02, 0x03, 0x04];
-        let mut context = Context::new(script.clone(), vec![], mock_checksig);
-        let mut expected = Context::new(script.clone(), vec![], mock_checksig);
+        let mut context = Context::new(script.clone(), vec![], &mock_checksig);
+        let mut expected = Context::new(script.clone(), vec![], &mock_checksig);
         for _ in 0..3 {
             context.script.next();
             expected.script.next();
         }
 
-        expected.codeseparator = 4;
+        let output = OpCode::CodeSeparator.execute(context);
+        assert_eq!(expected, output);
+        // The script code starts after the separator
+        assert_eq!(output.codeseparator, 4);
+    }
+
+    fn test_arithmetic_ext(op: OpCode, input: Vec<Vec<u8>>, output: Vec<Vec<u8>>) {
+        let mut context = get_context(input);
+        context.flags.allow_arithmetic_ext = true;
+
+        let mut expected = get_context(output);
+        expected.flags.allow_arithmetic_ext = true;
+
+        assert_eq!(expected, op.execute(context));
+    }
+
+    #[test]
+    fn test_op_lshift() {
+        test_arithmetic_ext(OpCode::LShift, vec![vec![0x03], vec![0x01]], vec![vec![0x06]]);
+        test_arithmetic_ext(OpCode::LShift, vec![vec![0x01], vec![0x08]], vec![vec![0x00, 0x01]]);
+        test_arithmetic_ext(OpCode::LShift, vec![vec![0x85], vec![0x02]], vec![vec![0x94]]);
+        // The result can be longer than 4 bytes
+        test_arithmetic_ext(OpCode::LShift, vec![vec![0x01], vec![0x20]],
+                            vec![vec![0x00, 0x00, 0x00, 0x00, 0x01]]);
+
+        let output = OpCode::LShift.execute(get_context(vec![vec![0x03], vec![0x01]]));
+        assert!(!output.valid);
+    }
+
+    #[test]
+    fn test_op_rshift() {
+        test_arithmetic_ext(OpCode::RShift, vec![vec![0x06], vec![0x01]], vec![vec![0x03]]);
+        test_arithmetic_ext(OpCode::RShift, vec![vec![0x00, 0x01], vec![0x04]], vec![vec![0x10]]);
+        // Rounds towards zero
+        test_arithmetic_ext(OpCode::RShift, vec![vec![0x85], vec![0x01]], vec![vec![0x82]]);
+        test_arithmetic_ext(OpCode::RShift, vec![vec![0x01], vec![0x02]], vec![vec![]]);
+
+        let output = OpCode::RShift.execute(get_context(vec![vec![0x06], vec![0x01]]));
+        assert!(!output.valid);
+    }
+
+    fn equal_checksig(_: &[u8], x: &Vec<u8>, y: &Vec<u8>) -> bool { x.eq(y) }
+
+    fn test_checksigadd(stack: Vec<Vec<u8>>) -> Context<'static> {
+        let mut context = Context::new(vec![], get_stack(stack), &equal_checksig);
+        context.flags.tapscript = true;
+        OpCode::CheckSigAdd.execute(context)
+    }
+
+    #[test]
+    fn test_op_checksigadd() {
+        let output = test_checksigadd(vec![vec![0x0a], vec![0x02], vec![0x0a]]);
+        assert!(output.valid);
+        assert_eq!(output.stack, vec![vec![0x03]]);
 
-        assert_eq!(expected, OpCode::CodeSeparator.execute(context));
+        let output = test_checksigadd(vec![vec![0x0a], vec![0x02], vec![0x0b]]);
+        assert!(output.valid);
+        assert_eq!(output.stack, vec![vec![0x02]]);
+
+        let output = test_checksigadd(vec![vec![0x0a], vec![], vec![0x0a]]);
+        assert_eq!(output.stack, vec![vec![0x01]]);
+
+        let output = OpCode::CheckSigAdd.execute(
+            get_context(vec![vec![0x0a], vec![0x02], vec![0x0a]]));
+        assert!(!output.valid);
+    }
+
+    #[test]
+    fn test_op_within() {
+        // 5 0 10 WITHIN
+        test_stack_base(OpCode::Within, vec![vec![0x05], vec![], vec![0x0a]], vec![vec![0x01]]);
+        // The lower bound is included, the upper one is not
+        test_stack_base(OpCode::Within, vec![vec![], vec![], vec![0x0a]], vec![vec![0x01]]);
+        test_stack_base(OpCode::Within, vec![vec![0x0a], vec![], vec![0x0a]], vec![vec![]]);
+        // -1 -100 100 WITHIN
+        test_stack_base(OpCode::Within, vec![vec![0x81], vec![0xe4], vec![0x64]], vec![vec![0x01]]);
+        // 2147483647 -100 100 WITHIN
+        test_stack_base(OpCode::Within, vec![vec![0xff, 0xff, 0xff, 0x7f], vec![0xe4], vec![0x64]],
+                        vec![vec![]]);
+        // 5 10 0 WITHIN, a reversed range doesn't contain anything
+        test_stack_base(OpCode::Within, vec![vec![0x05], vec![0x0a], vec![]], vec![vec![]]);
+
+        let output = OpCode::Within.execute(
+            get_context(vec![vec![0x00, 0x00, 0x00, 0x00, 0x01], vec![], vec![0x0a]]));
+        assert!(!output.valid);
+    }
+
+    #[test]
+    fn test_comparison_operand_order() {
+        // 1 2 LESSTHAN, the top of the stack is the right operand
+        test_stack_base(OpCode::LessThan, vec![vec![0x01], vec![0x02]], vec![vec![0x01]]);
+        test_stack_base(OpCode::LessThan, vec![vec![0x02], vec![0x01]], vec![vec![]]);
+        test_stack_base(OpCode::GreaterThan, vec![vec![0x02], vec![0x01]], vec![vec![0x01]]);
+        test_stack_base(OpCode::LessThanOrEqual, vec![vec![0x81], vec![0x81]], vec![vec![0x01]]);
+        test_stack_base(OpCode::GreaterThanOrEqual, vec![vec![0x81], vec![]], vec![vec![]]);
+        test_stack_base(OpCode::Sub, vec![vec![0x05], vec![0x02]], vec![vec![0x03]]);
+    }
+
+    #[test]
+    fn test_op_else_without_if() {
+        let output = OpCode::Else.execute(get_context(vec![vec![0x01]]));
+        assert!(!output.valid);
+        assert_eq!(output.stack, vec![vec![0x01]]);
+    }
+
+    #[test]
+    fn test_op_endif_without_if() {
+        let output = OpCode::EndIf.execute(get_context(vec![vec![0x01]]));
+        assert!(!output.valid);
+        assert_eq!(output.stack, vec![vec![0x01]]);
     }
 }
@@ -0,0 +1,161 @@
+use super::secp256k1::{lift_x, sub_mod, Point, U256, G_X, G_Y, N, P, ZERO};
+use utils::CryptoUtils;
+
+// BIP340 Schnorr signature verification over secp256k1.
+//
+// Only verification is implemented, all its inputs are public. The
+// arithmetic isn't constant time so it can't be reused to sign.
+
+fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = CryptoUtils::sha256(tag.as_bytes());
+
+    let mut input = vec![];
+    input.extend(tag_hash.iter());
+    input.extend(tag_hash.iter());
+    input.extend(data);
+
+    CryptoUtils::sha256(&input)
+}
+
+pub fn schnorr_checksig(msg: &[u8; 32], pubkey: &[u8; 32], sig: &[u8; 64]) -> bool {
+    let p = match lift_x(&U256::from_bytes(pubkey)) {
+        Some(p) => p,
+        None => return false,
+    };
+
+    let r = U256::from_bytes(&sig[0..32]);
+    let s = U256::from_bytes(&sig[32..64]);
+
+    if r >= P || s >= N {
+        return false;
+    }
+
+    let mut challenge = vec![];
+    challenge.extend(&sig[0..32]);
+    challenge.extend(pubkey.iter());
+    challenge.extend(msg.iter());
+
+    // The hash is less than 2 * N so one subtraction is enough
+    let mut e = U256::from_bytes(&tagged_hash("BIP0340/challenge", &challenge));
+    if e >= N {
+        e = e.overflowing_sub(&N).0;
+    }
+
+    let g = Point::from_affine(G_X, G_Y);
+    let point = g.mul(&s).add(&p.mul(&sub_mod(&ZERO, &e, &N)));
+
+    match point.to_affine() {
+        Some((x, y)) => !y.is_odd() && x == r,
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustc_serialize::hex::FromHex;
+
+    fn to_array(hex: &str, array: &mut [u8]) {
+        array.copy_from_slice(&hex.from_hex().unwrap());
+    }
+
+    fn test_vector(pubkey: &str, msg: &str, sig: &str) -> bool {
+        let mut pubkey_array = [0u8; 32];
+        let mut msg_array = [0u8; 32];
+        let mut sig_array = [0u8; 64];
+
+        to_array(pubkey, &mut pubkey_array);
+        to_array(msg, &mut msg_array);
+        to_array(sig, &mut sig_array);
+
+        schnorr_checksig(&msg_array, &pubkey_array, &sig_array)
+    }
+
+    #[test]
+    fn test_bip340_vectors() {
+        // Test vectors 0 and 1 from BIP340
+        assert!(test_vector(
+            "F9308A019258C31049344F85F89D5229B531C845836F99B08601F113BCE036F9",
+            "0000000000000000000000000000000000000000000000000000000000000000",
+            "E907831F80848D1069A5371B402410364BDF1C5F8307B0084C55F1CE2DCA8215\
+             25F66A4A85EA8B71E482A74F382D2CE5EBEEE8FDB2172F477DF4900D310536C0"));
+
+        assert!(test_vector(
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE3341\
+             8906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A"));
+
+        // Test vectors 2 to 4
+        assert!(test_vector(
+            "DD308AFEC5777E13121FA72B9CC1B7CC0139715309B086C960E18FD969774EB8",
+            "7E2D58D8B3BCDF1ABADEC7829054F90DDA9805AAB56C77333024B9D0A508B75C",
+            "5831AAEED7B44BB74E5EAB94BA9D4294C49BCF2A60728D8B4C200F50DD313C1B\
+             AB745879A5AD954A72C45A91C3A51D3C7ADEA98D82F8481E0E1E03674A6F3FB7"));
+
+        assert!(test_vector(
+            "25D1DFF95105F5253C4022F628A996AD3A0D95FBF21D468A1B33F8C160D8F517",
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF",
+            "7EB0509757E246F19449885651611CB965ECC1A187DD51B64FDA1EDC9637D5EC\
+             97582B9CB13DB3933705B32BA982AF5AF25FD78881EBB32771FC5922EFC66EA3"));
+
+        assert!(test_vector(
+            "D69C3509BB99E412E68B0FE8544E72837DFA30746D8BE2AA65975F29D22DC7B9",
+            "4DF3C3F68FCC83B27E9D42C90431A72499F17875C81A599B566C9889B9696703",
+            "00000000000000000000003B78CE563F89A0ED9414F5AA28AD0D96D6795F9C63\
+             76AFB1548AF603B3EB45C9F8207DEE1060CB71C04E80F593060B07D28308D7F4"));
+    }
+
+    #[test]
+    fn test_bip340_invalid() {
+        // Tampered signature
+        assert!(!test_vector(
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE3341\
+             8906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0B"));
+
+        // Different message
+        assert!(!test_vector(
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C88",
+            "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE3341\
+             8906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A"));
+
+        // Public key not on the curve (test vector 5)
+        assert!(!test_vector(
+            "EEFDEA4CDB677750A420FEE807EACF21EB9898AE79B9768766E4FAA04A2D4A34",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "6896BD60EEAE296DB48A229FF71DFE071BDE413E6D43F917DC8DCF8C78DE3341\
+             8906D11AC976ABCCB20B091292BFF4EA897EFCB639EA871CFA95F6DE339E4B0A"));
+
+        // R has an odd y (test vector 6)
+        assert!(!test_vector(
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "FFF97BD5755EEEA420453A14355235D382F6472F8568A18B2F057A1460297556\
+             3CC27944640AC607CD107AE10923D9EF7A73C643E166BE5EBEAFA34B1AC553E2"));
+
+        // R is the field size (test vector 12)
+        assert!(!test_vector(
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2F\
+             69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B"));
+
+        // S is the curve order (test vector 13)
+        assert!(!test_vector(
+            "DFF1D77F2A671C5F36183726DB2341BE58FEAE1DA2DECED843240F7B502BA659",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+             FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEBAAEDCE6AF48A03BBFD25E8CD0364141"));
+
+        // Public key exceeds the field size (test vector 14)
+        assert!(!test_vector(
+            "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC30",
+            "243F6A8885A308D313198A2E03707344A4093822299F31D0082EFA98EC4E6C89",
+            "6CFF5C3BA86C69EA4B7376F31A9BCB4F74C1976089B2D9963DA2E5543E177769\
+             69E89B4C5564D00349106B8497785DD7D1D713A8AE82B32FA79D5F7FC407D39B"));
+    }
+}
@@ -0,0 +1,283 @@
+use std::cmp::Ordering;
+
+// Arithmetic on secp256k1, shared by the signature schemes.
+//
+// Nothing here runs in constant time, the arithmetic is kept as simple as
+// possible. Anything derived from a private key leaks through timing.
+
+// 256 bit unsigned integer, limbs are stored least significant first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct U256(pub [u64; 4]);
+
+pub const ZERO: U256 = U256([0, 0, 0, 0]);
+pub const ONE: U256 = U256([1, 0, 0, 0]);
+
+// Field size
+pub const P: U256 = U256([0xfffffffefffffc2f, 0xffffffffffffffff,
+                      0xffffffffffffffff, 0xffffffffffffffff]);
+// Group order
+pub const N: U256 = U256([0xbfd25e8cd0364141, 0xbaaedce6af48a03b,
+                      0xfffffffffffffffe, 0xffffffffffffffff]);
+// (P + 1) / 4, used to compute square roots since P = 3 mod 4
+pub const SQRT_EXP: U256 = U256([0xffffffffbfffff0c, 0xffffffffffffffff,
+                             0xffffffffffffffff, 0x3fffffffffffffff]);
+// N - 2, used to compute inverses modulo the group order
+pub const N_INV_EXP: U256 = U256([0xbfd25e8cd036413f, 0xbaaedce6af48a03b,
+                                  0xfffffffffffffffe, 0xffffffffffffffff]);
+// P - 2, used to compute inverses
+pub const INV_EXP: U256 = U256([0xfffffffefffffc2d, 0xffffffffffffffff,
+                            0xffffffffffffffff, 0xffffffffffffffff]);
+
+pub const G_X: U256 = U256([0x59f2815b16f81798, 0x029bfcdb2dce28d9,
+                        0x55a06295ce870b07, 0x79be667ef9dcbbac]);
+pub const G_Y: U256 = U256([0x9c47d08ffb10d4b8, 0xfd17b448a6855419,
+                        0x5da4fbfc0e1108a8, 0x483ada7726a3c465]);
+
+impl U256 {
+    pub fn from_bytes(bytes: &[u8]) -> U256 {
+        assert_eq!(bytes.len(), 32);
+
+        let mut limbs = [0u64; 4];
+        for (i, byte) in bytes.iter().enumerate() {
+            let limb = 3 - i / 8;
+            limbs[limb] = (limbs[limb] << 8) | *byte as u64;
+        }
+
+        U256(limbs)
+    }
+
+    // Big endian, like from_bytes expects. Only signing needs it.
+    #[cfg(test)]
+    pub fn to_bytes(&self) -> [u8; 32] {
+        let mut bytes = [0u8; 32];
+        for i in 0..32 {
+            bytes[i] = (self.0[3 - i / 8] >> (56 - 8 * (i % 8))) as u8;
+        }
+
+        bytes
+    }
+
+    pub fn bit(&self, i: usize) -> bool {
+        (self.0[i / 64] >> (i % 64)) & 1 == 1
+    }
+
+    pub fn is_odd(&self) -> bool { self.bit(0) }
+
+    pub fn overflowing_add(&self, other: &U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut carry = false;
+
+        for i in 0..4 {
+            let (sum, c1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, c2) = sum.overflowing_add(carry as u64);
+            result[i] = sum;
+            carry = c1 || c2;
+        }
+
+        (U256(result), carry)
+    }
+
+    pub fn overflowing_sub(&self, other: &U256) -> (U256, bool) {
+        let mut result = [0u64; 4];
+        let mut borrow = false;
+
+        for i in 0..4 {
+            let (diff, b1) = self.0[i].overflowing_sub(other.0[i]);
+            let (diff, b2) = diff.overflowing_sub(borrow as u64);
+            result[i] = diff;
+            borrow = b1 || b2;
+        }
+
+        (U256(result), borrow)
+    }
+}
+
+impl PartialOrd for U256 {
+    fn partial_cmp(&self, other: &U256) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for U256 {
+    fn cmp(&self, other: &U256) -> Ordering {
+        for i in (0..4).rev() {
+            match self.0[i].cmp(&other.0[i]) {
+                Ordering::Equal => continue,
+                x => return x,
+            }
+        }
+
+        Ordering::Equal
+    }
+}
+
+// All the modular functions expect inputs already reduced modulo m
+pub fn add_mod(a: &U256, b: &U256, m: &U256) -> U256 {
+    let (sum, carry) = a.overflowing_add(b);
+    if carry || sum >= *m {
+        sum.overflowing_sub(m).0
+    } else {
+        sum
+    }
+}
+
+pub fn sub_mod(a: &U256, b: &U256, m: &U256) -> U256 {
+    let (diff, borrow) = a.overflowing_sub(b);
+    if borrow {
+        diff.overflowing_add(m).0
+    } else {
+        diff
+    }
+}
+
+pub fn mul_mod(a: &U256, b: &U256, m: &U256) -> U256 {
+    let mut result = ZERO;
+    for i in (0..256).rev() {
+        result = add_mod(&result, &result, m);
+        if b.bit(i) {
+            result = add_mod(&result, a, m);
+        }
+    }
+
+    result
+}
+
+pub fn pow_mod(a: &U256, exponent: &U256, m: &U256) -> U256 {
+    let mut result = ONE;
+    for i in (0..256).rev() {
+        result = mul_mod(&result, &result, m);
+        if exponent.bit(i) {
+            result = mul_mod(&result, a, m);
+        }
+    }
+
+    result
+}
+
+pub fn fmul(a: &U256, b: &U256) -> U256 { mul_mod(a, b, &P) }
+pub fn fadd(a: &U256, b: &U256) -> U256 { add_mod(a, b, &P) }
+pub fn fsub(a: &U256, b: &U256) -> U256 { sub_mod(a, b, &P) }
+
+// Point in jacobian coordinates, z == 0 is the point at infinity
+#[derive(Debug, Clone, Copy)]
+pub struct Point {
+    pub x: U256,
+    pub y: U256,
+    pub z: U256,
+}
+
+pub const INFINITY: Point = Point { x: ONE, y: ONE, z: ZERO };
+
+impl Point {
+    pub fn from_affine(x: U256, y: U256) -> Point {
+        Point { x: x, y: y, z: ONE }
+    }
+
+    pub fn is_infinity(&self) -> bool { self.z == ZERO }
+
+    pub fn to_affine(&self) -> Option<(U256, U256)> {
+        if self.is_infinity() {
+            return None;
+        }
+
+        let z_inv = pow_mod(&self.z, &INV_EXP, &P);
+        let z_inv2 = fmul(&z_inv, &z_inv);
+
+        Some((fmul(&self.x, &z_inv2), fmul(&self.y, &fmul(&z_inv2, &z_inv))))
+    }
+
+    pub fn double(&self) -> Point {
+        if self.is_infinity() || self.y == ZERO {
+            return INFINITY;
+        }
+
+        let y2 = fmul(&self.y, &self.y);
+        let s = fmul(&fmul(&self.x, &y2), &U256([4, 0, 0, 0]));
+        let x2 = fmul(&self.x, &self.x);
+        let m = fadd(&fadd(&x2, &x2), &x2);
+
+        let x = fsub(&fmul(&m, &m), &fadd(&s, &s));
+        let y = fsub(&fmul(&m, &fsub(&s, &x)),
+                     &fmul(&fmul(&y2, &y2), &U256([8, 0, 0, 0])));
+        let z = fmul(&fadd(&self.y, &self.y), &self.z);
+
+        Point { x: x, y: y, z: z }
+    }
+
+    pub fn add(&self, other: &Point) -> Point {
+        if self.is_infinity() {
+            return *other;
+        }
+
+        if other.is_infinity() {
+            return *self;
+        }
+
+        let z1z1 = fmul(&self.z, &self.z);
+        let z2z2 = fmul(&other.z, &other.z);
+        let u1 = fmul(&self.x, &z2z2);
+        let u2 = fmul(&other.x, &z1z1);
+        let s1 = fmul(&self.y, &fmul(&z2z2, &other.z));
+        let s2 = fmul(&other.y, &fmul(&z1z1, &self.z));
+
+        if u1 == u2 {
+            return if s1 == s2 { self.double() } else { INFINITY };
+        }
+
+        let h = fsub(&u2, &u1);
+        let r = fsub(&s2, &s1);
+        let h2 = fmul(&h, &h);
+        let h3 = fmul(&h2, &h);
+        let u1h2 = fmul(&u1, &h2);
+
+        let x = fsub(&fsub(&fmul(&r, &r), &h3), &fadd(&u1h2, &u1h2));
+        let y = fsub(&fmul(&r, &fsub(&u1h2, &x)), &fmul(&s1, &h3));
+        let z = fmul(&h, &fmul(&self.z, &other.z));
+
+        Point { x: x, y: y, z: z }
+    }
+
+    pub fn mul(&self, k: &U256) -> Point {
+        let mut result = INFINITY;
+        for i in (0..256).rev() {
+            result = result.double();
+            if k.bit(i) {
+                result = result.add(self);
+            }
+        }
+
+        result
+    }
+}
+
+// Returns the point with the given x coordinate and an even y
+pub fn lift_x(x: &U256) -> Option<Point> {
+    if *x >= P {
+        return None;
+    }
+
+    let c = fadd(&fmul(&fmul(x, x), x), &U256([7, 0, 0, 0]));
+    let y = pow_mod(&c, &SQRT_EXP, &P);
+
+    if fmul(&y, &y) != c {
+        return None;
+    }
+
+    let y = if y.is_odd() { fsub(&ZERO, &y) } else { y };
+    Some(Point::from_affine(*x, y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustc_serialize::hex::FromHex;
+
+    #[test]
+    fn test_u256_from_bytes() {
+        let bytes = "FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFEFFFFFC2F"
+            .from_hex().unwrap();
+        assert_eq!(U256::from_bytes(&bytes), P);
+        assert_eq!(P.to_bytes().to_vec(), bytes);
+    }
+}
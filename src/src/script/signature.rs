@@ -0,0 +1,122 @@
+// Encoding rules that make signatures and pushes unique, anything else could
+// be rewritten by a third party without invalidating the transaction.
+
+// Half the order of secp256k1, bigger S values have an equivalent lower one
+const HALF_ORDER: [u8; 32] = [0x7f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                              0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+                              0x5d, 0x57, 0x6e, 0x73, 0x57, 0xa4, 0x50, 0x1d,
+                              0xdf, 0xe9, 0x2f, 0x46, 0x68, 0x1b, 0x20, 0xa0];
+
+// Strict DER signature followed by the hash type (BIP66):
+// 0x30 <total length> 0x02 <R length> <R> 0x02 <S length> <S> <hash type>
+pub fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+
+    if sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+
+    let r_length = sig[3] as usize;
+    if 5 + r_length >= sig.len() {
+        return false;
+    }
+
+    let s_length = sig[5 + r_length] as usize;
+    if r_length + s_length + 7 != sig.len() {
+        return false;
+    }
+
+    is_valid_integer(&sig[2..4 + r_length]) && is_valid_integer(&sig[4 + r_length..sig.len() - 1])
+}
+
+// 0x02 <length> <value>, positive and without unneeded leading zeros
+fn is_valid_integer(element: &[u8]) -> bool {
+    let value = &element[2..];
+
+    if element[0] != 0x02 || value.is_empty() || value[0] & 0x80 != 0 {
+        return false;
+    }
+
+    !(value.len() > 1 && value[0] == 0x00 && value[1] & 0x80 == 0)
+}
+
+// Expects a valid encoding, see is_valid_signature_encoding
+pub fn is_low_s(sig: &[u8]) -> bool {
+    let r_length = sig[3] as usize;
+    let s_length = sig[5 + r_length] as usize;
+    let s = &sig[6 + r_length..6 + r_length + s_length];
+
+    // Leading zeros only keep S positive
+    let start = s.iter().position(|byte| *byte != 0x00).unwrap_or(s.len());
+    let s = &s[start..];
+
+    s.len() < HALF_ORDER.len() || (s.len() == HALF_ORDER.len() && s <= &HALF_ORDER[..])
+}
+
+// Whether `op` is the smallest way of pushing `data`
+pub fn is_minimal_push(op: u8, data: &[u8]) -> bool {
+    match data.len() {
+        0 => op == 0x00,
+        1 if data[0] >= 1 && data[0] <= 16 => op == 0x50 + data[0],
+        1 if data[0] == 0x81 => op == 0x4f,
+        length if length <= 75 => op as usize == length,
+        length if length <= 0xff => op == 0x4c,
+        length if length <= 0xffff => op == 0x4d,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test::utils::get_sig;
+
+    #[test]
+    fn test_signature_encoding() {
+        assert!(is_valid_signature_encoding(&get_sig(&[0x01])));
+        assert!(is_valid_signature_encoding(&get_sig(&[0x00, 0x80])));
+
+        // Negative, padded and empty S
+        assert!(!is_valid_signature_encoding(&get_sig(&[0x80])));
+        assert!(!is_valid_signature_encoding(&get_sig(&[0x00, 0x01])));
+        assert!(!is_valid_signature_encoding(&get_sig(&[])));
+
+        let mut sig = get_sig(&[0x01]);
+        sig[1] += 1;
+        assert!(!is_valid_signature_encoding(&sig));
+        sig.push(0x01);
+        assert!(!is_valid_signature_encoding(&sig));
+    }
+
+    #[test]
+    fn test_low_s() {
+        assert!(is_low_s(&get_sig(&[0x01])));
+        assert!(is_low_s(&get_sig(&HALF_ORDER)));
+
+        let mut s = HALF_ORDER;
+        s[31] += 1;
+        assert!(!is_low_s(&get_sig(&s)));
+
+        let mut high = vec![0x00];
+        high.extend(&[0xff; 32]);
+        assert!(!is_low_s(&get_sig(&high)));
+    }
+
+    #[test]
+    fn test_minimal_push() {
+        assert!(is_minimal_push(0x00, &[]));
+        assert!(is_minimal_push(0x51, &[0x01]));
+        assert!(is_minimal_push(0x4f, &[0x81]));
+        assert!(is_minimal_push(0x01, &[0x11]));
+        assert!(is_minimal_push(0x4c, &[0x00; 76]));
+        assert!(is_minimal_push(0x4d, &[0x00; 256]));
+
+        assert!(!is_minimal_push(0x01, &[0x01]));
+        assert!(!is_minimal_push(0x01, &[0x81]));
+        assert!(!is_minimal_push(0x4c, &[]));
+        assert!(!is_minimal_push(0x4c, &[0x00; 75]));
+        assert!(!is_minimal_push(0x4e, &[0x00; 256]));
+    }
+}
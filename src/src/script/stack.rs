@@ -0,0 +1,224 @@
+use utils::IntUtils;
+
+use super::op_codes::OpCode;
+
+use std::error;
+use std::fmt;
+use std::ops::Deref;
+
+// Numbers read from the stack can't be longer than this, results of
+// arithmetic operations can.
+pub const MAX_NUMBER_SIZE: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScriptErrorKind {
+    // The element is too long to be interpreted as a number
+    NumberOverflow,
+    // Not enough elements on the stack for the operation
+    StackUnderflow,
+    // FROMALTSTACK with an empty altstack
+    AltStackUnderflow,
+    // CHECKMULTISIG with a negative or more than 20 keys
+    PubKeyCount,
+    // CHECKMULTISIG with a negative or bigger than the keys signature count
+    SigCount,
+    // The scripts ran but didn't leave true on the stack
+    EvalFalse,
+    // The script can't be parsed, e.g. a push past its end
+    BadOpcode,
+    // The spent outputs don't match the inputs of the transaction
+    BadPrevouts,
+}
+
+// Why a script failed and, once the interpreter knows it, where
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScriptError {
+    pub kind: ScriptErrorKind,
+    // The failing opcode and its index in the script
+    pub position: Option<(OpCode, usize)>,
+}
+
+impl ScriptError {
+    pub fn new(kind: ScriptErrorKind) -> ScriptError {
+        ScriptError {
+            kind: kind,
+            position: None,
+        }
+    }
+
+    pub fn at(self, op_code: OpCode, pc: usize) -> ScriptError {
+        ScriptError {
+            kind: self.kind,
+            position: Some((op_code, pc)),
+        }
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.position {
+            Some((op_code, pc)) => write!(f, "{:?} at op {} (pc={})", self.kind, op_code.name(), pc),
+            None => write!(f, "{:?}", self.kind),
+        }
+    }
+}
+
+impl error::Error for ScriptError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ScriptErrorKind::NumberOverflow => "number overflow",
+            ScriptErrorKind::StackUnderflow => "stack underflow",
+            ScriptErrorKind::AltStackUnderflow => "altstack underflow",
+            ScriptErrorKind::PubKeyCount => "pub key count out of range",
+            ScriptErrorKind::SigCount => "signature count out of range",
+            ScriptErrorKind::EvalFalse => "script evaluated to false",
+            ScriptErrorKind::BadOpcode => "malformed script",
+            ScriptErrorKind::BadPrevouts => "spent outputs don't match the inputs",
+        }
+    }
+}
+
+// Element of the script stack, numbers are little endian with the sign in
+// the most significant bit (CScriptNum in the official client).
+#[derive(Clone, PartialEq)]
+pub struct StackItem {
+    data: Vec<u8>,
+}
+
+impl StackItem {
+    pub fn new(data: Vec<u8>) -> StackItem {
+        StackItem {
+            data: data,
+        }
+    }
+
+    pub fn from_number(number: i64) -> StackItem {
+        StackItem::new(IntUtils::to_vec_u8(number))
+    }
+
+    pub fn from_bool(value: bool) -> StackItem {
+        if value {
+            StackItem::new(vec![0x01])
+        } else {
+            StackItem::new(vec![])
+        }
+    }
+
+    pub fn as_number(&self) -> Result<i64, ScriptError> {
+        if self.data.len() > MAX_NUMBER_SIZE {
+            return Err(ScriptError::new(ScriptErrorKind::NumberOverflow));
+        }
+
+        Ok(IntUtils::to_i32(&self.data) as i64)
+    }
+
+    // Anything but zero or negative zero, of any length, is true
+    pub fn as_bool(&self) -> bool {
+        let last = match self.data.last() {
+            Some(last) => *last,
+            None => return false,
+        };
+
+        if self.data[..self.data.len() - 1].iter().any(|x| *x != 0x00) {
+            return true;
+        }
+
+        last != 0x00 && last != 0x80
+    }
+}
+
+// Hex, followed by the value when the element can be read as a number
+impl fmt::Debug for StackItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.data.is_empty() {
+            try!(write!(f, "<empty>"));
+        } else {
+            try!(write!(f, "0x"));
+            for byte in &self.data {
+                try!(write!(f, "{:02x}", byte));
+            }
+        }
+
+        match self.as_number() {
+            Ok(number) => write!(f, " ({})", number),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl Deref for StackItem {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.data
+    }
+}
+
+impl From<Vec<u8>> for StackItem {
+    fn from(data: Vec<u8>) -> StackItem {
+        StackItem::new(data)
+    }
+}
+
+impl PartialEq<Vec<u8>> for StackItem {
+    fn eq(&self, other: &Vec<u8>) -> bool {
+        self.data == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(data: Vec<u8>) -> StackItem {
+        StackItem::new(data)
+    }
+
+    #[test]
+    fn test_as_number() {
+        assert_eq!(item(vec![]).as_number(), Ok(0));
+        assert_eq!(item(vec![0x81]).as_number(), Ok(-1));
+        assert_eq!(item(vec![0xff, 0x00]).as_number(), Ok(255));
+        assert_eq!(item(vec![0xff, 0xff, 0xff, 0x7f]).as_number(), Ok(0x7fffffff));
+        assert_eq!(item(vec![0xff, 0xff, 0xff, 0xff]).as_number(), Ok(-0x7fffffff));
+
+        let overflow = Err(ScriptError::new(ScriptErrorKind::NumberOverflow));
+        assert_eq!(item(vec![0x00, 0x00, 0x00, 0x00, 0x01]).as_number(), overflow);
+        assert_eq!(item(vec![0x00; 5]).as_number(), overflow);
+    }
+
+    #[test]
+    fn test_error_display() {
+        let error = ScriptError::new(ScriptErrorKind::StackUnderflow);
+        assert_eq!(error.to_string(), "StackUnderflow");
+        assert_eq!(error.at(OpCode::Add, 5).to_string(), "StackUnderflow at op ADD (pc=5)");
+    }
+
+    #[test]
+    fn test_from_number() {
+        for n in &[0, 1, -1, 127, 128, -128, 255, 0x7fffffff, -0x7fffffff] {
+            assert_eq!(StackItem::from_number(*n).as_number(), Ok(*n));
+        }
+
+        assert_eq!(StackItem::from_number(0), vec![]);
+        assert_eq!(StackItem::from_number(-1), vec![0x81]);
+        assert_eq!(StackItem::from_number(128), vec![0x80, 0x00]);
+    }
+
+    #[test]
+    fn test_as_bool() {
+        assert!(!item(vec![]).as_bool());
+        assert!(!item(vec![0x80]).as_bool());
+        assert!(!item(vec![0x00, 0x00, 0x80]).as_bool());
+        assert!(!item(vec![0x00]).as_bool());
+        assert!(!item(vec![0x00, 0x00]).as_bool());
+        assert!(!item(vec![0x00, 0x80]).as_bool());
+
+        assert!(item(vec![0x01]).as_bool());
+        assert!(item(vec![0x00, 0x01]).as_bool());
+        assert!(item(vec![0x80, 0x00]).as_bool());
+        assert!(item(vec![0x81]).as_bool());
+        // Long elements are fine, they are not numbers
+        assert!(item(vec![0x00, 0x00, 0x00, 0x00, 0x01]).as_bool());
+    }
+}
@@ -1,18 +1,49 @@
 use rustc_serialize::json::Json;
+use script::ScriptFlags;
+use std::fs::File;
 use std::io::Read;
+use std::path::PathBuf;
 
-use hyper::Client;
-use hyper::header::Connection;
-
+// The vectors are vendored in tests/data, in the layout of Bitcoin Core's
+// src/test/data so that they can be swapped for the upstream files.
 pub struct Tester;
 
 impl Tester {
     pub fn test<F> (callback: F) -> i32
     where F: Fn(&str, &str, &str) -> bool {
-        let data = Tester::download_test();
+        Tester::test_file("script_valid.json", callback).0
+    }
+
+    // The callback should return true when the script is rejected. Returns the
+    // number of rejected scripts and the total number of scripts.
+    pub fn test_invalid<F> (callback: F) -> (i32, i32)
+    where F: Fn(&str, &str, &str) -> bool {
+        Tester::test_file("script_invalid.json", callback)
+    }
+
+    // Flags are named as in the official client, see script_tests.cpp
+    pub fn parse_flags(flags: &str) -> Result<ScriptFlags, String> {
+        let mut result = ScriptFlags::default();
+        for flag in flags.split(',').filter(|flag| !flag.is_empty()) {
+            match flag {
+                "NONE" => {},
+                "P2SH" => result.p2sh = true,
+                "STRICTENC" => result.strictenc = true,
+                "DERSIG" => result.dersig = true,
+                "DISCOURAGE_UPGRADABLE_NOPS" => result.discourage_upgradable_nops = true,
+                _ => return Err(format!("Unknown flag {}", flag)),
+            }
+        }
+        Ok(result)
+    }
+
+    fn test_file<F> (name: &str, callback: F) -> (i32, i32)
+    where F: Fn(&str, &str, &str) -> bool {
+        let data = Tester::load_test(name);
         let json = Tester::get_array(Json::from_str(&data).unwrap());
 
         let mut passed = 0;
+        let mut total = 0;
         let mut failed = vec![];
         for test in json {
             let mut test_array = Tester::get_array(test);
@@ -22,38 +53,38 @@ impl Tester {
             if test_array.len() > 1 {
                 let script_sig = Tester::get_string(test_array.pop().unwrap());
                 let script_pub_key = Tester::get_string(test_array.pop().unwrap());
-                // let flags = Tester::get_string(test_array.pop().unwrap());
-
-                if callback(&script_sig, &script_pub_key, "") {
+                let flags = test_array.pop().map(Tester::get_string).unwrap_or_default();
+                if callback(&script_sig, &script_pub_key, &flags) {
                     passed += 1;
                 } else {
                     failed.push((script_sig, script_pub_key));
                 }
+
+                total += 1;
             }
             print!("passed={}\n", passed);
         }
 
         println!("=====================================");
-        println!("Failed tests.");
+        println!("Failed tests in {}.", name);
         println!("=====================================");
         for t in failed {
             println!("sig=`{}`, pub_key=`{}`\n", t.0, t.1);
         }
         println!("=====================================");
 
-        passed
+        (passed, total)
     }
 
-    fn download_test() -> String {
-        let client = Client::new();
-
-        let mut res = client.get("https://raw.githubusercontent.com/bitcoin/bitcoin/master/src/test/data/script_valid.json")
-            .header(Connection::close())
-            .send().unwrap();
+    fn data_path(name: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("src/script/tests/data")
+            .join(name)
+    }
 
+    fn load_test(name: &str) -> String {
         let mut body = String::new();
-        res.read_to_string(&mut body).unwrap();
-
+        File::open(Tester::data_path(name)).unwrap().read_to_string(&mut body).unwrap();
         body
     }
 
@@ -0,0 +1,39 @@
+// Conversions for the fields the protocol doesn't send little-endian.
+// Integers are little-endian everywhere except ports and IP addresses, and
+// hashes are displayed with their bytes reversed.
+use std::net::Ipv6Addr;
+
+use super::{Deserializer, Error, Serializer};
+
+pub fn u16_to_be(x: u16) -> [u8; 2] {
+    [(x >> 8) as u8, x as u8]
+}
+
+pub fn u16_from_be(data: [u8; 2]) -> u16 {
+    (data[0] as u16) << 8 | data[1] as u16
+}
+
+pub fn ipv6_to_be(address: &Ipv6Addr) -> [u8; 16] {
+    address.octets()
+}
+
+pub fn ipv6_from_be(data: [u8; 16]) -> Ipv6Addr {
+    Ipv6Addr::from(data)
+}
+
+// Hashes are stored as they are serialized but shown in the opposite order
+pub fn reversed(hash: &[u8; 32]) -> [u8; 32] {
+    let mut result = *hash;
+    result.reverse();
+    result
+}
+
+pub fn write_u16_be(serializer: &mut Serializer, x: u16) {
+    serializer.push_bytes(&u16_to_be(x));
+}
+
+pub fn read_u16_be(deserializer: &mut Deserializer) -> Result<u16, Error> {
+    let mut data = [0; 2];
+    try!(deserializer.read_ex(&mut data));
+    Ok(u16_from_be(data))
+}
@@ -1,55 +1,55 @@
 use time;
 
-use std::io::Read;
+use std::fs::File;
+use std::io::Cursor;
 
-use super::{Deserialize, Deserializer, VarInt};
+use super::{Deserialize, Deserializer, Error, VarInt, MAX_SIZE};
 
 impl Deserialize for i32 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         deserializer.to_i(4).map(|r| r as i32)
     }
 }
 
 impl Deserialize for i64 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         deserializer.to_i(8)
     }
 }
 
 impl Deserialize for u8 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         deserializer.to_u(1).map(|r| r as u8)
     }
 }
 
 impl Deserialize for u16 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         deserializer.to_u(2).map(|r| r as u16)
     }
 }
 
 impl Deserialize for u32 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         deserializer.to_u(4).map(|r| r as u32)
     }
 }
 
 impl Deserialize for u64 {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         deserializer.to_u(8).map(|r| r as u64)
     }
 }
 
 impl Deserialize for time::Tm {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let sec = try!(deserializer.to_i(8));
         // Somewhere around 2033 this will break
         // unfortunately time::Tm crashes with an invalid time :-(
         // so we need to do some validation.
         // TODO: switch to a better library
         if sec < 0 || sec > 2000000000 {
-            panic!();
-            // Err(format!("Invalid time sec={}", sec))
+            Err(Error::InvalidValue(sec as u64))
         } else {
             Ok(time::at_utc(time::Timespec::new(sec, 0)))
         }
@@ -57,33 +57,39 @@ impl Deserialize for time::Tm {
 }
 
 impl Deserialize for bool {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let data = try!(deserializer.to_u_fixed(1));
         Ok(data != 0)
     }
 }
 
 impl Deserialize for String {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let length = try!(VarInt::deserialize(deserializer)).as_u64() as usize;
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let length = try!(VarInt::deserialize(deserializer)).as_u64();
 
         if length > 1024 {
-            return Err(format!("String is too long, length={}", length));
+            return Err(Error::LengthTooLarge(length));
         }
 
+        let length = length as usize;
+
         let mut bytes = [0; 1024];
         try!(deserializer.read_ex(&mut bytes[0..length]));
 
         let mut bytes_vector = vec![];
         bytes_vector.extend(bytes[0..length].into_iter());
 
-        String::from_utf8(bytes_vector).map_err(|e| format!("Error: {:?}", e))
+        String::from_utf8(bytes_vector).map_err(|_| Error::InvalidUtf8)
     }
 }
 
 impl<U: Deserialize> Deserialize for Vec<U> {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let length = try!(VarInt::deserialize(deserializer)).as_u64() as usize;
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let length = try!(VarInt::deserialize(deserializer)).as_u64();
+
+        if length > MAX_SIZE {
+            return Err(Error::LengthTooLarge(length));
+        }
 
         let mut result = vec![];
         for _ in 0..length {
@@ -95,7 +101,7 @@ impl<U: Deserialize> Deserialize for Vec<U> {
 }
 
 impl<U:Deserialize, K: Deserialize> Deserialize for (U, K) {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let first  = try!(U::deserialize(deserializer));
         let second = try!(K::deserialize(deserializer));
 
@@ -106,7 +112,7 @@ impl<U:Deserialize, K: Deserialize> Deserialize for (U, K) {
 // TODO: figure out a way to generalize this
 // probably related to https://github.com/rust-lang/rfcs/issues/1038
 impl<U: Deserialize + Default + Copy> Deserialize for [U; 4] {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let mut result = [U::default(); 4];
         for i in 0..4 {
             result[i] = try!(U::deserialize(deserializer));
@@ -116,10 +122,10 @@ impl<U: Deserialize + Default + Copy> Deserialize for [U; 4] {
     }
 }
 
-impl<U: Deserialize + Default + Copy> Deserialize for [U; 32] {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
-        let mut result = [U::default(); 32];
-        for i in 0..32 {
+impl<U: Deserialize + Default + Copy> Deserialize for [U; 12] {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let mut result = [U::default(); 12];
+        for i in 0..12 {
             result[i] = try!(U::deserialize(deserializer));
         }
 
@@ -127,51 +133,33 @@ impl<U: Deserialize + Default + Copy> Deserialize for [U; 32] {
     }
 }
 
-impl<T: Read> Deserializer for T {
-    fn to_u_slice(&self, data: &[u8]) -> u64 {
-        let mut result = 0;
-        let mut multiplier: u64 = 1;
-
-        for i in 0..data.len() {
-            if i != 0 { multiplier *= 0x100 };
-            result += data[i] as u64 * multiplier;
+impl<U: Deserialize + Default + Copy> Deserialize for [U; 32] {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let mut result = [U::default(); 32];
+        for i in 0..32 {
+            result[i] = try!(U::deserialize(deserializer));
         }
 
-        result
-    }
-
-    fn read_ex(&mut self, out: &mut [u8]) -> Result<(), String> {
-        self.read_exact(out).map_err(|e| format!("Error: {:?}", e))
+        Ok(result)
     }
+}
 
-    fn to_i(&mut self, size: usize) -> Result<i64, String> {
-        assert!(size == 1 || size == 2 || size == 4 || size == 8);
-
-        let mut data = [0; 8];
-        try!(self.read_ex(&mut data[0..size]));
-
-        let sign     = data[size-1] & 0x80;
-        data[size-1] = data[size-1] & 0x7F;
-
-        let unsigned = self.to_u_slice(&data[0..size]) as i64;
-
-        if sign > 0 {
-            Ok(unsigned * -1)
-        } else {
-            Ok(unsigned)
-        }
+impl<T: AsRef<[u8]>> Deserializer for Cursor<T> {
+    fn remaining(&self) -> Option<usize> {
+        let len = self.get_ref().as_ref().len() as u64;
+        Some(len.saturating_sub(self.position()) as usize)
     }
+}
 
-    fn to_u_fixed(&mut self, size: usize) -> Result<u64, String> {
-        assert!(size == 1 || size == 2 || size == 4 || size == 8);
-
-        let mut data = [0; 8];
-        try!(self.read_ex(&mut data[0..size]));
-
-        Ok(self.to_u_slice(&data[0..size]))
+impl<'a> Deserializer for &'a [u8] {
+    fn remaining(&self) -> Option<usize> {
+        Some(self.len())
     }
+}
 
-    fn to_u(&mut self, size: usize) -> Result<u64, String> {
-        self.to_u_fixed(size)
+// Streams don't know how much data is left
+impl Deserializer for File {
+    fn remaining(&self) -> Option<usize> {
+        None
     }
 }
@@ -1,14 +1,79 @@
 mod serialize;
 mod deserialize;
 mod var_int;
+mod script_num;
+
+pub mod byte_order;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+
+use std::fmt;
+use std::io;
+use std::io::{Read, Write};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Error {
+    // The data ended in the middle of an object
+    UnexpectedEof,
+    // Any other error from the underlying reader
+    Io(io::ErrorKind),
+    InvalidUtf8,
+    // A VarInt that could have been encoded with fewer bytes
+    NonCanonicalVarInt,
+    // A ScriptNum with extra zero bytes or a negative zero
+    NonMinimalScriptNum,
+    // The payload of a network message doesn't match its checksum
+    InvalidChecksum,
+    // Bytes left over after the end of an object that should use all of them
+    TrailingData,
+    LengthTooLarge(u64),
+    // A field that doesn't map to anything we know, e.g. a magic number
+    InvalidValue(u64),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Error::UnexpectedEof => write!(f, "Unexpected end of data"),
+            &Error::Io(kind) => write!(f, "Read error: {:?}", kind),
+            &Error::InvalidUtf8 => write!(f, "Invalid UTF-8 string"),
+            &Error::NonCanonicalVarInt => write!(f, "Non-canonical VarInt"),
+            &Error::NonMinimalScriptNum => write!(f, "Non-minimal ScriptNum"),
+            &Error::InvalidChecksum => write!(f, "Invalid checksum"),
+            &Error::TrailingData => write!(f, "Unexpected data after the end"),
+            &Error::LengthTooLarge(length) => write!(f, "Length is too large, length={}", length),
+            &Error::InvalidValue(value) => write!(f, "Invalid value {}", value),
+        }
+    }
+}
+
+// Largest number of elements accepted for a vector, same as MAX_SIZE in the
+// official client. Every element takes at least a byte so no bigger vector
+// fits in a network message.
+pub const MAX_SIZE: u64 = 0x02000000;
+
+// Most callers report errors as strings, this lets them use try!
+impl From<Error> for String {
+    fn from(error: Error) -> String {
+        error.to_string()
+    }
+}
 
 pub trait Serialize {
     fn serialize(&self, serializer: &mut Serializer);
     fn size() -> usize where Self: Sized;
+
+    // Number of bytes written by serialize, including VarInt prefixes
+    fn serialized_size(&self) -> usize {
+        let mut counter = ByteCounter { count: 0 };
+        self.serialize(&mut counter);
+        counter.count
+    }
 }
 
 pub trait Deserialize: Sized {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String>;
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error>;
 }
 
 pub trait Serializer {
@@ -20,12 +85,109 @@ pub trait Serializer {
     fn i_to_fixed(&mut self, x: i64, bytes: usize);
 }
 
-pub trait Deserializer {
-    fn read_ex(&mut self, out: &mut [u8]) -> Result<(), String>;
-    fn to_i(&mut self, size: usize) -> Result<i64, String>;
-    fn to_u_fixed(&mut self, size: usize) -> Result<u64, String>;
-    fn to_u(&mut self, size: usize) -> Result<u64, String>;
-    fn to_u_slice(&self, data: &[u8]) -> u64;
+// Every method but remaining() works on any Read
+pub trait Deserializer: Read {
+    fn to_u_slice(&self, data: &[u8]) -> u64 {
+        let mut result = 0;
+        let mut multiplier: u64 = 1;
+
+        for i in 0..data.len() {
+            if i != 0 { multiplier *= 0x100 };
+            result += data[i] as u64 * multiplier;
+        }
+
+        result
+    }
+
+    fn read_ex(&mut self, out: &mut [u8]) -> Result<(), Error> {
+        self.read_exact(out).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+            kind => Error::Io(kind),
+        })
+    }
+
+    fn to_i(&mut self, size: usize) -> Result<i64, Error> {
+        assert!(size == 1 || size == 2 || size == 4 || size == 8);
+
+        let mut data = [0; 8];
+        try!(self.read_ex(&mut data[0..size]));
+
+        let sign     = data[size-1] & 0x80;
+        data[size-1] = data[size-1] & 0x7F;
+
+        let unsigned = self.to_u_slice(&data[0..size]) as i64;
+
+        if sign > 0 {
+            Ok(unsigned * -1)
+        } else {
+            Ok(unsigned)
+        }
+    }
+
+    fn to_u_fixed(&mut self, size: usize) -> Result<u64, Error> {
+        assert!(size == 1 || size == 2 || size == 4 || size == 8);
+
+        let mut data = [0; 8];
+        try!(self.read_ex(&mut data[0..size]));
+
+        Ok(self.to_u_slice(&data[0..size]))
+    }
+
+    fn to_u(&mut self, size: usize) -> Result<u64, Error> {
+        self.to_u_fixed(size)
+    }
+
+    // Number of bytes left to read, if the reader knows it. Parsers use it
+    // to detect trailing data.
+    fn remaining(&self) -> Option<usize>;
+}
+
+struct ByteCounter {
+    count: usize,
+}
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.count += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+
+// Feeds serialized bytes straight into SHA256, so objects can be hashed
+// without keeping their serialization in memory
+pub struct HashWriter {
+    sha256: Sha256,
+}
+
+impl HashWriter {
+    pub fn new() -> HashWriter {
+        HashWriter {
+            sha256: Sha256::new(),
+        }
+    }
+
+    // SHA256 of the SHA256 of everything written so far
+    pub fn double_sha256(mut self) -> [u8; 32] {
+        let mut result = [0; 32];
+        self.sha256.result(&mut result);
+
+        let mut second = Sha256::new();
+        second.input(&result);
+        second.result(&mut result);
+
+        result
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sha256.input(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
 }
 
 #[derive(PartialEq, Debug, Clone, Copy)]
@@ -33,3 +195,9 @@ pub struct VarInt {
     data: u64,
 }
 
+// A number encoded like on the script stack, little endian with the sign in
+// the highest bit, prefixed by its length
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct ScriptNum {
+    value: i32,
+}
@@ -0,0 +1,51 @@
+use utils::IntUtils;
+
+use super::{Deserialize, Deserializer, Error, ScriptNum, Serialize, Serializer};
+
+// Numbers on the stack take at most 4 bytes
+const MAX_SCRIPT_NUM_SIZE: usize = 4;
+
+impl ScriptNum {
+    // i32::min_value() needs 5 bytes and could not be read back
+    pub fn new(value: i32) -> Option<ScriptNum> {
+        if value == i32::min_value() {
+            return None;
+        }
+
+        Some(ScriptNum {
+            value: value,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn as_i32(&self) -> i32 {
+        self.value
+    }
+}
+
+impl Serialize for ScriptNum {
+    fn serialize(&self, serializer: &mut Serializer) {
+        IntUtils::to_vec_u8(self.value as i64).serialize(serializer);
+    }
+
+    fn size() -> usize { 1 + MAX_SCRIPT_NUM_SIZE }
+}
+
+impl Deserialize for ScriptNum {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        let data: Vec<u8> = try!(Deserialize::deserialize(deserializer));
+        if data.len() > MAX_SCRIPT_NUM_SIZE {
+            return Err(Error::LengthTooLarge(data.len() as u64));
+        }
+
+        let value = IntUtils::to_i32(&data);
+        if IntUtils::to_vec_u8(value as i64) != data {
+            return Err(Error::NonMinimalScriptNum);
+        }
+
+        // At most 4 bytes, so the value is never i32::min_value()
+        Ok(ScriptNum {
+            value: value,
+        })
+    }
+}
@@ -126,6 +126,11 @@ impl Serialize for String {
     }
 
     fn size() -> usize { usize::MAX }
+
+    fn serialized_size(&self) -> usize {
+        let length = self.as_bytes().len();
+        VarInt::new(length as u64).serialized_size() + length
+    }
 }
 
 impl<U: Serialize> Serialize for Vec<U> {
@@ -139,6 +144,11 @@ impl<U: Serialize> Serialize for Vec<U> {
     }
 
     fn size() -> usize { usize::MAX }
+
+    fn serialized_size(&self) -> usize {
+        VarInt::new(self.len() as u64).serialized_size() +
+            self.iter().map(|x| x.serialized_size()).sum::<usize>()
+    }
 }
 
 impl <U: Serialize, V: Serialize> Serialize for (U,V) {
@@ -148,6 +158,10 @@ impl <U: Serialize, V: Serialize> Serialize for (U,V) {
     }
 
     fn size() -> usize { U::size() + V::size() }
+
+    fn serialized_size(&self) -> usize {
+        self.0.serialized_size() + self.1.serialized_size()
+    }
 }
 
 impl <U: Serialize> Serialize for [U] {
@@ -158,6 +172,10 @@ impl <U: Serialize> Serialize for [U] {
     }
 
     fn size() -> usize { usize::MAX }
+
+    fn serialized_size(&self) -> usize {
+        self.iter().map(|x| x.serialized_size()).sum()
+    }
 }
 
 impl <U: Serialize> Serialize for [U; 4] {
@@ -170,6 +188,16 @@ impl <U: Serialize> Serialize for [U; 4] {
     fn size() -> usize { U::size() * 4 }
 }
 
+impl <U: Serialize> Serialize for [U; 12] {
+    fn serialize(&self, serializer: &mut Serializer) {
+        for x in self {
+            x.serialize(serializer);
+        }
+    }
+
+    fn size() -> usize { U::size() * 12 }
+}
+
 impl <U: Serialize> Serialize for [U; 32] {
     fn serialize(&self, serializer: &mut Serializer) {
         for x in self {
@@ -186,4 +214,8 @@ impl <'a, U: Serialize> Serialize for &'a U {
     }
 
     fn size() -> usize { U::size() }
+
+    fn serialized_size(&self) -> usize {
+        (*self).serialized_size()
+    }
 }
@@ -1,4 +1,4 @@
-use super::{VarInt, Serialize, Serializer, Deserialize, Deserializer};
+use super::{VarInt, Serialize, Serializer, Deserialize, Deserializer, Error};
 
 impl VarInt {
     pub fn new(data: u64) -> VarInt {
@@ -10,6 +10,17 @@ impl VarInt {
     pub fn as_u64(&self) -> u64 {
         self.data
     }
+
+    // Bytes taken on the wire. Non-canonical encodings are rejected when
+    // deserializing, so this is also how many bytes were read.
+    pub fn encoded_len(&self) -> usize {
+        match self.data {
+            0x00000...0x0000000fc => 1,
+            0x000fd...0x00000ffff => 3,
+            0x10000...0x0ffffffff => 5,
+            _ => 9,
+        }
+    }
 }
 
 impl Serialize for VarInt {
@@ -36,10 +47,14 @@ impl Serialize for VarInt {
     }
 
     fn size() -> usize { 8 }
+
+    fn serialized_size(&self) -> usize {
+        self.encoded_len()
+    }
 }
 
 impl Deserialize for VarInt {
-    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, String> {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
         let mut data = [0; 1];
         try!(deserializer.read_ex(&mut data));
 
@@ -47,14 +62,19 @@ impl Deserialize for VarInt {
             return Ok(VarInt::new(data[0] as u64));
         }
 
-        let bytes = match data[0] {
-            0xfd => 2,
-            0xfe => 4,
-            0xff => 8,
-            _    => 0,
+        // Smallest value that needs this many bytes
+        let (bytes, min) = match data[0] {
+            0xfd => (2, 0xfd),
+            0xfe => (4, 0x10000),
+            _    => (8, 0x100000000),
         };
 
-        Ok(VarInt::new(try!(deserializer.to_u_fixed(bytes))))
+        let value = try!(deserializer.to_u_fixed(bytes));
+        if value < min {
+            return Err(Error::NonCanonicalVarInt);
+        }
+
+        Ok(VarInt::new(value))
     }
 }
 
@@ -0,0 +1,106 @@
+// Benchmarks for the hot paths, these need a nightly compiler:
+// cargo bench --features bench
+extern crate test;
+
+use self::test::Bencher;
+
+use net::messages::BlockMessage;
+use script::Parser;
+use serialize::Deserialize;
+use test::utils::mock_checksig;
+use utils::CryptoUtils;
+
+use std::fs::File;
+use std::io::{Cursor, Read};
+
+fn push(script: &mut Vec<u8>, data: &[u8]) {
+    assert!(data.len() <= 0x4b);
+
+    script.push(data.len() as u8);
+    script.extend(data.iter().cloned());
+}
+
+fn bench_execute(b: &mut Bencher, sig_script: Vec<u8>, pub_key_script: Vec<u8>) {
+    assert_eq!(Parser::execute(sig_script.clone(), pub_key_script.clone(), &mock_checksig),
+               Ok(true));
+
+    b.iter(|| Parser::execute(sig_script.clone(), pub_key_script.clone(), &mock_checksig));
+}
+
+#[bench]
+fn bench_p2pkh(b: &mut Bencher) {
+    let pub_key = vec![0x02; 33];
+    let hash = CryptoUtils::ripemd160(&CryptoUtils::sha256(&pub_key));
+
+    let mut sig_script = vec![];
+    push(&mut sig_script, &[0x30; 72]);
+    push(&mut sig_script, &pub_key);
+
+    // DUP HASH160 <hash> EQUALVERIFY CHECKSIG
+    let mut pub_key_script = vec![0x76, 0xa9];
+    push(&mut pub_key_script, &hash);
+    pub_key_script.extend(&[0x88, 0xac]);
+
+    bench_execute(b, sig_script, pub_key_script);
+}
+
+#[bench]
+fn bench_multisig_15_of_15(b: &mut Bencher) {
+    // The extra element popped by CHECKMULTISIG
+    let mut sig_script = vec![0x00];
+    for i in 0..15 {
+        push(&mut sig_script, &[0x30 + i; 72]);
+    }
+
+    let mut pub_key_script = vec![0x5f];
+    for i in 0..15 {
+        push(&mut pub_key_script, &[0x02 + i; 33]);
+    }
+    pub_key_script.extend(&[0x5f, 0xae]);
+
+    bench_execute(b, sig_script, pub_key_script);
+}
+
+#[bench]
+fn bench_nested_if(b: &mut Bencher) {
+    let depth = 100;
+
+    // 1 IF 1 IF ... 1 ENDIF ENDIF ...
+    let mut pub_key_script = vec![];
+    for _ in 0..depth {
+        pub_key_script.extend(&[0x51, 0x63]);
+    }
+    pub_key_script.push(0x51);
+    for _ in 0..depth {
+        pub_key_script.push(0x68);
+    }
+
+    bench_execute(b, vec![], pub_key_script);
+}
+
+// 1 1ADD 1ADD ..., the time should grow linearly with `len`
+fn bench_opcodes(b: &mut Bencher, len: usize) {
+    let mut pub_key_script = vec![0x51];
+    pub_key_script.extend(vec![0x8b; len - 1]);
+
+    bench_execute(b, vec![], pub_key_script);
+}
+
+#[bench]
+fn bench_100_opcodes(b: &mut Bencher) {
+    bench_opcodes(b, 100);
+}
+
+#[bench]
+fn bench_1000_opcodes(b: &mut Bencher) {
+    bench_opcodes(b, 1000);
+}
+
+#[bench]
+fn bench_deserialize_block(b: &mut Bencher) {
+    let mut data = vec![];
+    File::open("src/test/block.dat").unwrap().read_to_end(&mut data).unwrap();
+
+    b.bytes = data.len() as u64;
+    b.iter(|| BlockMessage::deserialize(&mut Cursor::new(&data[..])).unwrap());
+}
@@ -1 +1,6 @@
 mod net;
+mod roundtrip;
+pub mod utils;
+
+#[cfg(feature = "bench")]
+mod bench;
@@ -3,15 +3,76 @@ use net::messages::*;
 
 use utils::Debug;
 
-use std::io::Cursor;
+use std::io::{Cursor, Read};
 use std::fs::File;
+use std::net::Ipv6Addr;
 
-use serialize::{Serialize, Deserialize};
+use script::{BitcoinScript, ScriptErrorKind, ScriptFlags};
+use serialize::{byte_order, Serialize, Deserialize, HashWriter};
+use utils::CryptoUtils;
 
 #[test]
 fn test_block() {
-    let mut block_data = File::open("src/test/block.dat").unwrap();
-    BlockMessage::deserialize(&mut block_data).unwrap();
+    let mut data = vec![];
+    File::open("src/test/block.dat").unwrap().read_to_end(&mut data).unwrap();
+
+    let block = BlockMessage::deserialize(&mut Cursor::new(&data[..])).unwrap();
+    assert_eq!(block.serialized_size(), data.len());
+
+    // Writing it back must give the exact same bytes, the block store relies
+    // on this to read its own blocks
+    let mut result = Cursor::new(vec![]);
+    block.serialize(&mut result);
+    assert_eq!(result.into_inner(), data);
+}
+
+#[test]
+fn test_block_hash_from_bytes() {
+    let mut data = vec![];
+    File::open("src/test/block.dat").unwrap().read_to_end(&mut data).unwrap();
+
+    let block = BlockMessage::deserialize(&mut Cursor::new(&data[..])).unwrap();
+    assert_eq!(BlockMessage::hash_from_bytes(&data), block.hash());
+    assert_eq!(block.hash(), block.serialize_hash().1);
+    assert_eq!(block.metadata.serialized_size(), BLOCK_HEADER_SIZE);
+}
+
+// Value and script of a P2PKH output paying to `key`
+fn pay_to_key(key: &[u8]) -> (u64, Vec<u8>) {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend(CryptoUtils::ripemd160(&CryptoUtils::sha256(key)).iter());
+    script.extend(&[0x88, 0xac]);
+    (100000, script)
+}
+
+#[test]
+fn test_verify_tx() {
+    let block = BlockMessage::deserialize(&mut File::open("src/test/block.dat").unwrap()).unwrap();
+    let tx = &block.txns[1];
+
+    // Every input reveals its key, the outputs they spend pay to it
+    let prevouts: Vec<(u64, Vec<u8>)> = tx.tx_in.iter()
+        .map(|input| {
+            let script = BitcoinScript::new(input.script.clone());
+            let key = script.iter().last().unwrap().2.unwrap();
+            pay_to_key(&key)
+        })
+        .collect();
+
+    // The signatures in the block are real, so they must check out
+    assert_eq!(tx.verify(&prevouts, ScriptFlags::default()), Ok(()));
+
+    let mut forged = tx.clone();
+    forged.lock_time += 1;
+    assert_eq!(forged.verify(&prevouts, ScriptFlags::default()).unwrap_err().kind,
+               ScriptErrorKind::EvalFalse);
+
+    let wrong_key = vec![pay_to_key(&[0x02; 33]); prevouts.len()];
+    assert!(tx.verify(&wrong_key, ScriptFlags::default()).is_err());
+    assert_eq!(tx.verify(&[], ScriptFlags::default()).unwrap_err().kind,
+               ScriptErrorKind::BadPrevouts);
+    assert_eq!(block.txns[0].verify(&[], ScriptFlags::default()).unwrap_err().kind,
+               ScriptErrorKind::BadPrevouts);
 }
 
 #[test]
@@ -49,6 +110,7 @@ fn test_version_message() {
     assert_eq!(message.user_agent, "/Satoshi:0.7.2/");
     assert_eq!(message.start_height, 212672);
     assert_eq!(message.relay, false);
+    assert_eq!(message.serialized_size(), buffer.len());
 
     let mut result_buffer = Cursor::new(vec![]);
     message.serialize(&mut result_buffer);
@@ -59,6 +121,65 @@ fn test_version_message() {
     assert_eq!(result, buffer);
 }
 
+#[test]
+fn test_streaming_hash() {
+    let block = BlockMessage::deserialize(&mut File::open("src/test/block.dat").unwrap()).unwrap();
+
+    let mut writer = HashWriter::new();
+    block.metadata.serialize(&mut writer);
+    assert_eq!(BitcoinHash::new(writer.double_sha256()), block.hash());
+
+    // The whole block hashes the same as its buffered serialization
+    let mut writer = HashWriter::new();
+    block.serialize(&mut writer);
+    let mut buffer = vec![];
+    block.serialize(&mut buffer);
+    assert_eq!(writer.double_sha256(), CryptoUtils::sha256(&CryptoUtils::sha256(&buffer)));
+
+    for tx in &block.txns {
+        assert_eq!(tx.hash(), tx.serialize_hash().1);
+    }
+}
+
+#[test]
+fn test_address_byte_order() {
+    // addr_recv from test_version_message, ::ffff:0.0.0.0 port 0
+    let address = [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00];
+    let ip = byte_order::ipv6_from_be(address);
+    assert_eq!(ip, "::ffff:0.0.0.0".parse::<Ipv6Addr>().unwrap());
+    assert_eq!(byte_order::ipv6_to_be(&ip), address);
+
+    let ip = "2001:db8::ff00:42:8329".parse().unwrap();
+    let address = [0x20, 0x01, 0x0d, 0xb8, 0x00, 0x00, 0x00, 0x00,
+                   0x00, 0x00, 0xff, 0x00, 0x00, 0x42, 0x83, 0x29];
+    assert_eq!(byte_order::ipv6_to_be(&ip), address);
+    assert_eq!(byte_order::ipv6_from_be(address), ip);
+
+    assert_eq!(byte_order::u16_from_be([0x00, 0x00]), 0);
+    assert_eq!(byte_order::u16_to_be(8333), [0x20, 0x8d]);
+    assert_eq!(byte_order::u16_from_be([0x20, 0x8d]), 8333);
+
+    let address = IPAddress::new(Services::new(true), ip, 8333);
+    let mut buffer = vec![];
+    address.serialize(&mut buffer);
+    assert_eq!(&buffer[8..24], &byte_order::ipv6_to_be(&ip));
+    assert_eq!(&buffer[24..], &[0x20, 0x8d]);
+    assert_eq!(IPAddress::deserialize(&mut Cursor::new(&buffer[..])).unwrap(), address);
+}
+
+#[test]
+fn test_hash_display_order() {
+    let mut data = [0; 32];
+    data[0] = 0x01;
+    data[31] = 0xab;
+
+    let reversed = byte_order::reversed(&data);
+    assert_eq!(reversed[0], 0xab);
+    assert_eq!(reversed[31], 0x01);
+    assert!(format!("{:?}", BitcoinHash::new(data)).starts_with("AB00"));
+}
+
 #[test]
 fn test_complete_message() {
     let buffer = vec![
@@ -0,0 +1,447 @@
+// Round-trip tests: any message we can serialize must deserialize
+// back to exactly the same value.
+use net::*;
+use net::messages::*;
+
+use rand::{Rng, thread_rng};
+use time;
+
+use std::fmt::Debug;
+use std::io::Cursor;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use serialize::{Serialize, Deserialize, Error, ScriptNum, VarInt, MAX_SIZE};
+use transaction::Amount;
+
+const ITERATIONS: usize = 200;
+
+trait Arbitrary {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self;
+}
+
+fn arbitrary_vec<T: Arbitrary, R: Rng>(rng: &mut R, max_len: usize) -> Vec<T> {
+    let len = rng.gen_range(0, max_len + 1);
+    (0..len).map(|_| T::arbitrary(rng)).collect()
+}
+
+fn arbitrary_bytes<R: Rng>(rng: &mut R, max_len: usize) -> Vec<u8> {
+    let len = rng.gen_range(0, max_len + 1);
+    (0..len).map(|_| rng.gen()).collect()
+}
+
+impl Arbitrary for BitcoinHash {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        let mut data = [0; 32];
+        rng.fill_bytes(&mut data);
+        BitcoinHash::new(data)
+    }
+}
+
+impl Arbitrary for IPAddress {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        let address = Ipv6Addr::new(rng.gen(), rng.gen(), rng.gen(), rng.gen(),
+                                    rng.gen(), rng.gen(), rng.gen(), rng.gen());
+        IPAddress::new(Services::new(rng.gen()), address, rng.gen())
+    }
+}
+
+impl Arbitrary for ShortFormatTm {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        let sec = rng.gen::<u32>() as i64;
+        ShortFormatTm::new(time::at_utc(time::Timespec::new(sec, 0)))
+    }
+}
+
+impl Arbitrary for VersionMessage {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        let user_agent_len = rng.gen_range(0, 300);
+
+        VersionMessage {
+            version: rng.gen(),
+            services: Services::new(rng.gen()),
+            // time::Tm deserialization only accepts timestamps up to 2000000000
+            timestamp: time::at_utc(time::Timespec::new(rng.gen_range(0, 2000000000), 0)),
+            addr_recv: IPAddress::arbitrary(rng),
+            addr_from: IPAddress::arbitrary(rng),
+            nonce: rng.gen(),
+            user_agent: rng.gen_ascii_chars().take(user_agent_len).collect(),
+            start_height: rng.gen(),
+            relay: rng.gen(),
+        }
+    }
+}
+
+impl Arbitrary for OutPoint {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        OutPoint::new(BitcoinHash::arbitrary(rng), rng.gen())
+    }
+}
+
+impl Arbitrary for TxIn {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        // Scripts longer than 0xfc bytes exercise the multi-byte VarInt prefix
+        TxIn::new(OutPoint::arbitrary(rng), arbitrary_bytes(rng, 300), rng.gen())
+    }
+}
+
+impl Arbitrary for TxOut {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        TxOut::new(Amount::from_satoshis(rng.gen()), arbitrary_bytes(rng, 300))
+    }
+}
+
+impl Arbitrary for TxMessage {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        TxMessage {
+            version: rng.gen(),
+            tx_in: arbitrary_vec(rng, 5),
+            tx_out: arbitrary_vec(rng, 5),
+            lock_time: rng.gen(),
+        }
+    }
+}
+
+impl Arbitrary for InventoryVectorType {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        match rng.gen_range(0, 4) {
+            0 => InventoryVectorType::ERROR,
+            1 => InventoryVectorType::MSG_TX,
+            2 => InventoryVectorType::MSG_BLOCK,
+            _ => InventoryVectorType::MSG_FILTERED_BLOCK,
+        }
+    }
+}
+
+impl Arbitrary for InventoryVector {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        InventoryVector::new(InventoryVectorType::arbitrary(rng), BitcoinHash::arbitrary(rng))
+    }
+}
+
+impl Arbitrary for InvMessage {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        InvMessage::new(arbitrary_vec(rng, 300))
+    }
+}
+
+impl Arbitrary for BlockMetadata {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        BlockMetadata {
+            version: rng.gen(),
+            prev_block: BitcoinHash::arbitrary(rng),
+            merkle_root: BitcoinHash::arbitrary(rng),
+            timestamp: ShortFormatTm::arbitrary(rng),
+            bits: rng.gen(),
+            nonce: rng.gen(),
+        }
+    }
+}
+
+impl Arbitrary for CFilterMessage {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        CFilterMessage::new(rng.gen(), BitcoinHash::arbitrary(rng), arbitrary_bytes(rng, 300))
+    }
+}
+
+impl Arbitrary for CFHeadersMessage {
+    fn arbitrary<R: Rng>(rng: &mut R) -> Self {
+        CFHeadersMessage::new(rng.gen(), BitcoinHash::arbitrary(rng), BitcoinHash::arbitrary(rng),
+                              arbitrary_vec(rng, 100))
+    }
+}
+
+fn test_round_trip<T: Arbitrary + Serialize + Deserialize + PartialEq + Debug>() {
+    let mut rng = thread_rng();
+
+    for _ in 0..ITERATIONS {
+        let original = T::arbitrary(&mut rng);
+
+        let mut buffer = vec![];
+        original.serialize(&mut buffer);
+
+        let mut cursor = Cursor::new(&buffer[..]);
+        let result = T::deserialize(&mut cursor).unwrap();
+
+        assert_eq!(original, result);
+        // Every byte we wrote must have been consumed
+        assert_eq!(cursor.position() as usize, buffer.len());
+        assert_eq!(original.serialized_size(), buffer.len());
+    }
+}
+
+#[test]
+fn test_round_trip_version_message() {
+    test_round_trip::<VersionMessage>();
+}
+
+#[test]
+fn test_round_trip_tx_message() {
+    test_round_trip::<TxMessage>();
+}
+
+#[test]
+fn test_round_trip_inv_message() {
+    test_round_trip::<InvMessage>();
+}
+
+#[test]
+fn test_round_trip_block_metadata() {
+    test_round_trip::<BlockMetadata>();
+}
+
+#[test]
+fn test_round_trip_compact_filter_messages() {
+    test_round_trip::<CFilterMessage>();
+    test_round_trip::<CFHeadersMessage>();
+}
+
+#[test]
+fn test_network_message() {
+    let ping = PingMessage::new(0x0102030405060708);
+    let message = NetworkMessage::new(NetworkType::TestNet3, Command::Ping, ping.clone());
+
+    let mut data = vec![];
+    message.serialize(&mut data);
+    assert_eq!(data, get_serialized_message(NetworkType::TestNet3, Command::Ping,
+                                            Some(Box::new(ping.clone()))));
+    assert_eq!(data.len(), 24 + 8);
+
+    let deserialize = |data: &[u8]| {
+        NetworkMessage::<PingMessage>::deserialize(&mut Cursor::new(data))
+    };
+
+    assert_eq!(deserialize(&data), Ok(message));
+
+    // Same payload, the command tells them apart
+    let pong = NetworkMessage::new(NetworkType::TestNet3, Command::Pong, ping.clone());
+    let mut pong_data = vec![];
+    pong.serialize(&mut pong_data);
+    assert_eq!(deserialize(&pong_data).unwrap().command, Command::Pong);
+
+    // Any change to the payload breaks the checksum
+    let mut corrupted = data.clone();
+    corrupted[24] ^= 0x01;
+    assert_eq!(deserialize(&corrupted), Err(Error::InvalidChecksum));
+
+    let mut corrupted = data.clone();
+    corrupted[20] ^= 0x01;
+    assert_eq!(deserialize(&corrupted), Err(Error::InvalidChecksum));
+
+    assert_eq!(deserialize(&data[..30]), Err(Error::UnexpectedEof));
+
+    // The length is checked before reading that much
+    let mut oversized = data.clone();
+    oversized[16..20].copy_from_slice(&[0xff; 4]);
+    assert_eq!(deserialize(&oversized), Err(Error::LengthTooLarge(0xffffffff)));
+
+    // A payload longer than the ping itself, with a valid checksum
+    let long = get_serialized_message(NetworkType::TestNet3, Command::Ping,
+                                      Some(Box::new(vec![0u8; 9])));
+    assert_eq!(deserialize(&long), Err(Error::TrailingData));
+}
+
+#[test]
+fn test_check_payload() {
+    let data = get_serialized_message(NetworkType::TestNet3, Command::Ping,
+                                      Some(Box::new(PingMessage::new(1))));
+    let header = MessageHeader::deserialize(&mut Cursor::new(&data[..])).unwrap();
+
+    assert_eq!(header.check_payload(&data[24..]), Ok(()));
+    assert_eq!(header.check_payload(&data[24..31]), Err(Error::UnexpectedEof));
+
+    let mut longer = data[24..].to_vec();
+    longer.push(0);
+    assert_eq!(header.check_payload(&longer), Err(Error::TrailingData));
+
+    let mut corrupted = data[24..].to_vec();
+    corrupted[0] ^= 0x01;
+    assert_eq!(header.check_payload(&corrupted), Err(Error::InvalidChecksum));
+}
+
+#[test]
+fn test_truncated_message() {
+    let mut buffer = vec![];
+    TxMessage::arbitrary(&mut thread_rng()).serialize(&mut buffer);
+
+    for length in 0..buffer.len() {
+        let result = TxMessage::deserialize(&mut Cursor::new(&buffer[..length]));
+        assert_eq!(result, Err(Error::UnexpectedEof));
+    }
+}
+
+#[test]
+fn test_truncated_hash() {
+    let mut buffer = vec![];
+    BitcoinHash::arbitrary(&mut thread_rng()).serialize(&mut buffer);
+
+    for length in 0..32 {
+        let result = BitcoinHash::deserialize(&mut Cursor::new(&buffer[..length]));
+        assert_eq!(result, Err(Error::UnexpectedEof));
+    }
+}
+
+#[test]
+fn test_truncated_locator() {
+    let mut rng = thread_rng();
+    let locators = vec![BitcoinHash::arbitrary(&mut rng), BitcoinHash::arbitrary(&mut rng)];
+    let message = GetHeadersMessage::new(70001, locators, BitcoinHash::new([0; 32]));
+
+    let mut buffer = vec![];
+    message.serialize(&mut buffer);
+
+    // version, count, the first locator and half of the second one
+    let truncated = &buffer[..4 + 1 + 32 + 16];
+    assert_eq!(GetHeadersMessage::deserialize(&mut Cursor::new(truncated)),
+               Err(Error::UnexpectedEof));
+
+    // Missing hash_stop
+    let truncated = &buffer[..4 + 1 + 64];
+    assert_eq!(GetHeadersMessage::deserialize(&mut Cursor::new(truncated)),
+               Err(Error::UnexpectedEof));
+}
+
+#[test]
+fn test_filter_commands() {
+    for command in &[Command::FilterLoad, Command::FilterAdd, Command::FilterClear] {
+        let mut buffer = vec![];
+        command.serialize(&mut buffer);
+        assert_eq!(Command::deserialize(&mut Cursor::new(&buffer[..])), Ok(*command));
+    }
+}
+
+#[test]
+fn test_compact_filter_commands() {
+    for command in &[Command::GetCFilters, Command::CFilter, Command::GetCFHeaders,
+                     Command::CFHeaders, Command::GetCFCheckpt, Command::CFCheckpt] {
+        let mut buffer = vec![];
+        command.serialize(&mut buffer);
+        assert_eq!(Command::deserialize(&mut Cursor::new(&buffer[..])), Ok(*command));
+    }
+}
+
+#[test]
+fn test_huge_vector_length() {
+    // version followed by 2^64 - 1 inputs and nothing else
+    let data = [0x01, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+    assert_eq!(TxMessage::deserialize(&mut Cursor::new(&data[..])),
+               Err(Error::LengthTooLarge(u64::max_value())));
+
+    // Still too many inputs to fit in a message
+    let data = [0x01, 0x00, 0x00, 0x00, 0xfe, 0x01, 0x00, 0x00, 0x02];
+    assert_eq!(TxMessage::deserialize(&mut Cursor::new(&data[..])),
+               Err(Error::LengthTooLarge(MAX_SIZE + 1)));
+}
+
+#[test]
+fn test_non_canonical_var_int() {
+    let parse = |data: &[u8]| VarInt::deserialize(&mut Cursor::new(data));
+
+    assert_eq!(parse(&[0xfd, 0xfd, 0x00]), Ok(VarInt::new(0xfd)));
+    assert_eq!(parse(&[0xfd, 0xfc, 0x00]), Err(Error::NonCanonicalVarInt));
+    assert_eq!(parse(&[0xfe, 0xff, 0xff, 0x00, 0x00]), Err(Error::NonCanonicalVarInt));
+    assert_eq!(parse(&[0xff, 0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00]),
+               Err(Error::NonCanonicalVarInt));
+}
+
+#[test]
+fn test_var_int_encoded_len() {
+    for &(value, len) in &[(0, 1), (0xfc, 1), (0xfd, 3), (0xffff, 3), (0x10000, 5),
+                           (0xffffffff, 5), (0x100000000, 9), (u64::max_value(), 9)] {
+        let var_int = VarInt::new(value);
+        assert_eq!(var_int.encoded_len(), len);
+
+        let mut buffer = vec![];
+        var_int.serialize(&mut buffer);
+        assert_eq!(buffer.len(), len);
+
+        // Followed by something else, only the VarInt is consumed
+        buffer.push(0x00);
+        let mut cursor = Cursor::new(&buffer[..]);
+        assert_eq!(VarInt::deserialize(&mut cursor), Ok(var_int));
+        assert_eq!(cursor.position() as usize, len);
+    }
+}
+
+#[test]
+fn test_round_trip_script_num() {
+    for &(value, expected) in &[(0, &[0x00][..]), (127, &[0x01, 0x7f][..]),
+                                (-128, &[0x02, 0x80, 0x80][..]),
+                                (2147483647, &[0x04, 0xff, 0xff, 0xff, 0x7f][..])] {
+        let number = ScriptNum::new(value).unwrap();
+
+        let mut buffer = vec![];
+        number.serialize(&mut buffer);
+        assert_eq!(buffer, expected);
+        assert_eq!(number.serialized_size(), expected.len());
+
+        let deserialized = ScriptNum::deserialize(&mut Cursor::new(&buffer[..])).unwrap();
+        assert_eq!(deserialized, number);
+        assert_eq!(deserialized.as_i32(), value);
+    }
+}
+
+#[test]
+fn test_invalid_script_num() {
+    let parse = |data: &[u8]| ScriptNum::deserialize(&mut Cursor::new(data));
+
+    // Negative zero and a padded 1
+    assert_eq!(parse(&[0x01, 0x80]), Err(Error::NonMinimalScriptNum));
+    assert_eq!(parse(&[0x02, 0x01, 0x00]), Err(Error::NonMinimalScriptNum));
+    // The padding is needed when the sign bit is taken
+    assert_eq!(parse(&[0x02, 0xff, 0x00]), Ok(ScriptNum::new(255).unwrap()));
+
+    assert_eq!(parse(&[0x05, 0x00, 0x00, 0x00, 0x80, 0x80]), Err(Error::LengthTooLarge(5)));
+    assert_eq!(parse(&[0x02, 0x01]), Err(Error::UnexpectedEof));
+
+    assert_eq!(ScriptNum::new(i32::min_value()), None);
+    assert!(ScriptNum::new(-2147483647).is_some());
+}
+
+fn addrv2_round_trip(address: NetworkAddress, expected: &[u8]) {
+    let time = ShortFormatTm::new(time::at_utc(time::Timespec::new(1600000000, 0)));
+    let entry = AddrV2Entry::new(time, VarInt::new(1), address, 8333);
+    let message = AddrV2Message::new(vec![entry]);
+
+    let mut buffer = vec![];
+    message.serialize(&mut buffer);
+
+    // count, time, services, then the address and the port
+    let mut encoded = vec![0x01, 0x00, 0x10, 0x5e, 0x5f, 0x01];
+    encoded.extend(expected);
+    encoded.extend(&[0x20, 0x8d]);
+    assert_eq!(buffer, encoded);
+
+    let result = AddrV2Message::deserialize(&mut Cursor::new(&buffer[..])).unwrap();
+    assert_eq!(result, message);
+}
+
+#[test]
+fn test_addrv2_ipv4() {
+    addrv2_round_trip(NetworkAddress::IPv4(Ipv4Addr::new(1, 2, 3, 4)),
+                      &[0x01, 0x04, 0x01, 0x02, 0x03, 0x04]);
+}
+
+#[test]
+fn test_addrv2_ipv6() {
+    let address = Ipv6Addr::new(0x2001, 0x0db8, 0, 0, 0, 0, 0, 0x0001);
+
+    let mut expected = vec![0x02, 0x10, 0x20, 0x01, 0x0d, 0xb8];
+    expected.extend(vec![0x00; 11]);
+    expected.push(0x01);
+
+    addrv2_round_trip(NetworkAddress::IPv6(address), &expected);
+}
+
+#[test]
+fn test_addrv2_unknown_network() {
+    // Tor v3 addresses are 32 bytes long
+    let mut expected = vec![0x04, 0x20];
+    expected.extend(vec![0xab; 32]);
+
+    addrv2_round_trip(NetworkAddress::Unknown(0x04, vec![0xab; 32]), &expected);
+
+    // IPv4 addresses must be 4 bytes long
+    let parse = |data: &[u8]| NetworkAddress::deserialize(&mut Cursor::new(data));
+    assert_eq!(parse(&[0x01, 0x03, 0x01, 0x02, 0x03]), Err(Error::InvalidValue(3)));
+    assert_eq!(parse(&[0x04, 0xfd, 0x01, 0x02]), Err(Error::LengthTooLarge(513)));
+}
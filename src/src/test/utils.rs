@@ -0,0 +1,86 @@
+// Helpers shared by the unit tests of several modules
+use net::messages::{BitcoinHash, BlockMessage, OutPoint, SerializeHash, TxIn, TxMessage, TxOut};
+use net::store::BlockStore;
+use net::validation::{coinbase_height_prefix, merkle_root};
+use transaction::Amount;
+
+use rand;
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+
+// Accepts every signature, for tests that only care about the scripts
+pub fn mock_checksig(_: &[u8], _: &Vec<u8>, _: &Vec<u8>) -> bool { true }
+
+// An empty temporary file, it is deleted right away and the open handle
+// keeps the data around
+pub fn get_file(name: &str) -> File {
+    let path = env::temp_dir().join(
+        format!("bitcoin-rust-{}-{}.dat", name, rand::random::<u64>()));
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .append(true)
+        .create(true)
+        .open(&path)
+        .unwrap();
+
+    fs::remove_file(&path).unwrap();
+
+    file
+}
+
+// DER signature with R = 1, S = `s` and SIGHASH_ALL
+pub fn get_sig(s: &[u8]) -> Vec<u8> {
+    let mut sig = vec![0x30, 5 + s.len() as u8, 0x02, 0x01, 0x01, 0x02, s.len() as u8];
+    sig.extend(s);
+    sig.push(0x01);
+    sig
+}
+
+// Output of a made up transaction, different for every `id`
+pub fn get_outpoint(id: u8) -> OutPoint {
+    OutPoint::new(BitcoinHash::new([id; 32]), 0)
+}
+
+// Spends `inputs` with OP_1 into OP_1 OP_EQUAL outputs worth `values`. The
+// inputs have sequence 0 so they signal replaceability (BIP125).
+pub fn get_tx(inputs: Vec<OutPoint>, values: Vec<u64>) -> TxMessage {
+    TxMessage {
+        version: 1,
+        tx_in: inputs.into_iter().map(|input| TxIn::new(input, vec![0x51], 0)).collect(),
+        tx_out: values.into_iter()
+            .map(|value| TxOut::new(Amount::from_satoshis(value), vec![0x51, 0x87]))
+            .collect(),
+        lock_time: 0,
+    }
+}
+
+// Claims the 50 BTC subsidy, the script starts with the BIP34 height
+pub fn get_coinbase(height: usize) -> TxMessage {
+    let mut tx = get_tx(vec![OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff)],
+                        vec![5000000000]);
+
+    // Coinbase scripts are at least 2 bytes long
+    tx.tx_in[0].script = coinbase_height_prefix(height);
+    tx.tx_in[0].script.push(0x00);
+    tx.tx_in[0].sequence = 0xffffffff;
+    tx
+}
+
+// `txns` under the testnet3 genesis header, with their merkle root
+pub fn get_block(txns: Vec<TxMessage>) -> BlockMessage {
+    let mut block = BlockStore::genesis_block(1296688602, 414098458);
+    let hashes: Vec<BitcoinHash> = txns.iter().map(|tx| tx.hash()).collect();
+
+    block.metadata.merkle_root = merkle_root(&hashes);
+    block.txns = txns;
+    block
+}
+
+pub fn get_next_block(prev_block: BitcoinHash, txns: Vec<TxMessage>) -> BlockMessage {
+    let mut block = get_block(txns);
+    block.metadata.prev_block = prev_block;
+    block
+}
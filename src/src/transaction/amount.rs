@@ -0,0 +1,128 @@
+use serialize::{Serialize, Serializer, Deserialize, Deserializer, Error};
+
+// Number of satoshis in one bitcoin
+pub const COIN: u64 = 100000000;
+
+// No amount larger than this is valid, see Core's MoneyRange
+pub const MAX_MONEY: u64 = 21000000 * COIN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Amount {
+    satoshis: u64,
+}
+
+impl Amount {
+    pub fn from_satoshis(satoshis: u64) -> Amount {
+        Amount {
+            satoshis: satoshis,
+        }
+    }
+
+    pub fn from_btc(btc: f64) -> Result<Amount, String> {
+        if !btc.is_finite() || btc < 0.0 {
+            return Err(format!("Invalid amount {}", btc));
+        }
+
+        let satoshis = (btc * COIN as f64).round();
+        if satoshis > MAX_MONEY as f64 {
+            return Err(format!("Amount {} is larger than MAX_MONEY", btc));
+        }
+
+        Ok(Amount::from_satoshis(satoshis as u64))
+    }
+
+    pub fn as_satoshis(&self) -> u64 {
+        self.satoshis
+    }
+
+    pub fn to_btc(&self) -> f64 {
+        self.satoshis as f64 / COIN as f64
+    }
+
+    // Amounts read from the network can be anything, this checks that
+    // the value is in the range allowed by consensus.
+    pub fn is_valid(&self) -> bool {
+        self.satoshis <= MAX_MONEY
+    }
+
+    pub fn checked_add(&self, other: Amount) -> Option<Amount> {
+        self.satoshis.checked_add(other.satoshis)
+            .map(Amount::from_satoshis)
+            .and_then(|a| if a.is_valid() { Some(a) } else { None })
+    }
+
+    pub fn checked_sub(&self, other: Amount) -> Option<Amount> {
+        self.satoshis.checked_sub(other.satoshis)
+            .map(Amount::from_satoshis)
+    }
+}
+
+impl Serialize for Amount {
+    fn serialize(&self, serializer: &mut Serializer) {
+        self.satoshis.serialize(serializer);
+    }
+
+    fn size() -> usize { 8 }
+}
+
+impl Deserialize for Amount {
+    fn deserialize(deserializer: &mut Deserializer) -> Result<Self, Error> {
+        u64::deserialize(deserializer).map(Amount::from_satoshis)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialize::{Serialize, Deserialize};
+
+    use std::io::Cursor;
+
+    #[test]
+    fn test_checked_add() {
+        let a = Amount::from_satoshis(5 * COIN);
+        let b = Amount::from_satoshis(3 * COIN);
+
+        assert_eq!(a.checked_add(b), Some(Amount::from_satoshis(8 * COIN)));
+        assert_eq!(a.checked_sub(b), Some(Amount::from_satoshis(2 * COIN)));
+        assert_eq!(b.checked_sub(a), None);
+
+        let max = Amount::from_satoshis(MAX_MONEY);
+        assert_eq!(max.checked_add(Amount::default()), Some(max));
+        assert_eq!(max.checked_add(Amount::from_satoshis(1)), None);
+
+        let huge = Amount::from_satoshis(u64::max_value());
+        assert!(!huge.is_valid());
+        assert_eq!(huge.checked_add(Amount::from_satoshis(1)), None);
+    }
+
+    #[test]
+    fn test_btc_conversion() {
+        assert_eq!(Amount::from_btc(1.5), Ok(Amount::from_satoshis(150000000)));
+        assert_eq!(Amount::from_btc(0.1), Ok(Amount::from_satoshis(10000000)));
+        assert_eq!(Amount::from_btc(0.00000001), Ok(Amount::from_satoshis(1)));
+
+        // Fractions of a satoshi are rounded to the nearest one
+        assert_eq!(Amount::from_btc(0.123456789), Ok(Amount::from_satoshis(12345679)));
+        assert_eq!(Amount::from_btc(0.000000004), Ok(Amount::from_satoshis(0)));
+
+        assert_eq!(Amount::from_btc(21000000.0), Ok(Amount::from_satoshis(MAX_MONEY)));
+        assert!(Amount::from_btc(21000000.00000001).is_err());
+        assert!(Amount::from_btc(-1.0).is_err());
+
+        assert_eq!(Amount::from_satoshis(150000000).to_btc(), 1.5);
+        assert_eq!(Amount::from_satoshis(1).to_btc(), 0.00000001);
+    }
+
+    #[test]
+    fn test_serialize() {
+        let amount = Amount::from_satoshis(5000000000);
+
+        let mut buffer = vec![];
+        amount.serialize(&mut buffer);
+        assert_eq!(buffer, vec![0x00, 0xf2, 0x05, 0x2a, 0x01, 0x00, 0x00, 0x00]);
+
+        let result = Amount::deserialize(&mut Cursor::new(&buffer[..]));
+        assert_eq!(result, Ok(amount));
+    }
+}
@@ -0,0 +1,582 @@
+use net::messages::{TxMessage, OutPoint, BitcoinHash, SerializeHash};
+use serialize::Serialize;
+
+use std::collections::{HashMap, HashSet};
+
+use super::{Amount, DEFAULT_MIN_RELAY_TX_FEE};
+
+// Same default as Core, 300MB
+pub const DEFAULT_MAX_MEMPOOL_SIZE: usize = 300 * 1000 * 1000;
+
+// Same defaults as Core, counts and sizes include the transaction itself
+pub const DEFAULT_ANCESTOR_LIMIT: usize = 25;
+pub const DEFAULT_ANCESTOR_SIZE_LIMIT: usize = 101 * 1000;
+pub const DEFAULT_DESCENDANT_LIMIT: usize = 25;
+pub const DEFAULT_DESCENDANT_SIZE_LIMIT: usize = 101 * 1000;
+
+// Bounds on the chains of unconfirmed transactions in the pool
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PackageLimits {
+    pub ancestor_count: usize,
+    pub ancestor_size: usize,
+    pub descendant_count: usize,
+    pub descendant_size: usize,
+}
+
+impl Default for PackageLimits {
+    fn default() -> PackageLimits {
+        PackageLimits {
+            ancestor_count: DEFAULT_ANCESTOR_LIMIT,
+            ancestor_size: DEFAULT_ANCESTOR_SIZE_LIMIT,
+            descendant_count: DEFAULT_DESCENDANT_LIMIT,
+            descendant_size: DEFAULT_DESCENDANT_SIZE_LIMIT,
+        }
+    }
+}
+
+struct MempoolEntry {
+    tx: TxMessage,
+    fee: u64,
+    size: usize,
+    // Insertion order, used to evict older transactions first on ties
+    sequence: u64,
+    // Transactions in the pool that this one spends from
+    parents: HashSet<BitcoinHash>,
+    // Transactions in the pool that spend from this one
+    children: HashSet<BitcoinHash>,
+    // Unconfirmed ancestors and descendants, including this transaction
+    ancestor_count: usize,
+    ancestor_size: usize,
+    descendant_count: usize,
+    descendant_size: usize,
+}
+
+impl MempoolEntry {
+    fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.size as f64
+    }
+}
+
+pub struct Mempool {
+    entries: HashMap<BitcoinHash, MempoolEntry>,
+    max_size: usize,
+    size: usize,
+    next_sequence: u64,
+    limits: PackageLimits,
+}
+
+impl Mempool {
+    pub fn new(max_size: usize) -> Mempool {
+        Mempool::with_limits(max_size, PackageLimits::default())
+    }
+
+    pub fn with_limits(max_size: usize, limits: PackageLimits) -> Mempool {
+        Mempool {
+            entries: HashMap::new(),
+            max_size: max_size,
+            size: 0,
+            next_sequence: 0,
+            limits: limits,
+        }
+    }
+
+    pub fn contains(&self, hash: &BitcoinHash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &BitcoinHash) -> Option<&TxMessage> {
+        self.entries.get(hash).map(|entry| &entry.tx)
+    }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+
+    // Total size in bytes of the transactions in the pool
+    pub fn size(&self) -> usize { self.size }
+
+    // Value of an output created by a transaction in the pool
+    pub fn output_value(&self, output: &OutPoint) -> Option<Amount> {
+        self.entries.get(&output.hash)
+            .and_then(|entry| entry.tx.tx_out.get(output.index as usize))
+            .map(|tx_out| tx_out.value)
+    }
+
+    // Number of unconfirmed ancestors and descendants of a transaction in
+    // the pool, both including the transaction itself
+    pub fn package_counts(&self, hash: &BitcoinHash) -> Option<(usize, usize)> {
+        self.entries.get(hash).map(|entry| (entry.ancestor_count, entry.descendant_count))
+    }
+
+    // All the transactions in the pool that `parents` depend on, directly
+    // or not
+    fn ancestors<'a, I: Iterator<Item=&'a BitcoinHash>>(&self, parents: I) -> HashSet<BitcoinHash> {
+        let mut ancestors = HashSet::new();
+        let mut pending: Vec<BitcoinHash> = parents.cloned().collect();
+
+        while let Some(hash) = pending.pop() {
+            if let Some(entry) = self.entries.get(&hash) {
+                if ancestors.insert(hash) {
+                    pending.extend(entry.parents.iter().cloned());
+                }
+            }
+        }
+
+        ancestors
+    }
+
+    fn descendants(&self, hash: &BitcoinHash) -> HashSet<BitcoinHash> {
+        let mut descendants = HashSet::new();
+        let mut pending = vec![*hash];
+
+        while let Some(current) = pending.pop() {
+            if let Some(entry) = self.entries.get(&current) {
+                for child in &entry.children {
+                    if descendants.insert(*child) {
+                        pending.push(*child);
+                    }
+                }
+            }
+        }
+
+        descendants
+    }
+
+    // Adds `tx` and evicts the transactions with the lowest fee rate until
+    // the pool fits in max_size again, this can include `tx` itself.
+    // Returns the hashes of the evicted transactions, or an error if `tx`
+    // would make a chain of unconfirmed transactions exceed the limits.
+    pub fn insert(&mut self, tx: TxMessage, fee: u64) -> Result<Vec<BitcoinHash>, String> {
+        let hash = tx.hash();
+        if self.contains(&hash) {
+            return Ok(vec![]);
+        }
+
+        try!(tx.is_valid_structure());
+
+        let size = tx.vsize();
+        let parents: HashSet<BitcoinHash> = tx.tx_in.iter()
+            .map(|input| input.previous_output.hash)
+            .filter(|parent| self.contains(parent))
+            .collect();
+
+        let ancestors = self.ancestors(parents.iter());
+        let ancestor_count = ancestors.len() + 1;
+        let ancestor_size = ancestors.iter()
+            .map(|ancestor| self.entries[ancestor].size)
+            .sum::<usize>() + size;
+
+        if ancestor_count > self.limits.ancestor_count {
+            return Err(format!("Too many unconfirmed ancestors: {} > {}",
+                               ancestor_count, self.limits.ancestor_count));
+        }
+
+        if ancestor_size > self.limits.ancestor_size {
+            return Err(format!("Unconfirmed ancestors too large: {} > {}",
+                               ancestor_size, self.limits.ancestor_size));
+        }
+
+        for ancestor in &ancestors {
+            let entry = &self.entries[ancestor];
+            if entry.descendant_count + 1 > self.limits.descendant_count {
+                return Err(format!("Too many unconfirmed descendants for {:?}", ancestor));
+            }
+
+            if entry.descendant_size + size > self.limits.descendant_size {
+                return Err(format!("Unconfirmed descendants too large for {:?}", ancestor));
+            }
+        }
+
+        for ancestor in &ancestors {
+            let entry = self.entries.get_mut(ancestor).unwrap();
+            entry.descendant_count += 1;
+            entry.descendant_size += size;
+        }
+
+        for parent in &parents {
+            self.entries.get_mut(parent).unwrap().children.insert(hash);
+        }
+
+        self.entries.insert(hash, MempoolEntry {
+            tx: tx,
+            fee: fee,
+            size: size,
+            sequence: self.next_sequence,
+            parents: parents,
+            children: HashSet::new(),
+            ancestor_count: ancestor_count,
+            ancestor_size: ancestor_size,
+            descendant_count: 1,
+            descendant_size: size,
+        });
+
+        self.next_sequence += 1;
+        self.size += size;
+
+        let mut evicted = vec![];
+        while self.size > self.max_size {
+            let cheapest = match self.cheapest() {
+                Some(hash) => hash,
+                None => break,
+            };
+
+            evicted.extend(self.remove_recursive(&cheapest));
+        }
+
+        Ok(evicted)
+    }
+
+    pub fn remove(&mut self, hash: &BitcoinHash) -> Option<TxMessage> {
+        let (size, parents) = match self.entries.get(hash) {
+            Some(entry) => (entry.size, entry.parents.clone()),
+            None => return None,
+        };
+
+        for ancestor in self.ancestors(parents.iter()) {
+            let entry = self.entries.get_mut(&ancestor).unwrap();
+            entry.descendant_count -= 1;
+            entry.descendant_size -= size;
+        }
+
+        for descendant in self.descendants(hash) {
+            let entry = self.entries.get_mut(&descendant).unwrap();
+            entry.ancestor_count -= 1;
+            entry.ancestor_size -= size;
+        }
+
+        self.entries.remove(hash).map(|entry| {
+            for parent in &entry.parents {
+                self.entries.get_mut(parent).map(|parent| parent.children.remove(hash));
+            }
+
+            for child in &entry.children {
+                self.entries.get_mut(child).map(|child| child.parents.remove(hash));
+            }
+
+            self.size -= entry.size;
+            entry.tx
+        })
+    }
+
+    // Removes `hash` and everything that spends from it, like Core does when
+    // evicting, returns the hashes of the removed transactions
+    pub fn remove_recursive(&mut self, hash: &BitcoinHash) -> Vec<BitcoinHash> {
+        if !self.contains(hash) {
+            return vec![];
+        }
+
+        let mut removed: Vec<BitcoinHash> = self.descendants(hash).into_iter().collect();
+        removed.push(*hash);
+
+        // Children go first so that the counters of the ancestors that stay
+        // in the pool are still updated through them
+        removed.sort_by(|a, b| {
+            self.entries[b].ancestor_count.cmp(&self.entries[a].ancestor_count)
+        });
+
+        for removed_hash in &removed {
+            self.remove(removed_hash);
+        }
+
+        removed
+    }
+
+    // Transactions and their fee, highest fee rate first
+    pub fn by_fee_rate(&self) -> Vec<(&TxMessage, u64)> {
+        let mut entries: Vec<&MempoolEntry> = self.entries.values().collect();
+        entries.sort_by(|a, b| {
+            b.fee_rate().partial_cmp(&a.fee_rate()).unwrap()
+                .then(a.sequence.cmp(&b.sequence))
+        });
+
+        entries.into_iter().map(|entry| (&entry.tx, entry.fee)).collect()
+    }
+
+    fn cheapest(&self) -> Option<BitcoinHash> {
+        let mut result: Option<(&BitcoinHash, &MempoolEntry)> = None;
+
+        for (hash, entry) in &self.entries {
+            let is_cheaper = match result {
+                None => true,
+                Some((_, current)) =>
+                    entry.fee_rate() < current.fee_rate() ||
+                    (entry.fee_rate() == current.fee_rate() &&
+                     entry.sequence < current.sequence),
+            };
+
+            if is_cheaper {
+                result = Some((hash, entry));
+            }
+        }
+
+        result.map(|(hash, _)| *hash)
+    }
+}
+
+// Checks whether `new` may replace `old` in the mempool following BIP125.
+// Fees are in satoshis, `is_unconfirmed` tells whether an output belongs
+// to a transaction that is still in the mempool.
+pub fn can_replace(old: &TxMessage, new: &TxMessage, old_fee: u64, new_fee: u64,
+                   is_unconfirmed: &Fn(&OutPoint) -> bool) -> Result<(), String> {
+    if !old.is_rbf_signaled() {
+        return Err(format!("Original transaction {:?} is not replaceable", old.hash()));
+    }
+
+    let old_size = old.serialized_size() as u64;
+    let new_size = new.serialized_size() as u64;
+
+    // The replacement has to pay for its own relay on top of the original fee
+    let min_fee = old_fee + DEFAULT_MIN_RELAY_TX_FEE * new_size / 1000;
+    if new_fee < min_fee {
+        return Err(format!("Insufficient fee: {} < {}", new_fee, min_fee));
+    }
+
+    if new_fee * old_size <= old_fee * new_size {
+        return Err(format!("Insufficient fee rate: {}/{} <= {}/{}",
+                           new_fee, new_size, old_fee, old_size));
+    }
+
+    for input in &new.tx_in {
+        let output = &input.previous_output;
+        let spent_by_old = old.tx_in.iter().any(|i| &i.previous_output == output);
+
+        if !spent_by_old && is_unconfirmed(output) {
+            return Err(format!("Replacement adds unconfirmed input {:?}", output));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::messages::{TxMessage, OutPoint, SerializeHash};
+    use test::utils::{get_outpoint, get_tx};
+    use transaction::SEQUENCE_FINAL;
+
+    fn none_unconfirmed(_: &OutPoint) -> bool { false }
+
+    #[test]
+    fn test_mempool_eviction() {
+        // Each transaction has a different output so they all hash differently
+        let txs: Vec<TxMessage> = (0..5)
+            .map(|i| get_tx(vec![get_outpoint(i)], vec![1000]))
+            .collect();
+        let size = txs[0].vsize();
+
+        let mut mempool = Mempool::new(size * 3);
+        assert!(mempool.insert(txs[0].clone(), 500).unwrap().is_empty());
+        assert!(mempool.insert(txs[1].clone(), 100).unwrap().is_empty());
+        assert!(mempool.insert(txs[2].clone(), 300).unwrap().is_empty());
+        assert_eq!(mempool.size(), size * 3);
+
+        // The pool is full, the cheapest transaction has to go
+        assert_eq!(mempool.insert(txs[3].clone(), 400), Ok(vec![txs[1].hash()]));
+        assert!(!mempool.contains(&txs[1].hash()));
+        assert!(mempool.contains(&txs[0].hash()));
+        assert!(mempool.contains(&txs[2].hash()));
+        assert!(mempool.contains(&txs[3].hash()));
+
+        // A transaction cheaper than everything else doesn't get in
+        assert_eq!(mempool.insert(txs[4].clone(), 50), Ok(vec![txs[4].hash()]));
+        assert_eq!(mempool.len(), 3);
+        assert_eq!(mempool.size(), size * 3);
+
+        // Inserting the same transaction twice is a no-op
+        assert!(mempool.insert(txs[0].clone(), 500).unwrap().is_empty());
+        assert_eq!(mempool.len(), 3);
+    }
+
+    #[test]
+    fn test_mempool_eviction_by_age() {
+        let txs: Vec<TxMessage> = (0..3)
+            .map(|i| get_tx(vec![get_outpoint(i)], vec![1000]))
+            .collect();
+        let size = txs[0].vsize();
+
+        let mut mempool = Mempool::new(size * 2);
+        mempool.insert(txs[0].clone(), 100).unwrap();
+        mempool.insert(txs[1].clone(), 100).unwrap();
+
+        // Same fee rate, the oldest one is evicted
+        assert_eq!(mempool.insert(txs[2].clone(), 100), Ok(vec![txs[0].hash()]));
+    }
+
+    #[test]
+    fn test_mempool_eviction_descendants() {
+        let first = get_tx(vec![get_outpoint(1)], vec![100000]);
+        let second = spend(&[&first], 90000);
+        let third = spend(&[&second], 80000);
+        let other = get_tx(vec![get_outpoint(2)], vec![100000]);
+        let size = first.vsize();
+
+        let mut mempool = Mempool::new(size * 3);
+        mempool.insert(first.clone(), 5000).unwrap();
+        mempool.insert(second.clone(), 100).unwrap();
+        mempool.insert(third.clone(), 1000).unwrap();
+        assert_eq!(mempool.package_counts(&first.hash()), Some((1, 3)));
+
+        // Evicting the second transaction takes the third one with it
+        let evicted = mempool.insert(other.clone(), 2000).unwrap();
+        assert_eq!(evicted, vec![third.hash(), second.hash()]);
+        assert!(!mempool.contains(&second.hash()));
+        assert!(!mempool.contains(&third.hash()));
+        assert!(mempool.contains(&other.hash()));
+        assert_eq!(mempool.package_counts(&first.hash()), Some((1, 1)));
+        assert_eq!(mempool.size(), size * 2);
+    }
+
+    #[test]
+    fn test_mempool_shared_descendants() {
+        let first = get_tx(vec![get_outpoint(1)], vec![100000]);
+        let second = get_tx(vec![get_outpoint(2)], vec![100000]);
+        let child = spend(&[&first, &second], 190000);
+        let grandchild = spend(&[&child], 180000);
+
+        let mut mempool = Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE);
+        for tx in &[&first, &second, &child, &grandchild] {
+            mempool.insert((*tx).clone(), 1000).unwrap();
+        }
+        assert_eq!(mempool.package_counts(&first.hash()), Some((1, 3)));
+        assert_eq!(mempool.package_counts(&second.hash()), Some((1, 3)));
+        assert_eq!(mempool.package_counts(&grandchild.hash()), Some((4, 1)));
+
+        // Confirming one parent leaves the other one linked to the children
+        mempool.remove(&first.hash());
+        assert_eq!(mempool.package_counts(&child.hash()), Some((2, 2)));
+        assert_eq!(mempool.remove_recursive(&second.hash()),
+                   vec![grandchild.hash(), child.hash(), second.hash()]);
+        assert_eq!(mempool.len(), 0);
+        assert_eq!(mempool.size(), 0);
+    }
+
+    #[test]
+    fn test_mempool_output_value() {
+        let tx = get_tx(vec![get_outpoint(1)], vec![1000]);
+        let mut mempool = Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE);
+        mempool.insert(tx.clone(), 0).unwrap();
+
+        assert_eq!(mempool.output_value(&OutPoint::new(tx.hash(), 0)),
+                   Some(Amount::from_satoshis(1000)));
+        assert_eq!(mempool.output_value(&OutPoint::new(tx.hash(), 1)), None);
+        assert_eq!(mempool.output_value(&get_outpoint(1)), None);
+
+        assert_eq!(mempool.remove(&tx.hash()), Some(tx));
+        assert_eq!(mempool.size(), 0);
+    }
+
+    fn spend(parents: &[&TxMessage], value: u64) -> TxMessage {
+        get_tx(parents.iter().map(|parent| OutPoint::new(parent.hash(), 0)).collect(),
+               vec![value])
+    }
+
+    #[test]
+    fn test_mempool_duplicate_inputs() {
+        let mut mempool = Mempool::new(100000);
+        let tx = get_tx(vec![get_outpoint(1), get_outpoint(1)], vec![1000]);
+        assert!(mempool.insert(tx.clone(), 1000).is_err());
+        assert!(!mempool.contains(&tx.hash()));
+    }
+
+    #[test]
+    fn test_mempool_ancestor_limit() {
+        let mut mempool = Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE);
+        let mut chain = vec![get_tx(vec![get_outpoint(1)], vec![100000])];
+        for i in 1..26 {
+            let tx = spend(&[&chain[i - 1]], 100000 - i as u64 * 1000);
+            chain.push(tx);
+        }
+
+        for tx in &chain[..25] {
+            assert!(mempool.insert(tx.clone(), 1000).is_ok());
+        }
+        assert_eq!(mempool.package_counts(&chain[0].hash()), Some((1, 25)));
+        assert_eq!(mempool.package_counts(&chain[24].hash()), Some((25, 1)));
+
+        // The 26th transaction would have 25 unconfirmed ancestors
+        assert!(mempool.insert(chain[25].clone(), 1000).is_err());
+        assert!(!mempool.contains(&chain[25].hash()));
+        assert_eq!(mempool.package_counts(&chain[0].hash()), Some((1, 25)));
+
+        // Once the first one is confirmed the chain is short enough
+        mempool.remove(&chain[0].hash());
+        assert_eq!(mempool.package_counts(&chain[24].hash()), Some((24, 1)));
+        assert!(mempool.insert(chain[25].clone(), 1000).is_ok());
+    }
+
+    #[test]
+    fn test_mempool_descendant_limit() {
+        let parent = get_tx(vec![get_outpoint(1)], vec![1000; 30]);
+
+        let mut mempool = Mempool::new(DEFAULT_MAX_MEMPOOL_SIZE);
+        mempool.insert(parent.clone(), 1000).unwrap();
+
+        let children: Vec<TxMessage> = (0..25)
+            .map(|i| get_tx(vec![OutPoint::new(parent.hash(), i)], vec![500]))
+            .collect();
+
+        for child in &children[..24] {
+            assert!(mempool.insert(child.clone(), 500).is_ok());
+        }
+        assert!(mempool.insert(children[24].clone(), 500).is_err());
+        assert_eq!(mempool.package_counts(&parent.hash()), Some((1, 25)));
+    }
+
+    #[test]
+    fn test_mempool_package_size_limit() {
+        let first = get_tx(vec![get_outpoint(1)], vec![100000]);
+        let second = spend(&[&first], 90000);
+        let limits = PackageLimits {
+            ancestor_size: first.vsize() + second.vsize() - 1,
+            ..PackageLimits::default()
+        };
+
+        let mut mempool = Mempool::with_limits(DEFAULT_MAX_MEMPOOL_SIZE, limits);
+        mempool.insert(first, 1000).unwrap();
+        assert!(mempool.insert(second.clone(), 1000).is_err());
+
+        // Unrelated transactions are not affected
+        assert!(mempool.insert(get_tx(vec![get_outpoint(2)], vec![100000]), 1000).is_ok());
+    }
+
+    #[test]
+    fn test_can_replace() {
+        let old = get_tx(vec![get_outpoint(1)], vec![100000]);
+        let new = get_tx(vec![get_outpoint(1)], vec![90000]);
+
+        assert_eq!(can_replace(&old, &new, 1000, 11000, &none_unconfirmed), Ok(()));
+
+        // Adding a confirmed input is fine
+        let new = get_tx(vec![get_outpoint(1), get_outpoint(2)], vec![90000]);
+        assert_eq!(can_replace(&old, &new, 1000, 11000, &none_unconfirmed), Ok(()));
+    }
+
+    #[test]
+    fn test_can_replace_rejected() {
+        let old = get_tx(vec![get_outpoint(1)], vec![100000]);
+        let new = get_tx(vec![get_outpoint(1)], vec![99000]);
+
+        // Same fee, and a bump that doesn't cover relaying the replacement
+        assert!(can_replace(&old, &new, 1000, 1000, &none_unconfirmed).is_err());
+        assert!(can_replace(&old, &new, 1000, 1050, &none_unconfirmed).is_err());
+
+        // A bigger replacement has to pay a higher fee rate too
+        let big = get_tx((1..11).map(get_outpoint).collect(), vec![99000]);
+        let big_size = big.serialized_size() as u64;
+        let old_size = old.serialized_size() as u64;
+        let fee = 100000 * big_size / old_size;
+        assert!(can_replace(&old, &big, 100000, fee, &none_unconfirmed).is_err());
+        assert_eq!(can_replace(&old, &big, 100000, fee + 1, &none_unconfirmed), Ok(()));
+
+        let mut final_tx = get_tx(vec![get_outpoint(1)], vec![100000]);
+        final_tx.tx_in[0].sequence = SEQUENCE_FINAL;
+        assert!(can_replace(&final_tx, &new, 1000, 11000, &none_unconfirmed).is_err());
+
+        let unconfirmed = |output: &OutPoint| *output == get_outpoint(2);
+        let new = get_tx(vec![get_outpoint(1), get_outpoint(2)], vec![90000]);
+        assert!(can_replace(&old, &new, 1000, 11000, &unconfirmed).is_err());
+
+        // Inputs already spent by the original don't count as new
+        let old = get_tx(vec![get_outpoint(1), get_outpoint(2)], vec![100000]);
+        assert_eq!(can_replace(&old, &new, 1000, 11000, &unconfirmed), Ok(()));
+    }
+}
@@ -0,0 +1,665 @@
+mod amount;
+pub mod mempool;
+
+pub use self::amount::{Amount, COIN, MAX_MONEY};
+
+use net::messages::{TxMessage, TxIn, TxOut, OutPoint, BitcoinHash};
+#[cfg(test)]
+use net::messages::SerializeHash;
+use script::{ecdsa_verify, find_and_delete, is_low_s, is_minimal_push, is_push_only,
+             is_valid_signature_encoding, BitcoinScript, Parser, ScriptError, ScriptErrorKind,
+             ScriptFlags};
+#[cfg(test)]
+use script::{build_p2pkh_script_sig, classify, ecdsa_sign, public_key, ScriptType};
+use serialize::Serialize;
+use utils::CryptoUtils;
+
+use std::collections::HashSet;
+
+pub const SEQUENCE_FINAL: u32 = 0xffffffff;
+
+// BIP125: inputs with a sequence up to this value opt in to replacement
+pub const MAX_BIP125_RBF_SEQUENCE: u32 = 0xfffffffd;
+
+// Below this value lock_time is interpreted as a block height,
+// otherwise as a unix timestamp.
+pub const LOCKTIME_THRESHOLD: u32 = 500000000;
+
+// BIP68 sequence number semantics
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000ffff;
+
+// Size limits of the coinbase sigScript
+const MIN_COINBASE_SCRIPT_SIZE: usize = 2;
+const MAX_COINBASE_SCRIPT_SIZE: usize = 100;
+
+// Minimum relay fee in satoshis per 1000 bytes
+pub const DEFAULT_MIN_RELAY_TX_FEE: u64 = 1000;
+
+// Size of a typical input spending an output: outpoint, script length,
+// a 107 byte signature script and sequence.
+const SPENDING_INPUT_SIZE: u64 = 32 + 4 + 1 + 107 + 4;
+
+const OP_RETURN: u8 = 0x6a;
+const OP_CODESEPARATOR: u8 = 0xab;
+
+pub const SIGHASH_ALL: u32 = 1;
+pub const SIGHASH_NONE: u32 = 2;
+pub const SIGHASH_SINGLE: u32 = 3;
+pub const SIGHASH_ANYONECANPAY: u32 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RelativeLockTime {
+    Blocks(u16),
+    // Expressed in units of 512 seconds
+    Time(u16),
+}
+
+impl RelativeLockTime {
+    pub fn as_seconds(&self) -> Option<u32> {
+        match self {
+            &RelativeLockTime::Time(units) => Some(units as u32 * 512),
+            &RelativeLockTime::Blocks(_) => None,
+        }
+    }
+}
+
+impl TxIn {
+    pub fn is_final(&self) -> bool {
+        self.sequence == SEQUENCE_FINAL
+    }
+
+    pub fn relative_locktime(&self) -> Option<RelativeLockTime> {
+        if self.sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return None;
+        }
+
+        let value = (self.sequence & SEQUENCE_LOCKTIME_MASK) as u16;
+
+        if self.sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            Some(RelativeLockTime::Time(value))
+        } else {
+            Some(RelativeLockTime::Blocks(value))
+        }
+    }
+}
+
+impl TxOut {
+    // An output is dust if spending it costs more than a third of its value
+    // at the given fee rate (in satoshis per 1000 bytes).
+    pub fn is_dust(&self, dust_relay_fee: u64) -> bool {
+        // Provably unspendable outputs are never dust
+        if self.pk_script.first() == Some(&OP_RETURN) {
+            return false;
+        }
+
+        let spending_size = self.serialized_size() as u64 + SPENDING_INPUT_SIZE;
+        let threshold = 3 * spending_size * dust_relay_fee / 1000;
+
+        self.value.as_satoshis() < threshold
+    }
+}
+
+impl TxMessage {
+    pub fn is_final(&self, height: u32, time: u32) -> bool {
+        if self.lock_time == 0 {
+            return true;
+        }
+
+        let threshold = if self.lock_time < LOCKTIME_THRESHOLD { height } else { time };
+        if self.lock_time < threshold {
+            return true;
+        }
+
+        // A lock_time that is not yet satisfied can still be overridden
+        // by finalizing every input.
+        self.tx_in.iter().all(|input| input.is_final())
+    }
+
+    // The coinbase has a single input that doesn't spend anything
+    pub fn is_coinbase(&self) -> bool {
+        self.tx_in.len() == 1 &&
+            self.tx_in[0].previous_output.hash == BitcoinHash::new([0; 32]) &&
+            self.tx_in[0].previous_output.index == 0xffffffff
+    }
+
+    // Spending the same output twice makes the transaction invalid
+    pub fn has_duplicate_inputs(&self) -> bool {
+        let mut spent = HashSet::new();
+        !self.tx_in.iter().all(|input| spent.insert(&input.previous_output))
+    }
+
+    // Checks that don't depend on the outputs being spent, like Core's
+    // CheckTransaction
+    pub fn is_valid_structure(&self) -> Result<(), String> {
+        if self.tx_in.is_empty() {
+            return Err(format!("Transaction has no inputs"));
+        }
+
+        if self.tx_out.is_empty() {
+            return Err(format!("Transaction has no outputs"));
+        }
+
+        let mut output_value = Amount::default();
+        for output in &self.tx_out {
+            output_value = match output_value.checked_add(output.value) {
+                Some(value) => value,
+                None => return Err(format!("Outputs are worth more than MAX_MONEY")),
+            };
+        }
+
+        if self.has_duplicate_inputs() {
+            return Err(format!("Transaction spends an output twice"));
+        }
+
+        if self.is_coinbase() {
+            let length = self.tx_in[0].script.len();
+            if length < MIN_COINBASE_SCRIPT_SIZE || length > MAX_COINBASE_SCRIPT_SIZE {
+                return Err(format!("Invalid coinbase script size {}", length));
+            }
+        } else if self.tx_in.iter().any(|input| input.previous_output.index == 0xffffffff &&
+                                        input.previous_output.hash == BitcoinHash::new([0; 32])) {
+            return Err(format!("Input spends a null output"));
+        }
+
+        Ok(())
+    }
+
+    pub fn is_rbf_signaled(&self) -> bool {
+        self.tx_in.iter().any(|input| input.sequence <= MAX_BIP125_RBF_SEQUENCE)
+    }
+
+    // We don't support segwit so this is just the serialized size
+    pub fn vsize(&self) -> usize {
+        self.serialized_size()
+    }
+
+    // `spent_value` returns the value of an output spent by this transaction.
+    // Returns None if any of them is unknown or if the outputs are worth
+    // more than the inputs.
+    pub fn fee(&self, spent_value: &Fn(&OutPoint) -> Option<Amount>) -> Option<Amount> {
+        let mut input_value = Amount::default();
+        for input in &self.tx_in {
+            input_value = match spent_value(&input.previous_output)
+                    .and_then(|value| input_value.checked_add(value)) {
+                Some(value) => value,
+                None => return None,
+            };
+        }
+
+        let mut output_value = Amount::default();
+        for output in &self.tx_out {
+            output_value = match output_value.checked_add(output.value) {
+                Some(value) => value,
+                None => return None,
+            };
+        }
+
+        input_value.checked_sub(output_value)
+    }
+
+    // Whether the txid can't be changed by a third party rewriting the
+    // sigScripts: they have to only push data using minimal pushes and any
+    // signature (anything starting like a DER sequence) must be strict DER
+    // with a low S. Witness data is not supported.
+    pub fn is_non_malleable(&self) -> bool {
+        if self.is_coinbase() {
+            return true;
+        }
+
+        self.tx_in.iter().all(|input| {
+            if !is_push_only(&input.script) {
+                return false;
+            }
+
+            BitcoinScript::new(input.script.clone()).iter().all(|(_, op_code, data)| {
+                let data = match data {
+                    Some(data) => data,
+                    // OP_1NEGATE, OP_1 to OP_16
+                    None => return true,
+                };
+
+                if !is_minimal_push(op_code.to_byte(), &data) {
+                    return false;
+                }
+
+                data.first() != Some(&0x30) ||
+                    (is_valid_signature_encoding(&data) && is_low_s(&data))
+            })
+        })
+    }
+
+    // Runs the scripts of every input, `prevouts` are the value and script
+    // of the outputs they spend in the same order. Useful to check a
+    // transaction without having the chain. Witness data is not supported,
+    // so the values are not used yet. A coinbase doesn't spend anything.
+    pub fn verify(&self, prevouts: &[(u64, Vec<u8>)], flags: ScriptFlags)
+        -> Result<(), ScriptError> {
+        if self.is_coinbase() || prevouts.len() != self.tx_in.len() {
+            return Err(ScriptError::new(ScriptErrorKind::BadPrevouts));
+        }
+
+        for (index, &(_, ref script)) in prevouts.iter().enumerate() {
+            let checksig = |script_code: &[u8], pub_key: &Vec<u8>, sig: &Vec<u8>| {
+                self.check_signature(index, script_code, pub_key, sig)
+            };
+
+            let script_sig = self.tx_in[index].script.clone();
+            match Parser::execute_with_flags(script_sig, script.clone(), &checksig, flags) {
+                Ok(true) => {},
+                Ok(false) => return Err(ScriptError::new(ScriptErrorKind::EvalFalse)),
+                Err(_) => return Err(ScriptError::new(ScriptErrorKind::BadOpcode)),
+            }
+        }
+
+        Ok(())
+    }
+
+    // Legacy (pre-segwit) signature hash for the input at `input_index`.
+    pub fn signature_hash(&self, input_index: usize, script_code: &[u8],
+                          hash_type: u32) -> BitcoinHash {
+        // Core signs the value 1 instead of failing when the input doesn't
+        // exist or, because of a bug, when SIGHASH_SINGLE has no matching
+        // output. This is consensus so we have to do the same.
+        let mut one = [0; 32];
+        one[0] = 1;
+
+        let base_type = hash_type & 0x1f;
+        if input_index >= self.tx_in.len() ||
+                (base_type == SIGHASH_SINGLE && input_index >= self.tx_out.len()) {
+            return BitcoinHash::new(one);
+        }
+
+        let script_code = find_and_delete(script_code, &[OP_CODESEPARATOR]);
+
+        let mut tx = self.clone();
+        for (i, input) in tx.tx_in.iter_mut().enumerate() {
+            input.script = if i == input_index { script_code.clone() } else { vec![] };
+        }
+
+        if base_type == SIGHASH_NONE || base_type == SIGHASH_SINGLE {
+            // Other inputs can be updated freely
+            for (i, input) in tx.tx_in.iter_mut().enumerate() {
+                if i != input_index { input.sequence = 0; }
+            }
+        }
+
+        if base_type == SIGHASH_NONE {
+            tx.tx_out.clear();
+        } else if base_type == SIGHASH_SINGLE {
+            tx.tx_out.truncate(input_index + 1);
+            for output in tx.tx_out[..input_index].iter_mut() {
+                *output = TxOut::new(Amount::from_satoshis(u64::max_value()), vec![]);
+            }
+        }
+
+        if hash_type & SIGHASH_ANYONECANPAY != 0 {
+            let input = tx.tx_in.swap_remove(input_index);
+            tx.tx_in = vec![input];
+        }
+
+        let mut buffer = vec![];
+        tx.serialize(&mut buffer);
+        hash_type.serialize(&mut buffer);
+
+        BitcoinHash::new(CryptoUtils::sha256(&CryptoUtils::sha256(&buffer)))
+    }
+
+    // Checks `sig`, followed by its hash type, against the legacy signature
+    // hash of input `input_index`. Meant to be the checksig of the interpreter.
+    pub fn check_signature(&self, input_index: usize, script_code: &[u8], pub_key: &[u8],
+                           sig: &[u8]) -> bool {
+        let (hash_type, signature) = match sig.split_last() {
+            Some((hash_type, signature)) => (*hash_type as u32, signature),
+            None => return false,
+        };
+
+        let hash = self.signature_hash(input_index, script_code, hash_type);
+        ecdsa_verify(hash.inner(), pub_key, signature)
+    }
+
+    // Signs input `input_index`, which spends the P2PKH output
+    // `prevout_script` paying to the compressed key of `secret`, and sets its
+    // sigScript. Signing is not constant time and can leak `secret` through
+    // timing, so it is only built for the tests.
+    #[cfg(test)]
+    pub fn sign_input(&mut self, input_index: usize, prevout_script: &[u8], secret: &[u8; 32],
+                      hash_type: u32) -> Result<(), String> {
+        if input_index >= self.tx_in.len() {
+            return Err(format!("{:?} has no input {}", self.hash(), input_index));
+        }
+
+        if classify(prevout_script) != ScriptType::PubKeyHash {
+            return Err(format!("Can only sign P2PKH outputs, got {:?}",
+                               classify(prevout_script)));
+        }
+
+        let key = match public_key(secret) {
+            Some(key) => key,
+            None => return Err("Invalid private key".to_string()),
+        };
+
+        if CryptoUtils::ripemd160(&CryptoUtils::sha256(&key))[..] != prevout_script[3..23] {
+            return Err(format!("Output {:?} is not paying to this key", prevout_script));
+        }
+
+        let hash = self.signature_hash(input_index, prevout_script, hash_type);
+        // The key is valid so signing can't fail
+        let mut signature = ecdsa_sign(hash.inner(), secret).unwrap();
+        signature.push(hash_type as u8);
+
+        self.tx_in[input_index].script = build_p2pkh_script_sig(&signature, &key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use net::messages::{TxMessage, TxIn, TxOut, OutPoint, BitcoinHash};
+    use net::utxo::UtxoEntry;
+    use net::validation::verify_input;
+    use script::{build_p2pkh_script_pubkey, push_data};
+    use test::utils::{get_outpoint, get_sig, get_tx};
+
+    fn get_input(sequence: u32) -> TxIn {
+        TxIn::new(get_outpoint(1), vec![], sequence)
+    }
+
+    // Spends the same output once for each sequence
+    fn get_locked_tx(lock_time: u32, sequences: Vec<u32>) -> TxMessage {
+        let mut tx = get_tx(sequences.iter().map(|_| get_outpoint(1)).collect(), vec![1000]);
+        for (input, sequence) in tx.tx_in.iter_mut().zip(sequences) {
+            input.sequence = sequence;
+        }
+
+        tx.lock_time = lock_time;
+        tx
+    }
+
+    fn spending(script_sig: Vec<u8>) -> TxMessage {
+        let mut tx = get_locked_tx(0, vec![SEQUENCE_FINAL]);
+        tx.tx_in[0].script = script_sig;
+        tx
+    }
+
+    #[test]
+    fn test_is_non_malleable() {
+        let key = vec![0x02; 33];
+
+        // <sig> <key>
+        let mut script = push_data(&get_sig(&[0x01]));
+        script.extend(push_data(&key));
+        assert!(spending(script).is_non_malleable());
+        assert!(spending(vec![0x00, 0x51]).is_non_malleable());
+
+        // The key pushed with PUSHDATA1 instead of a direct push
+        let mut script = push_data(&get_sig(&[0x01]));
+        script.extend(&[0x4c, 33]);
+        script.extend(&key);
+        assert!(!spending(script).is_non_malleable());
+
+        // 1 pushed as data instead of OP_1
+        assert!(!spending(vec![0x01, 0x01]).is_non_malleable());
+
+        // High S, which has an equivalent low S version
+        let mut high = vec![0x00];
+        high.extend(&[0xff; 32]);
+        let mut script = push_data(&get_sig(&high));
+        script.extend(push_data(&key));
+        assert!(!spending(script).is_non_malleable());
+
+        // Padded S
+        assert!(!spending(push_data(&get_sig(&[0x00, 0x01]))).is_non_malleable());
+
+        // Not push only
+        assert!(!spending(vec![0x51, 0x76]).is_non_malleable());
+    }
+
+    #[test]
+    fn test_is_final() {
+        assert!(get_locked_tx(0, vec![0]).is_final(100, 0));
+
+        // lock_time by height
+        assert!(get_locked_tx(100, vec![0]).is_final(101, 0));
+        assert!(!get_locked_tx(100, vec![0]).is_final(100, 0));
+
+        // lock_time by timestamp
+        assert!(get_locked_tx(1500000000, vec![0]).is_final(0, 1500000001));
+        assert!(!get_locked_tx(1500000000, vec![0]).is_final(1600000000, 1400000000));
+
+        // All inputs are final so lock_time is ignored
+        assert!(get_locked_tx(100, vec![SEQUENCE_FINAL, SEQUENCE_FINAL]).is_final(0, 0));
+        assert!(!get_locked_tx(100, vec![SEQUENCE_FINAL, 0]).is_final(0, 0));
+    }
+
+    fn get_p2pkh_output(value: u64) -> TxOut {
+        let mut pk_script = vec![0x76, 0xa9, 0x14];
+        pk_script.extend(vec![0x01; 20]);
+        pk_script.extend(vec![0x88, 0xac]);
+
+        TxOut::new(Amount::from_satoshis(value), pk_script)
+    }
+
+    #[test]
+    fn test_is_dust() {
+        // 546 satoshis is the threshold for P2PKH at the default fee rate
+        assert!(get_p2pkh_output(545).is_dust(DEFAULT_MIN_RELAY_TX_FEE));
+        assert!(!get_p2pkh_output(546).is_dust(DEFAULT_MIN_RELAY_TX_FEE));
+        assert!(!get_p2pkh_output(100000).is_dust(DEFAULT_MIN_RELAY_TX_FEE));
+
+        // The threshold scales with the fee rate
+        assert!(get_p2pkh_output(1000).is_dust(2 * DEFAULT_MIN_RELAY_TX_FEE));
+        assert!(!get_p2pkh_output(1).is_dust(0));
+
+        assert!(!TxOut::new(Amount::default(), vec![OP_RETURN, 0x01, 0x02]).is_dust(DEFAULT_MIN_RELAY_TX_FEE));
+    }
+
+    fn get_one_hash() -> BitcoinHash {
+        let mut one = [0; 32];
+        one[0] = 1;
+
+        BitcoinHash::new(one)
+    }
+
+    #[test]
+    fn test_signature_hash_single_bug() {
+        let tx = get_locked_tx(0, vec![SEQUENCE_FINAL, SEQUENCE_FINAL]);
+
+        // There is only one output so input 1 signs the special value 1
+        assert_eq!(tx.signature_hash(1, &[0x51], SIGHASH_SINGLE), get_one_hash());
+        assert_eq!(tx.signature_hash(1, &[0x51], SIGHASH_SINGLE | SIGHASH_ANYONECANPAY),
+                   get_one_hash());
+        assert!(tx.signature_hash(0, &[0x51], SIGHASH_SINGLE) != get_one_hash());
+        assert!(tx.signature_hash(1, &[0x51], SIGHASH_ALL) != get_one_hash());
+
+        // Same for inputs that don't exist
+        assert_eq!(tx.signature_hash(2, &[0x51], SIGHASH_ALL), get_one_hash());
+    }
+
+    #[test]
+    fn test_signature_hash() {
+        let tx = get_locked_tx(0, vec![SEQUENCE_FINAL, SEQUENCE_FINAL]);
+
+        let mut other_output = tx.clone();
+        other_output.tx_out[0].value = Amount::from_satoshis(2000);
+
+        let mut other_sequence = tx.clone();
+        other_sequence.tx_in[1].sequence = 0;
+
+        let mut extra_input = tx.clone();
+        extra_input.tx_in.push(get_input(0));
+
+        let hash = |tx: &TxMessage, hash_type| tx.signature_hash(0, &[0x51], hash_type);
+
+        assert!(hash(&tx, SIGHASH_ALL) != hash(&other_output, SIGHASH_ALL));
+        assert!(hash(&tx, SIGHASH_ALL) != hash(&other_sequence, SIGHASH_ALL));
+
+        assert_eq!(hash(&tx, SIGHASH_NONE), hash(&other_output, SIGHASH_NONE));
+        assert_eq!(hash(&tx, SIGHASH_NONE), hash(&other_sequence, SIGHASH_NONE));
+        assert_eq!(hash(&tx, SIGHASH_SINGLE), hash(&other_sequence, SIGHASH_SINGLE));
+        assert!(hash(&tx, SIGHASH_SINGLE) != hash(&other_output, SIGHASH_SINGLE));
+
+        assert!(hash(&tx, SIGHASH_ALL) != hash(&extra_input, SIGHASH_ALL));
+        assert_eq!(hash(&tx, SIGHASH_ALL | SIGHASH_ANYONECANPAY),
+                   hash(&extra_input, SIGHASH_ALL | SIGHASH_ANYONECANPAY));
+
+        // Code separators are not signed, pushed data that looks like one is
+        assert_eq!(tx.signature_hash(0, &[0xab, 0x51, 0xab], SIGHASH_ALL),
+                   tx.signature_hash(0, &[0x51], SIGHASH_ALL));
+        assert_eq!(tx.signature_hash(0, &[0x01, 0xab, 0xab], SIGHASH_ALL),
+                   tx.signature_hash(0, &[0x01, 0xab], SIGHASH_ALL));
+    }
+
+    #[test]
+    fn test_is_coinbase() {
+        assert!(!get_locked_tx(0, vec![0]).is_coinbase());
+
+        let mut coinbase = get_locked_tx(0, vec![SEQUENCE_FINAL]);
+        coinbase.tx_in[0].previous_output = OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff);
+        assert!(coinbase.is_coinbase());
+
+        coinbase.tx_in.push(get_input(0));
+        assert!(!coinbase.is_coinbase());
+    }
+
+    #[test]
+    fn test_has_duplicate_inputs() {
+        assert!(!get_locked_tx(0, vec![0]).has_duplicate_inputs());
+
+        // Both inputs spend (0x01.., 0)
+        let mut tx = get_locked_tx(0, vec![0, SEQUENCE_FINAL]);
+        assert!(tx.has_duplicate_inputs());
+
+        tx.tx_in[1].previous_output.index = 1;
+        assert!(!tx.has_duplicate_inputs());
+    }
+
+    #[test]
+    fn test_is_valid_structure() {
+        assert_eq!(get_locked_tx(0, vec![0]).is_valid_structure(), Ok(()));
+
+        let no_inputs = get_locked_tx(0, vec![]);
+        assert_eq!(no_inputs.is_valid_structure(), Err(format!("Transaction has no inputs")));
+
+        let mut no_outputs = get_locked_tx(0, vec![0]);
+        no_outputs.tx_out.clear();
+        assert_eq!(no_outputs.is_valid_structure(), Err(format!("Transaction has no outputs")));
+
+        let mut too_much = get_locked_tx(0, vec![0]);
+        too_much.tx_out.push(TxOut::new(Amount::from_satoshis(MAX_MONEY), vec![]));
+        assert!(too_much.is_valid_structure().is_err());
+
+        assert!(get_locked_tx(0, vec![0, 0]).is_valid_structure().is_err());
+
+        let mut coinbase = get_locked_tx(0, vec![SEQUENCE_FINAL]);
+        coinbase.tx_in[0].previous_output = OutPoint::new(BitcoinHash::new([0; 32]), 0xffffffff);
+        coinbase.tx_in[0].script = vec![0x01];
+        assert!(coinbase.is_valid_structure().is_err());
+        coinbase.tx_in[0].script = vec![0x01, 0x01];
+        assert_eq!(coinbase.is_valid_structure(), Ok(()));
+        coinbase.tx_in[0].script = vec![0x00; 101];
+        assert!(coinbase.is_valid_structure().is_err());
+
+        // Only the coinbase can have a null prevout
+        coinbase.tx_in[0].script = vec![0x01, 0x01];
+        coinbase.tx_in.push(get_input(0));
+        assert_eq!(coinbase.is_valid_structure(), Err(format!("Input spends a null output")));
+    }
+
+    #[test]
+    fn test_is_rbf_signaled() {
+        assert!(!get_locked_tx(0, vec![SEQUENCE_FINAL]).is_rbf_signaled());
+        assert!(!get_locked_tx(0, vec![SEQUENCE_FINAL - 1]).is_rbf_signaled());
+        assert!(get_locked_tx(0, vec![MAX_BIP125_RBF_SEQUENCE]).is_rbf_signaled());
+        assert!(get_locked_tx(0, vec![SEQUENCE_FINAL, 0]).is_rbf_signaled());
+    }
+
+    #[test]
+    fn test_fee() {
+        let tx = get_locked_tx(0, vec![0, 0]);
+        let input = |value| move |_: &OutPoint| Some(Amount::from_satoshis(value));
+
+        assert_eq!(tx.fee(&input(600)), Some(Amount::from_satoshis(200)));
+        assert_eq!(tx.fee(&input(500)), Some(Amount::default()));
+        assert_eq!(tx.fee(&input(400)), None);
+        assert_eq!(tx.fee(&|_| None), None);
+
+        assert_eq!(tx.vsize(), tx.serialized_size());
+    }
+
+    #[test]
+    fn test_relative_locktime() {
+        assert_eq!(get_input(SEQUENCE_FINAL).relative_locktime(), None);
+        assert_eq!(get_input(1 << 31 | 10).relative_locktime(), None);
+
+        assert_eq!(get_input(10).relative_locktime(), Some(RelativeLockTime::Blocks(10)));
+
+        let time_locked = get_input(1 << 22 | 0x1234);
+        assert_eq!(time_locked.relative_locktime(), Some(RelativeLockTime::Time(0x1234)));
+        assert_eq!(time_locked.relative_locktime().unwrap().as_seconds(), Some(0x1234 * 512));
+
+        // Bits outside of the mask are ignored
+        assert_eq!(get_input(1 << 22 | 1 << 20 | 0x02).relative_locktime(),
+                   Some(RelativeLockTime::Time(0x02)));
+    }
+
+    fn verify_signed(tx: &TxMessage, index: usize, spent: &UtxoEntry) -> Result<(), String> {
+        let checksig = |script_code: &[u8], pub_key: &Vec<u8>, sig: &Vec<u8>| {
+            tx.check_signature(index, script_code, pub_key, sig)
+        };
+        verify_input(tx, index, spent, ScriptFlags::default(), &checksig, None)
+    }
+
+    #[test]
+    fn test_sign_input() {
+        let secret = [0x42; 32];
+        let key = public_key(&secret).unwrap();
+        let mut key_hash = [0; 20];
+        key_hash.copy_from_slice(&CryptoUtils::ripemd160(&CryptoUtils::sha256(&key)));
+        let prevout_script = build_p2pkh_script_pubkey(&key_hash);
+
+        let spent = UtxoEntry {
+            value: Amount::from_satoshis(2000),
+            script: prevout_script.clone(),
+            height: 0,
+            coinbase: false,
+        };
+
+        let mut tx = get_locked_tx(0, vec![SEQUENCE_FINAL, SEQUENCE_FINAL]);
+        tx.sign_input(1, &prevout_script, &secret, SIGHASH_ALL).unwrap();
+        assert!(is_push_only(&tx.tx_in[1].script));
+        assert!(tx.is_non_malleable());
+        assert_eq!(verify_signed(&tx, 1, &spent), Ok(()));
+
+        // The signature commits to the outputs and to the input it signs
+        let mut changed = tx.clone();
+        changed.tx_out[0].value = Amount::from_satoshis(999);
+        assert!(verify_signed(&changed, 1, &spent).is_err());
+
+        let mut moved = tx.clone();
+        moved.tx_in[0].script = moved.tx_in[1].script.clone();
+        assert!(verify_signed(&moved, 0, &spent).is_err());
+
+        // Other outputs can be added when only the input is signed
+        tx.sign_input(0, &prevout_script, &secret, SIGHASH_NONE | SIGHASH_ANYONECANPAY).unwrap();
+        tx.tx_out.push(TxOut::new(Amount::from_satoshis(1), vec![]));
+        assert_eq!(verify_signed(&tx, 0, &spent), Ok(()));
+        assert!(verify_signed(&tx, 1, &spent).is_err());
+    }
+
+    #[test]
+    fn test_sign_input_errors() {
+        let secret = [0x42; 32];
+        let mut tx = get_locked_tx(0, vec![SEQUENCE_FINAL]);
+        let unsigned = tx.clone();
+        let other_key = build_p2pkh_script_pubkey(&[0x22; 20]);
+
+        assert!(tx.sign_input(1, &other_key, &secret, SIGHASH_ALL).is_err());
+        assert!(tx.sign_input(0, &other_key, &secret, SIGHASH_ALL).is_err());
+        assert!(tx.sign_input(0, &[0x51], &secret, SIGHASH_ALL).is_err());
+        assert!(tx.sign_input(0, &other_key, &[0; 32], SIGHASH_ALL).is_err());
+        assert_eq!(tx, unsigned);
+    }
+}
@@ -4,49 +4,76 @@ use crypto::sha2;
 use crypto::ripemd160;
 
 use std::env;
-use std::fs::{File, OpenOptions};
-use std::net::SocketAddr;
+use std::fs::File;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+
+use net::messages::NetworkType;
+use net::p2pclient::DEFAULT_SYNC_WINDOW;
+use net::store::BlockStore;
 
 pub struct Config {
     pub port: u16,
-    pub blocks_file: File,
+    pub listen_address: IpAddr,
+    // When false no inbound connections are accepted, we only dial out
+    pub listen: bool,
+    // Address advertised to peers when it differs from the listen address,
+    // e.g. behind a NAT
+    pub external_address: Option<IpAddr>,
+    pub network: NetworkType,
+    // Where the blocks of each network are saved
+    pub data_dir: PathBuf,
+    // Overrides the block file in `data_dir`
+    pub blocks_file: Option<File>,
     pub connect_to: Option<SocketAddr>,
+    // Stop syncing from a peer this many blocks before its announced height
+    pub sync_window: usize,
 }
 
 impl Config {
-    fn get_store(filename: &str) -> Result<File, String> {
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(filename)
-            .map_err(|e| format!("Error: {:?}", e))
-    }
-
     pub fn from_command_line() -> Result<Config, String> {
         let mut args = env::args();
 
         // The first argument is the filename
         args.next();
 
-        let mut config = Config {
-            port: 18333,
-            blocks_file: try!(Self::get_store("block.dat")),
-            connect_to: None,
-        };
+        Self::from_args(args)
+    }
+
+    pub fn from_args<I: Iterator<Item=String>>(mut args: I) -> Result<Config, String> {
+        let mut port = None;
+        let mut blocks_file = None;
+        let mut data_dir = PathBuf::from(".");
+        let mut listen_address = IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0));
+        let mut network = NetworkType::TestNet3;
+        let mut connect_to = None;
+        let mut listen = true;
+        let mut external_address = None;
+        let mut sync_window = DEFAULT_SYNC_WINDOW;
 
         loop {
             match args.next() {
                 Some(arg) => {
-                    let next = args.next();
                     match arg.as_ref() {
                         "-c" | "--connect" =>
-                            config.connect_to = Some(try!(Self::parse_address(next))),
+                            connect_to = Some(try!(Self::parse_address(args.next()))),
                         "-p" | "--port" =>
-                            config.port = try!(Self::parse_port(next)),
+                            port = Some(try!(Self::parse_port(args.next()))),
                         "-f" | "--block-file" =>
-                            config.blocks_file = try!(Self::parse_block_file(next)),
+                            blocks_file = Some(try!(Self::parse_block_file(args.next()))),
+                        "-l" | "--listen" =>
+                            listen_address = try!(Self::parse_listen_address(args.next())),
+                        "-e" | "--external" =>
+                            external_address = Some(
+                                try!(Self::parse_listen_address(args.next()))),
+                        "-n" | "--network" =>
+                            network = try!(Self::parse_network(args.next())),
+                        "-d" | "--data-dir" =>
+                            data_dir = try!(Self::parse_data_dir(args.next())),
+                        "--no-listen" =>
+                            listen = false,
+                        "--sync-window" =>
+                            sync_window = try!(Self::parse_sync_window(args.next())),
                         _ => try!(Self::parse_error(arg)),
                     }
                 }
@@ -54,7 +81,48 @@ impl Config {
             };
         }
 
-        Ok(config)
+        Ok(Config {
+            port: port.unwrap_or(Self::default_port(network)),
+            listen_address: listen_address,
+            listen: listen,
+            external_address: external_address,
+            network: network,
+            data_dir: data_dir,
+            blocks_file: blocks_file,
+            connect_to: connect_to,
+            sync_window: sync_window,
+        })
+    }
+
+    // The address we tell peers to connect to
+    pub fn local_address(&self) -> SocketAddr {
+        SocketAddr::new(self.external_address.unwrap_or(self.listen_address), self.port)
+    }
+
+    fn default_port(network: NetworkType) -> u16 {
+        match network {
+            NetworkType::Main => 8333,
+            _ => 18333,
+        }
+    }
+
+    fn parse_network(arg: Option<String>) -> Result<NetworkType, String> {
+        match arg {
+            Some(ref network) => match network.as_ref() {
+                "main" | "mainnet" => Ok(NetworkType::Main),
+                "testnet3" | "testnet" => Ok(NetworkType::TestNet3),
+                _ => Err(format!("Unrecognized network `{}`", network)),
+            },
+            None => Err(format!("Missing network.")),
+        }
+    }
+
+    fn parse_listen_address(arg: Option<String>) -> Result<IpAddr, String> {
+        match arg {
+            Some(address) => address.parse()
+                .map_err(|e| format!("Unrecognized address `{}`, message: {:?}", address, e)),
+            None => Err(format!("Missing listen address.")),
+        }
     }
 
     fn parse_address(arg: Option<String>) -> Result<SocketAddr, String> {
@@ -67,11 +135,18 @@ impl Config {
 
     fn parse_block_file(arg: Option<String>) -> Result<File, String> {
         match arg {
-            Some(ref path) => Self::get_store(path),
+            Some(ref path) => BlockStore::open_file(Path::new(path)),
             None => Err(format!("Missing block file.")),
         }
     }
 
+    fn parse_data_dir(arg: Option<String>) -> Result<PathBuf, String> {
+        match arg {
+            Some(path) => Ok(PathBuf::from(path)),
+            None => Err(format!("Missing data directory.")),
+        }
+    }
+
     fn parse_port(arg: Option<String>) -> Result<u16, String> {
         match arg {
             Some(ref port) => port.parse()
@@ -80,6 +155,14 @@ impl Config {
         }
     }
 
+    fn parse_sync_window(arg: Option<String>) -> Result<usize, String> {
+        match arg {
+            Some(ref window) => window.parse()
+                .map_err(|e| format!("Unrecognized sync window `{}`, message: {:?}", window, e)),
+            None => Err(format!("Missing sync window.")),
+        }
+    }
+
     fn parse_error(arg: String) -> Result<(), String> {
         Err(format!("Unrecognized param: `{}`", arg))
     }
@@ -257,11 +340,85 @@ mod tests {
     use super::*;
     use rustc_serialize::base64::FromBase64;
 
+    use net::messages::NetworkType;
+
+    use std::env;
+    use std::net::IpAddr;
+
     fn test_hash(hash: &Fn(&[u8]) -> [u8;20], input: &str, expected: &str) {
         let output = hash(&input.from_base64().unwrap());
         assert_eq!(&output, &expected.from_base64().unwrap()[..]);
     }
 
+    fn get_config(args: &[&str]) -> Result<Config, String> {
+        let block_file = env::temp_dir().join("bitcoin-rust-config-test.dat");
+        let mut all_args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        all_args.push("-f".to_string());
+        all_args.push(block_file.to_str().unwrap().to_string());
+
+        Config::from_args(all_args.into_iter())
+    }
+
+    #[test]
+    fn test_config_network() {
+        let config = get_config(&[]).unwrap();
+        assert_eq!(config.network, NetworkType::TestNet3);
+        assert_eq!(config.port, 18333);
+        assert_eq!(config.listen_address, "0.0.0.0".parse::<IpAddr>().unwrap());
+
+        let config = get_config(&["--network", "main", "-l", "127.0.0.1"]).unwrap();
+        assert_eq!(config.network, NetworkType::Main);
+        assert_eq!(config.port, 8333);
+        assert_eq!(config.listen_address, "127.0.0.1".parse::<IpAddr>().unwrap());
+
+        let config = get_config(&["-p", "1234", "-n", "mainnet"]).unwrap();
+        assert_eq!(config.network, NetworkType::Main);
+        assert_eq!(config.port, 1234);
+
+        assert!(get_config(&["-n", "regtest"]).is_err());
+    }
+
+    #[test]
+    fn test_config_no_listen() {
+        assert!(get_config(&[]).unwrap().listen);
+
+        let config = get_config(&["--no-listen", "-c", "127.0.0.1:18333"]).unwrap();
+        assert!(!config.listen);
+        assert_eq!(config.connect_to, Some("127.0.0.1:18333".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_config_external_address() {
+        let config = get_config(&["-l", "192.168.1.10", "-p", "18444"]).unwrap();
+        assert_eq!(config.local_address(), "192.168.1.10:18444".parse().unwrap());
+
+        let config = get_config(&["-l", "192.168.1.10", "-e", "203.0.113.5"]).unwrap();
+        assert_eq!(config.external_address, Some("203.0.113.5".parse().unwrap()));
+        assert_eq!(config.local_address(), "203.0.113.5:18333".parse().unwrap());
+
+        assert!(get_config(&["--external", "nowhere"]).is_err());
+    }
+
+    #[test]
+    fn test_config_data_dir() {
+        let config = Config::from_args(vec![].into_iter()).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("."));
+        assert!(config.blocks_file.is_none());
+
+        let args = vec!["-d".to_string(), "/tmp/bitcoin".to_string()];
+        let config = Config::from_args(args.into_iter()).unwrap();
+        assert_eq!(config.data_dir, PathBuf::from("/tmp/bitcoin"));
+        assert!(Config::from_args(vec!["-d".to_string()].into_iter()).is_err());
+    }
+
+    #[test]
+    fn test_config_sync_window() {
+        assert_eq!(get_config(&[]).unwrap().sync_window, DEFAULT_SYNC_WINDOW);
+        assert_eq!(get_config(&["--sync-window", "6"]).unwrap().sync_window, 6);
+        assert!(get_config(&["--sync-window", "-1"]).is_err());
+        assert!(get_config(&["--sync-window"]).is_err());
+    }
+
     #[test]
     fn test_ripemd160() {
         test_hash(&CryptoUtils::ripemd160, "MQ==", "xHkHq9KoBJLKk4iwXA44JRj/OWA=");